@@ -2,6 +2,12 @@
 
 CMD=$1
 
+# Refuse to run under a protocol this filter wasn't written for
+if [ -n "$MIRRORMAN_FILTER_PROTOCOL" ] && [ "$MIRRORMAN_FILTER_PROTOCOL" != "1" ]; then
+    echo "Unsupported filter protocol version $MIRRORMAN_FILTER_PROTOCOL" >&2
+    exit 1
+fi
+
 if [ "$CMD" == "ext" ]; then
     EXT=$2
     if [ "$EXT" == "m4a" ] || [ "$EXT" == "wav" ] || [ "$EXT" == "ogg" ] || [ "$EXT" == "flac" ]; then
@@ -0,0 +1,161 @@
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use std::{
+    fs::{create_dir_all, File},
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+};
+use zip::ZipArchive;
+
+/// An archive which can stand in for a source directory
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl ArchiveKind {
+    /// Detects an archive from its file name, `None` if `path` isn't a supported archive file
+    pub fn detect(path: &Path) -> Option<Self> {
+        if !path.is_file() {
+            return None;
+        }
+
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Self::TarGz)
+        } else if name.ends_with(".tar") {
+            Some(Self::Tar)
+        } else if name.ends_with(".zip") {
+            Some(Self::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// A single entry of an archive, extracted to a staging directory
+pub struct ArchiveEntry {
+    /// Path of the entry within the archive
+    pub relative: PathBuf,
+    /// Where the entry was extracted to (or created, for directories)
+    pub staged: PathBuf,
+}
+
+/// Counts the entries in `archive`, so progress can be reported while extracting
+pub fn count_entries(archive: &Path, kind: ArchiveKind) -> Result<usize> {
+    let file = open(archive)?;
+    match kind {
+        ArchiveKind::Tar => count_tar_entries(file),
+        ArchiveKind::TarGz => count_tar_entries(GzDecoder::new(file)),
+        ArchiveKind::Zip => Ok(ZipArchive::new(file)
+            .with_context(|| format!("Failed to read zip archive `{0}`", archive.display()))?
+            .len()),
+    }
+    .with_context(|| format!("Failed to read archive `{0}`", archive.display()))
+}
+
+/// Extracts the entries of `archive` into `staging` one at a time, handing each to `handle`,
+/// which is responsible for removing the staged file once it's done with it
+pub fn for_each_entry(
+    archive: &Path,
+    kind: ArchiveKind,
+    staging: &Path,
+    mut handle: impl FnMut(ArchiveEntry) -> Result<()>,
+) -> Result<()> {
+    let file = open(archive)?;
+    match kind {
+        ArchiveKind::Tar => for_each_tar_entry(file, staging, &mut handle),
+        ArchiveKind::TarGz => for_each_tar_entry(GzDecoder::new(file), staging, &mut handle),
+        ArchiveKind::Zip => for_each_zip_entry(file, staging, &mut handle),
+    }
+    .with_context(|| format!("Failed to extract archive `{0}`", archive.display()))
+}
+
+fn open(archive: &Path) -> Result<File> {
+    File::open(archive).with_context(|| format!("Failed to open archive `{0}`", archive.display()))
+}
+
+fn count_tar_entries(reader: impl Read) -> Result<usize> {
+    let mut count = 0;
+    for entry in tar::Archive::new(reader).entries()? {
+        entry?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn for_each_tar_entry(
+    reader: impl Read,
+    staging: &Path,
+    handle: &mut impl FnMut(ArchiveEntry) -> Result<()>,
+) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let relative = checked_entry_path(&entry.path()?)?;
+        let entry_type = entry.header().entry_type();
+
+        let staged = staging.join(&relative);
+        if entry_type.is_dir() {
+            create_dir_all(&staged)?;
+        } else if entry_type.is_file() {
+            create_parent(&staged)?;
+            entry.unpack(&staged)?;
+        } else {
+            log::debug!(
+                "Skipping archive entry `{0}`, only files and directories are mirrored",
+                relative.display()
+            );
+            continue;
+        };
+        handle(ArchiveEntry { relative, staged })?;
+    }
+    Ok(())
+}
+
+fn for_each_zip_entry(
+    file: File,
+    staging: &Path,
+    handle: &mut impl FnMut(ArchiveEntry) -> Result<()>,
+) -> Result<()> {
+    let mut archive = ZipArchive::new(file)?;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let relative = match entry.enclosed_name() {
+            Some(relative) => checked_entry_path(&relative)?,
+            None => bail!("Archive entry `{0}` escapes the archive", entry.name()?),
+        };
+
+        let staged = staging.join(&relative);
+        if entry.is_dir() {
+            create_dir_all(&staged)?;
+        } else {
+            create_parent(&staged)?;
+            io::copy(&mut entry, &mut File::create(&staged)?)?;
+        }
+        handle(ArchiveEntry { relative, staged })?;
+    }
+    Ok(())
+}
+
+/// Rejects entry paths which would land outside the archive root (e.g. `../x` or `/x`)
+fn checked_entry_path(path: &Path) -> Result<PathBuf> {
+    let relative = path
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect::<PathBuf>();
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        bail!("Archive entry `{0}` escapes the archive", path.display());
+    }
+    Ok(relative)
+}
+
+fn create_parent(path: &Path) -> Result<()> {
+    create_dir_all(path.parent().with_context(|| "Failed to get file parent")?)
+        .with_context(|| format!("Failed to create directory for `{0}`", path.display()))
+}
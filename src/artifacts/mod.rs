@@ -0,0 +1,83 @@
+use anyhow::{Context, Result};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A directory every artifact of a sync (manifest, report, trace and filter log) is written to,
+/// named `{name}-{timestamp}.{artifact}` so repeated runs collect side by side rather than
+/// overwriting each other
+pub struct OutputDir {
+    directory: PathBuf,
+    prefix: String,
+}
+
+impl OutputDir {
+    /// Creates `directory` if it doesn't exist, naming artifacts after `name` and the current time
+    pub fn create(directory: &Path, name: &str) -> Result<Self> {
+        fs::create_dir_all(directory).with_context(|| {
+            format!(
+                "Failed to create output directory `{0}`",
+                directory.display()
+            )
+        })?;
+        Ok(Self {
+            directory: directory.to_path_buf(),
+            prefix: format!("{name}-{0}", timestamp_now()),
+        })
+    }
+
+    /// Where the artifact with the given extension is written, e.g. `report.md`
+    pub fn path(&self, artifact: &str) -> PathBuf {
+        self.directory.join(format!("{0}.{artifact}", self.prefix))
+    }
+}
+
+/// The current time as a `YYYYMMDDTHHMMSSZ` timestamp
+pub fn timestamp_now() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    timestamp(seconds)
+}
+
+/// Formats seconds since the Unix epoch as a UTC `YYYYMMDDTHHMMSSZ` timestamp, which sorts
+/// chronologically and is safe in file names everywhere
+fn timestamp(seconds: u64) -> String {
+    let (days, time) = (seconds / 86400, seconds % 86400);
+    // Civil date from days since the epoch, counting in 400 year eras which start on March 1st
+    let days = days as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}{month:02}{day:02}T{0:02}{1:02}{2:02}Z",
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::timestamp;
+
+    #[test]
+    fn formats_utc_timestamps() {
+        assert_eq!(timestamp(0), "19700101T000000Z");
+        assert_eq!(timestamp(951782400), "20000229T000000Z");
+        assert_eq!(timestamp(1792111815), "20261016T005015Z");
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    sync::{Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// How many files are mirrored at once before anything has been measured
+const START_LIMIT: usize = 2;
+
+/// How long throughput is measured for at each limit
+const WINDOW: Duration = Duration::from_millis(500);
+
+/// How much faster a higher limit has to be to keep it, so noise doesn't push the limit up
+const MIN_GAIN: f64 = 1.05;
+
+/// Tunes how many files are mirrored at once (`--concurrency-auto`). Starting from a modest limit,
+/// throughput is measured for a while at each limit, doubling it for as long as that helps. Once
+/// it stops helping (or there are no more threads to use), the limit settles on whichever was
+/// fastest for the rest of the sync.
+pub struct AutoConcurrency {
+    max: usize,
+    tuning: Mutex<Tuning>,
+    available: Condvar,
+}
+
+struct Tuning {
+    limit: usize,
+    active: usize,
+    window_start: Instant,
+    // Bytes processed when the current window started
+    window_bytes: u64,
+    // The fastest limit so far and its throughput, in bytes per second
+    best: Option<(usize, f64)>,
+    settled: bool,
+}
+
+pub struct AutoConcurrencySlot<'a> {
+    auto: &'a AutoConcurrency,
+}
+
+impl AutoConcurrency {
+    /// Tunes between one file at a time and `max`, e.g. the number of worker threads
+    pub fn new(max: usize) -> Self {
+        let max = max.max(1);
+        Self {
+            max,
+            tuning: Mutex::new(Tuning {
+                limit: START_LIMIT.min(max),
+                active: 0,
+                window_start: Instant::now(),
+                window_bytes: 0,
+                best: None,
+                settled: false,
+            }),
+            available: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Tuning> {
+        match self.tuning.lock() {
+            Ok(tuning) => tuning,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// Waits until fewer files than the current limit are being mirrored
+    pub fn acquire(&self) -> AutoConcurrencySlot<'_> {
+        let mut tuning = self.lock();
+        while tuning.active >= tuning.limit {
+            tuning = match self.available.wait(tuning) {
+                Ok(tuning) => tuning,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+        }
+        tuning.active += 1;
+        AutoConcurrencySlot { auto: self }
+    }
+
+    /// Called as files finish with the total bytes processed so far, moving on to the next limit
+    /// once the current one has been measured for long enough
+    pub fn measure(&self, processed: u64) {
+        let mut tuning = self.lock();
+        let elapsed = tuning.window_start.elapsed();
+        if tuning.settled || elapsed < WINDOW {
+            return;
+        }
+
+        let throughput =
+            processed.saturating_sub(tuning.window_bytes) as f64 / elapsed.as_secs_f64();
+        let improved = tuning
+            .best
+            .is_none_or(|(_, best)| throughput > best * MIN_GAIN);
+        if improved {
+            tuning.best = Some((tuning.limit, throughput));
+        }
+        if improved && tuning.limit < self.max {
+            log::debug!(
+                "{0:.2} MB/s mirroring {1} files at once, trying more...",
+                throughput / 1_000_000.0,
+                tuning.limit
+            );
+            tuning.limit = (tuning.limit * 2).min(self.max);
+        } else if let Some((limit, best)) = tuning.best {
+            tuning.limit = limit;
+            tuning.settled = true;
+            log::info!(
+                "Settled on mirroring {limit} files at once ({0:.2} MB/s)",
+                best / 1_000_000.0
+            );
+        }
+        tuning.window_start = Instant::now();
+        tuning.window_bytes = processed;
+        self.available.notify_all();
+    }
+}
+
+impl Drop for AutoConcurrencySlot<'_> {
+    fn drop(&mut self) {
+        self.auto.lock().active -= 1;
+        self.auto.available.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AutoConcurrency, WINDOW};
+    use std::time::Instant;
+
+    /// Ends the current window as if `bytes` more had been processed over exactly `WINDOW`
+    fn finish_window(auto: &AutoConcurrency, processed: &mut u64, bytes: u64) {
+        auto.lock().window_start = Instant::now() - WINDOW;
+        *processed += bytes;
+        auto.measure(*processed);
+    }
+
+    #[test]
+    fn settles_on_the_fastest_limit() {
+        let auto = AutoConcurrency::new(16);
+        let mut processed = 0;
+        assert_eq!(auto.lock().limit, 2);
+        finish_window(&auto, &mut processed, 1_000_000);
+        assert_eq!(auto.lock().limit, 4);
+        finish_window(&auto, &mut processed, 2_000_000);
+        assert_eq!(auto.lock().limit, 8);
+        // Slower, so it goes back down and stays there
+        finish_window(&auto, &mut processed, 1_500_000);
+        assert_eq!(auto.lock().limit, 4);
+        finish_window(&auto, &mut processed, 10_000_000);
+        assert_eq!(auto.lock().limit, 4);
+    }
+
+    #[test]
+    fn never_exceeds_the_maximum() {
+        let auto = AutoConcurrency::new(3);
+        let mut processed = 0;
+        finish_window(&auto, &mut processed, 1_000_000);
+        assert_eq!(auto.lock().limit, 3);
+        finish_window(&auto, &mut processed, 2_000_000);
+        assert_eq!(auto.lock().limit, 3);
+    }
+}
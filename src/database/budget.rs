@@ -0,0 +1,34 @@
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+/// How long a run of `sync` may spend starting on files (`--time-budget`). Once it's up, files
+/// already being mirrored are finished, the rest are left for the next sync.
+pub struct TimeBudget {
+    deadline: Instant,
+    deferred: AtomicUsize,
+}
+
+impl TimeBudget {
+    pub fn new(budget: Duration) -> Self {
+        Self {
+            deadline: Instant::now() + budget,
+            deferred: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether the budget is up, in which case the entry asking is counted as deferred
+    pub fn defer(&self) -> bool {
+        let exhausted = Instant::now() >= self.deadline;
+        if exhausted {
+            self.deferred.fetch_add(1, Ordering::Relaxed);
+        }
+        exhausted
+    }
+
+    /// Entries left for the next sync, across every database synced
+    pub fn deferred(&self) -> usize {
+        self.deferred.load(Ordering::Relaxed)
+    }
+}
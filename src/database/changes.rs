@@ -0,0 +1,20 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Added,
+    Changed,
+}
+
+/// What the last sync did to the mirror, for reporting
+#[derive(Default, Clone)]
+pub struct SyncChanges {
+    /// Source files (relative to the database) mirrored for the first time
+    pub added: Vec<PathBuf>,
+    /// Source files (relative to the database) mirrored again as their contents changed
+    pub changed: Vec<PathBuf>,
+    /// Mirror files (relative to the mirror root) removed as their source no longer exists
+    pub removed: Vec<PathBuf>,
+    /// Key = Added or changed source file, Value = Its hash, only filled in by plans
+    pub hashes: BTreeMap<PathBuf, String>,
+}
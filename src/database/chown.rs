@@ -0,0 +1,149 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    io::{self, ErrorKind},
+    path::Path,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// A fixed owner given to every file written to the mirror, whoever owns its source
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Owner {
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+impl FromStr for Owner {
+    type Err = anyhow::Error;
+
+    /// Parses `{user}:{group}`, `{user}` or `:{group}`, each a name or a numeric id
+    fn from_str(s: &str) -> Result<Self> {
+        let (user, group) = match s.split_once(':') {
+            Some((user, group)) => (user, group),
+            None => (s, ""),
+        };
+        let uid = (!user.is_empty())
+            .then(|| resolve_id(user, "user", lookup_user))
+            .transpose()?;
+        let gid = (!group.is_empty())
+            .then(|| resolve_id(group, "group", lookup_group))
+            .transpose()?;
+        if uid.is_none() && gid.is_none() {
+            bail!("Invalid owner `{s}`, expected `{{user}}:{{group}}`");
+        }
+        Ok(Self { uid, gid })
+    }
+}
+
+fn resolve_id(name: &str, kind: &str, lookup: fn(&str) -> Option<u32>) -> Result<u32> {
+    match name.parse() {
+        Ok(id) => Ok(id),
+        Err(_) => lookup(name).with_context(|| format!("There's no {kind} named `{name}`")),
+    }
+}
+
+#[cfg(unix)]
+fn lookup_user(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    // SAFETY: `name` is a valid C string, and the entry is read before anything else could call
+    // `getpwnam` again, as owners are parsed along with the arguments
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    (!entry.is_null()).then(|| unsafe { (*entry).pw_uid })
+}
+
+#[cfg(unix)]
+fn lookup_group(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+    // SAFETY: As for `getpwnam` above
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    (!entry.is_null()).then(|| unsafe { (*entry).gr_gid })
+}
+
+#[cfg(not(unix))]
+fn lookup_user(_name: &str) -> Option<u32> {
+    None
+}
+
+#[cfg(not(unix))]
+fn lookup_group(_name: &str) -> Option<u32> {
+    None
+}
+
+/// Gives mirror files a fixed owner. Without the privileges to do so (or off Unix), a warning is
+/// logged once and files are left owned by whoever runs the sync, rather than failing every file.
+pub struct Chown {
+    owner: Owner,
+    unavailable: AtomicBool,
+}
+
+impl Chown {
+    pub fn new(owner: Owner) -> Self {
+        Self {
+            owner,
+            unavailable: AtomicBool::new(false),
+        }
+    }
+
+    pub fn apply(&self, path: &Path) -> Result<()> {
+        if self.unavailable.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        match chown(path, self.owner) {
+            Ok(()) => Ok(()),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::PermissionDenied | ErrorKind::Unsupported
+                ) =>
+            {
+                if !self.unavailable.swap(true, Ordering::Relaxed) {
+                    log::warn!(
+                        "Can't change the owner of `{0}` ({e}), mirror files will be left owned by the user running the sync. `--chown` needs root (or `CAP_CHOWN`) on Unix.",
+                        path.display()
+                    );
+                }
+                Ok(())
+            }
+            Err(e) => Err(e)
+                .with_context(|| format!("Failed to change the owner of `{0}`", path.display())),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn chown(path: &Path, owner: Owner) -> io::Result<()> {
+    std::os::unix::fs::chown(path, owner.uid, owner.gid)
+}
+
+#[cfg(not(unix))]
+fn chown(_path: &Path, _owner: Owner) -> io::Result<()> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "owners can only be changed on Unix",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Owner;
+
+    #[test]
+    fn parses_numeric_owners() {
+        let owner = "1000:100".parse::<Owner>().unwrap();
+        assert_eq!((owner.uid, owner.gid), (Some(1000), Some(100)));
+        let owner = "1000".parse::<Owner>().unwrap();
+        assert_eq!((owner.uid, owner.gid), (Some(1000), None));
+        let owner = ":100".parse::<Owner>().unwrap();
+        assert_eq!((owner.uid, owner.gid), (None, Some(100)));
+        assert!(":".parse::<Owner>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolves_names() {
+        let owner = "root:root".parse::<Owner>().unwrap();
+        assert_eq!((owner.uid, owner.gid), (Some(0), Some(0)));
+        assert!("no-such-user-mirrorman".parse::<Owner>().is_err());
+    }
+}
@@ -0,0 +1,29 @@
+use super::SyncSettings;
+use crate::transform::Transform;
+use std::{collections::BTreeMap, path::PathBuf};
+
+/// Configuration for a new database, fixed at `init`
+#[derive(Default)]
+pub struct DatabaseConfig {
+    pub filters: Vec<String>,
+    pub filter_dirs: BTreeMap<String, PathBuf>,
+    pub filter_paths: BTreeMap<String, Vec<String>>,
+    pub filter_chains: BTreeMap<String, Vec<String>>,
+    pub filter_configs: BTreeMap<String, PathBuf>,
+    /// Directory filters given as relative paths are found in, the database's directory if `None`.
+    /// Relative to the current directory until the database is created.
+    pub filters_dir: Option<PathBuf>,
+    pub transforms: Vec<Transform>,
+    pub mirror_template: Option<String>,
+    pub flatten: bool,
+    /// Directory the source's files are laid out in the mirror relative to, the source itself if
+    /// `None`. Relative to the current directory until the database is created.
+    pub relative_to: Option<PathBuf>,
+    /// Only clean up mirror files the database itself wrote, so the mirror can be shared
+    pub owned_cleanup: bool,
+    /// Only ever add files to the mirror, never overwriting or removing any
+    pub archive_append: bool,
+    pub encryption_key_file: Option<PathBuf>,
+    /// Defaults for every sync of the database
+    pub settings: SyncSettings,
+}
@@ -0,0 +1,320 @@
+use super::write_atomically;
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+/// Files smaller than this are always copied whole, as comparing them isn't worth it
+pub const DELTA_MIN_SIZE: u64 = 16 * 1024 * 1024;
+
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Modulus of the rolling checksum, the largest prime below 2^16 as in Adler-32
+const MODULUS: u64 = 65521;
+
+/// Rebuilds an existing `mirror` to match `source` rsync-style, and returns the number of bytes
+/// taken from `source` rather than from `mirror` itself.
+///
+/// The mirror's blocks are indexed by a rolling checksum, which is slid along the source a byte at
+/// a time, so blocks are found wherever data inserted or removed earlier has shifted them to. The
+/// result is written through a temporary file renamed over the mirror, so a failure part way
+/// through leaves the old mirror whole.
+pub fn delta_copy(source: &Path, mirror: &Path) -> Result<u64> {
+    let signature = Signature::of(mirror)?;
+    let source_file = File::open(source)
+        .with_context(|| format!("Failed to open `{0}` for reading", source.display()))?;
+    let mut source_file = BufReader::new(source_file);
+
+    let mut literal_bytes = 0;
+    write_atomically(mirror, None, |file| {
+        let mut mirror_file = File::open(mirror)
+            .with_context(|| format!("Failed to open `{0}` for reading", mirror.display()))?;
+        let mut writer = BufWriter::new(file);
+        let mut window = Window::default();
+        loop {
+            let filled = window
+                .fill(&mut source_file)
+                .with_context(|| format!("Failed to read `{0}`", source.display()))?;
+            if !filled {
+                break;
+            }
+            let block = window.block();
+            let found = signature.find(window.checksum.value(), block);
+            let Some(index) = found else {
+                window.slide();
+                if window.literal().len() >= BLOCK_SIZE {
+                    literal_bytes += window.flush_literal(&mut writer)?;
+                }
+                continue;
+            };
+            literal_bytes += window.flush_literal(&mut writer)?;
+            copy_block(&mut mirror_file, index, &mut writer)
+                .with_context(|| format!("Failed to read `{0}`", mirror.display()))?;
+            window.skip_block();
+        }
+
+        // What's left is shorter than a block, and can only match the mirror's last one
+        let tail = window.block();
+        match signature.tail.as_ref() {
+            Some((index, digest)) if !tail.is_empty() && Sha256::digest(tail)[..] == digest[..] => {
+                literal_bytes += window.flush_literal(&mut writer)?;
+                copy_block(&mut mirror_file, *index, &mut writer)
+                    .with_context(|| format!("Failed to read `{0}`", mirror.display()))?;
+            }
+            _ => {
+                window.start += tail.len();
+                literal_bytes += window.flush_literal(&mut writer)?;
+            }
+        }
+        writer
+            .flush()
+            .with_context(|| format!("Failed to write `{0}`", mirror.display()))
+    })?;
+    Ok(literal_bytes)
+}
+
+/// The mirror's blocks, found by rolling checksum and confirmed by SHA-256
+struct Signature {
+    // Key = Rolling checksum, Value = Index and digest of every full block with it
+    blocks: HashMap<u32, Vec<(u64, [u8; 32])>>,
+    // Index and digest of the last block, if it's shorter than the rest
+    tail: Option<(u64, [u8; 32])>,
+}
+
+impl Signature {
+    fn of(mirror: &Path) -> Result<Self> {
+        let mirror_file = File::open(mirror)
+            .with_context(|| format!("Failed to open `{0}` for reading", mirror.display()))?;
+        let mut mirror_file = BufReader::new(mirror_file);
+        let mut signature = Self {
+            blocks: HashMap::new(),
+            tail: None,
+        };
+        let mut block = vec![0; BLOCK_SIZE];
+        for index in 0.. {
+            let read = read_block(&mut mirror_file, &mut block)
+                .with_context(|| format!("Failed to read `{0}`", mirror.display()))?;
+            let digest = Sha256::digest(&block[..read]).into();
+            match read {
+                0 => break,
+                BLOCK_SIZE => signature
+                    .blocks
+                    .entry(Checksum::of(&block).value())
+                    .or_default()
+                    .push((index, digest)),
+                _ => {
+                    signature.tail = Some((index, digest));
+                    break;
+                }
+            }
+        }
+        Ok(signature)
+    }
+
+    /// The index of a mirror block holding exactly `block`, which has the rolling `checksum`
+    fn find(&self, checksum: u32, block: &[u8]) -> Option<u64> {
+        let candidates = self.blocks.get(&checksum)?;
+        let digest = Sha256::digest(block);
+        candidates
+            .iter()
+            .find(|(_, candidate)| candidate[..] == digest[..])
+            .map(|(index, _)| *index)
+    }
+}
+
+/// Adler-32 style checksum of a block, which can be slid along a byte at a time
+#[derive(Default)]
+struct Checksum {
+    a: u64,
+    b: u64,
+}
+
+impl Checksum {
+    fn of(block: &[u8]) -> Self {
+        let len = block.len() as u64;
+        let mut checksum = Self { a: 1, b: len };
+        for (i, byte) in block.iter().enumerate() {
+            checksum.a += u64::from(*byte);
+            checksum.b += (len - i as u64) * u64::from(*byte);
+        }
+        checksum.a %= MODULUS;
+        checksum.b %= MODULUS;
+        checksum
+    }
+
+    /// Slides a full block's window along by one byte, dropping `out` and taking in `into`
+    fn roll(&mut self, out: u8, into: u8) {
+        let (out, into) = (u64::from(out), u64::from(into));
+        self.a = (self.a + MODULUS - out + into) % MODULUS;
+        let dropped = BLOCK_SIZE as u64 * out % MODULUS;
+        self.b = (self.b + 2 * MODULUS + self.a - 1 - dropped) % MODULUS;
+    }
+
+    fn value(&self) -> u32 {
+        (self.b << 16 | self.a) as u32
+    }
+}
+
+/// Source bytes buffered for comparison: a block at `start`, after the literal bytes still to be
+/// written since `literal_start`
+#[derive(Default)]
+struct Window {
+    buffer: Vec<u8>,
+    literal_start: usize,
+    start: usize,
+    checksum: Checksum,
+    // Whether the checksum is of the current block, rather than needing to be recomputed
+    rolling: bool,
+    eof: bool,
+}
+
+impl Window {
+    /// Buffers a full block at `start` if the source has one, returning whether it does
+    fn fill(&mut self, source: &mut impl Read) -> io::Result<bool> {
+        let needed = self.start + BLOCK_SIZE + 1;
+        if self.buffer.len() < needed && !self.eof {
+            // Written bytes aren't needed again
+            self.buffer.drain(..self.literal_start);
+            self.start -= self.literal_start;
+            self.literal_start = 0;
+            let wanted = (self.start + 4 * BLOCK_SIZE).saturating_sub(self.buffer.len());
+            let read = source.take(wanted as u64).read_to_end(&mut self.buffer)?;
+            self.eof = read < wanted;
+        }
+        if self.buffer.len() < self.start + BLOCK_SIZE {
+            return Ok(false);
+        }
+        if !self.rolling {
+            self.checksum = Checksum::of(self.block());
+            self.rolling = true;
+        }
+        Ok(true)
+    }
+
+    fn block(&self) -> &[u8] {
+        let end = (self.start + BLOCK_SIZE).min(self.buffer.len());
+        &self.buffer[self.start..end]
+    }
+
+    fn literal(&self) -> &[u8] {
+        &self.buffer[self.literal_start..self.start]
+    }
+
+    /// Moves along a byte which matched nothing, leaving it to be written as it is
+    fn slide(&mut self) {
+        match self.buffer.get(self.start + BLOCK_SIZE) {
+            Some(into) => self.checksum.roll(self.buffer[self.start], *into),
+            None => self.rolling = false,
+        }
+        self.start += 1;
+    }
+
+    /// Moves past a block which was found in the mirror
+    fn skip_block(&mut self) {
+        self.start += BLOCK_SIZE;
+        self.literal_start = self.start;
+        self.rolling = false;
+    }
+
+    /// Writes the literal bytes before the current block, returning how many there were
+    fn flush_literal(&mut self, writer: &mut impl Write) -> Result<u64> {
+        let literal = self.literal();
+        writer.write_all(literal)?;
+        let written = literal.len() as u64;
+        self.literal_start = self.start;
+        Ok(written)
+    }
+}
+
+/// Copies the block at `index` of `mirror` to `writer`
+fn copy_block(mirror: &mut File, index: u64, writer: &mut impl Write) -> io::Result<()> {
+    mirror.seek(SeekFrom::Start(index * BLOCK_SIZE as u64))?;
+    io::copy(&mut mirror.take(BLOCK_SIZE as u64), writer)?;
+    Ok(())
+}
+
+/// Fills `block` as far as possible, only returning less at the end of the file
+fn read_block(file: &mut impl Read, block: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < block.len() {
+        match file.read(&mut block[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{delta_copy, Checksum, BLOCK_SIZE};
+    use std::{
+        env::temp_dir,
+        fs::{self, create_dir_all, remove_dir_all},
+        process,
+    };
+
+    /// Pseudo-random bytes, so no two blocks are alike
+    fn contents(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rolling_matches_recomputing() {
+        let data = contents(BLOCK_SIZE + 100, 1);
+        let mut checksum = Checksum::of(&data[..BLOCK_SIZE]);
+        for start in 1..=100 {
+            checksum.roll(data[start - 1], data[start + BLOCK_SIZE - 1]);
+            let expected = Checksum::of(&data[start..start + BLOCK_SIZE]);
+            assert_eq!(checksum.value(), expected.value(), "{start}");
+        }
+    }
+
+    #[test]
+    fn shifted_blocks_are_reused() {
+        let dir = temp_dir().join(format!("mirrorman-test-{0}-delta", process::id()));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        let original = contents(8 * BLOCK_SIZE + 123, 2);
+
+        // Bytes inserted near the start, a block changed in the middle, and the end cut short
+        let mut changed = original.clone();
+        changed.splice(10..10, *b"inserted");
+        changed[4 * BLOCK_SIZE..4 * BLOCK_SIZE + 5].copy_from_slice(b"edits");
+        changed.truncate(changed.len() - 50);
+
+        // As (source, bytes which had to come from it rather than the mirror)
+        let cases = [
+            (original.clone(), 0),
+            // The first and edited blocks, and the end, which no longer matches the last block
+            (changed, (BLOCK_SIZE + 8 + BLOCK_SIZE + 73) as u64),
+            (original[BLOCK_SIZE..].to_vec(), 0),
+            (Vec::new(), 0),
+        ];
+        for (index, (source, literal)) in cases.iter().enumerate() {
+            let source_path = dir.join(format!("{index}.source"));
+            let mirror_path = dir.join(format!("{index}.mirror"));
+            fs::write(&source_path, source).unwrap();
+            fs::write(&mirror_path, &original).unwrap();
+            assert_eq!(
+                delta_copy(&source_path, &mirror_path).unwrap(),
+                *literal,
+                "{index}"
+            );
+            assert_eq!(&fs::read(&mirror_path).unwrap(), source, "{index}");
+        }
+        remove_dir_all(dir).unwrap();
+    }
+}
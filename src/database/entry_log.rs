@@ -0,0 +1,54 @@
+use log::Level;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+// Keep messages attributed to the database module, where they were logged before buffering
+const TARGET: &str = "mirrorman::database";
+
+/// Per-entry log messages, either logged immediately or buffered and flushed sorted by path so
+/// that output is reproducible between runs.
+pub struct EntryLog {
+    ordered: bool,
+    messages: Mutex<BTreeMap<PathBuf, Vec<(Level, String)>>>,
+}
+
+impl EntryLog {
+    pub fn new(ordered: bool) -> Self {
+        Self {
+            ordered,
+            messages: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn log(&self, entry: &Path, level: Level, message: String) {
+        if !self.ordered {
+            log::log!(target: TARGET, level, "{message}");
+            return;
+        }
+        if !log::log_enabled!(target: TARGET, level) {
+            return;
+        }
+
+        let mut messages = match self.messages.lock() {
+            Ok(messages) => messages,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        messages
+            .entry(entry.to_path_buf())
+            .or_default()
+            .push((level, message));
+    }
+
+    pub fn flush(&self) {
+        let mut messages = match self.messages.lock() {
+            Ok(messages) => messages,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        for (level, message) in std::mem::take(&mut *messages).into_values().flatten() {
+            log::log!(target: TARGET, level, "{message}");
+        }
+    }
+}
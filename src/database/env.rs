@@ -0,0 +1,99 @@
+use anyhow::{bail, Result};
+use std::{env, path::PathBuf};
+
+/// Expands `$VAR` and `${VAR}` environment variables anywhere in `path`, and a leading `~` to the
+/// home directory, so one database can be used on machines which mount drives in different places.
+/// A `$` not followed by a variable name is kept as it is.
+pub fn expand_env(path: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(path.len());
+    let mut rest = path;
+    if let Some(after) = rest.strip_prefix('~') {
+        if after.is_empty() || after.starts_with(['/', '\\']) {
+            expanded.push_str(&variable(path, home_var())?);
+            rest = after;
+        }
+    }
+
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, remainder) = match after.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => bail!("Unclosed `${{` in `{path}`"),
+            },
+            None => {
+                let end = after
+                    .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                    .unwrap_or(after.len());
+                (&after[..end], &after[end..])
+            }
+        };
+        if name.is_empty() {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        expanded.push_str(&variable(path, name)?);
+        rest = remainder;
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn variable(path: &str, name: &str) -> Result<String> {
+    match env::var(name) {
+        Ok(value) => Ok(value),
+        Err(_) => bail!("`{path}` refers to the environment variable `{name}`, which isn't set"),
+    }
+}
+
+/// The current user's home directory, if it's known
+pub fn home_dir() -> Option<PathBuf> {
+    env::var_os(home_var())
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+}
+
+fn home_var() -> &'static str {
+    match cfg!(windows) {
+        true => "USERPROFILE",
+        false => "HOME",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::expand_env;
+
+    #[test]
+    fn expands_variables() {
+        std::env::set_var("MIRRORMAN_TEST_ROOT", "/mnt/backup");
+        assert_eq!(
+            expand_env("$MIRRORMAN_TEST_ROOT/photos").unwrap(),
+            "/mnt/backup/photos"
+        );
+        assert_eq!(
+            expand_env("${MIRRORMAN_TEST_ROOT}_old/photos").unwrap(),
+            "/mnt/backup_old/photos"
+        );
+        assert_eq!(expand_env("photos/$/a").unwrap(), "photos/$/a");
+    }
+
+    #[test]
+    fn unset_variables_are_errors() {
+        std::env::remove_var("MIRRORMAN_TEST_UNSET");
+        assert!(expand_env("$MIRRORMAN_TEST_UNSET/photos").is_err());
+        assert!(expand_env("${MIRRORMAN_TEST_ROOT").is_err());
+    }
+
+    #[test]
+    fn expands_leading_tilde_only() {
+        let home = std::env::var("HOME").unwrap_or_default();
+        if cfg!(unix) && !home.is_empty() {
+            assert_eq!(expand_env("~/photos").unwrap(), format!("{home}/photos"));
+        }
+        assert_eq!(expand_env("photos/~a").unwrap(), "photos/~a");
+        assert_eq!(expand_env("~user/photos").unwrap(), "~user/photos");
+    }
+}
@@ -0,0 +1,73 @@
+use base32::{encode, Alphabet};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+/// Length of the suffix disambiguating flattened files which share a name
+const SUFFIX_LENGTH: usize = 8;
+
+/// Suffixes for every one of a sync's source files (relative to the source root) which shares a
+/// stem with another, and so would collide once flattened. Stems are compared rather than full
+/// names, as filters may give files with different extensions the same one. They all get a hash of
+/// their path appended, so a mirror file never changes hands between source files as others come
+/// and go (which unchanged files wouldn't notice).
+pub fn flatten_suffixes(
+    relative_entries: impl IntoIterator<Item = PathBuf>,
+) -> BTreeMap<PathBuf, String> {
+    let mut by_stem = BTreeMap::<OsString, Vec<PathBuf>>::new();
+    for relative_entry in relative_entries {
+        by_stem
+            .entry(
+                relative_entry
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_os_string(),
+            )
+            .or_default()
+            .push(relative_entry);
+    }
+
+    let suffixes = by_stem
+        .into_values()
+        .filter(|paths| paths.len() > 1)
+        .flatten()
+        .map(|path| {
+            let hash = Sha256::digest(path.to_string_lossy().as_bytes());
+            let mut suffix = encode(Alphabet::Crockford, &hash);
+            suffix.truncate(SUFFIX_LENGTH);
+            (path, suffix)
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    if !suffixes.is_empty() {
+        log::warn!(
+            "{0} files share a name with another once flattened, they've been given a hash suffix (e.g. `song-{1}.mp3`)",
+            suffixes.len(),
+            "X".repeat(SUFFIX_LENGTH)
+        );
+    }
+    suffixes
+}
+
+/// The flattened mirror path of a file, relative to the mirror root: just its file name, with
+/// `suffix` appended to the stem if there is one
+pub fn flat_mirror_path(relative_mirror: &Path, suffix: Option<&str>) -> PathBuf {
+    let file_name = relative_mirror.file_name().unwrap_or_default();
+    let Some(suffix) = suffix else {
+        return PathBuf::from(file_name);
+    };
+
+    let mut flat = relative_mirror
+        .file_stem()
+        .unwrap_or_default()
+        .to_os_string();
+    flat.push(format!("-{suffix}"));
+    if let Some(extension) = relative_mirror.extension() {
+        flat.push(".");
+        flat.push(extension);
+    }
+    PathBuf::from(flat)
+}
@@ -0,0 +1,110 @@
+use super::write_atomically;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, Metadata},
+    io::{BufReader, BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::UNIX_EPOCH,
+};
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct FileStamp {
+    size: u64,
+    modified_secs: u64,
+    modified_nanos: u32,
+}
+
+impl FileStamp {
+    /// `None` where the platform doesn't record modification times
+    fn of(metadata: &Metadata) -> Option<Self> {
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Self {
+            size: metadata.len(),
+            modified_secs: modified.as_secs(),
+            modified_nanos: modified.subsec_nanos(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedHash {
+    #[serde(flatten)]
+    stamp: FileStamp,
+    hash: String,
+}
+
+/// Hashes of source files shared between databases and runs, keyed by real path. An entry is only
+/// trusted while the file's size and modification time are unchanged.
+pub struct HashCache {
+    path: PathBuf,
+    entries: Mutex<BTreeMap<PathBuf, CachedHash>>,
+}
+
+impl HashCache {
+    /// Loads the cache at `path`, starting an empty one if it doesn't exist yet
+    pub fn load(path: &Path) -> Result<Self> {
+        let entries = match File::open(path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))
+                .with_context(|| format!("Failed to parse hash cache `{0}`", path.display()))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to open hash cache `{0}`", path.display()))
+            }
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// The cached hash of `source`, if it hasn't changed since it was cached
+    pub fn get(&self, source: &Path, metadata: &Metadata) -> Option<String> {
+        let stamp = FileStamp::of(metadata)?;
+        let real_path = fs::canonicalize(source).ok()?;
+        let entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        entries
+            .get(&real_path)
+            .filter(|cached| cached.stamp == stamp)
+            .map(|cached| cached.hash.clone())
+    }
+
+    pub fn insert(&self, source: &Path, metadata: &Metadata, hash: &str) {
+        let (Some(stamp), Ok(real_path)) = (FileStamp::of(metadata), fs::canonicalize(source))
+        else {
+            return;
+        };
+        let mut entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        entries.insert(
+            real_path,
+            CachedHash {
+                stamp,
+                hash: hash.to_owned(),
+            },
+        );
+    }
+
+    pub fn save(&self, tmpdir: Option<&Path>) -> Result<()> {
+        let entries = match self.entries.lock() {
+            Ok(entries) => entries,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        write_atomically(&self.path, tmpdir, |file| {
+            let mut writer = BufWriter::new(file);
+            serde_json::to_writer(&mut writer, &*entries)
+                .with_context(|| "Failed to format hash cache to json")?;
+            writer
+                .flush()
+                .with_context(|| format!("Failed to write {0}", self.path.display()))
+        })
+    }
+}
@@ -0,0 +1,111 @@
+use crate::{
+    remote::{RemoteFiles, RemoteMirror},
+    transform::glob_match,
+};
+use anyhow::{Context, Result};
+use std::{fs, path::Path};
+
+pub const MIRROR_IGNORE_FILENAME: &str = ".mmdbignore";
+
+/// Globs of the in-progress files other programs leave in the source (editor swap and backup
+/// files, partial downloads, office lock files, and mirrorman's own temporary files), which change
+/// constantly and are never worth mirroring. Skipped unless `--no-default-ignores` is passed.
+pub const DEFAULT_SOURCE_IGNORES: &[&str] = &[
+    "*.swp",
+    "*.swo",
+    "*~",
+    ".#*",
+    "#*#",
+    "*.part",
+    "*.partial",
+    "*.crdownload",
+    "*.download",
+    ".~lock.*#",
+    "~$*",
+    ".mirrorman-*",
+    ".*.mirrorman-*.tmp",
+];
+
+/// Whether the source file `relative_entry` is a temporary artifact in [`DEFAULT_SOURCE_IGNORES`]
+pub fn is_default_ignored(relative_entry: &Path) -> bool {
+    DEFAULT_SOURCE_IGNORES
+        .iter()
+        .any(|pattern| glob_match(pattern, relative_entry))
+}
+
+/// Globs (relative to the mirror root) of files that are managed externally, read from a
+/// `.mmdbignore` in the mirror directory. Cleanup never removes matching files.
+#[derive(Default)]
+pub struct MirrorIgnore {
+    patterns: Vec<String>,
+}
+
+impl MirrorIgnore {
+    pub fn load(mirror_root: &Path) -> Result<Self> {
+        let ignore_path = mirror_root.join(MIRROR_IGNORE_FILENAME);
+        if !ignore_path.exists() {
+            return Ok(Self::default());
+        }
+
+        let contents = fs::read_to_string(&ignore_path)
+            .with_context(|| format!("Failed to read `{0}`", ignore_path.display()))?;
+        Ok(Self::parse(&contents))
+    }
+
+    /// Reads the `.mmdbignore` of a remote mirror, if it has one
+    pub fn load_remote(mirror: &RemoteMirror, files: &dyn RemoteFiles) -> Result<Self> {
+        let ignore_path = Path::new(MIRROR_IGNORE_FILENAME);
+        let contents = files
+            .read(ignore_path)
+            .with_context(|| format!("Failed to read `{0}`", mirror.url_of(ignore_path)))?;
+        Ok(contents.map_or_else(Self::default, |contents| {
+            Self::parse(&String::from_utf8_lossy(&contents))
+        }))
+    }
+
+    fn parse(contents: &str) -> Self {
+        let patterns = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_owned)
+            .collect();
+        Self { patterns }
+    }
+
+    pub fn is_ignored(&self, relative_entry: &Path) -> bool {
+        relative_entry == Path::new(MIRROR_IGNORE_FILENAME)
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| glob_match(pattern, relative_entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_default_ignored;
+    use std::path::Path;
+
+    #[test]
+    fn temporary_artifacts_are_ignored() {
+        for name in [
+            "notes/.todo.md.swp",
+            "notes/todo.md~",
+            "downloads/film.mkv.part",
+            "downloads/film.mkv.crdownload",
+            "docs/.~lock.report.odt#",
+            "docs/~$report.docx",
+            ".photos.mmdb.mirrorman-123.tmp",
+        ] {
+            assert!(is_default_ignored(Path::new(name)), "{name}");
+        }
+        for name in [
+            "notes/todo.md",
+            "music/partita.flac",
+            "docs/report~final.docx",
+        ] {
+            assert!(!is_default_ignored(Path::new(name)), "{name}");
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    ffi::OsString,
+    fs::{File, OpenOptions, TryLockError},
+    path::{Path, PathBuf},
+};
+
+/// The lock file guarding `database_path`, e.g. `music.mmdb.lock`
+pub fn lock_path(database_path: &Path) -> PathBuf {
+    let mut file_name = database_path
+        .file_name()
+        .map(OsString::from)
+        .unwrap_or_default();
+    file_name.push(".lock");
+    database_path.with_file_name(file_name)
+}
+
+/// An exclusive advisory lock on a database, so overlapping runs can't sync it at once. Released
+/// when dropped, the lock file itself is left in place.
+pub struct DatabaseLock {
+    _file: File,
+}
+
+impl DatabaseLock {
+    /// Locks `database_path`, blocking until any other holder is done if `wait` is set, and
+    /// failing straight away otherwise
+    pub fn acquire(database_path: &Path, wait: bool) -> Result<Self> {
+        let path = lock_path(database_path);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open lock file `{0}`", path.display()))?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) if wait => {
+                println!(
+                    "Database `{0}` is being synced by another process, waiting for it to finish...",
+                    database_path.display()
+                );
+                file.lock()
+                    .with_context(|| format!("Failed to lock `{0}`", path.display()))?;
+            }
+            Err(TryLockError::WouldBlock) => bail!(
+                "Database `{0}` is already being synced by another process (`{1}` is locked), pass `--wait` to wait for it to finish",
+                database_path.display(),
+                path.display()
+            ),
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("Failed to lock `{0}`", path.display()))
+            }
+        }
+
+        Ok(Self { _file: file })
+    }
+}
@@ -0,0 +1,188 @@
+use super::{DatabaseFiles, SyncOptions};
+use anyhow::{Context, Result};
+use base32::{encode, Alphabet};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs::{self, Metadata},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+/// Computes a rolled-up hash for every directory under `root` from its children's names, sizes,
+/// modification times and (for subdirectories) rollups. This only touches metadata, so it's much
+/// cheaper than hashing file contents. Keys are `key_root` joined with the path relative to `root`.
+///
+/// Symlinks and filesystem boundaries are treated as the sync's walk treats them, so a rollup
+/// only ever covers what that walk would visit.
+pub fn directory_rollups(
+    root: &Path,
+    key_root: &Path,
+    database_files: &DatabaseFiles,
+    options: &SyncOptions,
+) -> Result<BTreeMap<PathBuf, String>> {
+    let root_metadata = fs::metadata(root)
+        .with_context(|| format!("Failed to read metadata of `{0}`", root.display()))?;
+    let mut rollups = BTreeMap::new();
+    let walk = RollupWalk {
+        database_files,
+        options,
+        root_device: device(&root_metadata),
+    };
+    walk.rollup_directory(root, key_root, &mut rollups)?;
+    Ok(rollups)
+}
+
+struct RollupWalk<'a> {
+    database_files: &'a DatabaseFiles,
+    options: &'a SyncOptions,
+    root_device: Option<u64>,
+}
+
+impl RollupWalk<'_> {
+    fn rollup_directory(
+        &self,
+        dir: &Path,
+        key: &Path,
+        rollups: &mut BTreeMap<PathBuf, String>,
+    ) -> Result<String> {
+        let mut entries = dir
+            .read_dir()
+            .with_context(|| format!("Failed to read directory `{0}`", dir.display()))?
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Failed to read directory `{0}`", dir.display()))?;
+        // The database is rewritten every sync, so would otherwise always change its folder's
+        // rollup
+        entries.retain(|entry| !self.database_files.contains(&entry.path()));
+        entries.sort_by_key(|entry| entry.file_name());
+
+        let mut hasher = Sha256::new();
+        for entry in entries {
+            let name = entry.file_name();
+            let path = entry.path();
+            let mut metadata = path
+                .symlink_metadata()
+                .with_context(|| format!("Failed to read metadata of `{0}`", path.display()))?;
+            if metadata.is_symlink() {
+                let target = fs::read_link(&path)
+                    .with_context(|| format!("Failed to read link `{0}`", path.display()))?;
+                hasher.update(name.as_encoded_bytes());
+                hasher.update(b"l");
+                hasher.update(target.as_os_str().as_encoded_bytes());
+                match self.options.dereference_db {
+                    // A dangling link has nothing more to hash
+                    true => match path.metadata() {
+                        Ok(target_metadata) => metadata = target_metadata,
+                        Err(_) => continue,
+                    },
+                    false => continue,
+                }
+                // A link back to one of its own ancestors would never finish
+                if metadata.is_dir() && is_loop(dir, &path) {
+                    continue;
+                }
+            } else {
+                hasher.update(name.as_encoded_bytes());
+            }
+
+            if metadata.is_dir() {
+                if self.options.one_file_system && device(&metadata) != self.root_device {
+                    hasher.update(b"m");
+                    continue;
+                }
+                let child = self.rollup_directory(&path, &key.join(&name), rollups)?;
+                hasher.update(b"d");
+                hasher.update(child.as_bytes());
+            } else {
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .unwrap_or_default();
+                hasher.update(b"f");
+                hasher.update(metadata.len().to_le_bytes());
+                hasher.update(modified.as_nanos().to_le_bytes());
+            }
+        }
+
+        let rollup = encode(Alphabet::Crockford, &hasher.finalize());
+        rollups.insert(key.to_path_buf(), rollup.clone());
+        Ok(rollup)
+    }
+}
+
+/// Whether the directory `link` (inside `dir`) resolves to `dir` or one of its ancestors.
+/// Unresolvable links are treated as loops, since they can't be walked safely either.
+fn is_loop(dir: &Path, link: &Path) -> bool {
+    match (fs::canonicalize(dir), fs::canonicalize(link)) {
+        (Ok(dir), Ok(target)) => dir.starts_with(target),
+        _ => true,
+    }
+}
+
+#[cfg(unix)]
+fn device(metadata: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.dev())
+}
+
+/// Devices aren't exposed on other platforms, so every directory counts as on the same one
+#[cfg(not(unix))]
+fn device(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::directory_rollups;
+    use crate::database::{DatabaseFiles, SyncOptions};
+    use std::{
+        env::temp_dir,
+        fs::{self, create_dir_all, remove_dir_all},
+        os::unix::fs::symlink,
+        path::Path,
+        process,
+    };
+
+    #[test]
+    fn symlink_loops_are_not_followed() {
+        let root = temp_dir().join(format!("mirrorman-test-{0}-merkle", process::id()));
+        let _ = remove_dir_all(&root);
+        create_dir_all(root.join("source/a/b")).unwrap();
+        fs::write(root.join("source/a/b/file.txt"), "contents").unwrap();
+        symlink("..", root.join("source/a/b/up")).unwrap();
+        symlink("a", root.join("source/alias")).unwrap();
+        let database_files = DatabaseFiles::new(&root.join("db.mmdb"));
+        let source = root.join("source");
+        let rollups = |dereference_db| {
+            let options = SyncOptions {
+                dereference_db,
+                ..Default::default()
+            };
+            directory_rollups(&source, Path::new("source"), &database_files, &options).unwrap()
+        };
+
+        let keys = rollups(false).into_keys().collect::<Vec<_>>();
+        assert_eq!(keys, ["source", "source/a", "source/a/b"].map(Path::new));
+        // The alias is followed, but neither copy of `up` is
+        let keys = rollups(true).into_keys().collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            [
+                "source",
+                "source/a",
+                "source/a/b",
+                "source/alias",
+                "source/alias/b"
+            ]
+            .map(Path::new)
+        );
+
+        // Retargeting a link changes its folder's rollup even without following it
+        let before = rollups(false)[Path::new("source/a/b")].clone();
+        fs::remove_file(root.join("source/a/b/up")).unwrap();
+        symlink(".", root.join("source/a/b/up")).unwrap();
+        assert_ne!(rollups(false)[Path::new("source/a/b")], before);
+        remove_dir_all(root).unwrap();
+    }
+}
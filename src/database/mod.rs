@@ -1,271 +1,3452 @@
+mod autotune;
+mod budget;
+mod changes;
+mod chown;
+mod config;
+mod delta;
+mod entry_log;
+mod env;
+mod flatten;
 mod hash;
+mod hash_cache;
+mod ignore;
+mod lock;
+mod merkle;
+mod names;
+mod options;
+mod owned;
 mod path;
+mod quota;
+mod reflink;
+mod remote_sync;
+mod scratch;
+mod settings;
+mod since;
+mod snapshots;
+mod stats;
+mod tee;
+mod template;
+mod times;
+mod trace;
 
+pub use autotune::*;
+pub use budget::*;
+pub use changes::*;
+pub use chown::*;
+pub use config::*;
+pub use delta::*;
+pub use entry_log::*;
+pub use env::*;
+pub use flatten::*;
 pub use hash::*;
+pub use hash_cache::*;
+pub use ignore::*;
+pub use lock::*;
+pub use merkle::*;
+pub use names::*;
+pub use options::*;
+pub use owned::*;
 pub use path::*;
+pub use quota::*;
+pub use reflink::*;
+pub use remote_sync::*;
+pub use scratch::*;
+pub use settings::*;
+pub use since::*;
+pub use snapshots::*;
+pub use stats::*;
+pub use tee::*;
+pub use template::*;
+pub use times::*;
+pub use trace::*;
 
-use crate::filter::{find_filter_for_entry, run_filter_for_entry};
-use anyhow::{Context, Result};
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use crate::{
+    archive::{count_entries, for_each_entry, ArchiveEntry, ArchiveKind},
+    encryption::{encrypted_path, Cipher},
+    filter::{
+        batch_support, entry_extension, find_filter_for_entry, fits_batch_line, probe_filter,
+        run_filter_batch, run_filter_chain, run_filter_for_entry, BatchInput, BatchSupport, Filter,
+        FilterLog, Semaphore, BATCH_SIZE,
+    },
+    remote::{RemoteFiles, RemoteMirror},
+    transform::{
+        apply_path_transforms, find_transforms_for_entry, has_content_transforms,
+        run_transforms_for_entry, Transform, TransformKind,
+    },
+    walk::describe_walk_error,
+};
+use anyhow::{bail, Context, Result};
+use log::Level;
+use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::{
     collections::{BTreeMap, BTreeSet},
-    fs::{copy, create_dir_all, File},
-    io::{stdout, Read, Write},
-    iter::repeat,
-    path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    env::temp_dir,
+    fmt::Display,
+    fs::{self, create_dir_all, File, Metadata},
+    io::{self, stdout, BufRead, BufReader, BufWriter, ErrorKind, Write},
+    iter::repeat_n,
+    mem::take,
+    path::{absolute, Component, Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
 };
-use walkdir::WalkDir;
+use walkdir::{DirEntry, WalkDir};
+
+/// Version of the database file format, bumped whenever a change isn't backwards compatible
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Databases written before the format version was stored are all of the first
+fn first_format_version() -> u32 {
+    1
+}
+
+/// Where entries of an archive source are extracted to while syncing `database_path`, numbered so
+/// databases sharing a name can be synced at once
+fn staging_dir(database_path: &Path, tmpdir: Option<&Path>) -> PathBuf {
+    static SYNC_COUNT: AtomicUsize = AtomicUsize::new(0);
+    tmpdir
+        .map_or_else(temp_dir, Path::to_path_buf)
+        .join(format!(
+            "mirrorman-{0}-{1}-{2}",
+            process::id(),
+            SYNC_COUNT.fetch_add(1, Ordering::Relaxed),
+            database_path
+                .file_stem()
+                .unwrap_or_default()
+                .to_string_lossy()
+        ))
+}
+
+/// The files of a remote mirror, connecting to its host unless `options` has them already
+pub fn remote_files(remote: &RemoteMirror, options: &SyncOptions) -> Result<Arc<dyn RemoteFiles>> {
+    match &options.remote_files {
+        Some(files) => Ok(files.clone()),
+        None => Ok(Arc::new(remote.connect()?)),
+    }
+}
+
+/// Whether a source entry is a temporary artifact skipped by default, only ever files
+fn is_temp_artifact(entry: &DirEntry) -> bool {
+    let skipped = !entry.file_type().is_dir() && is_default_ignored(Path::new(entry.file_name()));
+    if skipped {
+        log::trace!(
+            "`{0}` looks like a temporary file, skipping...",
+            entry.path().display()
+        );
+    }
+    skipped
+}
+
+/// The files among walked source entries, relative to the source root
+fn relative_files<'a>(
+    entries: &'a [DirEntry],
+    source_root: &'a Path,
+) -> impl Iterator<Item = PathBuf> + 'a {
+    entries
+        .iter()
+        .filter(|entry| entry.file_type().is_file())
+        .map(move |entry| {
+            entry
+                .path()
+                .strip_prefix(source_root)
+                .unwrap_or(entry.path())
+                .to_path_buf()
+        })
+}
+
+/// What's needed to predict where source files are mirrored to without syncing them
+struct PredictedLayout {
+    filters: Vec<Filter>,
+    mirror_prefix: PathBuf,
+    // Key = Source file relative to the source root, Value = Suffix disambiguating its flattened
+    // name
+    flatten_suffixes: BTreeMap<PathBuf, String>,
+    sniff: bool,
+    case_sensitive_ext: bool,
+    portable_names: bool,
+}
+
+/// What cleanup keeps in the mirror, besides what the sync wrote
+struct CleanupRules<'a> {
+    // Directories which couldn't be walked in the source
+    preserved_dirs: &'a [PathBuf],
+    // Mirror entries the database had written, `None` unless cleanup is limited to them
+    owned: Option<&'a BTreeSet<PathBuf>>,
+}
+
+impl CleanupRules<'_> {
+    /// Whether the mirror entry `relative_entry` is kept, logging why if so as `shown`
+    fn keeps(&self, relative_entry: &Path, ignore: &MirrorIgnore, shown: &dyn Display) -> bool {
+        let reason = if self
+            .preserved_dirs
+            .iter()
+            .any(|dir| relative_entry.starts_with(dir))
+        {
+            "couldn't be walked in the source"
+        } else if self
+            .owned
+            .is_some_and(|owned| !owned.contains(relative_entry))
+        {
+            "wasn't written by mirrorman"
+        } else if ignore.is_ignored(relative_entry) {
+            "is ignored"
+        } else {
+            return false;
+        };
+        log::trace!("`{shown}` {reason}, keeping...");
+        true
+    }
+}
+
+/// A file queued for a batch filter
+struct BatchEntry {
+    key: PathBuf,
+    source: PathBuf,
+    output: PathBuf,
+    change: Change,
+    /// Bytes of the source, only counted as copied once the batch has run
+    size: u64,
+    // Bytes held in the quota for the entry's mirror
+    reserved: u64,
+}
+
+/// A hash being computed by one thread, which others wait on
+type HashSlot = Arc<Mutex<Option<String>>>;
+
+/// State shared between the worker threads of a single sync
+struct SyncState<'a> {
+    mirror_root: PathBuf,
+    // The mirror root with symlinks resolved, `None` if it didn't exist when the sync started
+    real_mirror_root: Option<PathBuf>,
+    // Where the source's contents are placed within the mirror root, empty unless `relative_to`
+    mirror_prefix: PathBuf,
+    hashes: Arc<Mutex<BTreeMap<PathBuf, String>>>,
+    // Key = Source file, Value = Its size in bytes, for every file handled
+    sizes: Mutex<BTreeMap<PathBuf, u64>>,
+    // Key = Source file, Value = JSON metadata its filter reported, replacing the database's
+    metadata: Arc<Mutex<BTreeMap<PathBuf, Value>>>,
+    mirror_list: Arc<Mutex<BTreeSet<PathBuf>>>,
+    counter: AtomicUsize,
+    // Milliseconds after `started` the progress bar was last drawn, as redraws are throttled
+    last_draw: AtomicU64,
+    started: Instant,
+    // Held while drawing progress, so an older count is never drawn over a newer one
+    drawing: Mutex<()>,
+    stats: Arc<Mutex<SyncStats>>,
+    entry_log: EntryLog,
+    filter_slots: Option<Semaphore>,
+    auto_concurrency: Option<AutoConcurrency>,
+    // Key = Filter, Value = (Source, Mirror) pairs waiting to be run as a batch
+    batches: Mutex<BTreeMap<Filter, Vec<BatchEntry>>>,
+    trace: Option<&'a Trace>,
+    filter_log: Option<&'a FilterLog>,
+    // Directories whose rollup hasn't changed since the last sync
+    unchanged_dirs: BTreeSet<PathBuf>,
+    reflink: ReflinkMode,
+    preserve_times: Vec<PreservedTime>,
+    chown: Option<Chown>,
+    quota: Option<Quota>,
+    time_budget: Option<&'a TimeBudget>,
+    since: Option<SinceDb>,
+    snapshots: Option<Snapshots>,
+    // The mirror on another host, which `mirror_root` stages uploads to it, if it's remote
+    remote: Option<RemoteSync>,
+    // Whether any entries were left for the next sync once the time budget ran out
+    deferred: AtomicBool,
+    max_errors: Option<usize>,
+    newer_only: bool,
+    // How the extension a filter was chosen for is probed, for running chained filters
+    sniff: bool,
+    case_sensitive_ext: bool,
+    delta: bool,
+    single_pass: bool,
+    structure_only: bool,
+    checksum: bool,
+    hasher: &'a dyn Hasher,
+    hash_cache: Option<&'a HashCache>,
+    cipher: Option<Cipher>,
+    // Where files are written before they're encrypted, so plaintext never lands in the mirror
+    plain_staging: Option<StagingDir>,
+    // Key = Mirror file relative to the mirror root (before encryption), Value = Nonce
+    nonces: Mutex<BTreeMap<PathBuf, String>>,
+    // Key = Source file, Value = Extra outputs of its filter (relative to the mirror root, before
+    // encryption) and their hashes
+    outputs: Mutex<BTreeMap<PathBuf, BTreeMap<PathBuf, String>>>,
+    // Key = Mirror file relative to the mirror root, Value = Its hash and size, for files whose
+    // contents the sync has hashed
+    mirror_hashes: Mutex<BTreeMap<PathBuf, (String, u64)>>,
+    // Key = Mirror file, Value = Source key of the entry last written there, locked while writing
+    claims: Mutex<BTreeMap<PathBuf, Arc<Mutex<Option<PathBuf>>>>>,
+    // Key = Mirror file relative to the mirror root, Value = Every source key mapped to it
+    collisions: Mutex<BTreeMap<PathBuf, BTreeSet<PathBuf>>>,
+    // Source files which failed to mirror
+    failures: Mutex<BTreeSet<PathBuf>>,
+    // Key = Source file, Value = Whether it was new or changed, for files which were mirrored
+    changes: Mutex<BTreeMap<PathBuf, Change>>,
+    // Source files whose mirror was written as an empty placeholder
+    placeholders: Mutex<BTreeSet<PathBuf>>,
+    // Key = Source file relative to the source root, Value = Suffix disambiguating its flattened
+    // name, `None` unless flattening. Filled in from the source files once they're known.
+    flatten_suffixes: Option<BTreeMap<PathBuf, String>>,
+    // Key = Canonical path of a source file, Value = Its hash once computed, `None` unless
+    // dereferencing
+    real_hashes: Option<Mutex<BTreeMap<PathBuf, HashSlot>>>,
+}
+
+impl SyncState<'_> {
+    fn trace(
+        &self,
+        action: TraceAction,
+        source: Option<&Path>,
+        mirror: Option<&Path>,
+        hash: Option<&str>,
+    ) {
+        if let Some(trace) = self.trace {
+            trace.record(action, source, mirror, hash);
+        }
+    }
+
+    /// Whether the time budget has run out, leaving the entry asking for the next sync
+    fn defer(&self) -> bool {
+        let deferred = self.time_budget.is_some_and(TimeBudget::defer);
+        if deferred {
+            self.deferred.store(true, Ordering::Relaxed);
+        }
+        deferred
+    }
+
+    /// Waits to start on an entry while `--concurrency-auto` has as many underway as it allows
+    fn concurrency_slot(&self) -> Option<AutoConcurrencySlot<'_>> {
+        self.auto_concurrency.as_ref().map(AutoConcurrency::acquire)
+    }
+
+    /// Lets `--concurrency-auto` measure throughput once an entry is done
+    fn measure_throughput(&self) {
+        if let Some(auto_concurrency) = &self.auto_concurrency {
+            let processed = {
+                let stats = match self.stats.lock() {
+                    Ok(stats) => stats,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                stats.bytes_copied + stats.bytes_skipped
+            };
+            auto_concurrency.measure(processed);
+        }
+    }
+
+    /// How `filter` can be run in batches on `source`, if at all. Chained filters are run one file
+    /// at a time, so each output can feed the next, as are files whose paths can't be written on a
+    /// line of batch input.
+    fn batch_support(&self, filter: &Filter, source: &Path, output: &Path) -> Option<BatchSupport> {
+        if !filter.then.is_empty() || !fits_batch_line(source) || !fits_batch_line(output) {
+            return None;
+        }
+        batch_support(filter)
+    }
+
+    /// Where `output` is written before it's encrypted into the mirror, or `output` itself when
+    /// the mirror isn't encrypted
+    fn staged(&self, output: &Path) -> PathBuf {
+        match &self.plain_staging {
+            Some(staging) => staging
+                .path()
+                .join(output.strip_prefix(&self.mirror_root).unwrap_or(output)),
+            None => output.to_path_buf(),
+        }
+    }
+
+    /// The mirror path a file written by [`Self::staged`] is bound for
+    fn unstaged(&self, staged: &Path) -> PathBuf {
+        match self
+            .plain_staging
+            .as_ref()
+            .and_then(|staging| staged.strip_prefix(staging.path()).ok())
+        {
+            Some(relative) => self.mirror_root.join(relative),
+            None => staged.to_path_buf(),
+        }
+    }
+
+    fn chown(&self, mirror: &Path) -> Result<()> {
+        match &self.chown {
+            Some(chown) => chown.apply(mirror),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether the mirror file `mirror` exists, written by this sync or already on the host of a
+    /// remote mirror
+    fn mirror_exists(&self, mirror: &Path) -> bool {
+        mirror.exists()
+            || self.remote.as_ref().is_some_and(|remote| {
+                remote.contains(mirror.strip_prefix(&self.mirror_root).unwrap_or(mirror))
+            })
+    }
+
+    /// Size of the mirror file `mirror`, `None` if there isn't one
+    fn mirror_file_size(&self, mirror: &Path) -> Option<u64> {
+        let local_size = mirror
+            .metadata()
+            .ok()
+            .filter(Metadata::is_file)
+            .map(|metadata| metadata.len());
+        local_size.or_else(|| {
+            let remote = self.remote.as_ref()?;
+            remote.file_size(mirror.strip_prefix(&self.mirror_root).unwrap_or(mirror))
+        })
+    }
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct Database {
+    // Version of the format the file was last written in, see [`FORMAT_VERSION`]
+    #[serde(default = "first_format_version")]
+    format_version: u32,
     filters: Vec<String>,
+    // Key = Filter, Value = Working directory to run it in
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    filter_dirs: BTreeMap<String, PathBuf>,
+    // Key = Filter, Value = Globs over source paths it's restricted to
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    filter_paths: BTreeMap<String, Vec<String>>,
+    // Key = Filter, Value = Filters its output is passed through in turn
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    filter_chains: BTreeMap<String, Vec<String>>,
+    // Key = Filter, Value = Config file passed to it, relative to the database
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    filter_configs: BTreeMap<String, PathBuf>,
+    // Relative to the database, the directory filters given as relative paths are found in, the
+    // database's own directory if `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    filters_dir: Option<PathBuf>,
+    #[serde(default)]
+    transforms: Vec<Transform>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mirror_template: Option<String>,
+    // Mirror every file straight into the mirror root, rather than keeping the source's structure
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    flatten: bool,
+    // Relative to the database, the directory mirror paths are relative to, which contains the
+    // source. `None` lays the source's contents out directly in the mirror root.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    relative_to: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "SyncSettings::is_default")]
+    settings: SyncSettings,
+    // Relative to the database, the key itself is never stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encryption_key_file: Option<PathBuf>,
+    // May refer to environment variables and `~`, which are expanded on load
     source_path: PathBuf,
     mirror_path: PathBuf,
+    // `source_path` and `mirror_path` with environment variables expanded, as used on this machine
+    #[serde(skip)]
+    local_source_path: PathBuf,
+    #[serde(skip)]
+    local_mirror_path: PathBuf,
+    // Key = Filter, Value = Its program with environment variables expanded
+    #[serde(skip)]
+    local_programs: BTreeMap<String, String>,
+    #[serde(skip)]
+    local_filters_dir: Option<PathBuf>,
     // Key = Source, Value = Hash
     hashes: BTreeMap<PathBuf, String>,
+    // Key = Source, Value = Size in bytes when it was last hashed
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    sizes: BTreeMap<PathBuf, u64>,
+    // Key = Source, Value = Metadata reported by the filter
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    metadata: BTreeMap<PathBuf, Value>,
+    // Key = Source directory, Value = Rollup of its contents' metadata
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    dir_hashes: BTreeMap<PathBuf, String>,
+    // Key = Mirror file relative to the mirror root (before encryption), Value = Nonce
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    nonces: BTreeMap<PathBuf, String>,
+    // Key = Source file, Value = Extra outputs of its filter (relative to the mirror root, before
+    // encryption) and their hashes
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    outputs: BTreeMap<PathBuf, BTreeMap<PathBuf, String>>,
+    // Key = Mirror file relative to the mirror root, Value = Source files which mapped to it
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    collisions: BTreeMap<PathBuf, BTreeSet<PathBuf>>,
+    // Source files which failed to mirror during the last sync, to be retried
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    failed: BTreeSet<PathBuf>,
+    // Source files whose mirror is only an empty placeholder, written by `--structure-only`, to be
+    // filled in by the next sync
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    placeholders: BTreeSet<PathBuf>,
+    // Mirror files and directories (relative to the mirror root) this database has written, the
+    // only ones cleanup may remove, `None` unless cleanup is limited to them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    owned: Option<BTreeSet<PathBuf>>,
+    // Only ever add files to the mirror, never overwriting or removing any, for write-once archives
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    archive_append: bool,
+    #[serde(skip)]
+    changes: SyncChanges,
+    // Key = Mirror file relative to the mirror root, Value = Its hash and size, as known to the
+    // last sync
+    #[serde(skip)]
+    mirror_hashes: BTreeMap<PathBuf, (String, u64)>,
 }
 
 impl Database {
-    pub fn new(source_path: PathBuf, mirror_path: PathBuf, filters: Vec<String>) -> Self {
+    pub fn new(source_path: PathBuf, mirror_path: PathBuf, config: DatabaseConfig) -> Self {
+        let DatabaseConfig {
+            filters,
+            filter_dirs,
+            filter_paths,
+            filter_chains,
+            filter_configs,
+            filters_dir,
+            transforms,
+            mirror_template,
+            flatten,
+            relative_to,
+            owned_cleanup,
+            archive_append,
+            encryption_key_file,
+            settings,
+        } = config;
         let hashes = BTreeMap::new();
+        let metadata = BTreeMap::new();
+        let dir_hashes = BTreeMap::new();
+        let nonces = BTreeMap::new();
+        let outputs = BTreeMap::new();
+        let collisions = BTreeMap::new();
+        let failed = BTreeSet::new();
+        // Stored without trailing slashes, so `dir` and `dir/` mirror to the same layout
+        let source_path = normalize_path(&source_path);
+        // Normalizing would collapse the `//` of a remote mirror's URL
+        let mirror_path = match url_scheme(&mirror_path) {
+            Some(_) => mirror_path,
+            None => normalize_path(&mirror_path),
+        };
+        let relative_to = relative_to.as_deref().map(normalize_path);
+        let placeholders = BTreeSet::new();
+        let owned = owned_cleanup.then(BTreeSet::new);
 
         Self {
+            format_version: FORMAT_VERSION,
+            local_source_path: source_path.clone(),
+            local_mirror_path: mirror_path.clone(),
+            local_programs: BTreeMap::new(),
+            local_filters_dir: filters_dir.clone(),
             source_path,
             mirror_path,
             hashes,
+            sizes: BTreeMap::new(),
+            metadata,
+            dir_hashes,
+            nonces,
+            outputs,
+            collisions,
+            failed,
+            placeholders,
+            owned,
+            archive_append,
+            changes: SyncChanges::default(),
+            mirror_hashes: BTreeMap::new(),
             filters,
+            filter_dirs,
+            filter_paths,
+            filter_chains,
+            filter_configs,
+            filters_dir,
+            transforms,
+            mirror_template,
+            flatten,
+            relative_to,
+            settings,
+            encryption_key_file,
         }
     }
 
     pub fn load(file_path: &Path) -> Result<Self> {
-        let mut file = File::open(file_path)
-            .with_context(|| format!("Failed to open {0} for writing", file_path.display()))?;
-        let mut buf = String::new();
-        file.read_to_string(&mut buf)
-            .with_context(|| format!("Failed to read file {0}", file_path.display()))?;
-        serde_json::from_str(&buf).with_context(|| "Failed to read database from file")
+        let file = File::open(file_path)
+            .with_context(|| format!("Failed to open {0} for reading", file_path.display()))?;
+        // Parsed straight from the file, large databases would otherwise be held in memory twice
+        let mut reader = BufReader::new(file);
+
+        // Editors occasionally leave a byte order mark or stray whitespace behind, the latter is
+        // skipped by the parser
+        const BYTE_ORDER_MARK: &[u8] = "\u{feff}".as_bytes();
+        let has_byte_order_mark = reader
+            .fill_buf()
+            .with_context(|| format!("Failed to read file {0}", file_path.display()))?
+            .starts_with(BYTE_ORDER_MARK);
+        if has_byte_order_mark {
+            reader.consume(BYTE_ORDER_MARK.len());
+        }
+
+        let mut database: Self = serde_json::from_reader(reader).map_err(|e| {
+            let message = format!(
+                "Failed to read database from file {0} (line {1}, column {2})",
+                file_path.display(),
+                e.line(),
+                e.column()
+            );
+            anyhow::Error::new(e).context(message)
+        })?;
+        if database.format_version > FORMAT_VERSION {
+            bail!(
+                "Database {0} was written by a newer version of mirrorman (format version {1}, this version reads up to {FORMAT_VERSION})",
+                file_path.display(),
+                database.format_version
+            );
+        }
+        database
+            .expand_env()
+            .with_context(|| format!("Failed to load database {0}", file_path.display()))?;
+        Ok(database)
     }
 
-    pub fn sync(&mut self, database_path: &Path) -> Result<()> {
-        let old_dir = std::env::current_dir()?;
-        let database_folder = database_path
-            .parent()
-            .context("database file had no parent")?;
-        let database_filename = PathBuf::from(
-            database_path
-                .file_name()
-                .context("filename could not be read")?,
-        );
-        std::env::set_current_dir(database_folder)?;
+    /// Expands environment variables in the source and mirror paths, filter programs and filters
+    /// directory, leaving the stored paths as they were written
+    fn expand_env(&mut self) -> Result<()> {
+        let expand = |path: &Path| -> Result<PathBuf> {
+            Ok(PathBuf::from(expand_env(&path.to_string_lossy())?))
+        };
+        self.local_source_path = expand(&self.source_path)?;
+        self.local_mirror_path = expand(&self.mirror_path)?;
+        let chained = self.filter_chains.values().flatten();
+        self.local_programs = self
+            .filters
+            .iter()
+            .chain(chained)
+            .map(|filter| Ok((filter.clone(), expand_env(filter)?)))
+            .collect::<Result<_>>()?;
+        self.local_filters_dir = self.filters_dir.as_deref().map(expand).transpose()?;
+        Ok(())
+    }
 
-        let new_hashes = Arc::new(Mutex::new(BTreeMap::new()));
-        let mirror_list = Arc::new(Mutex::new(BTreeSet::new()));
-        let counter = Arc::new(Mutex::new(0_usize));
+    /// The source directory (or archive) on this machine
+    pub fn source_root(&self, database_folder: &Path) -> PathBuf {
+        database_folder.join(&self.local_source_path)
+    }
 
-        // Walk source directory
-        let source_entries = WalkDir::new(&self.source_path)
-            .into_iter()
-            .collect::<Vec<_>>();
-        let total_entries = source_entries.len();
+    /// The mirror directory on this machine, or a remote mirror's URL
+    pub fn mirror_root(&self, database_folder: &Path) -> PathBuf {
+        match url_scheme(&self.local_mirror_path) {
+            Some(_) => self.local_mirror_path.clone(),
+            None => database_folder.join(&self.local_mirror_path),
+        }
+    }
 
-        source_entries
-            .into_par_iter()
-            .try_for_each(|entry| -> Result<()> {
-                let source_entry = entry?.into_path();
+    /// The mirror on another host, if it isn't a local directory
+    pub fn remote_mirror(&self) -> Result<Option<RemoteMirror>> {
+        RemoteMirror::parse(&self.local_mirror_path)
+    }
 
-                let parts = self.source_path.components().count();
+    /// Fails for a remote mirror, which `action` can't be done to
+    pub fn require_local_mirror(&self, action: &str) -> Result<()> {
+        match self.remote_mirror()? {
+            Some(remote) => {
+                bail!("`{action}` only works with local mirrors, but `{remote}` is on another host")
+            }
+            None => Ok(()),
+        }
+    }
 
-                let mut mirror_entry = self
-                    .mirror_path
-                    .join(source_entry.components().skip(parts).collect::<PathBuf>());
-                let filter = find_filter_for_entry(&source_entry, &mut mirror_entry, &self.filters);
-                let mirror_entry = mirror_entry;
+    /// Connects to a remote mirror for a sync, checking the sync asks nothing of it which can only
+    /// be done to a local one
+    fn remote_sync(
+        &self,
+        database_path: &Path,
+        options: &SyncOptions,
+    ) -> Result<Option<RemoteSync>> {
+        let Some(remote) = self.remote_mirror()? else {
+            return Ok(None);
+        };
+        let unsupported = [
+            (options.snapshots.is_some(), "--snapshots"),
+            (options.delta, "--delta"),
+            (options.max_total_size.is_some(), "--max-total-size"),
+            (options.newer_only, "--newer-only"),
+            (options.chown.is_some(), "--chown"),
+        ];
+        if let Some((_, flag)) = unsupported.iter().find(|(used, _)| *used) {
+            bail!("`{flag}` only works with local mirrors, but `{remote}` is on another host");
+        }
 
-                {
-                    let mut mirror_list = match mirror_list.lock() {
-                        Ok(mirror_list) => mirror_list,
-                        Err(poisoned) => poisoned.into_inner(),
-                    };
-                    mirror_list.insert(mirror_entry.clone());
-                }
+        let files = remote_files(&remote, options)?;
+        let staging = staging_dir(database_path, options.tmpdir.as_deref());
+        RemoteSync::start(remote, files, staging).map(Some)
+    }
 
-                if source_entry.is_dir() {
-                    self.handle_dir_entry(&source_entry, &mirror_entry)?;
-                } else if source_entry.is_file() {
-                    self.handle_file_entry(
-                        new_hashes.clone(),
-                        filter,
-                        &source_entry,
-                        &mirror_entry,
-                    )?;
-                }
+    /// The directory filters given as relative paths are found in on this machine
+    pub fn filters_root(&self, database_folder: &Path) -> PathBuf {
+        match &self.local_filters_dir {
+            Some(filters_dir) => database_folder.join(filters_dir),
+            None => database_folder.to_path_buf(),
+        }
+    }
 
-                Self::log_progress(counter.clone(), total_entries)?;
+    /// The source file on this machine which `key` refers to
+    fn source_file(&self, database_folder: &Path, key: &Path) -> PathBuf {
+        match key.strip_prefix(&self.source_path) {
+            Ok(relative_entry) => self.source_root(database_folder).join(relative_entry),
+            Err(_) => database_folder.join(key),
+        }
+    }
 
-                Ok(())
-            })?;
+    /// Forgets every stored hash, so the next sync treats all files as new
+    pub fn clear_hashes(&mut self) {
+        self.hashes.clear();
+        self.metadata.clear();
+        self.dir_hashes.clear();
+        self.failed.clear();
+        self.placeholders.clear();
+    }
 
-        println!();
+    pub fn source_path(&self) -> &Path {
+        &self.source_path
+    }
 
-        self.hashes = match new_hashes.lock() {
-                Ok(new_hashes) => new_hashes,
-                Err(poisoned) => {
-                    log::warn!("One or more threads panicked, hash list may be incomplete. Consider (re-)running `sync`...");
-                    poisoned.into_inner()
-                }
-            }
-            .clone();
+    /// The database's filters, exactly as stored
+    pub fn filter_programs(&self) -> &[String] {
+        &self.filters
+    }
 
-        self.save(&database_filename)?;
+    /// Globs over source paths each restricted filter is limited to
+    pub fn filter_paths(&self, filter: &str) -> &[String] {
+        self.filter_paths.get(filter).map_or(&[], Vec::as_slice)
+    }
 
-        let mirror_list = match mirror_list.lock() {
-            Ok(mirror_list) => mirror_list,
-            Err(poisoned) => {
-                log::warn!("One or more threads panicked, mirror list may be incomplete. Consider (re-)running `sync`...");
-                poisoned.into_inner()
-            }
-        };
+    /// Filters the output of `filter` is passed through in turn
+    pub fn filter_chain(&self, filter: &str) -> &[String] {
+        self.filter_chains.get(filter).map_or(&[], Vec::as_slice)
+    }
 
-        self.cleanup(&mirror_list)?;
+    /// Config file passed to `filter`, relative to the database
+    pub fn filter_config(&self, filter: &str) -> Option<&Path> {
+        self.filter_configs.get(filter).map(PathBuf::as_path)
+    }
 
-        std::env::set_current_dir(old_dir)?;
+    pub fn transforms(&self) -> &[Transform] {
+        &self.transforms
+    }
 
-        Ok(())
+    pub fn mirror_template(&self) -> Option<&str> {
+        self.mirror_template.as_deref()
     }
 
-    fn save(&self, database_path: &Path) -> Result<()> {
-        self.write_to_file(database_path)
+    pub fn flatten(&self) -> bool {
+        self.flatten
     }
 
-    fn write_to_file(&self, file_path: &Path) -> Result<()> {
-        let file = File::create(file_path)
-            .with_context(|| format!("Failed to open {0} for writing", file_path.display()))?;
-        serde_json::to_writer_pretty(file, self)
-            .with_context(|| "Failed to format database to json")
+    /// Whether cleanup only removes files this database wrote to the mirror
+    pub fn owned_cleanup(&self) -> bool {
+        self.owned.is_some()
     }
 
-    fn handle_file_entry(
-        &self,
-        hashes: Arc<Mutex<BTreeMap<PathBuf, String>>>,
-        filter: Option<&String>,
-        source: &Path,
-        mirror: &Path,
-    ) -> Result<()> {
-        create_dir_all(
-            mirror
-                .parent()
-                .with_context(|| "Failed to get file parent")?,
-        )
-        .with_context(|| {
-            format!(
-                "Failed to create mirror directory ({0}) for entry `{1}`",
-                mirror.display(),
-                source.display()
-            )
-        })?;
+    /// Whether the mirror is an append-only archive, which files are never overwritten in or
+    /// removed from
+    pub fn archive_append(&self) -> bool {
+        self.archive_append
+    }
 
-        let digest = hash_file(source)?;
+    pub fn settings(&self) -> &SyncSettings {
+        &self.settings
+    }
 
-        {
-            let mut hashes = match hashes.lock() {
-                Ok(hashes) => hashes,
-                Err(poisoned) => poisoned.into_inner(),
-            };
-            hashes.insert(source.to_path_buf(), digest.clone());
+    pub fn encryption_key_file(&self) -> Option<&Path> {
+        self.encryption_key_file.as_deref()
+    }
+
+    /// Number of source files tracked as of the last sync
+    pub fn tracked_files(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// Total size of the tracked files when they were last hashed, `None` if any were hashed before
+    /// sizes were stored
+    pub fn tracked_bytes(&self) -> Option<u64> {
+        self.hashes
+            .keys()
+            .map(|key| self.sizes.get(key))
+            .sum::<Option<u64>>()
+    }
+
+    pub fn format_version(&self) -> u32 {
+        self.format_version
+    }
+
+    /// Whether files without an extension are sniffed, as the sync says or else by default
+    fn sniff(&self, options: &SyncOptions) -> bool {
+        options.sniff.unwrap_or(self.settings.sniff)
+    }
+
+    /// What syncing would change, worked out without writing anything: source files which are new
+    /// or have changed since the last sync (or are still to be retried), and mirror files cleanup
+    /// would remove. Mirror paths are predicted as for [`Self::unmanaged_mirror_files`], with extra
+    /// outputs taken from the last sync. Hashes are added to the `--hash-cache`, so with one the
+    /// sync doesn't read every file again.
+    pub fn plan(&self, database_path: &Path, options: &SyncOptions) -> Result<SyncChanges> {
+        let database_folder = database_folder(database_path)?;
+        let source_root = self.source_root(&database_folder);
+        if ArchiveKind::detect(&source_root).is_some() {
+            bail!(
+                "Source `{0}` is an archive, which can't be planned for without extracting it",
+                source_root.display()
+            );
         }
-        if let Some(prev_hash) = self.hashes.get(source) {
-            if mirror.exists() {
-                if &digest == prev_hash {
-                    log::trace!("File `{0}` unchanged, skipping...", source.display());
-                    return Ok(());
-                } else {
-                    log::info!("File `{0}` changed...", source.display());
+        let source_files = Self::predicted_source_files(database_path, &source_root, options)?;
+        let layout = PredictedLayout {
+            filters: self.native_filters(&database_folder, options),
+            mirror_prefix: self.mirror_prefix(&database_folder)?,
+            flatten_suffixes: match self.flatten {
+                true => flatten_suffixes(relative_files(&source_files, &source_root)),
+                false => BTreeMap::new(),
+            },
+            sniff: self.sniff(options),
+            case_sensitive_ext: options.case_sensitive_ext,
+            portable_names: options.portable_names,
+        };
+
+        let mut changes = SyncChanges::default();
+        let mut expected = BTreeSet::new();
+        for entry in &source_files {
+            let relative_entry = entry.path().strip_prefix(&source_root)?;
+            let key = self.source_path.join(relative_entry);
+            let digest = self.plan_hash(entry.path(), options)?;
+            let planned = match self.hashes.get(&key) {
+                None => Some(&mut changes.added),
+                Some(previous)
+                    if (*previous != digest || self.failed.contains(&key))
+                        && !self.archive_append =>
+                {
+                    Some(&mut changes.changed)
                 }
-            } else {
-                log::info!("New file `{0}`...", source.display());
-                // TODO: Chain if-let &&
+                Some(_) if self.placeholders.contains(&key) => Some(&mut changes.changed),
+                Some(_) => None,
+            };
+            if let Some(planned) = planned {
+                planned.push(key.clone());
+                changes.hashes.insert(key.clone(), digest.clone());
             }
-        } else {
-            log::info!("New file `{0}`...", source.display());
+
+            expected.insert(self.predicted_mirror_entry(
+                &layout,
+                entry.path(),
+                relative_entry,
+                || Ok(digest.clone()),
+            )?);
+            let extra_outputs = self.outputs.get(&key).into_iter().flat_map(BTreeMap::keys);
+            expected.extend(extra_outputs.map(|output| match self.encryption_key_file {
+                Some(_) => encrypted_path(output),
+                None => output.clone(),
+            }));
         }
 
-        match filter {
-            Some(filter) => {
-                run_filter_for_entry(source, mirror, filter);
+        if self.archive_append {
+            return Ok(changes);
+        }
+        changes.removed = self
+            .orphaned_mirror_files(database_path, &expected, options)?
+            .into_iter()
+            .filter(|orphan| {
+                let owned = self.owned.as_ref();
+                owned.is_none_or(|owned| owned.contains(orphan))
+            })
+            .collect();
+        Ok(changes)
+    }
+
+    /// Source files a sync would mirror, walked as the sync walks them. Collected up front, as
+    /// flattening needs every file name before any mirror path.
+    fn predicted_source_files(
+        database_path: &Path,
+        source_root: &Path,
+        options: &SyncOptions,
+    ) -> Result<Vec<DirEntry>> {
+        let database_files = DatabaseFiles::new(database_path);
+        WalkDir::new(source_root)
+            .min_depth(1)
+            .same_file_system(options.one_file_system)
+            .follow_links(options.dereference_db)
+            .into_iter()
+            .filter_entry(|entry| {
+                !database_files.contains(entry.path())
+                    && (options.no_default_ignores || !is_temp_artifact(entry))
+            })
+            .filter(|entry| {
+                entry
+                    .as_ref()
+                    .map_or(true, |entry| entry.file_type().is_file())
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| "Failed to walk source directory")
+    }
+
+    /// Hashes `source` for a plan, through the `--hash-cache` if there is one
+    fn plan_hash(&self, source: &Path, options: &SyncOptions) -> Result<String> {
+        let metadata = source
+            .metadata()
+            .with_context(|| format!("Failed to read metadata of `{0}`", source.display()))?;
+        let hash_cache = options.hash_cache.as_ref();
+        let cached = hash_cache
+            .filter(|_| !options.checksum)
+            .and_then(|hash_cache| hash_cache.get(source, &metadata));
+        if let Some(digest) = cached {
+            return Ok(digest);
+        }
+
+        let digest = options.hasher().hash_file(source)?;
+        if let Some(hash_cache) = hash_cache {
+            hash_cache.insert(source, &metadata, &digest);
+        }
+        Ok(digest)
+    }
+
+    /// Files already in the mirror which no source file maps to, so the first sync would clean
+    /// them up. Filters are asked about extensions as usual, but extra outputs can't be predicted,
+    /// and for archive sources every existing file is listed.
+    pub fn unmanaged_mirror_files(
+        &self,
+        database_path: &Path,
+        options: &SyncOptions,
+    ) -> Result<Vec<PathBuf>> {
+        let database_folder = database_folder(database_path)?;
+        let source_root = self.source_root(&database_folder);
+        let source_files = match ArchiveKind::detect(&source_root) {
+            Some(_) => Vec::new(),
+            None => Self::predicted_source_files(database_path, &source_root, options)?,
+        };
+        let layout = PredictedLayout {
+            filters: self.native_filters(&database_folder, options),
+            mirror_prefix: self.mirror_prefix(&database_folder)?,
+            flatten_suffixes: match self.flatten {
+                true => flatten_suffixes(relative_files(&source_files, &source_root)),
+                false => BTreeMap::new(),
+            },
+            sniff: self.sniff(options),
+            case_sensitive_ext: options.case_sensitive_ext,
+            portable_names: options.portable_names,
+        };
+
+        let mut expected = BTreeSet::new();
+        for entry in &source_files {
+            let relative_entry = entry.path().strip_prefix(&source_root)?;
+            expected.insert(self.predicted_mirror_entry(
+                &layout,
+                entry.path(),
+                relative_entry,
+                || hash_file(entry.path()),
+            )?);
+        }
+        self.orphaned_mirror_files(database_path, &expected, options)
+    }
+
+    /// Removes mirror files which no tracked source file maps to, without walking or hashing the
+    /// source, after forgetting tracked files whose source no longer exists. Mirror paths are
+    /// predicted from the recorded hashes as for [`Self::unmanaged_mirror_files`], with extra
+    /// outputs taken from the last sync. With `dry_run`, nothing is removed or recorded. Returns
+    /// the orphaned mirror files, relative to the mirror root.
+    pub fn purge_orphans(
+        &mut self,
+        database_path: &Path,
+        options: &SyncOptions,
+        dry_run: bool,
+    ) -> Result<Vec<PathBuf>> {
+        if self.archive_append {
+            bail!("The mirror is an append-only archive (`--archive-append`), nothing is ever removed from it");
+        }
+        self.require_local_mirror("purge-orphans")?;
+        let database_folder = database_folder(database_path)?;
+        let source_root = self.source_root(&database_folder);
+        let mirror_root = self.mirror_root(&database_folder);
+        if ArchiveKind::detect(&source_root).is_some() {
+            bail!(
+                "Source `{0}` is an archive, whose files can't be checked without reading it, sync it instead",
+                source_root.display()
+            );
+        }
+
+        let (tracked, vanished): (BTreeSet<_>, BTreeSet<_>) = self
+            .hashes
+            .keys()
+            .cloned()
+            .partition(|key| self.source_file(&database_folder, key).exists());
+        Self::check_source_shrinkage(&source_root, self.hashes.len(), tracked.len(), options)?;
+        let relative_entry = |key: &Path| {
+            key.strip_prefix(&self.source_path)
+                .unwrap_or(key)
+                .to_path_buf()
+        };
+        let layout = PredictedLayout {
+            filters: self.native_filters(&database_folder, options),
+            mirror_prefix: self.mirror_prefix(&database_folder)?,
+            flatten_suffixes: match self.flatten {
+                true => flatten_suffixes(tracked.iter().map(|key| relative_entry(key))),
+                false => BTreeMap::new(),
+            },
+            sniff: self.sniff(options),
+            case_sensitive_ext: options.case_sensitive_ext,
+            portable_names: options.portable_names,
+        };
+
+        let mut expected = BTreeSet::new();
+        for key in &tracked {
+            expected.insert(self.predicted_mirror_entry(
+                &layout,
+                &self.source_file(&database_folder, key),
+                &relative_entry(key),
+                || Ok(self.hashes[key].clone()),
+            )?);
+            let extra_outputs = self.outputs.get(key).into_iter().flat_map(BTreeMap::keys);
+            expected.extend(extra_outputs.map(|output| match self.encryption_key_file {
+                Some(_) => encrypted_path(output),
+                None => output.clone(),
+            }));
+        }
+
+        let orphans = self
+            .orphaned_mirror_files(database_path, &expected, options)?
+            .into_iter()
+            .filter(|orphan| {
+                let owned = self.owned.as_ref();
+                owned.is_none_or(|owned| owned.contains(orphan))
+            })
+            .collect::<Vec<_>>();
+        if dry_run {
+            return Ok(orphans);
+        }
+
+        for orphan in &orphans {
+            let orphan_path = mirror_root.join(orphan);
+            log::info!("Removing `{0}`...", orphan_path.display());
+            fs::remove_file(&orphan_path)
+                .with_context(|| format!("Failed to remove file `{0}`", orphan_path.display()))?;
+            if let Some(trace) = &options.trace {
+                trace.record(TraceAction::Deleted, None, Some(&orphan_path), None);
             }
-            None => {
-                copy(source, mirror).with_context(|| {
-                    format!(
-                        "Failed to copy source `{0}` to mirror `{1}`",
-                        source.display(),
-                        mirror.display()
-                    )
-                })?;
+            if let Some(owned) = &mut self.owned {
+                owned.remove(orphan);
             }
+        }
+        for key in &vanished {
+            self.hashes.remove(key);
+            self.sizes.remove(key);
+            self.metadata.remove(key);
+            self.outputs.remove(key);
+            self.failed.remove(key);
+            self.placeholders.remove(key);
+        }
+        for sources in self.collisions.values_mut() {
+            sources.retain(|source| !vanished.contains(source));
+        }
+        self.collisions.retain(|_, sources| sources.len() > 1);
+        self.changes = SyncChanges {
+            removed: orphans.clone(),
+            ..Default::default()
         };
+        self.save(database_path, options.tmpdir.as_deref())?;
+        Ok(orphans)
+    }
 
+    /// Removes everything in the mirror besides files its `.mmdbignore` protects (and the
+    /// database, if it lives there), along with the directories left empty
+    pub fn clear_mirror(&self, database_path: &Path) -> Result<()> {
+        self.require_local_mirror("rebuild --clean")?;
+        let mirror_root = self.mirror_root(&database_folder(database_path)?);
+        if !mirror_root.exists() {
+            return Ok(());
+        }
+        let ignore = MirrorIgnore::load(&mirror_root)?;
+        let database_files = DatabaseFiles::new(database_path);
+        for entry in WalkDir::new(&mirror_root).min_depth(1).contents_first(true) {
+            let entry = entry.with_context(|| "Failed to walk mirror directory")?;
+            let entry_path = entry.path();
+            if entry.file_type().is_dir() {
+                // Directories holding anything that was kept are kept too
+                let is_empty = entry_path
+                    .read_dir()
+                    .with_context(|| format!("Failed to read `{0}`", entry_path.display()))?
+                    .next()
+                    .is_none();
+                if is_empty {
+                    fs::remove_dir(entry_path)
+                        .with_context(|| format!("Failed to remove `{0}`", entry_path.display()))?;
+                }
+                continue;
+            }
+            let relative_entry = entry_path.strip_prefix(&mirror_root)?;
+            if ignore.is_ignored(relative_entry) || database_files.contains(entry_path) {
+                continue;
+            }
+            fs::remove_file(entry_path)
+                .with_context(|| format!("Failed to remove `{0}`", entry_path.display()))?;
+        }
         Ok(())
     }
 
-    fn handle_dir_entry(&self, source: &Path, mirror: &Path) -> Result<()> {
-        create_dir_all(source).with_context(|| {
-            format!(
-                "Failed to create mirror directory ({0}) for entry `{1}`",
-                mirror.display(),
-                source.display()
-            )
-        })
+    /// Where the source file `source_entry` is mirrored to, relative to the mirror root, as far as
+    /// can be told without syncing it. `digest` is only called for mirror templates using `{hash}`.
+    fn predicted_mirror_entry(
+        &self,
+        layout: &PredictedLayout,
+        source_entry: &Path,
+        relative_entry: &Path,
+        digest: impl FnOnce() -> Result<String>,
+    ) -> Result<PathBuf> {
+        let mut mirror_entry = layout.mirror_prefix.join(relative_entry);
+        find_filter_for_entry(
+            source_entry,
+            relative_entry,
+            &mut mirror_entry,
+            &layout.filters,
+            layout.sniff,
+            layout.case_sensitive_ext,
+        );
+        let transforms = find_transforms_for_entry(relative_entry, &self.transforms);
+        apply_path_transforms(&transforms, &mut mirror_entry);
+        if layout.portable_names {
+            mirror_entry = portable_relative_path(&mirror_entry);
+        }
+        if let Some(template) = &self.mirror_template {
+            let digest = match template.contains("{hash}") {
+                true => digest()?,
+                false => String::new(),
+            };
+            mirror_entry = render_mirror_template(template, &mirror_entry, &digest);
+        }
+        if self.flatten {
+            let suffix = layout.flatten_suffixes.get(relative_entry);
+            mirror_entry = flat_mirror_path(&mirror_entry, suffix.map(String::as_str));
+        }
+        if self.encryption_key_file.is_some() {
+            mirror_entry = encrypted_path(&mirror_entry);
+        }
+        Ok(mirror_entry)
     }
 
-    fn cleanup(&self, mirror_list: &BTreeSet<PathBuf>) -> Result<()> {
-        WalkDir::new(&self.mirror_path)
-            .into_iter()
-            .try_for_each(|entry| -> Result<()> {
-                let entry_path = entry?.into_path();
+    /// Files in the mirror, relative to its root, which aren't `expected` and aren't ignored
+    fn orphaned_mirror_files(
+        &self,
+        database_path: &Path,
+        expected: &BTreeSet<PathBuf>,
+        options: &SyncOptions,
+    ) -> Result<Vec<PathBuf>> {
+        if let Some(remote) = self.remote_mirror()? {
+            let files = remote_files(&remote, options)?;
+            let ignore = MirrorIgnore::load_remote(&remote, &*files)?;
+            let listing = files
+                .list()
+                .with_context(|| format!("Failed to list remote mirror `{remote}`"))?;
+            return Ok(listing
+                .into_iter()
+                .filter(|entry| {
+                    !entry.is_dir
+                        && !expected.contains(&entry.path)
+                        && !ignore.is_ignored(&entry.path)
+                })
+                .map(|entry| entry.path)
+                .collect());
+        }
 
-                if !mirror_list.contains(&entry_path) {
-                    log::info!("Removing `{0}`...", entry_path.display());
-                    if entry_path.is_dir() {
-                        std::fs::remove_dir_all(&entry_path).with_context(|| {
-                            format!("Failed to remove directory `{0}`", entry_path.display())
-                        })?;
-                    } else {
-                        std::fs::remove_file(&entry_path).with_context(|| {
-                            format!("Failed to remove file `{0}`", entry_path.display())
-                        })?;
-                    }
+        let mirror_root = self.mirror_root(&database_folder(database_path)?);
+        let ignore = MirrorIgnore::load(&mirror_root)?;
+        let database_files = DatabaseFiles::new(database_path);
+        WalkDir::new(&mirror_root)
+            .min_depth(1)
+            .into_iter()
+            .filter_map(|entry| match entry {
+                Ok(entry) if entry.file_type().is_dir() => None,
+                Ok(entry) => {
+                    let relative_entry = entry.path().strip_prefix(&mirror_root).ok()?;
+                    (!expected.contains(relative_entry)
+                        && !ignore.is_ignored(relative_entry)
+                        && !database_files.contains(entry.path()))
+                    .then(|| Ok(relative_entry.to_path_buf()))
                 }
-
-                Ok(())
+                Err(e) => Some(Err(e).with_context(|| "Failed to walk mirror directory")),
             })
+            .collect()
     }
 
-    fn log_progress(counter: Arc<Mutex<usize>>, max_count: usize) -> Result<()> {
-        let mut counter = match counter.lock() {
-            Ok(counter) => counter,
-            Err(poisoned) => poisoned.into_inner(),
+    /// The database's filters, with paths resolved relative to `database_folder`, or the filters
+    /// directory for relative filter programs
+    pub fn filters(&self, database_folder: &Path) -> Vec<Filter> {
+        let filters_root = self.filters_root(database_folder);
+        let resolve = |filter: &str| {
+            Filter::resolve(
+                self.local_programs
+                    .get(filter)
+                    .map_or(filter, String::as_str),
+                &filters_root,
+                self.filter_dirs.get(filter).map(PathBuf::as_path),
+                database_folder,
+            )
+            .configured(self.filter_config(filter), database_folder)
         };
-        *counter += 1;
+        self.filters
+            .iter()
+            .map(|filter| {
+                resolve(filter)
+                    .restricted_to(self.filter_paths(filter))
+                    .chained(
+                        self.filter_chain(filter)
+                            .iter()
+                            .map(|next| resolve(next))
+                            .collect(),
+                    )
+            })
+            .collect()
+    }
 
-        let progress = 100.0 * (*counter as f64 / max_count as f64);
+    /// Where files are staged before being encrypted into the mirror, when it's encrypted. It's
+    /// outside the mirror, which may be synced somewhere untrusted as soon as a file appears.
+    fn plain_staging(&self, database_path: &Path, options: &SyncOptions) -> Option<StagingDir> {
+        self.encryption_key_file
+            .as_ref()
+            .map(|_| StagingDir::new(staging_dir(database_path, options.tmpdir.as_deref())))
+    }
 
-        const BLOCK_COUNT: usize = 20;
-        let num_blocks = 20 * *counter / max_count;
+    /// [`Self::filters`], with any implemented natively in `options` run in-process as they are
+    /// while syncing
+    fn native_filters(&self, database_folder: &Path, options: &SyncOptions) -> Vec<Filter> {
+        self.filters(database_folder)
+            .into_iter()
+            .map(|filter| filter.natively(&options.native_filters))
+            .collect()
+    }
+
+    pub fn mirror_path(&self) -> &Path {
+        &self.mirror_path
+    }
+
+    pub fn filters_dir(&self) -> Option<&Path> {
+        self.filters_dir.as_deref()
+    }
+
+    pub fn relative_to(&self) -> Option<&Path> {
+        self.relative_to.as_deref()
+    }
+
+    /// Where the source's contents are placed within the mirror, the source's path relative to
+    /// `relative_to`. Compared by real path, so however either is spelled, every source file is
+    /// checked to be inside it.
+    pub fn mirror_prefix(&self, database_folder: &Path) -> Result<PathBuf> {
+        let Some(relative_to) = &self.relative_to else {
+            return Ok(PathBuf::new());
+        };
+        let real_path = |path: &Path| {
+            fs::canonicalize(path)
+                .with_context(|| format!("Failed to resolve real path of `{0}`", path.display()))
+        };
+        let source_root = real_path(&self.source_root(database_folder))?;
+        let layout_root = real_path(&database_folder.join(relative_to))?;
+        source_root
+            .strip_prefix(&layout_root)
+            .map(Path::to_path_buf)
+            .with_context(|| {
+                format!(
+                    "Source `{0}` isn't inside `{1}`, which mirror paths are relative to",
+                    source_root.display(),
+                    layout_root.display()
+                )
+            })
+    }
+
+    /// Source files which failed to mirror during the last sync
+    pub fn failed(&self) -> &BTreeSet<PathBuf> {
+        &self.failed
+    }
+
+    /// What the last sync or retry added, changed and removed, not saved with the database
+    pub fn changes(&self) -> &SyncChanges {
+        &self.changes
+    }
+
+    /// Hashes and sizes of mirror files (relative to the mirror root) the last sync already knew,
+    /// so they needn't be read again, e.g. for a manifest
+    pub fn mirror_hashes(&self) -> &BTreeMap<PathBuf, (String, u64)> {
+        &self.mirror_hashes
+    }
+
+    /// Mirror files which more than one source file mapped to during the last sync
+    pub fn collisions(&self) -> &BTreeMap<PathBuf, BTreeSet<PathBuf>> {
+        &self.collisions
+    }
+
+    pub fn sync(&mut self, database_path: &Path, options: &SyncOptions) -> Result<SyncStats> {
+        if let Some(file_list) = &options.file_list {
+            let keys = self.file_list_keys(database_path, file_list)?;
+            return self.sync_files(database_path, &keys, options);
+        }
+
+        let start = Instant::now();
+        let database_folder = database_folder(database_path)?;
+        // Source and mirror paths are stored relative to the database, resolve them once up front
+        // so nothing depends on the current working directory
+        let source_root = long_path(&self.source_root(&database_folder));
+        let snapshots = options
+            .snapshots
+            .as_deref()
+            .map(Snapshots::create)
+            .transpose()?;
+        let remote = self.remote_sync(database_path, options)?;
+        let mirror_root = long_path(&match (&snapshots, &remote) {
+            (Some(snapshots), _) => snapshots.root().to_path_buf(),
+            (_, Some(remote)) => remote.staging_root().to_path_buf(),
+            _ => self.mirror_root(&database_folder),
+        });
+        let database_files = DatabaseFiles::new(database_path);
+        let archive_kind = ArchiveKind::detect(&source_root);
+        let staging = staging_dir(database_path, options.tmpdir.as_deref());
+        let plain_staging = self.plain_staging(database_path, options);
+        let filters = self.sync_filters(
+            &database_folder,
+            &mirror_root,
+            archive_kind
+                .map(|_| staging.as_path())
+                .into_iter()
+                .chain(plain_staging.as_ref().map(StagingDir::path)),
+            options,
+        )?;
+
+        let dir_hashes = if options.merkle && archive_kind.is_none() {
+            directory_rollups(&source_root, &self.source_path, &database_files, options)?
+        } else {
+            BTreeMap::new()
+        };
+        // Rollups are still refreshed in checksum mode, they just aren't trusted
+        let unchanged_dirs = dir_hashes
+            .iter()
+            .filter(|_| !options.checksum)
+            .filter(|(dir, rollup)| self.dir_hashes.get(*dir) == Some(*rollup))
+            .map(|(dir, _)| dir.clone())
+            .collect();
+        let mut state = SyncState {
+            snapshots,
+            remote,
+            ..self.sync_state(
+                &database_folder,
+                &mirror_root,
+                unchanged_dirs,
+                plain_staging,
+                options,
+            )?
+        };
+
+        // Relative to the source root, their mirrors are left as they were
+        let mut unreadable_dirs = Vec::new();
+        match archive_kind {
+            Some(kind) => {
+                self.sync_archive(&state, &filters, options, &staging, &source_root, kind)?
+            }
+            None => {
+                // Walk source directory
+                let mut found = 0_usize;
+                let source_entries = WalkDir::new(&source_root)
+                    .same_file_system(options.one_file_system)
+                    .follow_links(options.dereference_db)
+                    .into_iter()
+                    // The database may live inside the source, but isn't part of it
+                    .filter_entry(|entry| {
+                        !database_files.contains(entry.path())
+                            && (options.no_default_ignores || !is_temp_artifact(entry))
+                    })
+                    .map(|entry| {
+                        found += 1;
+                        Self::log_walk_progress(found, options.progress, false).map(|_| entry)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Self::log_walk_progress(found, options.progress, true)?;
+                let (source_entries, walk_errors): (Vec<_>, Vec<_>) =
+                    source_entries.into_iter().partition(Result::is_ok);
+                for e in walk_errors.into_iter().filter_map(Result::err) {
+                    let Some(path) = e.path().filter(|_| options.keep_going_on_walk_errors) else {
+                        bail!(describe_walk_error(&e));
+                    };
+                    log::error!("{0}, skipping...", describe_walk_error(&e));
+                    Self::record_errors(&state, 1)?;
+                    unreadable_dirs.push(
+                        path.strip_prefix(&source_root)
+                            .unwrap_or(path)
+                            .to_path_buf(),
+                    );
+                }
+                let source_entries = source_entries
+                    .into_iter()
+                    .filter_map(Result::ok)
+                    .collect::<Vec<_>>();
+                if let Some(suffixes) = &mut state.flatten_suffixes {
+                    *suffixes = flatten_suffixes(relative_files(&source_entries, &source_root));
+                }
+                let total_entries = source_entries.len();
+                let parts = source_root.components().count();
+
+                source_entries
+                    .into_par_iter()
+                    .try_for_each(|entry| -> Result<()> {
+                        if state.defer() {
+                            return Ok(());
+                        }
+                        let source_entry = entry.into_path();
+                        let relative_entry =
+                            source_entry.components().skip(parts).collect::<PathBuf>();
+                        let _slot = state.concurrency_slot();
+                        self.handle_entry(
+                            &state,
+                            &filters,
+                            options,
+                            &relative_entry,
+                            &source_entry,
+                        )?;
+                        state.measure_throughput();
+                        Self::log_progress(&state, total_entries, options.progress)
+                    })?;
+            }
+        }
+
+        if options.progress == ProgressStyle::Bar {
+            println!();
+        }
+        state.entry_log.flush();
+
+        Self::run_batches(&state)?;
+        if let Some(quota) = &state.quota {
+            quota.report();
+        }
+
+        // Entries the time budget left for the next sync haven't gone anywhere, so everything
+        // recorded for them is kept, as for unreadable directories, and nothing is cleaned up
+        let deferred = state.deferred.load(Ordering::Relaxed);
+        if deferred {
+            unreadable_dirs.push(PathBuf::new());
+        }
+
+        let mut hashes = match state.hashes.lock() {
+                Ok(new_hashes) => new_hashes,
+                Err(poisoned) => {
+                    log::warn!("One or more threads panicked, hash list may be incomplete. Consider (re-)running `sync`...");
+                    poisoned.into_inner()
+                }
+            }
+            .clone();
+        let mut metadata = match state.metadata.lock() {
+            Ok(metadata) => metadata,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+        .clone();
+        // Files in directories which couldn't be walked haven't gone anywhere
+        let unreadable_keys = unreadable_dirs
+            .iter()
+            .map(|dir| self.source_path.join(dir))
+            .collect::<Vec<_>>();
+        let is_unreadable = |key: &Path| unreadable_keys.iter().any(|dir| key.starts_with(dir));
+        for (key, hash) in self.hashes.iter().filter(|(key, _)| is_unreadable(key)) {
+            hashes.entry(key.clone()).or_insert_with(|| hash.clone());
+        }
+        for (key, value) in self.metadata.iter().filter(|(key, _)| is_unreadable(key)) {
+            metadata.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+        // Nothing is removed from an append-only archive, so its source may shrink freely, and an
+        // incremental mirror only tracks what changed since its reference
+        if !self.archive_append && state.since.is_none() {
+            Self::check_source_shrinkage(&source_root, self.hashes.len(), hashes.len(), options)?;
+        }
+        let mut nonces = match state.nonces.lock() {
+            Ok(nonces) => nonces,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+        .clone();
+        let mut outputs = match state.outputs.lock() {
+            Ok(outputs) => outputs,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+        .clone();
+        let mut failed = match state.failures.lock() {
+            Ok(failures) => failures,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+        .clone();
+        if deferred {
+            for (key, value) in take(&mut self.outputs) {
+                outputs.entry(key).or_insert(value);
+            }
+            for (output, nonce) in take(&mut self.nonces) {
+                nonces.entry(output).or_insert(nonce);
+            }
+            let handled = match state.hashes.lock() {
+                Ok(handled) => handled,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            failed.extend(
+                take(&mut self.failed)
+                    .into_iter()
+                    .filter(|key| !handled.contains_key(key)),
+            );
+        } else {
+            // Rollups of directories which weren't finished would skip their files next time
+            self.dir_hashes = dir_hashes;
+        }
+        self.hashes = hashes;
+        self.settle_sizes(&state);
+        self.metadata = metadata;
+        self.nonces = nonces;
+        self.outputs = outputs;
+        self.collisions = match state.collisions.lock() {
+            Ok(collisions) => collisions,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+        .clone();
+        self.failed = failed;
+        self.update_placeholders(&state);
+
+        let mirror_list = match state.mirror_list.lock() {
+            Ok(mirror_list) => mirror_list,
+            Err(poisoned) => {
+                log::warn!("One or more threads panicked, mirror list may be incomplete. Consider (re-)running `sync`...");
+                poisoned.into_inner()
+            }
+        };
+        let previously_owned = self.owned.take();
+        if let Some(previously_owned) = &previously_owned {
+            self.owned = Some(owned_paths(
+                &mirror_root,
+                &mirror_list,
+                previously_owned,
+                &unreadable_dirs,
+            ));
+        }
+        // Nothing is recorded until it's on the host, so a failed upload is tried again next time
+        if let Some(remote) = &state.remote {
+            remote.upload()?;
+        }
+
+        self.save(database_path, options.tmpdir.as_deref())?;
+
+        // Nor are its files cleaned up if it lives inside the mirror
+        let rules = CleanupRules {
+            preserved_dirs: &unreadable_dirs,
+            owned: previously_owned.as_ref(),
+        };
+        let removed = match (self.archive_append || deferred, &state.remote) {
+            (true, _) => Vec::new(),
+            (false, Some(remote)) => {
+                Self::cleanup_remote(remote, &mirror_root, &mirror_list, &rules, options)?
+            }
+            (false, None) => {
+                self.cleanup(&mirror_root, &mirror_list, &database_files, &rules, options)?
+            }
+        };
+        self.changes = Self::collect_changes(&state, removed);
+        self.mirror_hashes = take(&mut *match state.mirror_hashes.lock() {
+            Ok(mirror_hashes) => mirror_hashes,
+            Err(poisoned) => poisoned.into_inner(),
+        });
+        if let Some(since) = state.since.as_ref().filter(|_| !deferred) {
+            since.write_changelog(&mirror_root, &unreadable_dirs)?;
+            if let Some(remote) = &state.remote {
+                remote.upload()?;
+            }
+        }
+
+        let mut stats = match state.stats.lock() {
+            Ok(stats) => stats,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+        .clone();
+        stats.elapsed = start.elapsed();
+
+        Ok(stats)
+    }
+
+    /// Refuses to record a sync which would empty (or nearly empty) a populated mirror, as that's
+    /// far more often an unmounted or mistyped source than files which were really deleted
+    fn check_source_shrinkage(
+        source_root: &Path,
+        previous_files: usize,
+        files: usize,
+        options: &SyncOptions,
+    ) -> Result<()> {
+        // Below this, small mirrors losing most of their files is too likely to be deliberate
+        const MIN_GUARDED_FILES: usize = 10;
+        const MAX_REMAINING_PERCENT: usize = 10;
+
+        let emptied = previous_files > 0 && files == 0;
+        let shrunk = previous_files >= MIN_GUARDED_FILES
+            && files * 100 < previous_files * MAX_REMAINING_PERCENT;
+        if (emptied || shrunk) && !options.empty_mirror_ok {
+            bail!(
+                "Source `{0}` has {files} files, down from {previous_files} at the last sync, is it mounted? Nothing has been recorded or cleaned up, pass `--empty-mirror-ok` if the files really were removed",
+                source_root.display()
+            );
+        }
+        if files == 0 {
+            log::warn!(
+                "Source `{0}` has no files, the mirror will be emptied...",
+                source_root.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// The database's filters, restricted by the sandbox if there is one. With `require_filters`,
+    /// every filter is probed first, failing before anything is synced if any don't respond.
+    fn sync_filters<'a>(
+        &self,
+        database_folder: &Path,
+        mirror_root: &Path,
+        staging: impl IntoIterator<Item = &'a Path>,
+        options: &SyncOptions,
+    ) -> Result<Vec<Filter>> {
+        // Filters need to write to the mirror, read staged archive entries, and write plaintext
+        // which is staged to be encrypted
+        let sandbox = options.sandbox.clone().map(|mut sandbox| {
+            sandbox.writable.push(mirror_root.to_path_buf());
+            sandbox
+                .writable
+                .extend(staging.into_iter().map(Path::to_path_buf));
+            sandbox.resolve()
+        });
+        let filters = self
+            .native_filters(database_folder, options)
+            .into_iter()
+            .map(|filter| filter.sandboxed(sandbox.as_ref()))
+            .collect::<Vec<_>>();
+
+        if options.require_filters {
+            let chained = filters.iter().flat_map(|filter| &filter.then);
+            let problems = filters
+                .iter()
+                .chain(chained)
+                .collect::<BTreeSet<_>>()
+                .into_par_iter()
+                .filter_map(probe_filter)
+                .collect::<Vec<_>>();
+            if !problems.is_empty() {
+                bail!(
+                    "{0} filters can't be used, nothing was synced:\n  {1}",
+                    problems.len(),
+                    problems.join("\n  ")
+                );
+            }
+        }
+        Ok(filters)
+    }
+
+    fn sync_state<'a>(
+        &self,
+        database_folder: &Path,
+        mirror_root: &Path,
+        unchanged_dirs: BTreeSet<PathBuf>,
+        plain_staging: Option<StagingDir>,
+        options: &'a SyncOptions,
+    ) -> Result<SyncState<'a>> {
+        Ok(SyncState {
+            mirror_root: mirror_root.to_path_buf(),
+            real_mirror_root: mirror_root.canonicalize().ok(),
+            mirror_prefix: self.mirror_prefix(database_folder)?,
+            hashes: Arc::new(Mutex::new(BTreeMap::new())),
+            sizes: Mutex::new(BTreeMap::new()),
+            metadata: Arc::new(Mutex::new(BTreeMap::new())),
+            mirror_list: Arc::new(Mutex::new(BTreeSet::new())),
+            counter: AtomicUsize::new(0),
+            last_draw: AtomicU64::new(0),
+            started: Instant::now(),
+            drawing: Mutex::new(()),
+            stats: Arc::new(Mutex::new(SyncStats::default())),
+            entry_log: EntryLog::new(options.ordered_log),
+            filter_slots: options
+                .filter_concurrency
+                .or(self.settings.filter_concurrency)
+                .map(Semaphore::new),
+            auto_concurrency: options
+                .concurrency_auto
+                .then(|| AutoConcurrency::new(rayon::current_num_threads())),
+            batches: Mutex::new(BTreeMap::new()),
+            trace: options.trace.as_ref(),
+            filter_log: options.filter_log.as_ref(),
+            unchanged_dirs,
+            reflink: options.reflink,
+            preserve_times: match options.preserve_times.is_empty() {
+                true => self.settings.preserve_times.clone(),
+                false => options.preserve_times.clone(),
+            },
+            chown: options.chown.map(Chown::new),
+            quota: options
+                .max_total_size
+                .map(|limit| Quota::new(limit, mirror_root))
+                .transpose()?,
+            time_budget: options.time_budget.as_ref(),
+            since: options.since_db.as_deref().map(SinceDb::load).transpose()?,
+            snapshots: None,
+            remote: None,
+            deferred: AtomicBool::new(false),
+            max_errors: options.max_errors.or(self.settings.max_errors),
+            newer_only: options.newer_only,
+            sniff: self.sniff(options),
+            case_sensitive_ext: options.case_sensitive_ext,
+            delta: options.delta,
+            single_pass: options.single_pass,
+            structure_only: options.structure_only,
+            checksum: options.checksum,
+            hasher: options.hasher(),
+            hash_cache: options.hash_cache.as_ref(),
+            cipher: self
+                .encryption_key_file
+                .as_ref()
+                .map(|key_file| Cipher::from_key_file(&database_folder.join(key_file)))
+                .transpose()?,
+            plain_staging,
+            nonces: Mutex::new(BTreeMap::new()),
+            outputs: Mutex::new(BTreeMap::new()),
+            mirror_hashes: Mutex::new(BTreeMap::new()),
+            claims: Mutex::new(BTreeMap::new()),
+            collisions: Mutex::new(BTreeMap::new()),
+            failures: Mutex::new(BTreeSet::new()),
+            changes: Mutex::new(BTreeMap::new()),
+            placeholders: Mutex::new(BTreeSet::new()),
+            flatten_suffixes: self.flatten.then(BTreeMap::new),
+            real_hashes: options.dereference_db.then(|| Mutex::new(BTreeMap::new())),
+        })
+    }
+
+    /// Re-attempts only the files which failed during the last sync, without walking the source
+    /// or cleaning up the mirror
+    pub fn retry(&mut self, database_path: &Path, options: &SyncOptions) -> Result<SyncStats> {
+        let failed = self.failed.clone();
+        self.sync_files(database_path, &failed, options)
+    }
+
+    /// The keys of the source files in a `--file-list`, which are relative to the source
+    /// directory, or absolute paths within it
+    fn file_list_keys(
+        &self,
+        database_path: &Path,
+        file_list: &[PathBuf],
+    ) -> Result<BTreeSet<PathBuf>> {
+        let source_root = self.source_root(&database_folder(database_path)?);
+        let database_files = DatabaseFiles::new(database_path);
+        let absolute_root = absolute(&source_root).with_context(|| {
+            format!(
+                "Failed to resolve absolute path of `{0}`",
+                source_root.display()
+            )
+        })?;
+
+        Ok(file_list
+            .iter()
+            .filter_map(|path| {
+                let relative = match path.strip_prefix(&absolute_root) {
+                    Ok(relative) => relative,
+                    Err(_) if path.is_absolute() => {
+                        log::warn!(
+                            "`{0}` isn't in the source directory, skipping...",
+                            path.display()
+                        );
+                        return None;
+                    }
+                    Err(_) => path,
+                };
+                let relative = relative
+                    .components()
+                    .filter(|component| *component != Component::CurDir)
+                    .collect::<PathBuf>();
+                if relative.as_os_str().is_empty()
+                    || relative
+                        .components()
+                        .any(|component| component == Component::ParentDir)
+                {
+                    log::warn!(
+                        "`{0}` isn't a file in the source directory, skipping...",
+                        path.display()
+                    );
+                    return None;
+                }
+                if database_files.contains(&source_root.join(&relative)) {
+                    log::warn!("`{0}` is the database itself, skipping...", path.display());
+                    return None;
+                }
+                Some(self.source_path.join(relative))
+            })
+            .collect())
+    }
+
+    /// Mirrors only the given source files (by key), without walking the source or cleaning up
+    /// the mirror
+    fn sync_files(
+        &mut self,
+        database_path: &Path,
+        keys: &BTreeSet<PathBuf>,
+        options: &SyncOptions,
+    ) -> Result<SyncStats> {
+        let start = Instant::now();
+        let database_folder = database_folder(database_path)?;
+        let source_root = long_path(&self.source_root(&database_folder));
+        if ArchiveKind::detect(&source_root).is_some() {
+            bail!(
+                "Files from an archive source can't be mirrored on their own, sync the whole \
+                 archive instead"
+            );
+        }
+        let remote = self.remote_sync(database_path, options)?;
+        let mirror_root = long_path(&match &remote {
+            Some(remote) => remote.staging_root().to_path_buf(),
+            None => self.mirror_root(&database_folder),
+        });
+
+        let plain_staging = self.plain_staging(database_path, options);
+        let filters = self.sync_filters(
+            &database_folder,
+            &mirror_root,
+            plain_staging.as_ref().map(StagingDir::path),
+            options,
+        )?;
+        let mut state = SyncState {
+            remote,
+            ..self.sync_state(
+                &database_folder,
+                &mirror_root,
+                BTreeSet::new(),
+                plain_staging,
+                options,
+            )?
+        };
+        // Without a walk, the source files are the tracked ones still there and those given
+        if let Some(suffixes) = &mut state.flatten_suffixes {
+            let source_files = self
+                .hashes
+                .keys()
+                .chain(keys)
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .filter(|key| self.source_file(&database_folder, key).is_file())
+                .filter_map(|key| key.strip_prefix(&self.source_path).ok())
+                .map(Path::to_path_buf);
+            *suffixes = flatten_suffixes(source_files);
+        }
+
+        let total_entries = keys.len();
+        let deferred = Mutex::new(BTreeSet::new());
+        keys.par_iter().try_for_each(|key| -> Result<()> {
+            if state.defer() {
+                match deferred.lock() {
+                    Ok(mut deferred) => deferred.insert(key),
+                    Err(poisoned) => poisoned.into_inner().insert(key),
+                };
+                return Ok(());
+            }
+            let source_entry = self.source_file(&database_folder, key);
+            match key.strip_prefix(&self.source_path) {
+                Ok(relative_entry) if source_entry.exists() => {
+                    let _slot = state.concurrency_slot();
+                    self.handle_entry(&state, &filters, options, relative_entry, &source_entry)?;
+                    state.measure_throughput();
+                }
+                _ => log::info!(
+                    "`{0}` no longer exists, it will be cleaned up by the next full sync...",
+                    key.display()
+                ),
+            }
+            Self::log_progress(&state, total_entries, options.progress)
+        })?;
+
+        if options.progress == ProgressStyle::Bar {
+            println!();
+        }
+        state.entry_log.flush();
+        Self::run_batches(&state)?;
+        if let Some(quota) = &state.quota {
+            quota.report();
+        }
+
+        // Only the given files have changed, everything else stays as it was
+        macro_rules! merge {
+            ($field:ident) => {
+                self.$field.extend(
+                    match state.$field.lock() {
+                        Ok($field) => $field,
+                        Err(poisoned) => poisoned.into_inner(),
+                    }
+                    .clone(),
+                )
+            };
+        }
+        merge!(hashes);
+        self.settle_sizes(&state);
+        merge!(metadata);
+        merge!(nonces);
+        merge!(outputs);
+        if let Some(owned) = &mut self.owned {
+            let mirror_list = match state.mirror_list.lock() {
+                Ok(mirror_list) => mirror_list,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            // Nothing is cleaned up, so what was owned before stays owned
+            owned.extend(owned_paths(
+                &mirror_root,
+                &mirror_list,
+                &BTreeSet::new(),
+                &[],
+            ));
+        }
+        // Files left for the next sync are still as they were
+        let deferred = match deferred.into_inner() {
+            Ok(deferred) => deferred,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        self.failed
+            .retain(|key| !keys.contains(key) || deferred.contains(key));
+        self.failed.extend(
+            match state.failures.lock() {
+                Ok(failures) => failures,
+                Err(poisoned) => poisoned.into_inner(),
+            }
+            .iter()
+            .cloned(),
+        );
+        self.changes = Self::collect_changes(&state, Vec::new());
+        self.update_placeholders(&state);
+        if let Some(remote) = &state.remote {
+            remote.upload()?;
+        }
+
+        self.save(database_path, options.tmpdir.as_deref())?;
+
+        let mut stats = match state.stats.lock() {
+            Ok(stats) => stats,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+        .clone();
+        stats.elapsed = start.elapsed();
+
+        Ok(stats)
+    }
+
+    /// Mirrors the entries of an archive source, extracting them one at a time to a staging
+    /// directory rather than unpacking the whole archive up front
+    fn sync_archive(
+        &self,
+        state: &SyncState,
+        filters: &[Filter],
+        options: &SyncOptions,
+        staging: &Path,
+        archive: &Path,
+        kind: ArchiveKind,
+    ) -> Result<()> {
+        let total_entries = count_entries(archive, kind)?;
+        create_dir_all(staging).with_context(|| {
+            format!(
+                "Failed to create staging directory `{0}`",
+                staging.display()
+            )
+        })?;
+
+        let result = for_each_entry(archive, kind, staging, |entry| {
+            let ArchiveEntry { relative, staged } = entry;
+            if state.defer() {
+                let _ = fs::remove_file(&staged);
+                return Ok(());
+            }
+            let batched = self.handle_entry(state, filters, options, &relative, &staged)?;
+            // Batched entries are read when the batch runs, the rest can go straight away
+            if staged.is_file() && !batched {
+                let _ = fs::remove_file(&staged);
+            }
+            Self::log_progress(state, total_entries, options.progress)
+        });
+
+        // Batches still need their staged files, so they're run before the staging directory goes
+        let result = result.and_then(|()| Self::run_batches(state));
+        if let Err(e) = fs::remove_dir_all(staging) {
+            log::warn!(
+                "Failed to remove staging directory `{0}`: {e}",
+                staging.display()
+            );
+        }
+        result
+    }
+
+    /// Mirrors a single source entry, returning whether it was queued for a batch filter
+    fn handle_entry(
+        &self,
+        state: &SyncState,
+        filters: &[Filter],
+        options: &SyncOptions,
+        relative_entry: &Path,
+        source_entry: &Path,
+    ) -> Result<bool> {
+        let key = self.source_path.join(relative_entry);
+        let mut mirror_entry = state
+            .mirror_root
+            .join(&state.mirror_prefix)
+            .join(relative_entry);
+        let filter = find_filter_for_entry(
+            source_entry,
+            relative_entry,
+            &mut mirror_entry,
+            filters,
+            state.sniff,
+            state.case_sensitive_ext,
+        );
+        let transforms = find_transforms_for_entry(relative_entry, &self.transforms);
+        apply_path_transforms(&transforms, &mut mirror_entry);
+        if options.portable_names {
+            let relative_mirror = mirror_entry
+                .strip_prefix(&state.mirror_root)
+                .unwrap_or(&mirror_entry);
+            mirror_entry = state
+                .mirror_root
+                .join(portable_relative_path(relative_mirror));
+        } else if cfg!(windows)
+            && relative_entry
+                .iter()
+                .any(|name| portable_file_name(name).is_some())
+        {
+            log::warn!(
+                "`{0}` can't be represented on Windows, pass `--portable-names` to rename it in the mirror",
+                relative_entry.display()
+            );
+        }
+        if let Some(suffixes) = state
+            .flatten_suffixes
+            .as_ref()
+            .filter(|_| !source_entry.is_dir())
+        {
+            let relative_mirror = mirror_entry
+                .strip_prefix(&state.mirror_root)
+                .unwrap_or(&mirror_entry);
+            let suffix = suffixes.get(relative_entry).map(String::as_str);
+            mirror_entry = state
+                .mirror_root
+                .join(flat_mirror_path(relative_mirror, suffix));
+        }
+        let mirror_entry = mirror_entry;
+        if !is_within(
+            &state.mirror_root,
+            state.real_mirror_root.as_deref(),
+            &mirror_entry,
+        ) {
+            log::error!(
+                "`{0}` would be mirrored to `{1}`, outside the mirror directory, skipping...",
+                key.display(),
+                mirror_entry.display()
+            );
+            Self::record_failure(state, &key)?;
+            return Ok(false);
+        }
+
+        let batched = filter.is_some_and(|filter| {
+            source_entry.is_file()
+                && state
+                    .batch_support(filter, source_entry, &mirror_entry)
+                    .is_some()
+        });
+
+        // With a template or flattening, the mirror's directory structure comes from the
+        // rendered file paths instead of the source, and when pruning, directories are only
+        // created to hold files
+        let mirror_entry = if source_entry.is_dir()
+            && self.mirror_template.is_none()
+            && !self.flatten
+            && options.empty_dirs != EmptyDirs::Prune
+        {
+            self.handle_dir_entry(source_entry, &mirror_entry)?;
+            Some(mirror_entry)
+        } else if source_entry.is_file() {
+            self.handle_file_entry(
+                state,
+                filter,
+                &transforms,
+                &key,
+                source_entry,
+                &mirror_entry,
+            )?
+        } else {
+            None
+        };
+
+        if let Some(mirror_entry) = mirror_entry {
+            let mut mirror_list = match state.mirror_list.lock() {
+                Ok(mirror_list) => mirror_list,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            mirror_list.insert(mirror_entry);
+        }
+
+        Ok(batched)
+    }
+
+    fn save(&mut self, database_path: &Path, tmpdir: Option<&Path>) -> Result<()> {
+        // Written in this version's format, whichever it was read in
+        self.format_version = FORMAT_VERSION;
+        write_atomically(database_path, tmpdir, |file| {
+            let mut writer = BufWriter::new(file);
+            serde_json::to_writer_pretty(&mut writer, self)
+                .with_context(|| "Failed to format database to json")?;
+            writer
+                .flush()
+                .with_context(|| format!("Failed to write {0}", database_path.display()))
+        })
+    }
+
+    fn handle_file_entry(
+        &self,
+        state: &SyncState,
+        filter: Option<&Filter>,
+        transforms: &[TransformKind],
+        key: &Path,
+        source: &Path,
+        mirror: &Path,
+    ) -> Result<Option<PathBuf>> {
+        // Read before hashing, which may update the access time
+        let source_metadata = match source.metadata() {
+            Ok(source_metadata) => source_metadata,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Self::log_vanished_source(state, key, source);
+                return Ok(None);
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read metadata of `{0}`", source.display()))
+            }
+        };
+        let size = source_metadata.len();
+        {
+            let mut sizes = match state.sizes.lock() {
+                Ok(sizes) => sizes,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            sizes.insert(key.to_path_buf(), size);
+        }
+        let unchanged_dir = key
+            .parent()
+            .is_some_and(|parent| state.unchanged_dirs.contains(parent));
+        // An unreadable source keeps its previous mirror, rather than being treated as deleted
+        let mut readable = true;
+        let mut teed = None;
+        let digest = match self.hashes.get(key) {
+            Some(prev_hash) if unchanged_dir => prev_hash.clone(),
+            prev_hash => {
+                // Falls back to reading twice if the copy can't be written, so errors from it
+                // aren't mistaken for the source being unreadable
+                let single_pass = self
+                    .copies_in_single_pass(state, filter, transforms, key, source, mirror)
+                    .then(|| Self::hash_and_copy_source(state, source, mirror, &source_metadata))
+                    .and_then(|hashed| {
+                        hashed
+                            .map_err(|e| log::debug!("{e:#}, hashing and copying separately..."))
+                            .ok()
+                    });
+                let hashed = match single_pass {
+                    Some((copy, digest)) => {
+                        teed = Some(copy);
+                        Ok(digest)
+                    }
+                    None => Self::hash_source(state, source, &source_metadata),
+                };
+                match hashed {
+                    Ok(digest) => digest,
+                    Err(e) => match e.downcast_ref::<io::Error>().map(io::Error::kind) {
+                        Some(ErrorKind::PermissionDenied) => match prev_hash {
+                            Some(prev_hash) => {
+                                state.entry_log.log(
+                                source,
+                                Level::Warn,
+                                format!(
+                                    "Permission denied reading `{0}`, keeping its previous mirror...",
+                                    key.display()
+                                ),
+                            );
+                                readable = false;
+                                prev_hash.clone()
+                            }
+                            None => {
+                                log::error!(
+                                "Permission denied reading `{0}`, which hasn't been mirrored before, skipping...",
+                                key.display()
+                            );
+                                Self::record_failure(state, key)?;
+                                return Ok(None);
+                            }
+                        },
+                        Some(ErrorKind::NotFound) => {
+                            Self::log_vanished_source(state, key, source);
+                            return Ok(None);
+                        }
+                        _ => return Err(e),
+                    },
+                }
+            }
+        };
+
+        if let Some(since) = &state.since {
+            let relative = key.strip_prefix(&self.source_path).unwrap_or(key);
+            if since.unchanged(relative, &digest) {
+                state.entry_log.log(
+                    source,
+                    Level::Debug,
+                    format!(
+                        "`{0}` is unchanged since `--since-db`, skipping...",
+                        key.display()
+                    ),
+                );
+                state.trace(TraceAction::Skipped, Some(source), None, None);
+                return Ok(None);
+            }
+        }
+
+        let mirror = match &self.mirror_template {
+            Some(template) => {
+                let relative_mirror = mirror.strip_prefix(&state.mirror_root).unwrap_or(mirror);
+                state
+                    .mirror_root
+                    .join(render_mirror_template(template, relative_mirror, &digest))
+            }
+            None => mirror.to_path_buf(),
+        };
+        if !is_within(
+            &state.mirror_root,
+            state.real_mirror_root.as_deref(),
+            &mirror,
+        ) {
+            log::error!(
+                "`{0}` would be mirrored to `{1}` by the mirror template, outside the mirror directory, skipping...",
+                key.display(),
+                mirror.display()
+            );
+            Self::record_failure(state, key)?;
+            return Ok(None);
+        }
+        // Filters and copies write to `output`, which is then encrypted to `mirror` if enabled
+        let output = mirror.as_path();
+        let mirror = match state.cipher {
+            Some(_) => encrypted_path(output),
+            None => output.to_path_buf(),
+        };
+        let mirror = mirror.as_path();
+
+        // Held until the entry is written, so colliding entries can't write over each other at once
+        let claim = Self::claim_mirror(state, mirror);
+        let mut owner = match claim.lock() {
+            Ok(owner) => owner,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let mut force = false;
+        if let Some(other) = owner.as_ref().filter(|other| *other != key) {
+            let relative_mirror = mirror.strip_prefix(&state.mirror_root).unwrap_or(mirror);
+            Self::record_collision(state, relative_mirror, [other.as_path(), key]);
+
+            // The first source path always wins, whichever order the entries are handled in
+            if key > other.as_path() {
+                log::warn!(
+                    "`{0}` and `{1}` both map to `{2}`, keeping `{1}`...",
+                    key.display(),
+                    other.display(),
+                    relative_mirror.display()
+                );
+                return Ok(Some(mirror.to_path_buf()));
+            }
+            log::warn!(
+                "`{0}` and `{1}` both map to `{2}`, keeping `{0}`...",
+                key.display(),
+                other.display(),
+                relative_mirror.display()
+            );
+            Self::forget_entry(state, other, output);
+            force = true;
+        }
+        *owner = Some(key.to_path_buf());
+
+        create_dir_all(
+            mirror
+                .parent()
+                .with_context(|| "Failed to get file parent")?,
+        )
+        .with_context(|| {
+            format!(
+                "Failed to create mirror directory ({0}) for entry `{1}`",
+                mirror.display(),
+                source.display()
+            )
+        })?;
+
+        {
+            let mut hashes = match state.hashes.lock() {
+                Ok(hashes) => hashes,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            hashes.insert(key.to_path_buf(), digest.clone());
+        }
+        let mut change = Change::Added;
+        // The mirror holds exactly the source's contents, so shares its hash
+        let exact_copy =
+            filter.is_none() && !has_content_transforms(transforms) && state.cipher.is_none();
+        // Placeholders are only kept while syncing structure, otherwise they're filled in
+        let is_placeholder = !state.structure_only && self.placeholders.contains(key);
+        // A failed filter leaves the previous mirror in place, which is out of date
+        let failed_before = self.failed.contains(key);
+        // An append-only archive never overwrites what's already there, besides placeholders
+        if self.archive_append && !is_placeholder && state.mirror_exists(mirror) {
+            state.entry_log.log(
+                source,
+                Level::Debug,
+                format!(
+                    "`{0}` is already archived, leaving it alone...",
+                    key.display()
+                ),
+            );
+            state.trace(TraceAction::Skipped, Some(source), Some(mirror), None);
+            Self::record_file(state.stats.clone(), key, None, 0, size);
+            self.keep_previous_hash(state, key);
+            self.carry_over(state, key, output);
+            return Ok(Some(mirror.to_path_buf()));
+        }
+        if let Some(prev_hash) = self
+            .hashes
+            .get(key)
+            .filter(|_| !force && !is_placeholder && !failed_before)
+        {
+            // A fresh snapshot starts out empty
+            if &digest == prev_hash && !mirror.exists() {
+                self.link_previous_snapshot(state, key, mirror);
+            }
+            if state.mirror_exists(mirror) || (state.snapshots.is_some() && &digest != prev_hash) {
+                if &digest == prev_hash {
+                    state.entry_log.log(
+                        source,
+                        Level::Debug,
+                        format!("File `{0}` unchanged, skipping...", key.display()),
+                    );
+                    state.trace(TraceAction::Skipped, Some(source), Some(mirror), None);
+                    Self::record_file(state.stats.clone(), key, None, 0, size);
+                    if exact_copy {
+                        Self::record_mirror_hash(state, mirror, &digest, size);
+                    }
+                    self.carry_over(state, key, output);
+                    return Ok(Some(mirror.to_path_buf()));
+                } else {
+                    state.entry_log.log(
+                        source,
+                        Level::Info,
+                        format!("File `{0}` changed...", key.display()),
+                    );
+                    change = Change::Changed;
+                }
+            } else {
+                state.entry_log.log(
+                    source,
+                    Level::Info,
+                    format!("New file `{0}`...", key.display()),
+                );
+                // TODO: Chain if-let &&
+            }
+        } else {
+            state.entry_log.log(
+                source,
+                Level::Info,
+                format!("New file `{0}`...", key.display()),
+            );
+        }
+
+        if !readable {
+            log::error!(
+                "Permission denied reading `{0}`, and its previous mirror is missing, skipping...",
+                key.display()
+            );
+            Self::record_failure(state, key)?;
+            return Ok(None);
+        }
+
+        if state.newer_only && Self::mirror_is_newer(&source_metadata, mirror) {
+            state.entry_log.log(
+                source,
+                Level::Warn,
+                format!(
+                    "Mirror of `{0}` is newer than the source, leaving it alone...",
+                    key.display()
+                ),
+            );
+            state.trace(TraceAction::Skipped, Some(source), Some(mirror), None);
+            Self::record_file(state.stats.clone(), key, None, 0, size);
+            // Keep the old hash, so the conflict is reported again until it's resolved
+            self.keep_previous_hash(state, key);
+            self.carry_over(state, key, output);
+            return Ok(Some(mirror.to_path_buf()));
+        }
+
+        // Placeholders are empty, so files only count towards the quota once they're filled in
+        let quota = state.quota.as_ref().filter(|_| !state.structure_only);
+        let reservation = quota.map(|quota| quota.reserve_for(mirror, size));
+        if let Some(None) = reservation {
+            state.entry_log.log(
+                source,
+                Level::Warn,
+                format!(
+                    "Mirroring `{0}` would take the mirror past `--max-total-size`, skipping...",
+                    key.display()
+                ),
+            );
+            state.trace(TraceAction::Skipped, Some(source), Some(mirror), None);
+            // Tried again by the next sync, once there may be room
+            self.keep_previous_hash(state, key);
+            if !mirror.exists() {
+                return Ok(None);
+            }
+            Self::record_file(state.stats.clone(), key, None, 0, size);
+            self.carry_over(state, key, output);
+            return Ok(Some(mirror.to_path_buf()));
+        }
+
+        {
+            let mut changes = match state.changes.lock() {
+                Ok(changes) => changes,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            changes.insert(key.to_path_buf(), change);
+        }
+
+        if state.structure_only {
+            File::create(mirror)
+                .with_context(|| format!("Failed to create placeholder `{0}`", mirror.display()))?;
+            state.trace(TraceAction::Placeholder, Some(source), Some(mirror), None);
+            let mut placeholders = match state.placeholders.lock() {
+                Ok(placeholders) => placeholders,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            placeholders.insert(key.to_path_buf());
+            Self::record_file(state.stats.clone(), key, Some(change), 0, size);
+            return Ok(Some(mirror.to_path_buf()));
+        }
+
+        // An encrypted mirror's plaintext is written outside it, then encrypted into place
+        let written = state.staged(output);
+        let written = written.as_path();
+        Self::create_staging_dir(state, written)?;
+
+        let mut copied = size;
+        match filter {
+            Some(filter) if state.batch_support(filter, source, output).is_some() => {
+                let mut batches = match state.batches.lock() {
+                    Ok(batches) => batches,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                batches.entry(filter.clone()).or_default().push(BatchEntry {
+                    key: key.to_path_buf(),
+                    source: source.to_path_buf(),
+                    output: output.to_path_buf(),
+                    change,
+                    size,
+                    // Settled once the batch has run
+                    reserved: reservation.flatten().map_or(0, Reservation::into_reserved),
+                });
+                state.trace(TraceAction::Batched, Some(source), Some(output), None);
+                // Batched outputs are encrypted and counted once the batch has run
+                return Ok(Some(mirror.to_path_buf()));
+            }
+            Some(filter) => {
+                let _slot = state.filter_slots.as_ref().map(Semaphore::acquire);
+                let relative_mirror = output.strip_prefix(&state.mirror_root).unwrap_or(output);
+                let filter_output = match filter.then.is_empty() {
+                    true => run_filter_for_entry(
+                        source,
+                        written,
+                        relative_mirror,
+                        filter,
+                        state.filter_log,
+                    ),
+                    false => entry_extension(source, state.sniff, state.case_sensitive_ext)
+                        .with_context(|| format!("`{0}` has no extension", source.display()))
+                        .and_then(|extension| {
+                            run_filter_chain(
+                                source,
+                                written,
+                                relative_mirror,
+                                filter,
+                                &extension,
+                                state.filter_log,
+                            )
+                        }),
+                };
+                match filter_output {
+                    Ok(filter_output) => {
+                        if let Some(metadata) = filter_output.metadata {
+                            Self::record_metadata(state, key, metadata);
+                        }
+                        for extra_output in &filter_output.extra_outputs {
+                            Self::record_extra_output(state, key, extra_output)?;
+                        }
+                    }
+                    Err(e) => {
+                        log::error!("{e:#}, skipping...");
+                        Self::record_failure(state, key)?;
+                        // The previous mirror is left in place, so cleanup mustn't remove it
+                        self.carry_over(state, key, output);
+                        return Ok(mirror.exists().then(|| mirror.to_path_buf()));
+                    }
+                }
+                state.trace(TraceAction::Filtered, Some(source), Some(output), None);
+            }
+            None if has_content_transforms(transforms) => {
+                run_transforms_for_entry(source, written, transforms)
+                    .map_err(|e| Self::write_error(e, written, mirror))?;
+                state.trace(TraceAction::Transformed, Some(source), Some(output), None);
+            }
+            // Encrypted mirrors are rewritten entirely anyway
+            None if state.delta
+                && state.cipher.is_none()
+                && size >= DELTA_MIN_SIZE
+                && output.is_file() =>
+            {
+                copied =
+                    delta_copy(source, output).map_err(|e| Self::write_error(e, output, mirror))?;
+                state.trace(TraceAction::Copied, Some(source), Some(output), None);
+            }
+            None => {
+                match teed.take() {
+                    Some(copy) => copy.commit(written),
+                    None => copy_file(source, written, state.reflink),
+                }
+                .map_err(|e| Self::write_error(e, written, mirror))?;
+                state.trace(TraceAction::Copied, Some(source), Some(output), None);
+            }
+        };
+        Self::encrypt_output(state, output, mirror)
+            .map_err(|e| Self::write_error(e, written, mirror))?;
+        if mirror.exists() {
+            preserve_times(&source_metadata, mirror, &state.preserve_times)?;
+            state.chown(mirror)?;
+        }
+        Self::record_file(
+            state.stats.clone(),
+            key,
+            Some(change),
+            copied,
+            size - copied,
+        );
+        if exact_copy {
+            Self::record_mirror_hash(state, mirror, &digest, size);
+        }
+
+        Ok(Some(mirror.to_path_buf()))
+    }
+
+    /// Once the destination is full, every remaining file would fail the same way, so rather than
+    /// leaving a partial mirror behind the sync is aborted with one clear error
+    fn write_error(error: anyhow::Error, output: &Path, mirror: &Path) -> anyhow::Error {
+        let storage_full = error
+            .chain()
+            .filter_map(|cause| cause.downcast_ref::<io::Error>())
+            .any(|e| matches!(e.kind(), ErrorKind::StorageFull | ErrorKind::QuotaExceeded));
+        if !storage_full {
+            return error;
+        }
+
+        for partial in [output, mirror] {
+            match fs::remove_file(partial) {
+                Ok(()) => log::debug!("Removed partially written `{0}`", partial.display()),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => log::warn!(
+                    "Failed to remove partially written `{0}`: {e}",
+                    partial.display()
+                ),
+            }
+        }
+        let destination = mirror.parent().unwrap_or(mirror);
+        error.context(format!(
+            "Destination full: no space left on the device holding `{0}`, free some up and sync again",
+            destination.display()
+        ))
+    }
+
+    /// Keeps everything recorded for an entry which is being left as it is
+    fn carry_over(&self, state: &SyncState, key: &Path, output: &Path) {
+        if let Some(metadata) = self.metadata.get(key) {
+            Self::record_metadata(state, key, metadata.clone());
+        }
+
+        let relative_output = output.strip_prefix(&state.mirror_root).unwrap_or(output);
+        let extra_outputs = self.outputs.get(key).into_iter().flatten();
+        let relative_outputs = extra_outputs
+            .clone()
+            .map(|(relative_output, _)| relative_output.as_path())
+            .chain([relative_output]);
+        for relative_output in relative_outputs {
+            if let Some(nonce) = self.nonces.get(relative_output) {
+                Self::record_nonce(state, relative_output, nonce.clone());
+            }
+        }
+
+        if let Some(outputs) = self.outputs.get(key) {
+            let mut mirror_list = match state.mirror_list.lock() {
+                Ok(mirror_list) => mirror_list,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            for relative_output in outputs.keys() {
+                let output = state.mirror_root.join(relative_output);
+                mirror_list.insert(match state.cipher {
+                    Some(_) => encrypted_path(&output),
+                    None => output,
+                });
+            }
+            drop(mirror_list);
+
+            let mut state_outputs = match state.outputs.lock() {
+                Ok(outputs) => outputs,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            state_outputs.insert(key.to_path_buf(), outputs.clone());
+        }
+    }
+
+    /// Hard links an unchanged entry, and any extra outputs of its filter, from the previous
+    /// snapshot when snapshotting
+    fn link_previous_snapshot(&self, state: &SyncState, key: &Path, mirror: &Path) {
+        let Some(snapshots) = &state.snapshots else {
+            return;
+        };
+        let extra_outputs =
+            self.outputs
+                .get(key)
+                .into_iter()
+                .flatten()
+                .map(|(relative_output, _)| match state.cipher {
+                    Some(_) => encrypted_path(relative_output),
+                    None => relative_output.clone(),
+                });
+        // The entry itself goes last, as it's only treated as unchanged once it's in place
+        let relative_mirrors = extra_outputs
+            .chain([mirror
+                .strip_prefix(&state.mirror_root)
+                .unwrap_or(mirror)
+                .to_path_buf()])
+            .collect::<Vec<_>>();
+        snapshots.link_previous(&relative_mirrors);
+    }
+
+    /// Encrypts (if enabled) and hashes an extra file a filter produced, keeping it in the mirror
+    fn record_extra_output(state: &SyncState, key: &Path, written: &Path) -> Result<()> {
+        if !written.is_file() {
+            log::error!(
+                "Filter reported output `{0}`, which it didn't create, ignoring...",
+                written.display()
+            );
+            return Ok(());
+        }
+        // Written next to the staged output when encrypting
+        let output = &state.unstaged(written);
+
+        let mirror = match state.cipher {
+            Some(_) => encrypted_path(output),
+            None => output.to_path_buf(),
+        };
+        Self::encrypt_output(state, output, &mirror)?;
+        state.chown(&mirror)?;
+        let digest = state.hasher.hash_file(&mirror)?;
+        if let Ok(metadata) = mirror.metadata() {
+            Self::record_mirror_hash(state, &mirror, &digest, metadata.len());
+        }
+
+        let relative_output = output.strip_prefix(&state.mirror_root).unwrap_or(output);
+        {
+            let mut outputs = match state.outputs.lock() {
+                Ok(outputs) => outputs,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            outputs
+                .entry(key.to_path_buf())
+                .or_default()
+                .insert(relative_output.to_path_buf(), digest);
+        }
+        let mut mirror_list = match state.mirror_list.lock() {
+            Ok(mirror_list) => mirror_list,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        mirror_list.insert(mirror);
+        Ok(())
+    }
+
+    /// Keeps a size for every tracked file, those handled by the sync taking the size they had
+    fn settle_sizes(&mut self, state: &SyncState) {
+        let mut handled = take(&mut *match state.sizes.lock() {
+            Ok(sizes) => sizes,
+            Err(poisoned) => poisoned.into_inner(),
+        });
+        let previous = take(&mut self.sizes);
+        self.sizes = self
+            .hashes
+            .keys()
+            .filter_map(|key| {
+                let size = handled.remove(key).or_else(|| previous.get(key).copied())?;
+                Some((key.clone(), size))
+            })
+            .collect();
+    }
+
+    fn record_mirror_hash(state: &SyncState, mirror: &Path, digest: &str, size: u64) {
+        let relative_mirror = mirror.strip_prefix(&state.mirror_root).unwrap_or(mirror);
+        let mut mirror_hashes = match state.mirror_hashes.lock() {
+            Ok(mirror_hashes) => mirror_hashes,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        mirror_hashes.insert(relative_mirror.to_path_buf(), (digest.to_owned(), size));
+    }
+
+    fn log_vanished_source(state: &SyncState, key: &Path, source: &Path) {
+        state.entry_log.log(
+            source,
+            Level::Warn,
+            format!(
+                "`{0}` was removed during the sync, treating it as deleted...",
+                key.display()
+            ),
+        );
+    }
+
+    /// Counts a mirrored file, `change` being `None` for files left as they were
+    fn record_file(
+        stats: Arc<Mutex<SyncStats>>,
+        key: &Path,
+        change: Option<Change>,
+        copied: u64,
+        skipped: u64,
+    ) {
+        let mut stats = match stats.lock() {
+            Ok(stats) => stats,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        stats.record(key, change, copied, skipped);
+    }
+
+    fn run_batches(state: &SyncState) -> Result<()> {
+        // Taken, so that running the batches twice doesn't filter anything twice
+        let batches = take(&mut *match state.batches.lock() {
+            Ok(batches) => batches,
+            Err(poisoned) => poisoned.into_inner(),
+        });
+
+        batches
+            .iter()
+            .flat_map(|(filter, entries)| {
+                entries.chunks(BATCH_SIZE).map(move |chunk| (filter, chunk))
+            })
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .try_for_each(|(filter, chunk)| -> Result<()> {
+                // Only filters which support batching are ever queued
+                let Some(support) = batch_support(filter) else {
+                    return Ok(());
+                };
+                let _slot = state.filter_slots.as_ref().map(Semaphore::acquire);
+                let entries = chunk
+                    .iter()
+                    .map(|entry| -> Result<BatchInput> {
+                        let written = state.staged(&entry.output);
+                        Self::create_staging_dir(state, &written)?;
+                        Ok(BatchInput {
+                            source_entry: entry.source.clone(),
+                            mirror_entry: written,
+                            relative_mirror_entry: entry
+                                .output
+                                .strip_prefix(&state.mirror_root)
+                                .unwrap_or(&entry.output)
+                                .to_path_buf(),
+                        })
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let filter_outputs =
+                    match run_filter_batch(filter, support, &entries, state.filter_log) {
+                        Ok(filter_outputs) => filter_outputs,
+                        Err(e) => {
+                            log::error!("{e:#}, skipping...");
+                            return chunk.iter().try_for_each(|entry| {
+                                Self::settle_batched(state, entry);
+                                Self::record_failure(state, &entry.key)
+                            });
+                        }
+                    };
+                for (entry, filter_output) in chunk.iter().zip(filter_outputs) {
+                    let BatchEntry {
+                        key,
+                        source,
+                        output,
+                        change,
+                        size,
+                        ..
+                    } = entry;
+                    let mirror = match state.cipher {
+                        Some(_) => encrypted_path(output),
+                        None => output.clone(),
+                    };
+                    let encrypted = Self::encrypt_output(state, output, &mirror);
+                    Self::settle_batched(state, entry);
+                    if let Err(e) = encrypted {
+                        log::error!("{e:#}");
+                        Self::record_failure(state, key)?;
+                        continue;
+                    }
+                    if let Some(metadata) = filter_output.metadata {
+                        Self::record_metadata(state, key, metadata);
+                    }
+                    for extra_output in &filter_output.extra_outputs {
+                        Self::record_extra_output(state, key, extra_output)?;
+                    }
+                    if let (Ok(source_metadata), true) = (source.metadata(), mirror.exists()) {
+                        if let Err(e) =
+                            preserve_times(&source_metadata, &mirror, &state.preserve_times)
+                                .and_then(|_| state.chown(&mirror))
+                        {
+                            log::error!("{e:#}");
+                            Self::record_failure(state, key)?;
+                            continue;
+                        }
+                    }
+                    Self::record_file(state.stats.clone(), key, Some(*change), *size, 0);
+                }
+                Ok(())
+            })
+    }
+
+    /// Settles the room held in the quota for a batched entry, once its batch has run
+    fn settle_batched(state: &SyncState, entry: &BatchEntry) {
+        if let Some(quota) = &state.quota {
+            let mirror = match state.cipher {
+                Some(_) => encrypted_path(&entry.output),
+                None => entry.output.clone(),
+            };
+            quota.settle_file(entry.reserved, &mirror);
+        }
+    }
+
+    /// Remembers a file which failed, so it can be retried
+    fn record_failure(state: &SyncState, key: &Path) -> Result<()> {
+        {
+            let mut failures = match state.failures.lock() {
+                Ok(failures) => failures,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            failures.insert(key.to_path_buf());
+        }
+        {
+            let mut changes = match state.changes.lock() {
+                Ok(changes) => changes,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            changes.remove(key);
+        }
+        Self::record_errors(state, 1)
+    }
+
+    /// Counts files which failed and were skipped, aborting the sync once there are more than
+    /// `--max-errors`, as something is likely wrong with the whole mirror
+    fn record_errors(state: &SyncState, count: usize) -> Result<()> {
+        let mut stats = match state.stats.lock() {
+            Ok(stats) => stats,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        stats.errors += count;
+        match state.max_errors {
+            Some(max_errors) if stats.errors > max_errors => bail!(
+                "Aborting sync after {0} errors (more than the maximum of {max_errors})",
+                stats.errors
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    fn mirror_is_newer(source_metadata: &Metadata, mirror: &Path) -> bool {
+        let mirror_modified = mirror.metadata().and_then(|metadata| metadata.modified());
+        match (source_metadata.modified(), mirror_modified) {
+            (Ok(source_modified), Ok(mirror_modified)) => mirror_modified > source_modified,
+            _ => false,
+        }
+    }
+
+    /// Hashes `source`, or when dereferencing, reuses the hash of the real file it resolves to
+    /// if it's already been hashed through another path this sync
+    fn hash_source(state: &SyncState, source: &Path, metadata: &Metadata) -> Result<String> {
+        let Some(real_hashes) = &state.real_hashes else {
+            return Self::hash_with_cache(state, source, metadata);
+        };
+
+        let real_path = fs::canonicalize(source)
+            .with_context(|| format!("Failed to resolve real path of `{0}`", source.display()))?;
+        // Held while hashing, so other paths to the same file wait for the result
+        let slot = {
+            let mut real_hashes = match real_hashes.lock() {
+                Ok(real_hashes) => real_hashes,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            real_hashes.entry(real_path).or_default().clone()
+        };
+        let mut slot = match slot.lock() {
+            Ok(slot) => slot,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Some(digest) = slot.as_ref() {
+            log::debug!(
+                "`{0}` was already hashed through another path, reusing its hash...",
+                source.display()
+            );
+            return Ok(digest.clone());
+        }
+
+        let digest = Self::hash_with_cache(state, source, metadata)?;
+        *slot = Some(digest.clone());
+        Ok(digest)
+    }
+
+    /// Whether to hash `source` while copying it with `--single-pass`. Only plain copies qualify,
+    /// and only when the copy is known to be needed before the hash is, as the mirror is new,
+    /// missing, out of date or a different size to the source.
+    fn copies_in_single_pass(
+        &self,
+        state: &SyncState,
+        filter: Option<&Filter>,
+        transforms: &[TransformKind],
+        key: &Path,
+        source: &Path,
+        mirror: &Path,
+    ) -> bool {
+        if !state.single_pass
+            || filter.is_some()
+            || has_content_transforms(transforms)
+            || state.cipher.is_some()
+            || self.mirror_template.is_some()
+            || state.structure_only
+            || state.newer_only
+            || state.real_hashes.is_some()
+            || state.since.is_some()
+            || state.reflink != ReflinkMode::Never
+        {
+            return false;
+        }
+        let Ok(source_metadata) = source.metadata() else {
+            return false;
+        };
+        // A cached hash needs no reading at all
+        if state
+            .hash_cache
+            .filter(|_| !state.checksum)
+            .is_some_and(|hash_cache| hash_cache.get(source, &source_metadata).is_some())
+        {
+            return false;
+        }
+
+        let Some(mirror_size) = state.mirror_file_size(mirror) else {
+            return true;
+        };
+        // Large mirrors are rebuilt from their own unchanged blocks instead
+        if state.delta && source_metadata.len() >= DELTA_MIN_SIZE {
+            return false;
+        }
+        !self.hashes.contains_key(key)
+            || self.failed.contains(key)
+            || self.placeholders.contains(key)
+            || mirror_size != source_metadata.len()
+    }
+
+    fn hash_and_copy_source(
+        state: &SyncState,
+        source: &Path,
+        mirror: &Path,
+        metadata: &Metadata,
+    ) -> Result<(TeedCopy, String)> {
+        let (copy, digest) = TeedCopy::hash_from(state.hasher, source, mirror)?;
+        state.trace(TraceAction::Hashed, Some(source), None, Some(&digest));
+        if let Some(hash_cache) = state.hash_cache {
+            hash_cache.insert(source, metadata, &digest);
+        }
+        Ok((copy, digest))
+    }
+
+    /// Hashes `source`, unless the `--hash-cache` has its hash from before it was last modified
+    fn hash_with_cache(state: &SyncState, source: &Path, metadata: &Metadata) -> Result<String> {
+        let cached = state
+            .hash_cache
+            .filter(|_| !state.checksum)
+            .and_then(|hash_cache| hash_cache.get(source, metadata));
+        if let Some(digest) = cached {
+            log::debug!(
+                "`{0}` is unchanged since its hash was cached, reusing it...",
+                source.display()
+            );
+            return Ok(digest);
+        }
+
+        let digest = state.hasher.hash_file(source)?;
+        state.trace(TraceAction::Hashed, Some(source), None, Some(&digest));
+        if let Some(hash_cache) = state.hash_cache {
+            hash_cache.insert(source, metadata, &digest);
+        }
+        Ok(digest)
+    }
+
+    /// Records the hash from the last sync again (or none for new files), for entries whose
+    /// mirror was left as it was
+    fn keep_previous_hash(&self, state: &SyncState, key: &Path) {
+        let mut hashes = match state.hashes.lock() {
+            Ok(hashes) => hashes,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        match self.hashes.get(key) {
+            Some(prev_hash) => hashes.insert(key.to_path_buf(), prev_hash.clone()),
+            None => hashes.remove(key),
+        };
+    }
+
+    fn claim_mirror(state: &SyncState, mirror: &Path) -> Arc<Mutex<Option<PathBuf>>> {
+        let mut claims = match state.claims.lock() {
+            Ok(claims) => claims,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        claims.entry(mirror.to_path_buf()).or_default().clone()
+    }
+
+    fn record_collision<'a>(
+        state: &SyncState,
+        relative_mirror: &Path,
+        keys: impl IntoIterator<Item = &'a Path>,
+    ) {
+        let mut collisions = match state.collisions.lock() {
+            Ok(collisions) => collisions,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        collisions
+            .entry(relative_mirror.to_path_buf())
+            .or_default()
+            .extend(keys.into_iter().map(Path::to_path_buf));
+    }
+
+    /// Drops everything recorded for an entry which lost a mirror path collision
+    fn forget_entry(state: &SyncState, key: &Path, output: &Path) {
+        {
+            let mut hashes = match state.hashes.lock() {
+                Ok(hashes) => hashes,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            hashes.remove(key);
+        }
+        {
+            let mut metadata = match state.metadata.lock() {
+                Ok(metadata) => metadata,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            metadata.remove(key);
+        }
+        {
+            let mut outputs = match state.outputs.lock() {
+                Ok(outputs) => outputs,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            outputs.remove(key);
+        }
+        let mut batches = match state.batches.lock() {
+            Ok(batches) => batches,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        batches
+            .values_mut()
+            .for_each(|entries| entries.retain(|entry| entry.output != output));
+    }
+
+    /// Creates the directory a file staged to be encrypted is written to
+    fn create_staging_dir(state: &SyncState, written: &Path) -> Result<()> {
+        let Some(parent) = written.parent().filter(|_| state.plain_staging.is_some()) else {
+            return Ok(());
+        };
+        create_dir_all(parent)
+            .with_context(|| format!("Failed to create staging directory `{0}`", parent.display()))
+    }
+
+    fn encrypt_output(state: &SyncState, output: &Path, mirror: &Path) -> Result<()> {
+        let Some(cipher) = &state.cipher else {
+            return Ok(());
+        };
+        let staged = state.staged(output);
+        // A failed filter won't have produced anything, and has already been reported
+        if !staged.exists() {
+            return Ok(());
+        }
+
+        if let Some(parent) = mirror.parent() {
+            create_dir_all(parent).with_context(|| {
+                format!("Failed to create mirror directory `{0}`", parent.display())
+            })?;
+        }
+        let nonce = cipher.encrypt_file(&staged, mirror)?;
+        let relative_output = output.strip_prefix(&state.mirror_root).unwrap_or(output);
+        Self::record_nonce(state, relative_output, nonce);
+        Ok(())
+    }
+
+    fn record_nonce(state: &SyncState, relative_output: &Path, nonce: String) {
+        let mut nonces = match state.nonces.lock() {
+            Ok(nonces) => nonces,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        nonces.insert(relative_output.to_path_buf(), nonce);
+    }
+
+    /// Decrypts every file in an encrypted mirror into `output_root`, returning how many were
+    /// restored
+    pub fn decrypt(&self, database_path: &Path, output_root: &Path) -> Result<usize> {
+        let Some(key_file) = &self.encryption_key_file else {
+            bail!(
+                "Database `{0}` isn't encrypted, there's nothing to decrypt",
+                database_path.display()
+            );
+        };
+        self.require_local_mirror("decrypt")?;
+        let database_folder = database_folder(database_path)?;
+        let cipher = Cipher::from_key_file(&database_folder.join(key_file))?;
+        let mirror_root = self.mirror_root(&database_folder);
+
+        self.nonces
+            .iter()
+            .try_for_each(|(relative_output, nonce)| -> Result<()> {
+                let output = output_root.join(relative_output);
+                create_dir_all(
+                    output
+                        .parent()
+                        .with_context(|| "Failed to get file parent")?,
+                )
+                .with_context(|| {
+                    format!("Failed to create directory for `{0}`", output.display())
+                })?;
+                cipher.decrypt_file(
+                    &encrypted_path(&mirror_root.join(relative_output)),
+                    nonce,
+                    &output,
+                )
+            })?;
+
+        Ok(self.nonces.len())
+    }
+
+    fn record_metadata(state: &SyncState, key: &Path, metadata: Value) {
+        let mut all_metadata = match state.metadata.lock() {
+            Ok(all_metadata) => all_metadata,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        all_metadata.insert(key.to_path_buf(), metadata);
+    }
+
+    fn handle_dir_entry(&self, source: &Path, mirror: &Path) -> Result<()> {
+        create_dir_all(mirror).with_context(|| {
+            format!(
+                "Failed to create mirror directory ({0}) for entry `{1}`",
+                mirror.display(),
+                source.display()
+            )
+        })
+    }
+
+    fn cleanup(
+        &self,
+        mirror_root: &Path,
+        mirror_list: &BTreeSet<PathBuf>,
+        database_files: &DatabaseFiles,
+        rules: &CleanupRules,
+        options: &SyncOptions,
+    ) -> Result<Vec<PathBuf>> {
+        let trace = options.trace.as_ref();
+        let progress = options.progress;
+        let empty_dirs = options.empty_dirs;
+        let ignore = MirrorIgnore::load(mirror_root)?;
+        let mut examined = 0_usize;
+        let mut removed = 0_usize;
+        let mut removed_files = Vec::new();
+
+        // Walk contents first, so directories are only removed once they've been emptied, and
+        // any holding ignored files are left alone
+        WalkDir::new(mirror_root)
+            .contents_first(true)
+            .into_iter()
+            .try_for_each(|entry| -> Result<()> {
+                let entry = entry?;
+                // Symlinks are removed themselves, never followed out of the mirror
+                let is_dir = entry.file_type().is_dir();
+                let entry_path = entry.into_path();
+                examined += 1;
+                Self::log_cleanup_progress(examined, removed, progress, false)?;
+                let relative_entry = entry_path.strip_prefix(mirror_root).unwrap_or(&entry_path);
+
+                if entry_path == mirror_root
+                    || mirror_list.contains(&entry_path)
+                    || database_files.contains(&entry_path)
+                    || rules.keeps(relative_entry, &ignore, &entry_path.display())
+                {
+                    return Ok(());
+                }
+
+                if is_dir && empty_dirs == EmptyDirs::Keep {
+                    log::trace!("`{0}` is a directory, keeping...", entry_path.display());
+                } else if is_dir {
+                    let is_empty = entry_path
+                        .read_dir()
+                        .with_context(|| {
+                            format!("Failed to inspect directory `{0}`", entry_path.display())
+                        })?
+                        .next()
+                        .is_none();
+                    if is_empty {
+                        log::info!("Removing `{0}`...", entry_path.display());
+                        std::fs::remove_dir(&entry_path).with_context(|| {
+                            format!("Failed to remove directory `{0}`", entry_path.display())
+                        })?;
+                        removed += 1;
+                        if let Some(trace) = trace {
+                            trace.record(TraceAction::Deleted, None, Some(&entry_path), None);
+                        }
+                    }
+                } else {
+                    log::info!("Removing `{0}`...", entry_path.display());
+                    std::fs::remove_file(&entry_path).with_context(|| {
+                        format!("Failed to remove file `{0}`", entry_path.display())
+                    })?;
+                    removed += 1;
+                    removed_files.push(relative_entry.to_path_buf());
+                    if let Some(trace) = trace {
+                        trace.record(TraceAction::Deleted, None, Some(&entry_path), None);
+                    }
+                }
+
+                Ok(())
+            })?;
+
+        Self::log_cleanup_progress(examined, removed, progress, true)?;
+        Ok(removed_files)
+    }
+
+    /// Cleans up a remote mirror as [`Self::cleanup`] does a local one, going by what was on the
+    /// host when the sync started. Directories are only empty once everything listed in them has
+    /// been removed and nothing was uploaded to them.
+    fn cleanup_remote(
+        remote: &RemoteSync,
+        staging_root: &Path,
+        mirror_list: &BTreeSet<PathBuf>,
+        rules: &CleanupRules,
+        options: &SyncOptions,
+    ) -> Result<Vec<PathBuf>> {
+        let trace = options.trace.as_ref();
+        let progress = options.progress;
+        let ignore = remote.ignore()?;
+        let mut examined = 0_usize;
+        let mut removed = 0_usize;
+        let mut removed_files = Vec::new();
+        // Directories which still hold something, whether kept from before or written by the sync
+        let mut occupied_dirs = mirror_list
+            .iter()
+            .filter_map(|entry| entry.strip_prefix(staging_root).ok())
+            .flat_map(Path::ancestors)
+            .map(Path::to_path_buf)
+            .collect::<BTreeSet<_>>();
+
+        for (relative_entry, is_dir) in remote.contents_first() {
+            examined += 1;
+            Self::log_cleanup_progress(examined, removed, progress, false)?;
+            let url = remote.url_of(relative_entry);
+            let kept = mirror_list.contains(&staging_root.join(relative_entry))
+                || rules.keeps(relative_entry, &ignore, &url)
+                || match is_dir {
+                    true if options.empty_dirs == EmptyDirs::Keep => {
+                        log::trace!("`{url}` is a directory, keeping...");
+                        true
+                    }
+                    true => occupied_dirs.contains(relative_entry),
+                    false => false,
+                };
+            if kept {
+                occupied_dirs.extend(relative_entry.ancestors().map(Path::to_path_buf));
+                continue;
+            }
+
+            log::info!("Removing `{url}`...");
+            match is_dir {
+                true => remote.remove_dir(relative_entry)?,
+                false => {
+                    remote.remove_file(relative_entry)?;
+                    removed_files.push(relative_entry.to_path_buf());
+                }
+            }
+            removed += 1;
+            if let Some(trace) = trace {
+                trace.record(TraceAction::Deleted, None, Some(Path::new(&url)), None);
+            }
+        }
+
+        Self::log_cleanup_progress(examined, removed, progress, true)?;
+        Ok(removed_files)
+    }
+
+    /// Keeps placeholders which were written this sync, or left alone, or failed to be filled in
+    fn update_placeholders(&mut self, state: &SyncState) {
+        let changes = match state.changes.lock() {
+            Ok(changes) => changes,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let failures = match state.failures.lock() {
+            Ok(failures) => failures,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let mut placeholders = match state.placeholders.lock() {
+            Ok(placeholders) => placeholders,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let hashes = &self.hashes;
+        placeholders.extend(take(&mut self.placeholders).into_iter().filter(|key| {
+            hashes.contains_key(key) && (!changes.contains_key(key) || failures.contains(key))
+        }));
+        self.placeholders = take(&mut *placeholders);
+    }
+
+    fn collect_changes(state: &SyncState, removed: Vec<PathBuf>) -> SyncChanges {
+        let changes = match state.changes.lock() {
+            Ok(changes) => changes,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let (added, changed): (Vec<_>, Vec<_>) = changes
+            .iter()
+            .partition(|(_, change)| **change == Change::Added);
+        SyncChanges {
+            added: added.into_iter().map(|(key, _)| key.clone()).collect(),
+            changed: changed.into_iter().map(|(key, _)| key.clone()).collect(),
+            removed,
+            ..Default::default()
+        }
+    }
+
+    /// Reports how many source entries have been found so far, as walking a large (or remote)
+    /// source can take a while before there's anything to sync
+    fn log_walk_progress(found: usize, style: ProgressStyle, done: bool) -> Result<()> {
+        const BAR_INTERVAL: usize = 100;
+        const PLAIN_INTERVAL: usize = 10_000;
+        const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+        match style {
+            ProgressStyle::Bar if done => {
+                let summary = format!("Scanned source: {found} entries found");
+                println!("\r{summary:<48}");
+            }
+            ProgressStyle::Bar if found.is_multiple_of(BAR_INTERVAL) => {
+                let frame = SPINNER[found / BAR_INTERVAL % SPINNER.len()];
+                print!("\r{frame} Scanning source: {found} entries found");
+                stdout()
+                    .flush()
+                    .with_context(|| "Failed to flush output buffer")?;
+            }
+            ProgressStyle::Plain if done => println!("Scanned source: {found} entries found"),
+            ProgressStyle::Plain if found.is_multiple_of(PLAIN_INTERVAL) => {
+                println!("Scanning source: {found} entries found");
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Reports how far cleanup has got, the total isn't known until the mirror has been walked
+    fn log_cleanup_progress(
+        examined: usize,
+        removed: usize,
+        style: ProgressStyle,
+        done: bool,
+    ) -> Result<()> {
+        // Redrawing for every entry would slow down large mirrors
+        const BAR_INTERVAL: usize = 100;
+        const PLAIN_INTERVAL: usize = 10_000;
+
+        match style {
+            ProgressStyle::Bar if done => {
+                // Padded to cover the longer in-progress line
+                let summary = format!("Cleaned up mirror: {removed} of {examined} entries removed");
+                println!("\r{summary:<72}");
+            }
+            ProgressStyle::Bar if examined.is_multiple_of(BAR_INTERVAL) => {
+                print!("\rCleaning up mirror: {examined} entries examined, {removed} removed");
+                stdout()
+                    .flush()
+                    .with_context(|| "Failed to flush output buffer")?;
+            }
+            ProgressStyle::Plain if done => {
+                println!("Cleaned up mirror: {removed} of {examined} entries removed");
+            }
+            ProgressStyle::Plain if examined.is_multiple_of(PLAIN_INTERVAL) => {
+                println!("Cleaning up mirror: {examined} entries examined, {removed} removed");
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Reports how many entries have been checked, and how many of those needed writing, as
+    /// unchanged trees spend most of their time hashing rather than copying
+    fn log_progress(state: &SyncState, max_count: usize, style: ProgressStyle) -> Result<()> {
+        // Redraw the bar at most 20 times a second, rather than once for every file
+        const BAR_INTERVAL_MS: u64 = 50;
+
+        let counter = state.counter.fetch_add(1, Ordering::Relaxed) + 1;
+        let due = match style {
+            ProgressStyle::None => false,
+            // Report roughly every 10%, and always on the final entry
+            ProgressStyle::Plain => {
+                const STEPS: usize = 10;
+                STEPS * counter / max_count != STEPS * (counter - 1) / max_count
+                    || counter == max_count
+            }
+            ProgressStyle::Bar => {
+                let now = state.started.elapsed().as_millis() as u64;
+                let last_draw = state.last_draw.load(Ordering::Relaxed);
+                counter == max_count
+                    || (now.saturating_sub(last_draw) >= BAR_INTERVAL_MS
+                        && state
+                            .last_draw
+                            .compare_exchange(last_draw, now, Ordering::Relaxed, Ordering::Relaxed)
+                            .is_ok())
+            }
+        };
+        if !due {
+            return Ok(());
+        }
+
+        let _drawing = match state.drawing.lock() {
+            Ok(drawing) => drawing,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let written = match state.changes.lock() {
+            Ok(changes) => changes,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+        .len();
+
+        if style == ProgressStyle::Plain {
+            println!("{counter}/{max_count} files checked, {written} written");
+            return Ok(());
+        }
+
+        // Other files may have been checked while waiting to draw
+        let counter = state.counter.load(Ordering::Relaxed);
+        let progress = 100.0 * (counter as f64 / max_count as f64);
+
+        const BLOCK_COUNT: usize = 20;
+        let num_blocks = 20 * counter / max_count;
 
         let mut bar = Vec::<char>::new();
-        bar.extend(repeat('=').take(num_blocks));
+        bar.extend(repeat_n('=', num_blocks));
         let count = if num_blocks < BLOCK_COUNT {
             bar.push('>');
             BLOCK_COUNT - num_blocks - 1
         } else {
             0
         };
-        bar.extend(repeat(' ').take(count));
+        bar.extend(repeat_n(' ', count));
         let bar = bar.into_iter().collect::<String>();
 
-        print!("\r[{bar}] {progress:.1}%");
+        print!("\r[{bar}] {progress:.1}% ({counter} checked, {written} written)");
         stdout()
             .flush()
             .with_context(|| "Failed to flush output buffer")?;
@@ -273,3 +3454,907 @@ impl Database {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Database, DatabaseConfig, EmptyDirs, Hasher, ProgressStyle, SyncOptions, SyncSettings,
+        WorkingDirGuard, FORMAT_VERSION,
+    };
+    use crate::{
+        filter::NativeFilter,
+        remote::{RemoteEntry, RemoteFiles},
+    };
+    use anyhow::Result;
+    use serde_json::{json, Value};
+    use std::{
+        env::temp_dir,
+        ffi::OsStr,
+        fs::{self, create_dir_all, remove_dir_all, write, File},
+        io::{self, Read},
+        path::{Path, PathBuf},
+        process,
+        sync::{Arc, Mutex},
+        time::SystemTime,
+    };
+    use walkdir::WalkDir;
+
+    /// Hashes files by their length, so tests can tell it was used
+    struct LengthHasher;
+
+    impl Hasher for LengthHasher {
+        fn hash_file(&self, path: &Path) -> Result<String> {
+            Ok(format!("len{0}", fs::metadata(path)?.len()))
+        }
+    }
+
+    /// Mirrors `.txt` files in upper case as `.TXT`
+    struct Uppercase;
+
+    impl NativeFilter for Uppercase {
+        fn ext(&self, extension: &OsStr) -> Option<String> {
+            (extension == "txt").then(|| "TXT".to_owned())
+        }
+
+        fn run(&self, input: &Path, output: &Path, _relative: &Path) -> Result<Option<Value>> {
+            fs::write(output, fs::read_to_string(input)?.to_uppercase())?;
+            Ok(Some(json!({ "uppercased": true })))
+        }
+    }
+
+    /// Fails for every `.txt` file, after writing part of its output
+    struct Failing;
+
+    impl NativeFilter for Failing {
+        fn ext(&self, extension: &OsStr) -> Option<String> {
+            (extension == "txt").then(|| "TXT".to_owned())
+        }
+
+        fn run(&self, _input: &Path, output: &Path, _relative: &Path) -> Result<Option<Value>> {
+            fs::write(output, "PARTIAL")?;
+            anyhow::bail!("Failed on purpose")
+        }
+    }
+
+    /// Mirrors `.txt` files as they are, remembering where it was asked to write them
+    #[derive(Default)]
+    struct RecordOutputs(Mutex<Vec<PathBuf>>);
+
+    impl NativeFilter for RecordOutputs {
+        fn ext(&self, extension: &OsStr) -> Option<String> {
+            (extension == "txt").then(|| "txt".to_owned())
+        }
+
+        fn run(&self, input: &Path, output: &Path, _relative: &Path) -> Result<Option<Value>> {
+            fs::copy(input, output)?;
+            self.0.lock().unwrap().push(output.to_path_buf());
+            Ok(None)
+        }
+    }
+
+    /// Stands in for the host of a remote mirror with a local directory, remembering what was
+    /// uploaded to it
+    struct LocalHost {
+        root: PathBuf,
+        uploads: Mutex<Vec<PathBuf>>,
+    }
+
+    impl RemoteFiles for LocalHost {
+        fn list(&self) -> io::Result<Vec<RemoteEntry>> {
+            WalkDir::new(&self.root)
+                .min_depth(1)
+                .into_iter()
+                .map(|entry| {
+                    let entry = entry?;
+                    Ok(RemoteEntry {
+                        path: entry.path().strip_prefix(&self.root).unwrap().into(),
+                        is_dir: entry.file_type().is_dir(),
+                        size: entry.metadata()?.len(),
+                    })
+                })
+                .collect()
+        }
+
+        fn open(&self, relative_entry: &Path) -> io::Result<Box<dyn Read + '_>> {
+            Ok(Box::new(File::open(self.root.join(relative_entry))?))
+        }
+
+        fn create_dir_all(&self, relative_entry: &Path) -> io::Result<()> {
+            create_dir_all(self.root.join(relative_entry))
+        }
+
+        fn upload(
+            &self,
+            local: &Path,
+            relative_entry: &Path,
+            _times: (SystemTime, SystemTime),
+        ) -> io::Result<()> {
+            fs::copy(local, self.root.join(relative_entry))?;
+            self.uploads.lock().unwrap().push(relative_entry.into());
+            Ok(())
+        }
+
+        fn remove_file(&self, relative_entry: &Path) -> io::Result<()> {
+            fs::remove_file(self.root.join(relative_entry))
+        }
+
+        fn remove_dir(&self, relative_entry: &Path) -> io::Result<()> {
+            fs::remove_dir(self.root.join(relative_entry))
+        }
+    }
+
+    /// Creates a fresh root for the test called `name`, holding `entries` relative to it as
+    /// `(path, contents)` pairs, where paths ending in `/` are directories
+    fn test_root(name: &str, entries: &[(&str, &str)]) -> PathBuf {
+        let root = temp_dir().join(format!("mirrorman-test-{0}-{name}", process::id()));
+        let _ = remove_dir_all(&root);
+        create_dir_all(&root).unwrap();
+        for (path, contents) in entries {
+            let path = root.join(path);
+            match path.to_string_lossy().ends_with('/') {
+                true => create_dir_all(&path).unwrap(),
+                false => {
+                    create_dir_all(path.parent().unwrap()).unwrap();
+                    write(&path, contents).unwrap();
+                }
+            }
+        }
+        root
+    }
+
+    /// Syncs `source` to `mirror` (relative to `root`) with the database `root/{name}.mmdb`,
+    /// without drawing progress
+    fn sync_test(
+        root: &Path,
+        name: &str,
+        (source, mirror): (&str, &str),
+        config: DatabaseConfig,
+        options: SyncOptions,
+    ) -> Database {
+        let mut database = Database::new(PathBuf::from(source), PathBuf::from(mirror), config);
+        let options = SyncOptions {
+            progress: ProgressStyle::None,
+            ..options
+        };
+        database
+            .sync(&root.join(format!("{name}.mmdb")), &options)
+            .unwrap();
+        database
+    }
+
+    /// Syncs a source holding an empty directory to a mirror holding an orphaned directory,
+    /// returning the root of the test
+    fn sync_with_empty_dirs(name: &str, empty_dirs: EmptyDirs) -> PathBuf {
+        let root = test_root(
+            name,
+            &[
+                ("source/empty/", ""),
+                ("source/full/file.txt", "file"),
+                ("mirror/orphan/old.txt", "old"),
+            ],
+        );
+        let options = SyncOptions {
+            empty_dirs,
+            ..Default::default()
+        };
+        sync_test(
+            &root,
+            "test",
+            ("source", "mirror"),
+            DatabaseConfig::default(),
+            options,
+        );
+        root
+    }
+
+    #[test]
+    fn trailing_slashes_mirror_the_same_layout() {
+        for (index, (source, mirror)) in [
+            ("source", "mirror"),
+            ("source/", "mirror"),
+            ("source", "mirror/"),
+            ("./source/", "./mirror//"),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let root = test_root(
+                &format!("trailing-{index}"),
+                &[("source/nested/file.txt", "file")],
+            );
+            let database = sync_test(
+                &root,
+                "test",
+                (source, mirror),
+                DatabaseConfig::default(),
+                SyncOptions::default(),
+            );
+            assert_eq!(database.source_path(), PathBuf::from("source"));
+            assert_eq!(database.mirror_path(), PathBuf::from("mirror"));
+
+            assert!(root.join("mirror/nested/file.txt").is_file());
+            assert!(!root.join("mirror/file.txt").exists());
+            remove_dir_all(root).unwrap();
+        }
+    }
+
+    #[test]
+    fn format_version_and_sizes_are_stored() {
+        let root = test_root(
+            "stored",
+            &[("source/a.txt", "aaa"), ("source/nested/b.txt", "bb")],
+        );
+        let database = sync_test(
+            &root,
+            "test",
+            ("source", "mirror"),
+            DatabaseConfig::default(),
+            SyncOptions::default(),
+        );
+        assert_eq!(database.tracked_bytes(), Some(5));
+
+        let database_path = root.join("test.mmdb");
+        let mut stored: Value =
+            serde_json::from_str(&fs::read_to_string(&database_path).unwrap()).unwrap();
+        assert_eq!(stored["format_version"], FORMAT_VERSION);
+        assert_eq!(
+            Database::load(&database_path).unwrap().tracked_bytes(),
+            Some(5)
+        );
+
+        // Databases from before either was stored
+        let fields = stored.as_object_mut().unwrap();
+        fields.remove("format_version");
+        fields.remove("sizes");
+        write(&database_path, stored.to_string()).unwrap();
+        let database = Database::load(&database_path).unwrap();
+        assert_eq!(database.format_version(), 1);
+        assert_eq!(database.tracked_bytes(), None);
+
+        stored["format_version"] = json!(FORMAT_VERSION + 1);
+        write(&database_path, stored.to_string()).unwrap();
+        assert!(Database::load(&database_path).is_err());
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn paths_are_relative_to_the_database_not_the_working_directory() {
+        let root = test_root(
+            "cwd",
+            &[("db/source/file.txt", "file"), ("elsewhere/nested/", "")],
+        );
+        // Anything resolved against the working directory would land in `elsewhere`
+        let working_dir = WorkingDirGuard::change_to(&root.join("elsewhere/nested"));
+        let database = sync_test(
+            &root.join("db"),
+            "test",
+            ("source", "../mirror"),
+            DatabaseConfig::default(),
+            SyncOptions::default(),
+        );
+        drop(working_dir);
+
+        assert!(root.join("mirror/file.txt").is_file());
+        assert!(!root.join("elsewhere/mirror").exists());
+        assert!(!root.join("elsewhere/nested/source").exists());
+        assert!(database.failed().is_empty());
+        remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unmanaged_files_are_found_as_the_sync_walks_the_source() {
+        let root = test_root(
+            "unmanaged",
+            &[
+                ("real/a.txt", "a"),
+                ("source/b.txt", "b"),
+                ("mirror/linked/a.txt", "a"),
+                ("mirror/b.txt", "b"),
+                ("mirror/stray.txt", "stray"),
+            ],
+        );
+        std::os::unix::fs::symlink(root.join("real"), root.join("source/linked")).unwrap();
+        let options = || SyncOptions {
+            dereference_db: true,
+            ..Default::default()
+        };
+        let database = Database::new("source".into(), "mirror".into(), DatabaseConfig::default());
+        let unmanaged = database
+            .unmanaged_mirror_files(&root.join("test.mmdb"), &options())
+            .unwrap();
+        assert_eq!(unmanaged, [Path::new("stray.txt")]);
+
+        // The first sync removes just what was listed
+        sync_test(
+            &root,
+            "test",
+            ("source", "mirror"),
+            DatabaseConfig::default(),
+            options(),
+        );
+        assert!(root.join("mirror/linked/a.txt").is_file());
+        assert!(!root.join("mirror/stray.txt").exists());
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn database_inside_source_is_never_mirrored() {
+        let root = test_root(
+            "self",
+            &[
+                ("source/file.txt", "file"),
+                ("source/.test.mmdb.mirrorman-1.tmp", "stale"),
+            ],
+        );
+        // Reached through a different spelling of the source directory, twice so the database
+        // itself exists in the source
+        let database_root = root.join("source/../source");
+        sync_test(
+            &database_root,
+            "test",
+            (".", "../mirror"),
+            DatabaseConfig::default(),
+            SyncOptions::default(),
+        );
+        let database = sync_test(
+            &database_root,
+            "test",
+            (".", "../mirror"),
+            DatabaseConfig::default(),
+            SyncOptions::default(),
+        );
+
+        assert!(root.join("mirror/file.txt").is_file());
+        assert!(!root.join("mirror/test.mmdb").exists());
+        assert!(!root.join("mirror/.test.mmdb.mirrorman-1.tmp").exists());
+        assert!(database.failed().is_empty());
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn custom_hasher_and_native_filters_are_used() {
+        let root = test_root(
+            "native",
+            &[("source/note.txt", "note"), ("source/data.bin", "binary")],
+        );
+        let config = DatabaseConfig {
+            filters: vec!["uppercase".to_owned()],
+            ..Default::default()
+        };
+        let options = SyncOptions {
+            hasher: Some(Arc::new(LengthHasher)),
+            native_filters: [("uppercase".to_owned(), Arc::new(Uppercase) as _)].into(),
+            ..Default::default()
+        };
+        let database = sync_test(&root, "test", ("source", "mirror"), config, options);
+
+        assert_eq!(
+            fs::read_to_string(root.join("mirror/note.TXT")).unwrap(),
+            "NOTE"
+        );
+        assert!(root.join("mirror/data.bin").is_file());
+        assert_eq!(database.hashes[Path::new("source/note.txt")], "len4");
+        assert_eq!(database.hashes[Path::new("source/data.bin")], "len6");
+        assert!(database.failed().is_empty());
+        remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn batch_rel_filters_get_relative_paths_and_report_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = test_root(
+            "batch-rel",
+            &[
+                ("source/a.txt", "a"),
+                ("source/nested/b.txt", "b"),
+                ("source/tab\tname.txt", "tab"),
+            ],
+        );
+        let filter = root.join("batch.sh");
+        write(
+            &filter,
+            r#"#!/bin/sh
+case "$1" in
+    ext) [ "$2" = txt ] && echo out ;;
+    caps) echo batch batch-rel ;;
+    batch) while IFS="$(printf '\t')" read -r input output relative; do
+        cp "$input" "$output"
+        printf '%s\t{"relative": "%s"}\n' "$output" "$relative"
+    done ;;
+    run) cp "$2" "$3" ;;
+    *) exit 1 ;;
+esac
+"#,
+        )
+        .unwrap();
+        fs::set_permissions(&filter, fs::Permissions::from_mode(0o755)).unwrap();
+        let config = DatabaseConfig {
+            filters: vec!["./batch.sh".to_owned()],
+            ..Default::default()
+        };
+        let database = sync_test(
+            &root,
+            "test",
+            ("source", "mirror"),
+            config,
+            SyncOptions::default(),
+        );
+
+        assert_eq!(fs::read_to_string(root.join("mirror/a.out")).unwrap(), "a");
+        assert!(root.join("mirror/nested/b.out").is_file());
+        assert_eq!(
+            database.metadata[Path::new("source/nested/b.txt")],
+            json!({ "relative": "nested/b.out" })
+        );
+        // Tabs would split the line of batch input, so the file is run on its own instead
+        assert_eq!(
+            fs::read_to_string(root.join("mirror/tab\tname.out")).unwrap(),
+            "tab"
+        );
+        assert!(!database
+            .metadata
+            .contains_key(Path::new("source/tab\tname.txt")));
+        assert!(database.failed().is_empty());
+        remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_batches_are_not_counted_as_copied() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = test_root(
+            "batch-stats",
+            &[("source/a.txt", "aaaa"), ("source/b.bin", "bb")],
+        );
+        let filter = root.join("batch.sh");
+        write(
+            &filter,
+            r#"#!/bin/sh
+case "$1" in
+    ext) [ "$2" = txt ] && echo out ;;
+    caps) echo batch ;;
+    *) exit 1 ;;
+esac
+"#,
+        )
+        .unwrap();
+        fs::set_permissions(&filter, fs::Permissions::from_mode(0o755)).unwrap();
+        let config = DatabaseConfig {
+            filters: vec!["./batch.sh".to_owned()],
+            ..Default::default()
+        };
+        let mut database = Database::new("source".into(), "mirror".into(), config);
+        let options = SyncOptions {
+            progress: ProgressStyle::None,
+            ..Default::default()
+        };
+        let stats = database.sync(&root.join("test.mmdb"), &options).unwrap();
+
+        // Only the copied file counts, the batched one failed
+        assert_eq!((stats.files, stats.bytes_copied, stats.errors), (1, 2, 1));
+        let json: Value = serde_json::from_str(&stats.to_json().unwrap()).unwrap();
+        assert_eq!(json["files"], 1);
+        assert_eq!(json["bytes_copied"], 2);
+        assert_eq!(json["errors"], 1);
+        assert_eq!(json["by_type"]["bin"]["added"], 1);
+        assert!(json["elapsed_secs"].is_f64() && json["mb_per_sec"].is_f64());
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn failed_filters_are_not_counted_as_written() {
+        let root = test_root(
+            "filter-failure",
+            &[("source/a.txt", "aaaa"), ("source/b.bin", "bb")],
+        );
+        let config = DatabaseConfig {
+            filters: vec!["uppercase".to_owned()],
+            ..Default::default()
+        };
+        let mut database = Database::new("source".into(), "mirror".into(), config);
+        let database_path = root.join("test.mmdb");
+        let options = |filter: Arc<dyn NativeFilter>| SyncOptions {
+            progress: ProgressStyle::None,
+            native_filters: [("uppercase".to_owned(), filter)].into(),
+            ..Default::default()
+        };
+        database
+            .sync(&database_path, &options(Arc::new(Uppercase)))
+            .unwrap();
+
+        write(root.join("source/a.txt"), "changed").unwrap();
+        write(root.join("source/c.txt"), "c").unwrap();
+        let stats = database
+            .sync(&database_path, &options(Arc::new(Failing)))
+            .unwrap();
+
+        // Only the unchanged file counts, neither failed file was written
+        assert_eq!((stats.files, stats.bytes_copied, stats.errors), (1, 0, 2));
+        assert!(database.changes().added.is_empty());
+        assert!(database.changes().changed.is_empty());
+        assert_eq!(
+            database.failed().iter().collect::<Vec<_>>(),
+            [Path::new("source/a.txt"), Path::new("source/c.txt")]
+        );
+        // The previous mirror is kept for the next sync to replace, a new file has none
+        assert_eq!(
+            fs::read_to_string(root.join("mirror/a.TXT")).unwrap(),
+            "AAAA"
+        );
+        assert!(!root.join("mirror/c.TXT").exists());
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn colliding_sources_keep_the_first_path() {
+        let root = test_root(
+            "collisions",
+            &[("source/note.TXT", "UPPER"), ("source/note.txt", "lower")],
+        );
+        let config = DatabaseConfig {
+            filters: vec!["uppercase".to_owned()],
+            ..Default::default()
+        };
+        let options = SyncOptions {
+            native_filters: [("uppercase".to_owned(), Arc::new(Uppercase) as _)].into(),
+            ..Default::default()
+        };
+        let database = sync_test(&root, "test", ("source", "mirror"), config, options);
+
+        assert_eq!(
+            fs::read_to_string(root.join("mirror/note.TXT")).unwrap(),
+            "UPPER"
+        );
+        assert_eq!(
+            database.collisions()[Path::new("note.TXT")],
+            [
+                PathBuf::from("source/note.TXT"),
+                PathBuf::from("source/note.txt")
+            ]
+            .into()
+        );
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn encrypted_mirrors_only_ever_hold_ciphertext() {
+        let root = test_root(
+            "encrypted",
+            &[
+                ("key", "secret"),
+                ("source/note.txt", "note"),
+                ("source/nested/data.bin", "binary"),
+                ("tmp/", ""),
+            ],
+        );
+        let config = DatabaseConfig {
+            filters: vec!["record".to_owned()],
+            encryption_key_file: Some(PathBuf::from("key")),
+            ..Default::default()
+        };
+        let record = Arc::new(RecordOutputs::default());
+        let options = SyncOptions {
+            native_filters: [("record".to_owned(), record.clone() as _)].into(),
+            tmpdir: Some(root.join("tmp")),
+            ..Default::default()
+        };
+        let database = sync_test(&root, "test", ("source", "mirror"), config, options);
+
+        assert!(root.join("mirror/note.txt.enc").is_file());
+        assert!(root.join("mirror/nested/data.bin.enc").is_file());
+        assert!(!root.join("mirror/note.txt").exists());
+        assert!(!root.join("mirror/nested/data.bin").exists());
+        assert_ne!(fs::read(root.join("mirror/note.txt.enc")).unwrap(), b"note");
+        // Plaintext is staged in the temporary directory, which is cleared out once encrypted
+        let outputs = record.0.lock().unwrap();
+        assert_eq!(outputs.len(), 1);
+        assert!(outputs[0].starts_with(root.join("tmp")));
+        assert_eq!(fs::read_dir(root.join("tmp")).unwrap().count(), 0);
+
+        let restored = root.join("restored");
+        assert_eq!(
+            database
+                .decrypt(&root.join("test.mmdb"), &restored)
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            fs::read_to_string(restored.join("note.txt")).unwrap(),
+            "note"
+        );
+        assert_eq!(
+            fs::read_to_string(restored.join("nested/data.bin")).unwrap(),
+            "binary"
+        );
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn since_db_only_mirrors_changes() {
+        let root = test_root(
+            "since",
+            &[
+                ("source/same.txt", "same"),
+                ("source/changed.txt", "before"),
+                ("source/removed.txt", "removed"),
+            ],
+        );
+        sync_test(
+            &root,
+            "reference",
+            ("source", "full"),
+            DatabaseConfig::default(),
+            SyncOptions::default(),
+        );
+
+        write(root.join("source/changed.txt"), "after").unwrap();
+        write(root.join("source/added.txt"), "added").unwrap();
+        fs::remove_file(root.join("source/removed.txt")).unwrap();
+        let options = SyncOptions {
+            since_db: Some(root.join("reference.mmdb")),
+            ..Default::default()
+        };
+        sync_test(
+            &root,
+            "incremental",
+            ("source", "incremental"),
+            DatabaseConfig::default(),
+            options,
+        );
+
+        assert!(root.join("incremental/changed.txt").is_file());
+        assert!(root.join("incremental/added.txt").is_file());
+        assert!(!root.join("incremental/same.txt").exists());
+        assert_eq!(
+            fs::read_to_string(root.join("incremental/mirrorman-changelog.txt")).unwrap(),
+            format!(
+                "# Changes since {0}\n+ added.txt\n~ changed.txt\n- removed.txt\n",
+                root.join("reference.mmdb").display()
+            )
+        );
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn clearing_the_mirror_keeps_ignored_files() {
+        let root = test_root(
+            "clear",
+            &[
+                ("source/file.txt", "file"),
+                ("mirror/.mmdbignore", "notes/*\n"),
+                ("mirror/notes/keep.txt", "keep"),
+            ],
+        );
+        let database = sync_test(
+            &root,
+            "test",
+            ("source", "mirror"),
+            DatabaseConfig::default(),
+            SyncOptions::default(),
+        );
+        // Left since the sync, e.g. by another program
+        create_dir_all(root.join("mirror/old")).unwrap();
+        write(root.join("mirror/old/gone.txt"), "gone").unwrap();
+        write(root.join("mirror/stray.txt"), "stray").unwrap();
+        database.clear_mirror(&root.join("test.mmdb")).unwrap();
+
+        assert!(root.join("mirror/.mmdbignore").is_file());
+        assert!(root.join("mirror/notes/keep.txt").is_file());
+        assert!(!root.join("mirror/file.txt").exists());
+        assert!(!root.join("mirror/old").exists());
+        assert!(!root.join("mirror/stray.txt").exists());
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn files_mirrored_from_a_list_are_owned() {
+        let root = test_root(
+            "owned",
+            &[("source/old.txt", "old"), ("mirror/unmanaged.txt", "keep")],
+        );
+        let config = DatabaseConfig {
+            owned_cleanup: true,
+            ..Default::default()
+        };
+        let mut database = sync_test(
+            &root,
+            "test",
+            ("source", "mirror"),
+            config,
+            SyncOptions::default(),
+        );
+
+        write(root.join("source/new.txt"), "new").unwrap();
+        let options = SyncOptions {
+            progress: ProgressStyle::None,
+            file_list: Some(vec![PathBuf::from("new.txt")]),
+            ..Default::default()
+        };
+        database.sync(&root.join("test.mmdb"), &options).unwrap();
+        assert!(root.join("mirror/new.txt").is_file());
+
+        fs::remove_file(root.join("source/new.txt")).unwrap();
+        let options = SyncOptions {
+            progress: ProgressStyle::None,
+            ..Default::default()
+        };
+        database.sync(&root.join("test.mmdb"), &options).unwrap();
+        assert!(!root.join("mirror/new.txt").exists());
+        assert!(root.join("mirror/old.txt").is_file());
+        assert!(root.join("mirror/unmanaged.txt").is_file());
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn empty_source_dirs_are_mirrored_by_default() {
+        let root = sync_with_empty_dirs("mirror", EmptyDirs::Mirror);
+        assert!(root.join("mirror/empty").is_dir());
+        assert!(root.join("mirror/full/file.txt").is_file());
+        assert!(!root.join("mirror/orphan").exists());
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn prune_removes_every_empty_dir() {
+        let root = sync_with_empty_dirs("prune", EmptyDirs::Prune);
+        assert!(!root.join("mirror/empty").exists());
+        assert!(root.join("mirror/full/file.txt").is_file());
+        assert!(!root.join("mirror/orphan").exists());
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn keep_leaves_emptied_dirs() {
+        let root = sync_with_empty_dirs("keep", EmptyDirs::Keep);
+        assert!(root.join("mirror/empty").is_dir());
+        assert!(root.join("mirror/full/file.txt").is_file());
+        assert!(root.join("mirror/orphan").is_dir());
+        assert!(!root.join("mirror/orphan/old.txt").exists());
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn flattening_only_disambiguates_walked_files() {
+        let root = test_root(
+            "flatten",
+            &[
+                ("source/music.mp3", "music"),
+                ("source/music.mmdb", "{}"),
+                ("source/a/song.mp3", "a"),
+                ("source/b/song.mp3", "b"),
+            ],
+        );
+        let config = DatabaseConfig {
+            flatten: true,
+            ..Default::default()
+        };
+        sync_test(
+            &root.join("source"),
+            "music",
+            (".", "../mirror"),
+            config,
+            SyncOptions::default(),
+        );
+
+        // The database isn't one of the source's files, so doesn't collide with `music.mp3`
+        assert_eq!(
+            fs::read_to_string(root.join("mirror/music.mp3")).unwrap(),
+            "music"
+        );
+        let mut mirrored = fs::read_dir(root.join("mirror"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        mirrored.sort();
+        assert_eq!(mirrored.len(), 3);
+        assert!(mirrored[1..].iter().all(|name| name.starts_with("song-")));
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn saved_sniffing_can_be_turned_off_for_a_sync() {
+        /// Mirrors `.pdf` files as `.txt`
+        struct PdfToText;
+
+        impl NativeFilter for PdfToText {
+            fn ext(&self, extension: &OsStr) -> Option<String> {
+                (extension == "pdf").then(|| "txt".to_owned())
+            }
+
+            fn run(&self, _input: &Path, output: &Path, _relative: &Path) -> Result<Option<Value>> {
+                fs::write(output, "text")?;
+                Ok(None)
+            }
+        }
+
+        let root = test_root("sniff-setting", &[("source/scan", "%PDF-1.7 scan")]);
+        for (name, sniff, mirrored) in [
+            ("saved", None, "scan.txt"),
+            ("overridden", Some(false), "scan"),
+        ] {
+            let config = DatabaseConfig {
+                filters: vec!["pdf-to-text".to_owned()],
+                settings: SyncSettings {
+                    sniff: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let options = SyncOptions {
+                native_filters: [("pdf-to-text".to_owned(), Arc::new(PdfToText) as _)].into(),
+                sniff,
+                ..Default::default()
+            };
+            sync_test(&root, name, ("source", name), config, options);
+            let entries = fs::read_dir(root.join(name))
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect::<Vec<_>>();
+            assert_eq!(entries, [mirrored], "{name}");
+        }
+        remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn remote_mirrors_are_synced_on_their_host() {
+        let root = test_root(
+            "remote",
+            &[
+                ("source/kept.txt", "kept"),
+                ("source/nested/gone.txt", "gone"),
+                ("host/.mmdbignore", "notes.txt"),
+                ("host/notes.txt", "notes"),
+                ("host/stale/old.txt", "old"),
+            ],
+        );
+        let host = Arc::new(LocalHost {
+            root: root.join("host"),
+            uploads: Mutex::default(),
+        });
+        let options = || SyncOptions {
+            progress: ProgressStyle::None,
+            remote_files: Some(host.clone()),
+            ..Default::default()
+        };
+        let mirror = "ssh://backup@example.com/srv/mirror";
+        let mut database = sync_test(
+            &root,
+            "test",
+            ("source", mirror),
+            DatabaseConfig::default(),
+            options(),
+        );
+        assert_eq!(database.mirror_path(), Path::new(mirror));
+        assert_eq!(
+            fs::read_to_string(root.join("host/nested/gone.txt")).unwrap(),
+            "gone"
+        );
+        assert!(root.join("host/notes.txt").exists());
+        assert!(!root.join("host/stale").exists());
+        assert_eq!(database.changes().removed, [Path::new("stale/old.txt")]);
+        assert_eq!(host.uploads.lock().unwrap().len(), 2);
+
+        // Only what changed is uploaded again, and what's gone from the source goes from the host
+        host.uploads.lock().unwrap().clear();
+        fs::write(root.join("source/kept.txt"), "changed").unwrap();
+        remove_dir_all(root.join("source/nested")).unwrap();
+        database.sync(&root.join("test.mmdb"), &options()).unwrap();
+        assert_eq!(*host.uploads.lock().unwrap(), [Path::new("kept.txt")]);
+        assert_eq!(
+            fs::read_to_string(root.join("host/kept.txt")).unwrap(),
+            "changed"
+        );
+        assert!(!root.join("host/nested").exists());
+
+        let delta = SyncOptions {
+            delta: true,
+            ..options()
+        };
+        assert!(database.sync(&root.join("test.mmdb"), &delta).is_err());
+        remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,184 @@
+use std::{
+    ffi::OsStr,
+    path::{Component, Path, PathBuf},
+};
+
+/// Device names Windows reserves in every directory, whatever the extension
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Characters which can't appear in a Windows file name
+const INVALID_CHARS: [char; 7] = ['<', '>', ':', '"', '|', '?', '*'];
+
+/// A version of `name` which can be created on Windows, or `None` if it already can be.
+///
+/// Invalid characters become `_`, as do trailing dots and spaces, and reserved device names
+/// (e.g. `aux`, `com1.txt`) get a `_` appended to their stem.
+pub fn portable_file_name(name: &OsStr) -> Option<String> {
+    let name = name.to_string_lossy();
+    let mut portable = name
+        .chars()
+        .map(|c| match c {
+            c if INVALID_CHARS.contains(&c) || c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>();
+
+    let trimmed = portable.trim_end_matches(['.', ' ']).len();
+    if trimmed < portable.len() {
+        let trailing = portable.len() - trimmed;
+        portable.truncate(trimmed);
+        portable.extend(std::iter::repeat_n('_', trailing));
+    }
+
+    let stem_end = portable.find('.').unwrap_or(portable.len());
+    let stem = portable[..stem_end].trim_end_matches(' ');
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        portable.insert(stem_end, '_');
+    }
+
+    (portable != name).then_some(portable)
+}
+
+/// `relative_path` with every component made portable to Windows, warning if any changed
+pub fn portable_relative_path(relative_path: &Path) -> PathBuf {
+    let portable = relative_path
+        .components()
+        .map(|component| match component {
+            Component::Normal(name) => {
+                portable_file_name(name).map_or_else(|| PathBuf::from(name), PathBuf::from)
+            }
+            component => PathBuf::from(component.as_os_str()),
+        })
+        .collect::<PathBuf>();
+    if portable != relative_path {
+        log::warn!(
+            "`{0}` can't be represented on Windows, mirroring it as `{1}`...",
+            relative_path.display(),
+            portable.display()
+        );
+    }
+    portable
+}
+
+/// `path` in its `\\?\` form on Windows, so paths beneath it aren't limited to `MAX_PATH`
+#[cfg(windows)]
+pub fn long_path(path: &Path) -> PathBuf {
+    use std::path::Prefix;
+
+    let Ok(path) = std::path::absolute(path) else {
+        return path.to_path_buf();
+    };
+    match path.components().next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(_) => PathBuf::from(format!(r"\\?\{0}", path.display())),
+            Prefix::UNC(..) => {
+                let share = path.to_string_lossy();
+                PathBuf::from(format!(r"\\?\UNC\{0}", share.trim_start_matches('\\')))
+            }
+            // Already verbatim, or a device path
+            _ => path,
+        },
+        _ => path,
+    }
+}
+
+/// `path` unchanged, only Windows limits path lengths this way
+#[cfg(not(windows))]
+pub fn long_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{long_path, portable_file_name, portable_relative_path};
+    use std::{
+        ffi::OsStr,
+        path::{Path, PathBuf},
+    };
+
+    fn portable(name: &str) -> Option<String> {
+        portable_file_name(OsStr::new(name))
+    }
+
+    #[test]
+    fn portable_names_are_left_alone() {
+        assert_eq!(portable("notes.txt"), None);
+        assert_eq!(portable(".hidden"), None);
+        // Only whole stems are reserved
+        assert_eq!(portable("auxiliary.txt"), None);
+        assert_eq!(portable("com10"), None);
+    }
+
+    #[test]
+    fn reserved_names_get_their_stem_suffixed() {
+        assert_eq!(portable("aux").as_deref(), Some("aux_"));
+        assert_eq!(portable("AUX").as_deref(), Some("AUX_"));
+        assert_eq!(portable("com1.txt").as_deref(), Some("com1_.txt"));
+        assert_eq!(portable("nul.tar.gz").as_deref(), Some("nul_.tar.gz"));
+        assert_eq!(portable("lpt9 .log").as_deref(), Some("lpt9 _.log"));
+    }
+
+    #[test]
+    fn trailing_dots_and_spaces_are_replaced() {
+        assert_eq!(portable("name.").as_deref(), Some("name_"));
+        assert_eq!(portable("name. .").as_deref(), Some("name___"));
+        // Which leaves a reserved name unreserved
+        assert_eq!(portable("con.").as_deref(), Some("con_"));
+    }
+
+    #[test]
+    fn forbidden_characters_are_replaced() {
+        assert_eq!(
+            portable("a<b>c:d\"e|f?g*h").as_deref(),
+            Some("a_b_c_d_e_f_g_h")
+        );
+        assert_eq!(portable("tab\there").as_deref(), Some("tab_here"));
+    }
+
+    #[test]
+    fn every_component_of_a_relative_path_is_made_portable() {
+        assert_eq!(
+            portable_relative_path(Path::new("aux/what?/file.txt.")),
+            PathBuf::from("aux_/what_/file.txt_")
+        );
+        assert_eq!(
+            portable_relative_path(Path::new("docs/notes.txt")),
+            PathBuf::from("docs/notes.txt")
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn long_paths_are_verbatim_on_windows() {
+        assert_eq!(
+            long_path(Path::new(r"C:\mirror\file.txt")),
+            PathBuf::from(r"\\?\C:\mirror\file.txt")
+        );
+        assert_eq!(
+            long_path(Path::new(r"\\server\share\file.txt")),
+            PathBuf::from(r"\\?\UNC\server\share\file.txt")
+        );
+        assert_eq!(
+            long_path(Path::new(r"\\?\C:\file.txt")),
+            PathBuf::from(r"\\?\C:\file.txt")
+        );
+        // Relative paths are made absolute first
+        let _working_dir = crate::database::lock_working_dir();
+        let relative = long_path(Path::new("mirror"))
+            .to_string_lossy()
+            .into_owned();
+        assert!(relative.starts_with(r"\\?\") && relative.ends_with(r"\mirror"));
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn long_paths_are_unchanged_elsewhere() {
+        assert_eq!(long_path(Path::new("mirror")), PathBuf::from("mirror"));
+    }
+}
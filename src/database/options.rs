@@ -0,0 +1,156 @@
+use super::{
+    HashCache, Hasher, Owner, PreservedTime, ReflinkMode, Sha256Hasher, TimeBudget, Trace,
+};
+use crate::{
+    filter::{FilterLog, NativeFilters, Sandbox},
+    remote::RemoteFiles,
+};
+use clap::ValueEnum;
+use std::{
+    io::{stdout, IsTerminal},
+    path::PathBuf,
+    sync::Arc,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ProgressStyle {
+    /// A single, continuously redrawn progress bar
+    Bar,
+    /// Periodic `X/Y files` lines, suitable for logs
+    Plain,
+    /// No progress output
+    None,
+}
+
+impl Default for ProgressStyle {
+    /// A bar when writing to a terminal, plain lines otherwise
+    fn default() -> Self {
+        if stdout().is_terminal() {
+            Self::Bar
+        } else {
+            Self::Plain
+        }
+    }
+}
+
+/// What happens to empty directories in the mirror
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum EmptyDirs {
+    /// Mirror empty source directories, and remove directories emptied by cleanup
+    #[default]
+    Mirror,
+    /// Never leave an empty directory in the mirror, even for empty source directories
+    Prune,
+    /// Never remove directories, only files
+    Keep,
+}
+
+#[derive(Default)]
+pub struct SyncOptions {
+    /// Buffer per-file log messages and flush them sorted by path at the end of the sync
+    pub ordered_log: bool,
+    /// Don't descend into directories on a different file system to the source directory
+    pub one_file_system: bool,
+    /// Maximum number of filter processes to run at once, unlimited if `None`
+    pub filter_concurrency: Option<usize>,
+    /// Tune how many files are mirrored at once from the throughput seen early in the sync
+    pub concurrency_auto: bool,
+    /// How progress is drawn while syncing
+    pub progress: ProgressStyle,
+    /// Print nothing but a closing summary once every database has synced, for scripts
+    pub summary_only: bool,
+    /// Print each database's statistics as a line of JSON in place of any other output
+    pub json: bool,
+    /// Show what each sync will change and ask before going ahead
+    pub confirm: bool,
+    /// Where to record every sync decision, if anywhere
+    pub trace: Option<Trace>,
+    /// Where to record everything filters write to stdout and stderr, if anywhere
+    pub filter_log: Option<FilterLog>,
+    /// Skip hashing files in directories whose metadata rollup is unchanged since the last sync
+    pub merkle: bool,
+    /// How file contents are hashed, SHA-256 if `None`
+    pub hasher: Option<Arc<dyn Hasher>>,
+    /// Memory map files of at least [`super::MMAP_THRESHOLD`] bytes to hash them, rather than
+    /// reading them
+    pub mmap: bool,
+    /// Filters run in-process in place of the programs they're named after
+    pub native_filters: NativeFilters,
+    /// Hash every file's contents, never skipping any based on metadata
+    pub checksum: bool,
+    /// Hashes shared with other databases, reused while a file's size and modification time are
+    /// unchanged
+    pub hash_cache: Option<HashCache>,
+    /// Whether to clone unfiltered files rather than copying them
+    pub reflink: ReflinkMode,
+    /// Detect the type of files without an extension from their contents when choosing a filter,
+    /// the database's default setting if `None`
+    pub sniff: Option<bool>,
+    /// Ask filters about extensions exactly as they're spelled, rather than lowercased
+    pub case_sensitive_ext: bool,
+    /// Source timestamps to carry over to mirrored files
+    pub preserve_times: Vec<PreservedTime>,
+    /// Owner given to every mirrored file, rather than whoever runs the sync
+    pub chown: Option<Owner>,
+    /// How long to spend starting on files, shared by every database synced
+    pub time_budget: Option<TimeBudget>,
+    /// Total size in bytes the mirror may grow to, unlimited if `None`
+    pub max_total_size: Option<u64>,
+    /// Abort once more than this many files have failed, unlimited if `None`
+    pub max_errors: Option<usize>,
+    /// Leave mirror files alone when they're newer than their source, e.g. after being edited
+    pub newer_only: bool,
+    /// Check every filter can be invoked and responds to the filter protocol before syncing
+    pub require_filters: bool,
+    /// Restrictions to run filters under, if any
+    pub sandbox: Option<Sandbox>,
+    /// Update large, existing mirror files in place, only writing the blocks which changed
+    pub delta: bool,
+    /// Hash plain copies while copying them where the copy is known to be needed up front,
+    /// rather than reading them once to hash and again to copy
+    pub single_pass: bool,
+    /// Write empty placeholders instead of mirroring file contents, to preview the mirror's layout
+    pub structure_only: bool,
+    /// Mirror temporary artifacts like swap files and partial downloads, which are skipped by
+    /// default
+    pub no_default_ignores: bool,
+    /// Log and skip source directories which can't be read, keeping their mirrors as they are
+    pub keep_going_on_walk_errors: bool,
+    /// Rename mirror files and directories whose names can't be created on Windows
+    pub portable_names: bool,
+    /// Follow symlinked directories in the source, hashing each real file only once however many
+    /// paths lead to it
+    pub dereference_db: bool,
+    /// Wait for other processes syncing the same database to finish, rather than failing
+    pub wait: bool,
+    /// Whether empty directories are mirrored, pruned or kept
+    pub empty_dirs: EmptyDirs,
+    /// Record a sync which leaves a populated mirror empty, or nearly so, rather than aborting
+    pub empty_mirror_ok: bool,
+    /// Where to create temporary files (the database while it's saved, staged archive entries),
+    /// rather than next to what they're for
+    pub tmpdir: Option<PathBuf>,
+    /// Reference database to mirror only the files which changed since, writing a changelog
+    pub since_db: Option<PathBuf>,
+    /// Directory to mirror to a fresh `{timestamp}` snapshot within, in place of the mirror
+    pub snapshots: Option<PathBuf>,
+    /// Mirror only these files (relative to the source directory), without walking the source or
+    /// cleaning up the mirror
+    pub file_list: Option<Vec<PathBuf>>,
+    /// Files of a remote mirror, used in place of connecting to its host over SFTP
+    pub remote_files: Option<Arc<dyn RemoteFiles>>,
+}
+
+impl SyncOptions {
+    /// Whether per-database progress and messages are left out of the output
+    pub fn quiet(&self) -> bool {
+        self.summary_only || self.json
+    }
+
+    pub fn hasher(&self) -> &dyn Hasher {
+        self.hasher.as_deref().unwrap_or(match self.mmap {
+            true => &Sha256Hasher { mmap: true },
+            false => &Sha256Hasher { mmap: false },
+        })
+    }
+}
@@ -0,0 +1,29 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+/// Everything in the mirror a sync wrote, and the directories holding it, relative to the mirror
+/// root. What was written before under source directories which couldn't be walked stays owned,
+/// as cleanup keeps it.
+pub fn owned_paths(
+    mirror_root: &Path,
+    mirror_list: &BTreeSet<PathBuf>,
+    previously_owned: &BTreeSet<PathBuf>,
+    preserved_dirs: &[PathBuf],
+) -> BTreeSet<PathBuf> {
+    let mut owned = mirror_list
+        .iter()
+        .filter_map(|entry| entry.strip_prefix(mirror_root).ok())
+        .flat_map(Path::ancestors)
+        .filter(|path| !path.as_os_str().is_empty())
+        .map(Path::to_path_buf)
+        .collect::<BTreeSet<_>>();
+    owned.extend(
+        previously_owned
+            .iter()
+            .filter(|path| preserved_dirs.iter().any(|dir| path.starts_with(dir)))
+            .cloned(),
+    );
+    owned
+}
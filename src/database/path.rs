@@ -1,18 +1,426 @@
-use anyhow::{Context, Result};
+use super::{is_temp_file_for, lock_path};
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
 use std::{
-    path::{Path, PathBuf},
+    ffi::OsString,
+    fs,
+    path::{absolute, Component, Path, PathBuf},
     str::FromStr,
 };
 
+/// Extension of database files, which `sync` looks for when discovering databases
+pub const DEFAULT_DB_EXT: &str = "mmdb";
+/// Environment variable overriding the extension `sync` looks for, as `--db-ext` does
+pub const DB_EXT_VAR: &str = "MIRRORMAN_DB_EXT";
+
+/// Where a new database is placed
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum DatabaseLocation {
+    /// The current directory
+    #[default]
+    Cwd,
+    /// Inside the mirror directory, so it travels with the mirror
+    Mirror,
+    /// Inside the source directory, so it travels with the source
+    Source,
+}
+
+impl DatabaseLocation {
+    /// The path of the database mirroring `source` to `mirror`
+    pub fn database_path(self, source: &Path, mirror: &Path) -> Result<PathBuf> {
+        let file_name = database_path_from_mirror(mirror)?;
+        match self {
+            Self::Cwd => Ok(file_name),
+            Self::Mirror => Ok(mirror.join(file_name)),
+            Self::Source if source.is_dir() => Ok(source.join(file_name)),
+            Self::Source => bail!(
+                "Source `{0}` isn't a directory, the database can't be placed inside it",
+                source.display()
+            ),
+        }
+    }
+
+    /// Where an existing database mirroring `source` to `mirror` is, checking where `self` places
+    /// it first, then the other locations. The source's is only checked if it's known.
+    pub fn find_database(self, source: Option<&Path>, mirror: &Path) -> Result<Option<PathBuf>> {
+        let file_name = database_path_from_mirror(mirror)?;
+        let others = [Self::Cwd, Self::Mirror, Self::Source]
+            .into_iter()
+            .filter(|location| *location != self);
+        for location in [self].into_iter().chain(others) {
+            let database_path = match (location, source) {
+                (Self::Cwd, _) => file_name.clone(),
+                (Self::Mirror, _) => mirror.join(&file_name),
+                (Self::Source, Some(source)) => source.join(&file_name),
+                (Self::Source, None) => continue,
+            };
+            if database_path.is_file() {
+                return Ok(Some(database_path));
+            }
+        }
+        Ok(None)
+    }
+
+    /// How `path` (relative to the current directory) is stored by a database in
+    /// `database_folder`. Paths are kept as they were given for a database in the current
+    /// directory. One kept in the mirror or source has to find them from wherever it's moved to,
+    /// so relative paths are made relative to it, as are absolute paths inside its folder.
+    pub fn stored_path(self, database_folder: &Path, path: &Path) -> Result<PathBuf> {
+        if self == Self::Cwd {
+            return Ok(path.to_path_buf());
+        }
+        let relative = relative_path(database_folder, path)?;
+        let outside = relative.is_absolute()
+            || relative
+                .components()
+                .next()
+                .is_some_and(|component| component == Component::ParentDir);
+        match path.is_absolute() && outside {
+            true => Ok(path.to_path_buf()),
+            false => Ok(relative),
+        }
+    }
+}
+
 pub fn database_path_from_mirror(mirror_path: &Path) -> Result<PathBuf> {
-    let path = mirror_path
+    // A remote mirror is named after its host and path, without the `ssh://`
+    let mirror_path = match (url_scheme(mirror_path), mirror_path.to_str()) {
+        (Some(scheme), Some(url)) => Path::new(&url[scheme.len() + "://".len()..]),
+        _ => mirror_path,
+    };
+    let path = normalize_path(mirror_path)
         .components()
         .filter_map(|c| c.as_os_str().to_ascii_lowercase().into_string().ok())
         .reduce(|a, b| format!("{0}_{1}", a, b))
         .with_context(|| "Failed to build database filename")?;
 
-    let path = PathBuf::from_str(&format!("{path}.mmdb"))
+    let path = PathBuf::from_str(&format!("{path}.{DEFAULT_DB_EXT}"))
         .with_context(|| "Failed to construct database path from mirror path")?;
 
     Ok(path)
 }
+
+/// Whether `path` is a database with extension `ext`, which may itself contain dots, e.g.
+/// `mirror.json`
+pub fn has_db_ext(path: &Path, ext: &str) -> bool {
+    let ext = ext.trim_start_matches('.');
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(ext))
+        .is_some_and(|stem| stem.len() > 1 && stem.ends_with('.'))
+}
+
+/// The scheme of a URL given where a path was expected, e.g. `ssh` for `ssh://host/path`, which
+/// would otherwise be taken as a local directory named `ssh:`
+pub fn url_scheme(path: &Path) -> Option<&str> {
+    let (scheme, _) = path.to_str()?.split_once("://")?;
+    (!scheme.is_empty()
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.')))
+    .then_some(scheme)
+}
+
+/// `path` without `.` components or trailing separators, so `dir`, `dir/` and `./dir/` are treated
+/// the same
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let normalized = path
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect::<PathBuf>();
+    if normalized.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        normalized
+    }
+}
+
+/// `path` relative to the directory `base`, e.g. `../source`, without resolving symlinks
+pub fn relative_path(base: &Path, path: &Path) -> Result<PathBuf> {
+    let base = absolute(base)
+        .with_context(|| format!("Failed to resolve absolute path of `{0}`", base.display()))?;
+    let path = absolute(path)
+        .with_context(|| format!("Failed to resolve absolute path of `{0}`", path.display()))?;
+    let base = base
+        .components()
+        .filter(|c| *c != Component::CurDir)
+        .collect::<Vec<_>>();
+    let path = path
+        .components()
+        .filter(|c| *c != Component::CurDir)
+        .collect::<Vec<_>>();
+
+    let common = base.iter().zip(&path).take_while(|(a, b)| a == b).count();
+    // Nothing in common (e.g. different drives on Windows), so only an absolute path will do
+    if common == 0 {
+        return Ok(path.iter().collect());
+    }
+
+    let relative = base[common..]
+        .iter()
+        .map(|_| Component::ParentDir)
+        .chain(path[common..].iter().copied())
+        .collect::<PathBuf>();
+    if relative.as_os_str().is_empty() {
+        Ok(PathBuf::from("."))
+    } else {
+        Ok(relative)
+    }
+}
+
+/// Whether `path` stays inside `root`, both lexically (no `..`) and once any symlinks along the
+/// part of it which already exists are resolved. `real_root` is `root` resolved, once per sync
+/// rather than for every path, or `None` if it didn't exist yet: everything in it was then written
+/// by the sync, which never creates symlinks.
+pub fn is_within(root: &Path, real_root: Option<&Path>, path: &Path) -> bool {
+    let Ok(relative) = path.strip_prefix(root) else {
+        return false;
+    };
+    if relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return false;
+    }
+
+    let Some(real_root) = real_root else {
+        return true;
+    };
+    match path.ancestors().find(|ancestor| ancestor.exists()) {
+        // Only the root exists so far, which is already resolved
+        Some(existing) if existing == root => true,
+        existing => existing
+            .and_then(|existing| existing.canonicalize().ok())
+            .is_none_or(|real_path| real_path.starts_with(real_root)),
+    }
+}
+
+/// A database file along with its lock and temporary siblings, which are never part of a source or
+/// mirror even when the database lives inside one
+pub struct DatabaseFiles {
+    /// `None` if the folder can't be resolved, in which case nothing is recognised
+    real_folder: Option<PathBuf>,
+    file_name: OsString,
+    lock_name: OsString,
+}
+
+impl DatabaseFiles {
+    pub fn new(database_path: &Path) -> Self {
+        let real_folder = database_folder(database_path)
+            .ok()
+            .and_then(|folder| fs::canonicalize(folder).ok());
+        Self {
+            real_folder,
+            file_name: database_path.file_name().unwrap_or_default().to_owned(),
+            lock_name: lock_path(database_path)
+                .file_name()
+                .unwrap_or_default()
+                .to_owned(),
+        }
+    }
+
+    /// Whether `path` is one of the database's files. Names are checked first, so only likely
+    /// matches have their folder resolved and compared.
+    pub fn contains(&self, path: &Path) -> bool {
+        let Some(name) = path.file_name() else {
+            return false;
+        };
+        if name != self.file_name
+            && name != self.lock_name
+            && !is_temp_file_for(name, &self.file_name)
+        {
+            return false;
+        }
+        let folder = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        self.real_folder.is_some() && fs::canonicalize(folder).ok() == self.real_folder
+    }
+}
+
+/// The absolute folder containing `database_path`, which source and mirror paths are relative to
+pub fn database_folder(database_path: &Path) -> Result<PathBuf> {
+    let database_folder = match database_path
+        .parent()
+        .context("database file had no parent")?
+    {
+        folder if folder.as_os_str().is_empty() => Path::new("."),
+        folder => folder,
+    };
+    std::path::absolute(database_folder).with_context(|| {
+        format!(
+            "Failed to resolve absolute path of `{0}`",
+            database_folder.display()
+        )
+    })
+}
+
+/// Held by tests which change or resolve paths against the working directory, which every test
+/// shares
+#[cfg(test)]
+static WORKING_DIR: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+#[cfg(test)]
+pub fn lock_working_dir() -> std::sync::MutexGuard<'static, ()> {
+    match WORKING_DIR.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Moves a test into another working directory, moving it back when dropped even if the test
+/// panics
+#[cfg(test)]
+pub struct WorkingDirGuard {
+    previous: PathBuf,
+    _lock: std::sync::MutexGuard<'static, ()>,
+}
+
+#[cfg(test)]
+impl WorkingDirGuard {
+    pub fn change_to(dir: &Path) -> Self {
+        let lock = lock_working_dir();
+        let previous = std::env::current_dir().unwrap();
+        std::env::set_current_dir(dir).unwrap();
+        Self {
+            previous,
+            _lock: lock,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for WorkingDirGuard {
+    fn drop(&mut self) {
+        let _ = std::env::set_current_dir(&self.previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        database_path_from_mirror, is_within, lock_working_dir, normalize_path, relative_path,
+        url_scheme, DatabaseLocation,
+    };
+    use std::{
+        env::temp_dir,
+        fs,
+        path::{Path, PathBuf},
+        process,
+    };
+
+    const SPELLINGS: [&str; 4] = ["music", "music/", "./music", "./music//"];
+
+    #[test]
+    fn normalize_ignores_trailing_slashes_and_dots() {
+        for spelling in SPELLINGS {
+            assert_eq!(normalize_path(Path::new(spelling)), PathBuf::from("music"));
+        }
+        assert_eq!(normalize_path(Path::new("./")), PathBuf::from("."));
+        assert_eq!(normalize_path(Path::new("a/./b/")), PathBuf::from("a/b"));
+    }
+
+    #[test]
+    fn database_path_ignores_trailing_slashes() {
+        for spelling in SPELLINGS {
+            assert_eq!(
+                database_path_from_mirror(Path::new(spelling)).unwrap(),
+                PathBuf::from("music.mmdb")
+            );
+        }
+        assert_eq!(
+            database_path_from_mirror(Path::new("mirrors/music/")).unwrap(),
+            PathBuf::from("mirrors_music.mmdb")
+        );
+    }
+
+    #[test]
+    fn relative_path_ignores_trailing_slashes() {
+        let _working_dir = lock_working_dir();
+        for base in ["base", "base/"] {
+            for spelling in SPELLINGS {
+                let path = Path::new("base").join(spelling);
+                assert_eq!(
+                    relative_path(Path::new(base), &path).unwrap(),
+                    PathBuf::from("music")
+                );
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_databases_in_the_mirror_or_source_store_relative_paths() {
+        let _working_dir = lock_working_dir();
+        let stored = |location: DatabaseLocation, folder: &str, path: &str| {
+            location
+                .stored_path(Path::new(folder), Path::new(path))
+                .unwrap()
+        };
+        assert_eq!(
+            stored(DatabaseLocation::Cwd, ".", "/mnt/src"),
+            Path::new("/mnt/src")
+        );
+        assert_eq!(stored(DatabaseLocation::Cwd, ".", "src"), Path::new("src"));
+
+        // Relative paths follow the database, absolute ones only inside its folder
+        assert_eq!(
+            stored(DatabaseLocation::Mirror, "mirror", "src"),
+            Path::new("../src")
+        );
+        assert_eq!(
+            stored(DatabaseLocation::Mirror, "mirror", "mirror"),
+            Path::new(".")
+        );
+        assert_eq!(
+            stored(
+                DatabaseLocation::Mirror,
+                "/mnt/usb/mirror",
+                "/mnt/usb/mirror"
+            ),
+            Path::new(".")
+        );
+        assert_eq!(
+            stored(DatabaseLocation::Mirror, "/mnt/usb/mirror", "/home/me/src"),
+            Path::new("/home/me/src")
+        );
+        assert_eq!(
+            stored(DatabaseLocation::Source, "/home/me/src", "/home/me/src/sub"),
+            Path::new("sub")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_out_of_the_root_are_caught() {
+        let root = temp_dir().join(format!("mirrorman-test-{0}-within", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("mirror/inside")).unwrap();
+        fs::create_dir_all(root.join("outside")).unwrap();
+        std::os::unix::fs::symlink("../outside", root.join("mirror/escape")).unwrap();
+        let mirror = root.join("mirror");
+        let real_mirror = mirror.canonicalize().unwrap();
+        let within = |path: &str| is_within(&mirror, Some(&real_mirror), &mirror.join(path));
+
+        assert!(within("inside/file.txt"));
+        assert!(within("new/dir/file.txt"));
+        assert!(!within("escape/file.txt"));
+        assert!(!within("../outside/file.txt"));
+        // A mirror which didn't exist can't hold symlinks
+        assert!(is_within(&mirror, None, &mirror.join("escape/file.txt")));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn urls_are_recognised() {
+        assert_eq!(url_scheme(Path::new("ssh://user@host/path")), Some("ssh"));
+        assert_eq!(url_scheme(Path::new("sftp://host/path")), Some("sftp"));
+        assert_eq!(url_scheme(Path::new("mirror/ssh:")), None);
+        assert_eq!(url_scheme(Path::new("./music")), None);
+        assert_eq!(
+            database_path_from_mirror(Path::new("ssh://backup@nas/srv/music/")).unwrap(),
+            PathBuf::from("backup@nas_srv_music.mmdb")
+        );
+    }
+}
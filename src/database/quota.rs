@@ -0,0 +1,173 @@
+use anyhow::{Context, Result};
+use std::{
+    fs,
+    mem::forget,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use walkdir::WalkDir;
+
+/// A cap on the total size of the mirror (`--max-total-size`). Files already in the mirror count
+/// towards it and are never removed to make room, new and grown files are only written while the
+/// mirror stays within it.
+pub struct Quota {
+    limit: u64,
+    used: AtomicU64,
+    skipped: AtomicUsize,
+}
+
+impl Quota {
+    /// Starts from the size of everything currently in the mirror
+    pub fn new(limit: u64, mirror_root: &Path) -> Result<Self> {
+        let mut used = 0;
+        if mirror_root.exists() {
+            for entry in WalkDir::new(mirror_root) {
+                let entry = entry.with_context(|| {
+                    format!("Failed to measure mirror `{0}`", mirror_root.display())
+                })?;
+                if entry.file_type().is_file() {
+                    used += entry
+                        .metadata()
+                        .with_context(|| {
+                            format!("Failed to read metadata of `{0}`", entry.path().display())
+                        })?
+                        .len();
+                }
+            }
+        }
+        if used > limit {
+            log::warn!(
+                "Mirror `{0}` already takes {used} bytes, more than the {limit} byte `--max-total-size`, nothing new will be mirrored until it's shrunk",
+                mirror_root.display()
+            );
+        }
+        Ok(Self {
+            limit,
+            used: AtomicU64::new(used),
+            skipped: AtomicUsize::new(0),
+        })
+    }
+
+    /// Makes room for a mirror file growing from `previous` to `size` bytes, returning whether it
+    /// fits. Files which shrink always fit.
+    pub fn reserve(&self, previous: u64, size: u64) -> bool {
+        let fits = self
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                let used = used.saturating_sub(previous);
+                (size <= previous || used + size <= self.limit).then_some(used + size)
+            })
+            .is_ok();
+        if !fits {
+            self.skipped.fetch_add(1, Ordering::Relaxed);
+        }
+        fits
+    }
+
+    /// Makes room for `mirror` to be written at `size` bytes, in place of whatever's there now, or
+    /// `None` if it doesn't fit
+    pub fn reserve_for(&self, mirror: &Path, size: u64) -> Option<Reservation<'_>> {
+        let previous = fs::metadata(mirror).map_or(0, |metadata| metadata.len());
+        self.reserve(previous, size).then(|| Reservation {
+            quota: self,
+            reserved: size,
+            mirror: mirror.to_path_buf(),
+        })
+    }
+
+    /// Corrects a reservation of `reserved` bytes once the file is written, e.g. by a filter which
+    /// changes its size
+    pub fn settle(&self, reserved: u64, actual: u64) {
+        let _ = self
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                Some(used.saturating_sub(reserved) + actual)
+            });
+    }
+
+    /// Settles a reservation of `reserved` bytes against the size `mirror` ended up, nothing if it
+    /// was never written, or its old size if writing it failed
+    pub fn settle_file(&self, reserved: u64, mirror: &Path) {
+        self.settle(
+            reserved,
+            fs::metadata(mirror).map_or(0, |metadata| metadata.len()),
+        );
+    }
+
+    /// Warns about the files left out of the mirror, if any
+    pub fn report(&self) {
+        let skipped = self.skipped.load(Ordering::Relaxed);
+        if skipped > 0 {
+            log::warn!(
+                "{skipped} files weren't mirrored to keep the mirror within `--max-total-size` ({0} of {1} bytes used)",
+                self.used.load(Ordering::Relaxed),
+                self.limit
+            );
+        }
+    }
+}
+
+/// Room held in the quota while a mirror file is written, settled against the size it ends up
+/// once dropped, so a write which fails gives back the room it didn't use
+pub struct Reservation<'a> {
+    quota: &'a Quota,
+    reserved: u64,
+    mirror: PathBuf,
+}
+
+impl Reservation<'_> {
+    /// Hands the reservation over to be settled later with [`Quota::settle_file`], e.g. once a
+    /// batch has run, returning the bytes reserved
+    pub fn into_reserved(self) -> u64 {
+        let reserved = self.reserved;
+        forget(self);
+        reserved
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.quota.settle_file(self.reserved, &self.mirror);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Quota;
+    use std::{
+        env::temp_dir,
+        process,
+        sync::atomic::{AtomicU64, AtomicUsize},
+    };
+
+    #[test]
+    fn reserves_within_limit() {
+        let quota = Quota {
+            limit: 100,
+            used: AtomicU64::new(60),
+            skipped: AtomicUsize::new(0),
+        };
+        assert!(quota.reserve(0, 40));
+        assert!(!quota.reserve(0, 1));
+        // Shrinking frees room, growing past the limit doesn't fit
+        assert!(quota.reserve(40, 10));
+        assert!(!quota.reserve(10, 50));
+        assert!(quota.reserve(0, 30));
+        quota.settle(30, 20);
+        assert!(quota.reserve(0, 10));
+    }
+
+    #[test]
+    fn failed_writes_release_their_reservation() {
+        let quota = Quota {
+            limit: 100,
+            used: AtomicU64::new(0),
+            skipped: AtomicUsize::new(0),
+        };
+        let never_written = temp_dir().join(format!("mirrorman-test-{0}-quota", process::id()));
+        let reservation = quota.reserve_for(&never_written, 80).unwrap();
+        assert!(quota.reserve_for(&never_written, 30).is_none());
+        drop(reservation);
+        assert!(quota.reserve_for(&never_written, 90).is_some());
+    }
+}
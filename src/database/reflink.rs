@@ -0,0 +1,78 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use std::{fs::copy, io, path::Path};
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ReflinkMode {
+    /// Clone where supported, falling back to a normal copy
+    Auto,
+    /// Always clone, failing if the file system doesn't support it
+    Always,
+    /// Always make a normal copy
+    #[default]
+    Never,
+}
+
+/// Copies `source` to `mirror`, sharing the underlying data via a copy-on-write clone
+/// (e.g. on Btrfs or XFS) when `mode` allows it
+pub fn copy_file(source: &Path, mirror: &Path, mode: ReflinkMode) -> Result<()> {
+    if mode != ReflinkMode::Never {
+        match reflink(source, mirror) {
+            Ok(()) => return Ok(()),
+            Err(e) if mode == ReflinkMode::Always => bail!(
+                "Failed to clone source `{0}` to mirror `{1}`: {e}",
+                source.display(),
+                mirror.display()
+            ),
+            Err(e) => log::debug!(
+                "Clone of `{0}` unsupported, falling back to copying: {e}",
+                source.display()
+            ),
+        }
+    }
+
+    copy(source, mirror).with_context(|| {
+        format!(
+            "Failed to copy source `{0}` to mirror `{1}`",
+            source.display(),
+            mirror.display()
+        )
+    })?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(source: &Path, mirror: &Path) -> io::Result<()> {
+    use std::{
+        fs::{remove_file, File},
+        os::fd::AsRawFd,
+    };
+
+    let source_file = File::open(source)?;
+    let mirror_file = File::create(mirror)?;
+    // SAFETY: Both file descriptors are valid and open for the duration of the call
+    let result = unsafe {
+        libc::ioctl(
+            mirror_file.as_raw_fd(),
+            // Encoded per architecture, and the request type differs between libcs
+            libc::FICLONE as _,
+            source_file.as_raw_fd(),
+        )
+    };
+    if result == 0 {
+        return Ok(());
+    }
+
+    let error = io::Error::last_os_error();
+    drop(mirror_file);
+    let _ = remove_file(mirror);
+    Err(error)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_source: &Path, _mirror: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "cloning is only supported on Linux",
+    ))
+}
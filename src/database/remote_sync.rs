@@ -0,0 +1,143 @@
+use super::{MirrorIgnore, StagingDir};
+use crate::remote::{RemoteFiles, RemoteMirror};
+use anyhow::{Context, Result};
+use std::{
+    collections::BTreeMap,
+    fs::{self, create_dir_all},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use walkdir::WalkDir;
+
+/// A sync to a mirror on another host. Files are written to a local staging directory, standing
+/// in for the mirror root, and uploaded once the sync has written them all. The mirror is listed
+/// when the sync starts, so unchanged files are recognised without asking the host about each.
+pub struct RemoteSync {
+    mirror: RemoteMirror,
+    files: Arc<dyn RemoteFiles>,
+    staging: StagingDir,
+    // Key = Entry relative to the mirror root, Value = Its size, `None` for directories, as listed
+    // when the sync started
+    listing: BTreeMap<PathBuf, Option<u64>>,
+}
+
+impl RemoteSync {
+    pub fn start(
+        mirror: RemoteMirror,
+        files: Arc<dyn RemoteFiles>,
+        staging: PathBuf,
+    ) -> Result<Self> {
+        let staging = StagingDir::new(staging);
+        create_dir_all(staging.path()).with_context(|| {
+            format!(
+                "Failed to create staging directory `{0}`",
+                staging.path().display()
+            )
+        })?;
+        let listing = files
+            .list()
+            .with_context(|| format!("Failed to list remote mirror `{mirror}`"))?
+            .into_iter()
+            .map(|entry| (entry.path, (!entry.is_dir).then_some(entry.size)))
+            .collect();
+
+        Ok(Self {
+            mirror,
+            files,
+            staging,
+            listing,
+        })
+    }
+
+    /// Where the sync writes what's to be uploaded, laid out as the mirror is
+    pub fn staging_root(&self) -> &Path {
+        self.staging.path()
+    }
+
+    /// Whether the mirror had `relative_entry` in it when the sync started
+    pub fn contains(&self, relative_entry: &Path) -> bool {
+        self.listing.contains_key(relative_entry)
+    }
+
+    /// The size the file `relative_entry` had when the sync started, `None` if it wasn't a file
+    pub fn file_size(&self, relative_entry: &Path) -> Option<u64> {
+        self.listing.get(relative_entry).copied().flatten()
+    }
+
+    /// Entries as listed when the sync started, each directory's contents before it
+    pub fn contents_first(&self) -> impl Iterator<Item = (&Path, bool)> {
+        self.listing
+            .iter()
+            .rev()
+            .map(|(path, size)| (path.as_path(), size.is_none()))
+    }
+
+    pub fn ignore(&self) -> Result<MirrorIgnore> {
+        MirrorIgnore::load_remote(&self.mirror, &*self.files)
+    }
+
+    pub fn url_of(&self, relative_entry: &Path) -> String {
+        self.mirror.url_of(relative_entry)
+    }
+
+    /// Uploads everything staged so far, removing each file once it's on the host
+    pub fn upload(&self) -> Result<()> {
+        let staging_root = self.staging_root();
+        let mut uploaded = 0;
+        self.files
+            .create_dir_all(Path::new(""))
+            .with_context(|| format!("Failed to create remote mirror `{0}`", self.mirror))?;
+        // Directories are walked before their contents, so they're created first
+        for entry in WalkDir::new(staging_root).min_depth(1).sort_by_file_name() {
+            let entry = entry.with_context(|| "Failed to walk staging directory")?;
+            let relative_entry = entry.path().strip_prefix(staging_root)?;
+            let url = self.url_of(relative_entry);
+            if entry.file_type().is_dir() {
+                if !self.contains(relative_entry) {
+                    self.files
+                        .create_dir_all(relative_entry)
+                        .with_context(|| format!("Failed to create directory `{url}`"))?;
+                }
+                continue;
+            }
+
+            let metadata = entry.metadata().with_context(|| {
+                format!("Failed to read metadata of `{0}`", entry.path().display())
+            })?;
+            let times = (
+                metadata
+                    .accessed()
+                    .with_context(|| "Failed to read access time")?,
+                metadata
+                    .modified()
+                    .with_context(|| "Failed to read modification time")?,
+            );
+            log::debug!("Uploading `{url}`...");
+            self.files
+                .upload(entry.path(), relative_entry, times)
+                .with_context(|| format!("Failed to upload `{url}`"))?;
+            fs::remove_file(entry.path())
+                .with_context(|| format!("Failed to remove `{0}`", entry.path().display()))?;
+            uploaded += 1;
+        }
+        if uploaded > 0 {
+            log::info!("Uploaded {uploaded} files to `{0}`", self.mirror);
+        }
+        Ok(())
+    }
+
+    pub fn remove_file(&self, relative_entry: &Path) -> Result<()> {
+        self.files
+            .remove_file(relative_entry)
+            .with_context(|| format!("Failed to remove file `{0}`", self.url_of(relative_entry)))
+    }
+
+    pub fn remove_dir(&self, relative_entry: &Path) -> Result<()> {
+        self.files.remove_dir(relative_entry).with_context(|| {
+            format!(
+                "Failed to remove directory `{0}`",
+                self.url_of(relative_entry)
+            )
+        })
+    }
+}
@@ -0,0 +1,152 @@
+use anyhow::{Context, Result};
+use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Environment variable naming the directory to create temporary files in
+pub const TMPDIR_VAR: &str = "MIRRORMAN_TMPDIR";
+
+/// Where to create a temporary file which will be renamed over `destination`.
+///
+/// Renames can't cross file systems, so `tmpdir` is only used if it's on the same one as
+/// `destination`, otherwise the temporary file goes next to `destination` as usual.
+pub fn scratch_dir_for(tmpdir: Option<&Path>, destination: &Path) -> PathBuf {
+    let destination_dir = match destination.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    match tmpdir {
+        Some(tmpdir) if same_file_system(tmpdir, destination_dir) => tmpdir.to_path_buf(),
+        Some(tmpdir) => {
+            log::warn!(
+                "Temporary directory `{0}` isn't on the same file system as `{1}`, writing temporary files next to it instead...",
+                tmpdir.display(),
+                destination.display()
+            );
+            destination_dir.to_path_buf()
+        }
+        None => destination_dir.to_path_buf(),
+    }
+}
+
+#[cfg(unix)]
+fn same_file_system(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (a.metadata(), b.metadata()) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file_system(a: &Path, b: &Path) -> bool {
+    // Without device numbers, assume paths on the same drive share a file system
+    match (std::path::absolute(a), std::path::absolute(b)) {
+        (Ok(a), Ok(b)) => a.components().next() == b.components().next(),
+        _ => false,
+    }
+}
+
+/// Writes `path` through a temporary file in `tmpdir` (if suitable), renamed into place once
+/// `write` succeeds, so `path` is never left half-written.
+///
+/// The temporary file is flushed to disk before the rename, and the rename itself after, so even a
+/// power cut leaves either the old or the new contents, never an empty or truncated file.
+pub fn write_atomically(
+    path: &Path,
+    tmpdir: Option<&Path>,
+    write: impl FnOnce(&File) -> Result<()>,
+) -> Result<()> {
+    let temp_dir = scratch_dir_for(tmpdir, path);
+    let temp_path = temp_dir.join(temp_file_name(path.file_name().unwrap_or_default()));
+    let result = File::create(&temp_path)
+        .with_context(|| format!("Failed to open {0} for writing", temp_path.display()))
+        .and_then(|file| {
+            write(&file)?;
+            file.sync_all()
+                .with_context(|| format!("Failed to flush {0} to disk", temp_path.display()))
+        })
+        .and_then(|_| {
+            fs::rename(&temp_path, path).with_context(|| {
+                format!(
+                    "Failed to move {0} into place at {1}",
+                    temp_path.display(),
+                    path.display()
+                )
+            })
+        });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+        return result;
+    }
+
+    let destination_dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    sync_dir(destination_dir)
+        .with_context(|| format!("Failed to flush {0} to disk", destination_dir.display()))
+}
+
+/// Flushes a directory's entries (e.g. a rename into it) to disk
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    // Directories can't be opened as files on Windows, where renames are journaled by NTFS anyway
+    Ok(())
+}
+
+/// Name of the temporary file written in place of one named `file_name`, before it's renamed over it
+pub fn temp_file_name(file_name: &OsStr) -> String {
+    format!("{0}{1}.tmp", temp_prefix(file_name), process::id())
+}
+
+fn temp_prefix(file_name: &OsStr) -> String {
+    format!(".{0}.mirrorman-", file_name.to_string_lossy())
+}
+
+/// Whether `name` is a temporary file `write_atomically` created for a file named `file_name`, by
+/// this or an earlier (possibly interrupted) run
+pub fn is_temp_file_for(name: &OsStr, file_name: &OsStr) -> bool {
+    name.to_str()
+        .and_then(|name| name.strip_prefix(&temp_prefix(file_name)))
+        .and_then(|rest| rest.strip_suffix(".tmp"))
+        .is_some_and(|pid| !pid.is_empty() && pid.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// A directory of temporary files, removed along with everything in it once dropped
+pub struct StagingDir {
+    path: PathBuf,
+}
+
+impl StagingDir {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        match fs::remove_dir_all(&self.path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!(
+                "Failed to remove staging directory `{0}`: {e}",
+                self.path.display()
+            ),
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use super::PreservedTime;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+/// Sync options chosen at `init`, used by every sync of the database unless overridden by flags.
+/// Databases from before they existed, or missing any of them, get the usual defaults.
+#[derive(Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SyncSettings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter_concurrency: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_errors: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub preserve_times: Vec<PreservedTime>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub sniff: bool,
+}
+
+impl SyncSettings {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// A short description of each setting which differs from the default, for `info`
+    pub fn describe(&self) -> Vec<String> {
+        let mut settings = Vec::new();
+        if let Some(filter_concurrency) = self.filter_concurrency {
+            settings.push(format!("filter concurrency {filter_concurrency}"));
+        }
+        if let Some(max_errors) = self.max_errors {
+            settings.push(format!("max errors {max_errors}"));
+        }
+        if !self.preserve_times.is_empty() {
+            let times = self
+                .preserve_times
+                .iter()
+                .filter_map(|time| time.to_possible_value())
+                .map(|value| value.get_name().to_owned())
+                .collect::<Vec<_>>();
+            settings.push(format!("preserve times {0}", times.join(",")));
+        }
+        if self.sniff {
+            settings.push("sniff".to_owned());
+        }
+        settings
+    }
+}
@@ -0,0 +1,108 @@
+use super::{Change, Database};
+use anyhow::{Context, Result};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Name of the changelog `--since-db` writes to the mirror root
+pub const CHANGELOG_NAME: &str = "mirrorman-changelog.txt";
+
+/// A reference database for `--since-db`. Only source files whose hash differs from the one it
+/// recorded are mirrored, so the mirror holds an incremental set of what changed since the
+/// reference was synced.
+pub struct SinceDb {
+    path: PathBuf,
+    // Key = Source file relative to the source root, Value = Its hash in the reference
+    hashes: BTreeMap<PathBuf, String>,
+    // Key = Source file relative to the source root, Value = How it differs from the reference
+    changes: Mutex<BTreeMap<PathBuf, Change>>,
+    // Source files relative to the source root, found by the sync
+    seen: Mutex<BTreeSet<PathBuf>>,
+}
+
+impl SinceDb {
+    pub fn load(path: &Path) -> Result<Self> {
+        let reference = Database::load(path)
+            .with_context(|| format!("Failed to load `--since-db` `{0}`", path.display()))?;
+        // Keys are relative to the reference's own database, which may be somewhere else
+        let hashes = reference
+            .hashes
+            .iter()
+            .map(|(key, hash)| {
+                let relative = key.strip_prefix(&reference.source_path).unwrap_or(key);
+                (relative.to_path_buf(), hash.clone())
+            })
+            .collect();
+        Ok(Self {
+            path: path.to_path_buf(),
+            hashes,
+            changes: Mutex::new(BTreeMap::new()),
+            seen: Mutex::new(BTreeSet::new()),
+        })
+    }
+
+    /// Whether the source file at `relative` (to the source root) has the same hash in the
+    /// reference, otherwise recording how it changed for the changelog
+    pub fn unchanged(&self, relative: &Path, digest: &str) -> bool {
+        match self.seen.lock() {
+            Ok(mut seen) => seen.insert(relative.to_path_buf()),
+            Err(poisoned) => poisoned.into_inner().insert(relative.to_path_buf()),
+        };
+        let change = match self.hashes.get(relative) {
+            Some(hash) if hash == digest => return true,
+            Some(_) => Change::Changed,
+            None => Change::Added,
+        };
+        match self.changes.lock() {
+            Ok(mut changes) => changes.insert(relative.to_path_buf(), change),
+            Err(poisoned) => poisoned.into_inner().insert(relative.to_path_buf(), change),
+        };
+        false
+    }
+
+    /// Writes what was added (`+`), changed (`~`) and removed (`-`) since the reference to the
+    /// mirror root, one file per line sorted by path. Files under `unreadable_dirs` (relative to
+    /// the source root) weren't looked at, so they're never listed as removed.
+    pub fn write_changelog(&self, mirror_root: &Path, unreadable_dirs: &[PathBuf]) -> Result<()> {
+        let changes = match self.changes.lock() {
+            Ok(changes) => changes,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let seen = match self.seen.lock() {
+            Ok(seen) => seen,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let mut lines = changes
+            .iter()
+            .map(|(path, change)| match change {
+                Change::Added => (path, '+'),
+                Change::Changed => (path, '~'),
+            })
+            .chain(
+                self.hashes
+                    .keys()
+                    .filter(|path| !seen.contains(*path))
+                    .filter(|path| !unreadable_dirs.iter().any(|dir| path.starts_with(dir)))
+                    .map(|path| (path, '-')),
+            )
+            .collect::<Vec<_>>();
+        lines.sort();
+
+        let file_path = mirror_root.join(CHANGELOG_NAME);
+        let file = File::create(&file_path)
+            .with_context(|| format!("Failed to open {0} for writing", file_path.display()))?;
+        let mut writer = BufWriter::new(file);
+        let mut write = || -> std::io::Result<()> {
+            writeln!(writer, "# Changes since {0}", self.path.display())?;
+            for (path, change) in &lines {
+                writeln!(writer, "{change} {0}", path.display())?;
+            }
+            writer.flush()
+        };
+        write().with_context(|| format!("Failed to write changelog `{0}`", file_path.display()))
+    }
+}
@@ -0,0 +1,141 @@
+use crate::artifacts::timestamp_now;
+use anyhow::{bail, Context, Result};
+use std::{
+    fs::{self, create_dir_all, hard_link, remove_file},
+    path::{Path, PathBuf},
+};
+
+/// Point-in-time mirrors for `--snapshots`, each sync writing to a fresh `{timestamp}` directory.
+/// Files unchanged since the last sync are hard linked from the latest earlier snapshot rather
+/// than mirrored again, so every snapshot is complete but only changed files take up space.
+pub struct Snapshots {
+    root: PathBuf,
+    previous: Option<PathBuf>,
+}
+
+impl Snapshots {
+    /// Creates a snapshot named after the current time in `directory`
+    pub fn create(directory: &Path) -> Result<Self> {
+        create_dir_all(directory).with_context(|| {
+            format!(
+                "Failed to create snapshot directory `{0}`",
+                directory.display()
+            )
+        })?;
+        let previous = fs::read_dir(directory)
+            .with_context(|| {
+                format!(
+                    "Failed to read snapshot directory `{0}`",
+                    directory.display()
+                )
+            })?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .map(|entry| entry.file_name())
+            .filter(|name| name.to_str().is_some_and(is_timestamp))
+            .max()
+            .map(|name| directory.join(name));
+
+        let root = directory.join(timestamp_now());
+        if root.exists() {
+            bail!(
+                "Snapshot `{0}` already exists, snapshots are at most one a second",
+                root.display()
+            );
+        }
+        fs::create_dir(&root)
+            .with_context(|| format!("Failed to create snapshot `{0}`", root.display()))?;
+        match &previous {
+            Some(previous) => log::info!(
+                "Snapshotting to `{0}`, linking unchanged files from `{1}`...",
+                root.display(),
+                previous.display()
+            ),
+            None => log::info!("Snapshotting to `{0}`...", root.display()),
+        }
+        Ok(Self { root, previous })
+    }
+
+    /// The new snapshot, which is mirrored to in place of the database's mirror
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Hard links each of `relative_mirrors` from the previous snapshot into this one. If any
+    /// can't be, none are left linked, so they're mirrored afresh rather than written through
+    /// links shared with earlier snapshots.
+    pub fn link_previous(&self, relative_mirrors: &[PathBuf]) {
+        let Some(previous) = &self.previous else {
+            return;
+        };
+        for (linked, relative) in relative_mirrors.iter().enumerate() {
+            let mirror = self.root.join(relative);
+            let result = mirror
+                .parent()
+                .map_or(Ok(()), create_dir_all)
+                .and_then(|_| hard_link(previous.join(relative), &mirror));
+            if let Err(e) = result {
+                log::debug!(
+                    "Failed to link `{0}` from the previous snapshot, mirroring it instead: {e}",
+                    relative.display()
+                );
+                for relative in &relative_mirrors[..linked] {
+                    let _ = remove_file(self.root.join(relative));
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Whether `name` is a `YYYYMMDDTHHMMSSZ` timestamp, as snapshots are named
+fn is_timestamp(name: &str) -> bool {
+    let bytes = name.as_bytes();
+    bytes.len() == 16
+        && bytes[8] == b'T'
+        && bytes[15] == b'Z'
+        && bytes[..8]
+            .iter()
+            .chain(&bytes[9..15])
+            .all(u8::is_ascii_digit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_timestamp, Snapshots};
+    use std::{
+        env::temp_dir,
+        fs::{create_dir_all, read_to_string, remove_dir_all, write},
+        path::PathBuf,
+        process,
+    };
+
+    #[test]
+    fn only_timestamps_are_snapshots() {
+        assert!(is_timestamp("20261016T005015Z"));
+        assert!(!is_timestamp("20261016T005015"));
+        assert!(!is_timestamp("2026-10-16T00:50"));
+        assert!(!is_timestamp("notes"));
+    }
+
+    #[test]
+    fn links_all_or_nothing_from_the_latest_snapshot() {
+        let directory = temp_dir().join(format!("mirrorman-test-{0}-snapshots", process::id()));
+        let _ = remove_dir_all(&directory);
+        create_dir_all(directory.join("20000101T000000Z")).unwrap();
+        create_dir_all(directory.join("20010101T000000Z/d")).unwrap();
+        create_dir_all(directory.join("unrelated")).unwrap();
+        write(directory.join("20000101T000000Z/a.txt"), "old").unwrap();
+        write(directory.join("20010101T000000Z/d/a.txt"), "latest").unwrap();
+
+        let snapshots = Snapshots::create(&directory).unwrap();
+        snapshots.link_previous(&[PathBuf::from("d/a.txt"), PathBuf::from("missing.txt")]);
+        assert!(!snapshots.root().join("d/a.txt").exists());
+        snapshots.link_previous(&[PathBuf::from("d/a.txt")]);
+        assert_eq!(
+            read_to_string(snapshots.root().join("d/a.txt")).unwrap(),
+            "latest"
+        );
+        remove_dir_all(directory).unwrap();
+    }
+}
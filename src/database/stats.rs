@@ -0,0 +1,129 @@
+use super::Change;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{collections::BTreeMap, fmt, path::Path, time::Duration};
+
+/// What a sync did with files of one type
+#[derive(Default, Clone, Serialize)]
+pub struct TypeStats {
+    pub added: usize,
+    pub changed: usize,
+    /// Files left as they were, e.g. as they were unchanged
+    pub skipped: usize,
+    pub bytes_copied: u64,
+    pub bytes_skipped: u64,
+}
+
+#[derive(Default, Clone, Serialize)]
+pub struct SyncStats {
+    pub files: usize,
+    pub bytes_copied: u64,
+    pub bytes_skipped: u64,
+    /// Files which failed to mirror and were skipped
+    pub errors: usize,
+    #[serde(skip)]
+    pub elapsed: Duration,
+    /// Key = Lowercase extension of the source files, empty for files without one
+    pub by_type: BTreeMap<String, TypeStats>,
+}
+
+impl SyncStats {
+    /// Counts a mirrored file, `change` being `None` for files left as they were
+    pub fn record(&mut self, source: &Path, change: Option<Change>, copied: u64, skipped: u64) {
+        self.files += 1;
+        self.bytes_copied += copied;
+        self.bytes_skipped += skipped;
+
+        let extension = source
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let by_type = self.by_type.entry(extension).or_default();
+        match change {
+            Some(Change::Added) => by_type.added += 1,
+            Some(Change::Changed) => by_type.changed += 1,
+            None => by_type.skipped += 1,
+        }
+        by_type.bytes_copied += copied;
+        by_type.bytes_skipped += skipped;
+    }
+
+    /// A table of what was done with each type of file, those copying the most bytes first
+    fn write_type_table(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut types = self.by_type.iter().collect::<Vec<_>>();
+        types.sort_by(|(a_name, a), (b_name, b)| {
+            b.bytes_copied
+                .cmp(&a.bytes_copied)
+                .then_with(|| a_name.cmp(b_name))
+        });
+
+        write!(
+            f,
+            "  {0:<10} {1:>8} {2:>8} {3:>8} {4:>14} {5:>14}",
+            "Type", "Added", "Changed", "Skipped", "Bytes copied", "Bytes skipped"
+        )?;
+        for (extension, stats) in types {
+            let name = match extension.is_empty() {
+                true => "(none)".to_owned(),
+                false => format!(".{extension}"),
+            };
+            write!(
+                f,
+                "\n  {name:<10} {0:>8} {1:>8} {2:>8} {3:>14} {4:>14}",
+                stats.added, stats.changed, stats.skipped, stats.bytes_copied, stats.bytes_skipped
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The stats as one line of JSON, with the elapsed time in seconds and the throughput they
+    /// work out to
+    pub fn to_json(&self) -> Result<String> {
+        #[derive(Serialize)]
+        struct JsonStats<'a> {
+            #[serde(flatten)]
+            stats: &'a SyncStats,
+            elapsed_secs: f64,
+            mb_per_sec: f64,
+        }
+
+        serde_json::to_string(&JsonStats {
+            stats: self,
+            elapsed_secs: self.elapsed.as_secs_f64(),
+            mb_per_sec: self.throughput(),
+        })
+        .context("Failed to format sync stats as json")
+    }
+
+    pub fn throughput(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            self.bytes_copied as f64 / 1_000_000.0 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+impl fmt::Display for SyncStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{0} files, {1} bytes copied, {2} bytes skipped in {3:.2}s ({4:.2} MB/s)",
+            self.files,
+            self.bytes_copied,
+            self.bytes_skipped,
+            self.elapsed.as_secs_f64(),
+            self.throughput()
+        )?;
+        if self.errors > 0 {
+            write!(f, ", {0} errors", self.errors)?;
+        }
+        // `{:#}` adds a breakdown by type of file
+        if f.alternate() && !self.by_type.is_empty() {
+            writeln!(f)?;
+            self.write_type_table(f)?;
+        }
+        Ok(())
+    }
+}
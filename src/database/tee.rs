@@ -0,0 +1,65 @@
+use super::Hasher;
+use anyhow::{Context, Result};
+use std::{
+    ffi::OsString,
+    fs::{self, create_dir_all},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A copy of a source file written beside its mirror while hashing it, so the source is only read
+/// once. Removed when dropped, unless it's been moved into place.
+pub struct TeedCopy {
+    path: PathBuf,
+}
+
+impl TeedCopy {
+    /// Copies `source` next to `mirror`, returning the copy and the source's hash
+    pub fn hash_from(hasher: &dyn Hasher, source: &Path, mirror: &Path) -> Result<(Self, String)> {
+        // Numbered, as entries colliding on the same mirror path may be copied at once
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let mut file_name = OsString::from(format!(
+            ".mirrorman-{0}-{1}-",
+            process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        file_name.push(mirror.file_name().unwrap_or_default());
+        let copy = Self {
+            path: mirror.with_file_name(file_name),
+        };
+
+        if let Some(parent) = mirror.parent() {
+            create_dir_all(parent).with_context(|| {
+                format!("Failed to create mirror directory `{0}`", parent.display())
+            })?;
+        }
+        let digest = hasher.hash_and_copy(source, &copy.path)?;
+        Ok((copy, digest))
+    }
+
+    /// Moves the copy into place at `mirror`, replacing anything already there
+    pub fn commit(self, mirror: &Path) -> Result<()> {
+        fs::rename(&self.path, mirror).with_context(|| {
+            format!(
+                "Failed to move `{0}` into place at `{1}`",
+                self.path.display(),
+                mirror.display()
+            )
+        })
+    }
+}
+
+impl Drop for TeedCopy {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => log::warn!(
+                "Failed to remove temporary copy `{0}`: {e}",
+                self.path.display()
+            ),
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use std::path::{Path, PathBuf};
+
+/// Renders a mirror path template for a file, relative to the mirror root.
+///
+/// Supported tokens are `{parent}` (the directory relative to the mirror root), `{stem}`, `{ext}`
+/// (the extension after filtering, without the dot) and `{hash}` (the source file's hash). If the
+/// file has no extension, a `.{ext}` in the template is dropped entirely.
+pub fn render_mirror_template(template: &str, relative_mirror: &Path, hash: &str) -> PathBuf {
+    let parent = relative_mirror
+        .parent()
+        .map(|parent| parent.to_string_lossy().replace('\\', "/"))
+        .unwrap_or_default();
+    let stem = relative_mirror
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let ext = relative_mirror
+        .extension()
+        .map(|ext| ext.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let mut rendered = template.to_owned();
+    if ext.is_empty() {
+        rendered = rendered.replace(".{ext}", "");
+    }
+    let rendered = rendered
+        .replace("{parent}", &parent)
+        .replace("{stem}", &stem)
+        .replace("{ext}", &ext)
+        .replace("{hash}", hash);
+
+    // Empty segments come from an empty `{parent}`, and shouldn't make the path absolute
+    rendered
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect()
+}
@@ -0,0 +1,81 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{FileTimes, Metadata, OpenOptions},
+    path::Path,
+};
+
+/// A source file timestamp which can be carried over to the mirror
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PreservedTime {
+    /// Last modification time
+    Modified,
+    /// Last access time
+    Accessed,
+    /// Creation (birth) time, only settable on Windows and macOS
+    Created,
+}
+
+/// Sets the chosen timestamps of `mirror` to those in `source_metadata`, skipping any the source
+/// or platform doesn't support
+pub fn preserve_times(
+    source_metadata: &Metadata,
+    mirror: &Path,
+    preserved: &[PreservedTime],
+) -> Result<()> {
+    if preserved.is_empty() {
+        return Ok(());
+    }
+
+    let mut times = FileTimes::new();
+    for time in preserved {
+        match time {
+            PreservedTime::Modified => {
+                if let Ok(modified) = source_metadata.modified() {
+                    times = times.set_modified(modified);
+                }
+            }
+            PreservedTime::Accessed => {
+                if let Ok(accessed) = source_metadata.accessed() {
+                    times = times.set_accessed(accessed);
+                }
+            }
+            PreservedTime::Created => match source_metadata.created() {
+                Ok(created) => times = set_created(times, created, mirror),
+                Err(e) => log::debug!(
+                    "Creation time unavailable for the source of `{0}`: {e}",
+                    mirror.display()
+                ),
+            },
+        }
+    }
+
+    OpenOptions::new()
+        .write(true)
+        .open(mirror)
+        .and_then(|file| file.set_times(times))
+        .with_context(|| format!("Failed to set timestamps of `{0}`", mirror.display()))
+}
+
+#[cfg(target_os = "macos")]
+fn set_created(times: FileTimes, created: std::time::SystemTime, _mirror: &Path) -> FileTimes {
+    use std::os::macos::fs::FileTimesExt;
+    times.set_created(created)
+}
+
+#[cfg(windows)]
+fn set_created(times: FileTimes, created: std::time::SystemTime, _mirror: &Path) -> FileTimes {
+    use std::os::windows::fs::FileTimesExt;
+    times.set_created(created)
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+fn set_created(times: FileTimes, _created: std::time::SystemTime, mirror: &Path) -> FileTimes {
+    log::debug!(
+        "Creation times can't be set on this platform, leaving `{0}` as is",
+        mirror.display()
+    );
+    times
+}
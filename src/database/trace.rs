@@ -0,0 +1,90 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceAction {
+    Hashed,
+    Skipped,
+    Copied,
+    Transformed,
+    Filtered,
+    Batched,
+    Placeholder,
+    Deleted,
+}
+
+#[derive(Serialize)]
+struct TraceRecord<'a> {
+    /// Milliseconds since the Unix epoch
+    time: u128,
+    action: TraceAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<&'a Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mirror: Option<&'a Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<&'a str>,
+}
+
+/// A complete record of every decision made while syncing, written as newline-delimited JSON in
+/// the order decisions were made, regardless of log level.
+pub struct Trace {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl Trace {
+    pub fn create(file_path: &Path) -> Result<Self> {
+        let file = File::create(file_path)
+            .with_context(|| format!("Failed to open {0} for writing", file_path.display()))?;
+        Ok(Self {
+            writer: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    pub fn record(
+        &self,
+        action: TraceAction,
+        source: Option<&Path>,
+        mirror: Option<&Path>,
+        hash: Option<&str>,
+    ) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let record = TraceRecord {
+            time,
+            action,
+            source,
+            mirror,
+            hash,
+        };
+
+        let mut writer = match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let result = serde_json::to_writer(&mut *writer, &record)
+            .map_err(std::io::Error::from)
+            .and_then(|_| writeln!(writer));
+        if let Err(e) = result {
+            log::error!("Failed to write trace record: {e}");
+        }
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        let mut writer = match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        writer.flush().with_context(|| "Failed to flush trace file")
+    }
+}
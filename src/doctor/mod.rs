@@ -0,0 +1,159 @@
+use crate::{
+    archive::ArchiveKind,
+    database::{database_folder, database_path_from_mirror, Database},
+    remote::RemoteFiles,
+};
+use anyhow::{bail, Result};
+use std::{fs, path::Path};
+
+/// Tallies the checks run by `doctor`, printing each as it goes
+#[derive(Default)]
+struct Checklist {
+    failures: usize,
+}
+
+impl Checklist {
+    fn pass(&mut self, message: &str) {
+        println!("[pass] {message}");
+    }
+
+    fn fail(&mut self, message: &str, hint: &str) {
+        self.failures += 1;
+        println!("[fail] {message}");
+        println!("       [hint] {hint}");
+    }
+
+    fn check(&mut self, ok: bool, message: &str, hint: &str) {
+        if ok {
+            self.pass(message);
+        } else {
+            self.fail(message, hint);
+        }
+    }
+}
+
+pub fn doctor(database_path: &Path) -> Result<()> {
+    let mut checklist = Checklist::default();
+
+    let database = match Database::load(database_path) {
+        Ok(database) => {
+            checklist.pass("Database parses cleanly");
+            database
+        }
+        Err(e) => {
+            checklist.fail(
+                &format!("Database could not be read: {e:#}"),
+                "Check the file is a `.mmdb` database created by `mirrorman init`",
+            );
+            bail!("1 check failed, further checks need a readable database");
+        }
+    };
+    let database_folder = database_folder(database_path)?;
+
+    let source_root = database.source_root(&database_folder);
+    checklist.check(
+        source_root.is_dir() || ArchiveKind::detect(&source_root).is_some(),
+        &format!("Source `{0}` exists", source_root.display()),
+        "Is the source on a drive that isn't mounted, or was it moved?",
+    );
+
+    let mirror_root = database.mirror_root(&database_folder);
+    match database.remote_mirror() {
+        Ok(Some(remote)) => match remote.connect().and_then(|files| Ok(files.list()?)) {
+            Ok(_) => checklist.pass(&format!("Remote mirror `{remote}` can be reached")),
+            Err(e) => checklist.fail(
+                &format!("Remote mirror `{remote}` can't be reached: {e:#}"),
+                "Check the host is up, is in `~/.ssh/known_hosts`, and accepts your SSH key",
+            ),
+        },
+        Err(e) => checklist.fail(
+            &format!("{e:#}"),
+            "Mirrors are local directories or `ssh://[user@]host[:port]/path` URLs",
+        ),
+        Ok(None) if mirror_root.is_dir() => {
+            checklist.pass(&format!(
+                "Mirror directory `{0}` exists",
+                mirror_root.display()
+            ));
+            let probe = mirror_root.join(".mmdb-doctor");
+            let writable = fs::write(&probe, []).is_ok();
+            let _ = fs::remove_file(&probe);
+            checklist.check(
+                writable,
+                &format!("Mirror directory `{0}` is writable", mirror_root.display()),
+                "Check the permissions of the mirror directory, and that it isn't mounted read-only",
+            );
+        }
+        Ok(None) => checklist.fail(
+            &format!("Mirror directory `{0}` exists", mirror_root.display()),
+            "Is the mirror on a drive that isn't mounted? It will be recreated on the next sync",
+        ),
+    }
+
+    // A database inside its mirror was named after the mirror path it was created with, which
+    // is just `.` now
+    if let (Ok(expected), Some(actual), false) = (
+        database_path_from_mirror(database.mirror_path()),
+        database_path.file_name(),
+        mirror_root == database_folder,
+    ) {
+        checklist.check(
+            expected.as_os_str() == actual,
+            &format!("Database is named after its mirror (`{0}`)", expected.display()),
+            "The database may have been renamed or copied from another mirror, double check the mirror path",
+        );
+    }
+
+    let filters = database.filters(&database_folder);
+    let chained = filters.iter().flat_map(|filter| &filter.then);
+    for filter in filters.iter().chain(chained) {
+        if let Some(config_file) = &filter.config_file {
+            checklist.check(
+                config_file.is_file(),
+                &format!(
+                    "Config file `{0}` of filter `{filter}` exists",
+                    config_file.display()
+                ),
+                "Config files given with `--filter-args-file` are relative to the database",
+            );
+        }
+        match filter.command().arg("ext").arg("mmdb-doctor").output() {
+            Ok(output) => checklist.check(
+                !output.status.success() || !output.stdout.is_empty(),
+                &format!("Filter `{filter}` responds to the filter protocol"),
+                "A filter should print an extension or exit with an error for `{filter} ext {extension}`",
+            ),
+            Err(e) => checklist.fail(
+                &format!("Filter `{filter}` could not be invoked: {e}"),
+                "Check the filter exists, is executable, and is on your `PATH` or relative to the database",
+            ),
+        }
+    }
+
+    for (mirror, sources) in database.collisions() {
+        let sources = sources
+            .iter()
+            .map(|source| format!("`{0}`", source.display()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        checklist.fail(
+            &format!(
+                "{sources} map to the same mirror file `{0}`",
+                mirror.display()
+            ),
+            "Only the first is kept, rename one of them, or adjust the filters or mirror template",
+        );
+    }
+    if database.collisions().is_empty() {
+        checklist.pass("No source files map to the same mirror file");
+    }
+
+    match checklist.failures {
+        0 => {
+            println!("All checks passed!");
+            Ok(())
+        }
+        1 => bail!("1 check failed"),
+        n => bail!("{n} checks failed"),
+    }
+}
@@ -0,0 +1,260 @@
+use crate::database::write_atomically;
+use anyhow::{anyhow, bail, Context, Result};
+use base32::{decode, encode, Alphabet};
+use chacha20poly1305::{
+    aead::{
+        rand_core::RngCore,
+        stream::{DecryptorBE32, EncryptorBE32},
+        Aead, KeyInit, OsRng,
+    },
+    ChaCha20Poly1305, Key, Nonce,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+pub const ENCRYPTED_EXTENSION: &str = "enc";
+
+/// Plaintext bytes encrypted as each message of the stream
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Bytes the authentication tag adds to each message
+const TAG_SIZE: usize = 16;
+
+/// Nonce length of the STREAM construction, the rest of ChaCha20-Poly1305's 12 bytes being its
+/// counter. Files encrypted whole, before streaming, were given full 12 byte nonces.
+const STREAM_NONCE_SIZE: usize = 7;
+
+/// Length of the nonces files encrypted whole were given
+const WHOLE_NONCE_SIZE: usize = 12;
+
+/// Encrypts mirrored files at rest with ChaCha20-Poly1305, keyed from the SHA-256 of a key file.
+/// Files are encrypted in chunks with the STREAM construction, so they're never read into memory
+/// whole.
+pub struct Cipher {
+    cipher: ChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub fn from_key_file(key_file: &Path) -> Result<Self> {
+        let key_material = fs::read(key_file)
+            .with_context(|| format!("Failed to read key file `{0}`", key_file.display()))?;
+        let key = Sha256::digest(&key_material);
+
+        Ok(Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+        })
+    }
+
+    /// Encrypts `plain` into `encrypted`, removing `plain` and returning the nonce used
+    pub fn encrypt_file(&self, plain: &Path, encrypted: &Path) -> Result<String> {
+        let mut reader =
+            File::open(plain).with_context(|| format!("Failed to read `{0}`", plain.display()))?;
+        let mut nonce = [0; STREAM_NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let mut encryptor = EncryptorBE32::from_aead(self.cipher.clone(), (&nonce).into());
+        let encrypt_error = || anyhow!("Failed to encrypt `{0}`", plain.display());
+
+        write_atomically(encrypted, None, |file| {
+            let mut writer = BufWriter::new(file);
+            // The last chunk is encrypted differently, so each is only written once the next is
+            // read
+            let mut chunk = read_chunk(&mut reader, CHUNK_SIZE)
+                .with_context(|| format!("Failed to read `{0}`", plain.display()))?;
+            loop {
+                let next = read_chunk(&mut reader, CHUNK_SIZE)
+                    .with_context(|| format!("Failed to read `{0}`", plain.display()))?;
+                if next.is_empty() {
+                    let ciphertext = encryptor
+                        .encrypt_last(chunk.as_slice())
+                        .map_err(|_| encrypt_error())?;
+                    writer.write_all(&ciphertext)?;
+                    break;
+                }
+                let ciphertext = encryptor
+                    .encrypt_next(chunk.as_slice())
+                    .map_err(|_| encrypt_error())?;
+                writer.write_all(&ciphertext)?;
+                chunk = next;
+            }
+            writer
+                .flush()
+                .with_context(|| format!("Failed to write `{0}`", encrypted.display()))
+        })?;
+        fs::remove_file(plain)
+            .with_context(|| format!("Failed to remove `{0}`", plain.display()))?;
+
+        Ok(encode(Alphabet::Crockford, &nonce))
+    }
+
+    pub fn decrypt_file(&self, encrypted: &Path, nonce: &str, output: &Path) -> Result<()> {
+        let nonce = decode(Alphabet::Crockford, nonce)
+            .with_context(|| format!("Invalid nonce stored for `{0}`", encrypted.display()))?;
+        let decrypt_error = || {
+            anyhow!(
+                "Failed to decrypt `{0}`, is the key correct?",
+                encrypted.display()
+            )
+        };
+
+        match nonce.len() {
+            STREAM_NONCE_SIZE => {
+                let mut reader = File::open(encrypted)
+                    .with_context(|| format!("Failed to read `{0}`", encrypted.display()))?;
+                let mut decryptor =
+                    DecryptorBE32::from_aead(self.cipher.clone(), nonce.as_slice().into());
+                write_atomically(output, None, |file| {
+                    let mut writer = BufWriter::new(file);
+                    let mut chunk = read_chunk(&mut reader, CHUNK_SIZE + TAG_SIZE)
+                        .with_context(|| format!("Failed to read `{0}`", encrypted.display()))?;
+                    loop {
+                        let next =
+                            read_chunk(&mut reader, CHUNK_SIZE + TAG_SIZE).with_context(|| {
+                                format!("Failed to read `{0}`", encrypted.display())
+                            })?;
+                        if next.is_empty() {
+                            let plaintext = decryptor
+                                .decrypt_last(chunk.as_slice())
+                                .map_err(|_| decrypt_error())?;
+                            writer.write_all(&plaintext)?;
+                            break;
+                        }
+                        let plaintext = decryptor
+                            .decrypt_next(chunk.as_slice())
+                            .map_err(|_| decrypt_error())?;
+                        writer.write_all(&plaintext)?;
+                        chunk = next;
+                    }
+                    writer
+                        .flush()
+                        .with_context(|| format!("Failed to write `{0}`", output.display()))
+                })
+            }
+            WHOLE_NONCE_SIZE => {
+                let contents = fs::read(encrypted)
+                    .with_context(|| format!("Failed to read `{0}`", encrypted.display()))?;
+                let plaintext = self
+                    .cipher
+                    .decrypt(Nonce::from_slice(&nonce), contents.as_slice())
+                    .map_err(|_| decrypt_error())?;
+                write_atomically(output, None, |mut file| {
+                    file.write_all(&plaintext)
+                        .with_context(|| format!("Failed to write `{0}`", output.display()))
+                })
+            }
+            _ => bail!("Invalid nonce stored for `{0}`", encrypted.display()),
+        }
+    }
+}
+
+/// Reads up to `size` bytes, only reading fewer at the end of `reader`
+fn read_chunk(reader: &mut impl Read, size: usize) -> io::Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(size);
+    reader.take(size as u64).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+/// Appends the `.enc` extension to `path`, keeping any existing extension
+pub fn encrypted_path(path: &Path) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(".");
+    path.push(ENCRYPTED_EXTENSION);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cipher, CHUNK_SIZE};
+    use base32::{encode, Alphabet};
+    use chacha20poly1305::{
+        aead::{Aead, AeadCore, OsRng},
+        ChaCha20Poly1305,
+    };
+    use std::{
+        env::temp_dir,
+        fs::{self, create_dir_all, remove_dir_all},
+        path::PathBuf,
+        process,
+    };
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = temp_dir().join(format!("mirrorman-test-{0}-{name}", process::id()));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        fs::write(dir.join("key"), "secret").unwrap();
+        dir
+    }
+
+    #[test]
+    fn files_round_trip() {
+        let dir = test_dir("encryption");
+        let cipher = Cipher::from_key_file(&dir.join("key")).unwrap();
+        // Empty, within a chunk, exactly a chunk, and spanning several
+        for (index, len) in [0, 5, CHUNK_SIZE, 2 * CHUNK_SIZE + 7]
+            .into_iter()
+            .enumerate()
+        {
+            let contents = (0..len).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            let plain = dir.join(format!("{index}.bin"));
+            let encrypted = dir.join(format!("{index}.bin.enc"));
+            let restored = dir.join(format!("{index}.restored"));
+            fs::write(&plain, &contents).unwrap();
+
+            let nonce = cipher.encrypt_file(&plain, &encrypted).unwrap();
+            assert!(!plain.exists());
+            assert_ne!(fs::read(&encrypted).unwrap(), contents);
+            cipher.decrypt_file(&encrypted, &nonce, &restored).unwrap();
+            assert_eq!(fs::read(&restored).unwrap(), contents);
+        }
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tampering_and_wrong_keys_are_caught() {
+        let dir = test_dir("tampering");
+        let cipher = Cipher::from_key_file(&dir.join("key")).unwrap();
+        let plain = dir.join("file.bin");
+        let encrypted = dir.join("file.bin.enc");
+        fs::write(&plain, vec![7; CHUNK_SIZE + 1]).unwrap();
+        let nonce = cipher.encrypt_file(&plain, &encrypted).unwrap();
+
+        fs::write(dir.join("other"), "guess").unwrap();
+        let wrong = Cipher::from_key_file(&dir.join("other")).unwrap();
+        assert!(wrong
+            .decrypt_file(&encrypted, &nonce, &dir.join("wrong"))
+            .is_err());
+        assert!(!dir.join("wrong").exists());
+
+        // Dropping the last chunk mustn't pass for a shorter file
+        let mut contents = fs::read(&encrypted).unwrap();
+        contents.truncate(contents.len() - 17);
+        fs::write(&encrypted, contents).unwrap();
+        assert!(cipher
+            .decrypt_file(&encrypted, &nonce, &dir.join("truncated"))
+            .is_err());
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files_encrypted_whole_still_decrypt() {
+        let dir = test_dir("whole");
+        let cipher = Cipher::from_key_file(&dir.join("key")).unwrap();
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher.cipher.encrypt(&nonce, b"legacy".as_slice()).unwrap();
+        fs::write(dir.join("file.enc"), ciphertext).unwrap();
+
+        cipher
+            .decrypt_file(
+                &dir.join("file.enc"),
+                &encode(Alphabet::Crockford, &nonce),
+                &dir.join("file"),
+            )
+            .unwrap();
+        assert_eq!(fs::read(dir.join("file")).unwrap(), b"legacy");
+        remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+use crate::database::{database_folder, Database, DatabaseConfig, ProgressStyle, SyncOptions};
+use anyhow::{Context, Result};
+use std::{
+    env::temp_dir,
+    fs,
+    path::{absolute, Path, PathBuf},
+    process,
+    time::Instant,
+};
+use walkdir::WalkDir;
+
+/// Mirrors an evenly spread sample of `source`'s files with `config` (filters, transforms,
+/// encryption and all) to a scratch directory, then extrapolates the size of the whole mirror and
+/// how long the first sync would take, without writing the real mirror or database
+pub fn estimate(
+    source: &Path,
+    database_path: &Path,
+    mut config: DatabaseConfig,
+    sample_size: usize,
+) -> Result<()> {
+    let mut files = WalkDir::new(source)
+        .min_depth(1)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| {
+            let size = entry.metadata().ok()?.len();
+            let relative = entry.path().strip_prefix(source).ok()?.to_path_buf();
+            Some((relative, size))
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    let total_bytes = files.iter().map(|(_, size)| size).sum::<u64>();
+    let step = files.len().div_ceil(sample_size.max(1)).max(1);
+    let sample = files.iter().step_by(step).collect::<Vec<_>>();
+    let sample_bytes = sample.iter().map(|(_, size)| size).sum::<u64>();
+    if sample.is_empty() {
+        println!("`{0}` has no files to estimate from.", source.display());
+        return Ok(());
+    }
+
+    // Kept out of the database's folder, which may be the source or mirror, with every path it
+    // holds made absolute so filters and key files resolve as they would for the real database
+    let absolute = |path: &Path| -> Result<PathBuf> {
+        absolute(path)
+            .with_context(|| format!("Failed to resolve absolute path of `{0}`", path.display()))
+    };
+    let database_folder = absolute(&database_folder(database_path)?)?;
+    let scratch_database = temp_dir().join(format!("mirrorman-estimate-{0}.mmdb", process::id()));
+    let scratch_mirror = temp_dir().join(format!("mirrorman-estimate-{0}", process::id()));
+    config.relative_to = config
+        .relative_to
+        .map(|relative_to| absolute(&relative_to))
+        .transpose()?;
+    config.filters_dir = Some(match config.filters_dir {
+        Some(filters_dir) => absolute(&filters_dir)?,
+        None => database_folder.clone(),
+    });
+    for path in config
+        .filter_dirs
+        .values_mut()
+        .chain(config.filter_configs.values_mut())
+        .chain(config.encryption_key_file.as_mut())
+    {
+        *path = database_folder.join(&*path);
+    }
+    let mut database = Database::new(absolute(source)?, scratch_mirror.clone(), config);
+    let options = SyncOptions {
+        progress: ProgressStyle::None,
+        file_list: Some(sample.iter().map(|(path, _)| path.clone()).collect()),
+        ..Default::default()
+    };
+
+    println!(
+        "Mirroring a sample of {0} of {1} files ({2} of {3})...",
+        sample.len(),
+        files.len(),
+        human_size(sample_bytes as f64),
+        human_size(total_bytes as f64)
+    );
+    let start = Instant::now();
+    let result = database
+        .sync(&scratch_database, &options)
+        .and_then(|stats| Ok((stats, directory_size(&scratch_mirror)?)));
+    let elapsed = start.elapsed();
+    let _ = fs::remove_file(&scratch_database);
+    let _ = fs::remove_dir_all(&scratch_mirror);
+    let (stats, output_bytes) = result?;
+
+    // Files which failed have no output, so aren't counted towards the ratio either
+    let failed_bytes = sample
+        .iter()
+        .filter(|(path, _)| {
+            database
+                .failed()
+                .contains(&database.source_path().join(path))
+        })
+        .map(|(_, size)| size)
+        .sum::<u64>();
+    let sample_bytes = sample_bytes - failed_bytes;
+    let ratio = match sample_bytes {
+        0 => 1.0,
+        sample_bytes => output_bytes as f64 / sample_bytes as f64,
+    };
+    let seconds_per_byte = match sample_bytes {
+        0 => 0.0,
+        sample_bytes => elapsed.as_secs_f64() / sample_bytes as f64,
+    };
+    println!(
+        "Sample mirrored to {0} ({1:.1}% of its size) in {2:.2}s",
+        human_size(output_bytes as f64),
+        ratio * 100.0,
+        elapsed.as_secs_f64()
+    );
+    if stats.errors > 0 {
+        println!(
+            "{0} sampled files failed to mirror, and aren't counted in the estimate",
+            stats.errors
+        );
+    }
+    println!(
+        "Estimated mirror size: {0}",
+        human_size(ratio * total_bytes as f64)
+    );
+    println!(
+        "Estimated first sync time: {0:.0}s",
+        seconds_per_byte * total_bytes as f64
+    );
+
+    Ok(())
+}
+
+/// `bytes` in the largest decimal unit it makes at least one of, e.g. `1.5 GB`, as throughput is
+/// shown in MB/s
+fn human_size(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    let mut size = bytes;
+    let mut unit = None;
+    for next in UNITS {
+        if size < 1000.0 {
+            break;
+        }
+        size /= 1000.0;
+        unit = Some(next);
+    }
+    match unit {
+        Some(unit) => format!("{size:.1} {unit}"),
+        None => format!("{bytes:.0} bytes"),
+    }
+}
+
+/// Total size of the files under `directory`
+fn directory_size(directory: &Path) -> Result<u64> {
+    WalkDir::new(directory)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| {
+            entry
+                .metadata()
+                .map(|metadata| metadata.len())
+                .with_context(|| {
+                    format!("Failed to read metadata of `{0}`", entry.path().display())
+                })
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::human_size;
+
+    #[test]
+    fn sizes_are_shown_in_the_largest_whole_unit() {
+        assert_eq!(human_size(0.0), "0 bytes");
+        assert_eq!(human_size(999.0), "999 bytes");
+        assert_eq!(human_size(1_000.0), "1.0 KB");
+        assert_eq!(human_size(1_500_000.0), "1.5 MB");
+        assert_eq!(human_size(2_000_000_000_000_000.0), "2000.0 TB");
+    }
+}
@@ -0,0 +1,194 @@
+use super::{
+    clear_mirror_entry, commit_temp_output, discard_temp_output, forward_stderr, log_failed_output,
+    parse_filter_output, temp_output_path, Filter, FilterLog, FilterOutput,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{Mutex, OnceLock},
+    thread,
+};
+
+/// Maximum number of files handed to a single batch invocation
+pub const BATCH_SIZE: usize = 64;
+
+/// How a filter can be run on many files at once, from the capabilities it lists
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum BatchSupport {
+    /// `batch`, reading `{input}\t{output}` lines and reporting nothing per file
+    Batch,
+    /// `batch` and `batch-rel`, also reading each file's relative path and reporting metadata and
+    /// extra outputs per file, as for `run`
+    BatchRel,
+}
+
+/// A file to be converted as part of a batch
+pub struct BatchInput {
+    pub source_entry: PathBuf,
+    pub mirror_entry: PathBuf,
+    /// `mirror_entry` relative to the mirror root, as for `MIRRORMAN_REL_PATH`
+    pub relative_mirror_entry: PathBuf,
+}
+
+/// How `filter` can be run in batches, from `{filter} caps`, or `None` if it can't. Asked once
+/// per filter for the whole run, and only for filters which handle some file.
+pub fn batch_support(filter: &Filter) -> Option<BatchSupport> {
+    // Native filters are already run in-process, so there's no invocation to save
+    if filter.native.is_some() {
+        return None;
+    }
+    static CACHE: OnceLock<Mutex<BTreeMap<Filter, Option<BatchSupport>>>> = OnceLock::new();
+    let cache = CACHE.get_or_init(Default::default);
+    let cached = match cache.lock() {
+        Ok(cache) => cache.get(filter).copied(),
+        Err(poisoned) => poisoned.into_inner().get(filter).copied(),
+    };
+    if let Some(support) = cached {
+        return support;
+    }
+
+    let caps = match filter.command().arg("caps").stderr(Stdio::null()).output() {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .map(str::to_owned)
+            .collect(),
+        _ => Vec::new(),
+    };
+    let has = |cap: &str| caps.iter().any(|listed| listed == cap);
+    let support = match (has("batch"), has("batch-rel")) {
+        (true, true) => Some(BatchSupport::BatchRel),
+        (true, false) => Some(BatchSupport::Batch),
+        (false, _) => None,
+    };
+    match cache.lock() {
+        Ok(mut cache) => cache.insert(filter.clone(), support),
+        Err(poisoned) => poisoned.into_inner().insert(filter.clone(), support),
+    };
+    support
+}
+
+/// Whether `path` can be given on a line of batch input, which is split on tabs and newlines
+pub fn fits_batch_line(path: &Path) -> bool {
+    !path.as_os_str().to_string_lossy().contains(['\t', '\n'])
+}
+
+/// Runs `filter` once for the whole batch, passing `{input}\t{output}` lines on stdin (followed by
+/// `\t{relative}` for `batch-rel`), and returning what it reported for each entry
+pub fn run_filter_batch(
+    filter: &Filter,
+    support: BatchSupport,
+    entries: &[BatchInput],
+    log: Option<&FilterLog>,
+) -> Result<Vec<FilterOutput>> {
+    // As with `run`, outputs are only moved into place once the whole batch succeeds
+    let temp_outputs = entries
+        .iter()
+        .map(|entry| temp_output_path(&entry.mirror_entry))
+        .collect::<Vec<_>>();
+    let discard_temp_outputs = || {
+        temp_outputs
+            .iter()
+            .for_each(|temp| discard_temp_output(temp))
+    };
+
+    if let Some(entry) = entries.iter().find(|entry| {
+        !fits_batch_line(&entry.source_entry) || !fits_batch_line(&entry.mirror_entry)
+    }) {
+        bail!(
+            "`{0}` can't be passed to filter `{1}` in a batch, as its path contains a tab or newline",
+            entry.source_entry.display(),
+            filter
+        );
+    }
+
+    let mut input = String::new();
+    for (entry, temp_output) in entries.iter().zip(&temp_outputs) {
+        clear_mirror_entry(temp_output);
+        input.push_str(&format!(
+            "{0}\t{1}",
+            entry.source_entry.display(),
+            temp_output.display()
+        ));
+        if support == BatchSupport::BatchRel {
+            input.push_str(&format!("\t{0}", entry.relative_mirror_entry.display()));
+        }
+        input.push('\n');
+    }
+
+    let mut child = filter
+        .command()
+        .arg("batch")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to invoke filter `{filter}`"))?;
+    let mut stdin = child.stdin.take().context("Failed to open filter stdin")?;
+
+    // The batch is written while the output pipes are read, otherwise a filter writing lots of
+    // output before reading all of its input would never finish
+    let (written, output) = thread::scope(|scope| {
+        let writer = scope.spawn(move || stdin.write_all(input.as_bytes()));
+        let output = child.wait_with_output();
+        let written = writer
+            .join()
+            .unwrap_or_else(|_| Err(io::ErrorKind::BrokenPipe.into()));
+        (written, output)
+    });
+    let output = match output {
+        Ok(output) => output,
+        Err(e) => {
+            discard_temp_outputs();
+            return Err(e).with_context(|| format!("Failed to wait on filter `{filter}`"));
+        }
+    };
+
+    let subject = format!("a batch of {0} files", entries.len());
+    if let Some(log) = log {
+        log.record(filter, &subject, &output);
+    }
+    if !output.status.success() {
+        discard_temp_outputs();
+        log_failed_output(filter, &subject, &output);
+        bail!(
+            "Filter `{filter}` failed for {subject} ({0})",
+            output.status
+        );
+    }
+    if let Err(e) = written {
+        discard_temp_outputs();
+        return Err(e).with_context(|| format!("Failed to pass batch to filter `{filter}`"));
+    }
+    forward_stderr(&output);
+
+    for (entry, temp_output) in entries.iter().zip(&temp_outputs) {
+        commit_temp_output(temp_output, &entry.mirror_entry)?;
+    }
+
+    // Each line of `batch-rel` output starts with the output it's about, followed by a line of
+    // what `run` would have printed for it
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut entry_stdout = vec![String::new(); entries.len()];
+    if support == BatchSupport::BatchRel {
+        for line in stdout.lines() {
+            let Some((output, line)) = line.split_once('\t') else {
+                continue;
+            };
+            if let Some(index) = temp_outputs
+                .iter()
+                .position(|temp_output| temp_output.as_os_str() == output)
+            {
+                entry_stdout[index].push_str(line);
+                entry_stdout[index].push('\n');
+            }
+        }
+    }
+    Ok(entries
+        .iter()
+        .zip(entry_stdout)
+        .map(|(entry, stdout)| parse_filter_output(filter, &entry.mirror_entry, stdout.as_bytes()))
+        .collect())
+}
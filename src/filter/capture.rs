@@ -0,0 +1,86 @@
+use super::Filter;
+use anyhow::{Context, Result};
+use std::{
+    fs::File,
+    io::{stderr, BufWriter, Write},
+    path::Path,
+    process::Output,
+    sync::Mutex,
+};
+
+/// Everything filters wrote to stdout and stderr, one section per invocation tagged with what it
+/// was run on
+pub struct FilterLog {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl FilterLog {
+    pub fn create(file_path: &Path) -> Result<Self> {
+        let file = File::create(file_path)
+            .with_context(|| format!("Failed to open {0} for writing", file_path.display()))?;
+        Ok(Self {
+            writer: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    pub fn record(&self, filter: &Filter, subject: &str, output: &Output) {
+        let mut writer = match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let result = writeln!(
+            writer,
+            "==> Filter `{filter}` on {subject} ({0})",
+            output.status
+        )
+        .and_then(|_| writeln!(writer, "--- stdout"))
+        .and_then(|_| write_stream(&mut *writer, &output.stdout))
+        .and_then(|_| writeln!(writer, "--- stderr"))
+        .and_then(|_| write_stream(&mut *writer, &output.stderr))
+        .and_then(|_| writeln!(writer));
+        if let Err(e) = result {
+            log::error!("Failed to write filter log: {e}");
+        }
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        let mut writer = match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        writer.flush().with_context(|| "Failed to flush filter log")
+    }
+}
+
+/// Writes a captured stream, ending it with a newline if it didn't have one
+fn write_stream(writer: &mut impl Write, stream: &[u8]) -> std::io::Result<()> {
+    writer.write_all(stream)?;
+    if !stream.is_empty() && !stream.ends_with(b"\n") {
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+/// Shows everything a failed filter wrote, as its stdout may explain the failure too
+pub fn log_failed_output(filter: &Filter, subject: &str, output: &Output) {
+    let captured = [&output.stdout, &output.stderr]
+        .into_iter()
+        .map(|stream| String::from_utf8_lossy(stream).trim().to_owned())
+        .filter(|stream| !stream.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if !captured.is_empty() {
+        log::error!("Output of filter `{filter}` on {subject}:\n{captured}");
+    }
+}
+
+/// Passes on what a filter wrote to stderr, which is meant for the user, in one piece so it isn't
+/// interleaved with other filters' output
+pub fn forward_stderr(output: &Output) {
+    if output.stderr.is_empty() {
+        return;
+    }
+    if let Err(e) = stderr().lock().write_all(&output.stderr) {
+        log::error!("Failed to forward filter output: {e}");
+    }
+}
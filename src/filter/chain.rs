@@ -0,0 +1,100 @@
+use super::{
+    discard_temp_output, mirror_path_for_extension, resolve_filter_extension, run_filter_for_entry,
+    Filter, FilterLog, FilterOutput,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    ffi::{OsStr, OsString},
+    iter,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// The raw `ext` output of `filter` and each filter chained after it for `entry`, each asked about
+/// the extension the one before it outputs, so the last is the mirror entry's extension. `None` if
+/// any filter in the chain doesn't handle its input.
+pub fn chain_extensions(filter: &Filter, extension: &OsStr, entry: &Path) -> Option<Vec<String>> {
+    let mut extensions = vec![resolve_filter_extension(filter, extension, entry)?];
+    for (previous, next) in iter::once(filter).chain(&filter.then).zip(&filter.then) {
+        let output = extensions.last()?.trim();
+        let extension = output.strip_prefix('.').unwrap_or(output);
+        match resolve_filter_extension(next, OsStr::new(extension), entry) {
+            Some(ext_output) => extensions.push(ext_output),
+            None => {
+                log::error!(
+                    "Ignoring filter chain `{0}` for `{1}`, `{next}` doesn't handle the `.{extension}` files `{previous}` outputs",
+                    filter,
+                    entry.display()
+                );
+                return None;
+            }
+        }
+    }
+    Some(extensions)
+}
+
+/// Runs `filter` and every filter chained after it on the given entry, each taking the output of
+/// the one before it, with only the last writing to `mirror_entry`. `extension` is the one the
+/// chain was chosen for.
+pub fn run_filter_chain(
+    source_entry: &Path,
+    mirror_entry: &Path,
+    relative_mirror_entry: &Path,
+    filter: &Filter,
+    extension: &OsStr,
+    log: Option<&FilterLog>,
+) -> Result<FilterOutput> {
+    let extensions = chain_extensions(filter, extension, source_entry).with_context(|| {
+        format!(
+            "Filter chain `{0}` no longer handles `{1}`",
+            filter,
+            source_entry.display()
+        )
+    })?;
+    let intermediates = extensions[..extensions.len() - 1]
+        .iter()
+        .enumerate()
+        .map(|(stage, ext_output)| stage_output_path(mirror_entry, stage, ext_output))
+        .collect::<Vec<_>>();
+
+    let outputs = intermediates
+        .iter()
+        .cloned()
+        .chain(iter::once(mirror_entry.to_path_buf()));
+    let mut input = source_entry.to_path_buf();
+    let mut chained = FilterOutput {
+        metadata: None,
+        extra_outputs: Vec::new(),
+    };
+    let result = iter::once(filter)
+        .chain(&filter.then)
+        .zip(outputs)
+        .try_for_each(|(stage, output)| {
+            if !input.exists() {
+                bail!(
+                    "Filter chain `{0}` stopped before `{stage}`, the filter before it produced no output for `{1}`",
+                    filter,
+                    source_entry.display()
+                );
+            }
+            let stage_output =
+                run_filter_for_entry(&input, &output, relative_mirror_entry, stage, log)?;
+            chained.metadata = stage_output.metadata.or(chained.metadata.take());
+            chained.extra_outputs.extend(stage_output.extra_outputs);
+            input = output;
+            Ok(())
+        });
+    intermediates
+        .iter()
+        .for_each(|intermediate| discard_temp_output(intermediate));
+    result.map(|_| chained)
+}
+
+/// Where a stage of a chain writes its output, next to the mirror entry and named after it with the
+/// stage's extension, as the next filter may choose how to read it from the extension
+fn stage_output_path(mirror_entry: &Path, stage: usize, ext_output: &str) -> PathBuf {
+    let stage_entry = mirror_path_for_extension(mirror_entry, ext_output);
+    let mut name = OsString::from(format!(".mirrorman-{0}-chain{stage}-", process::id()));
+    name.push(stage_entry.file_name().unwrap_or_default());
+    mirror_entry.with_file_name(name)
+}
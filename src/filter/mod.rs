@@ -1,10 +1,291 @@
+mod batch;
+mod capture;
+mod chain;
+mod native;
+mod sandbox;
+mod semaphore;
+mod sniff;
+
+pub use batch::*;
+pub use capture::*;
+pub use chain::*;
+pub use native::*;
+pub use sandbox::*;
+pub use semaphore::*;
+pub use sniff::*;
+
+use crate::transform::glob_match;
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
 use std::{
-    fs,
-    path::{Path, PathBuf},
-    process::Command,
+    ffi::{OsStr, OsString},
+    fmt, fs,
+    io::ErrorKind,
+    path::{absolute, Component, Path, PathBuf},
+    process::{self, Command},
 };
 
-pub fn run_filter_for_entry(source_entry: &Path, mirror_entry: &Path, filter: &str) {
+/// A filter program, ready to be invoked
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Filter {
+    pub program: String,
+    pub working_dir: Option<PathBuf>,
+    pub sandbox: Option<Sandbox>,
+    /// Globs over the relative source path the filter is restricted to, anywhere if empty
+    pub paths: Vec<String>,
+    /// Filters the output is passed through in turn, the last one producing the mirror entry
+    pub then: Vec<Filter>,
+    /// Config file passed to every invocation in `MIRRORMAN_FILTER_CONFIG`
+    pub config_file: Option<PathBuf>,
+    /// Run in-process in place of the program, if there's a native filter for it
+    pub native: Option<NativeHandle>,
+}
+
+/// Whether a filter is given as a relative path (e.g. `./filter.sh` or `filters/filter.sh`), rather
+/// than a bare program name or an absolute path
+pub fn is_relative_path(program: &str) -> bool {
+    let path = Path::new(program);
+    path.is_relative() && path.components().count() > 1
+}
+
+impl Filter {
+    /// Resolves a filter given as a relative path (e.g. `./filter.sh`) against `filters_root`, and
+    /// its working directory against `database_folder`, leaving bare program names to be looked up
+    /// on `PATH`
+    pub fn resolve(
+        program: &str,
+        filters_root: &Path,
+        working_dir: Option<&Path>,
+        database_folder: &Path,
+    ) -> Self {
+        let path = Path::new(program);
+        let program = if is_relative_path(program) {
+            filters_root
+                .join(path.strip_prefix(".").unwrap_or(path))
+                .to_string_lossy()
+                .into_owned()
+        } else {
+            program.to_owned()
+        };
+
+        Self {
+            program,
+            working_dir: working_dir.map(|working_dir| database_folder.join(working_dir)),
+            sandbox: None,
+            paths: Vec::new(),
+            then: Vec::new(),
+            config_file: None,
+            native: None,
+        }
+    }
+
+    pub fn restricted_to(self, paths: &[String]) -> Self {
+        Self {
+            paths: paths.to_vec(),
+            ..self
+        }
+    }
+
+    /// Passes `config_file` to the filter, made absolute so it's found from any working directory
+    pub fn configured(self, config_file: Option<&Path>, base: &Path) -> Self {
+        Self {
+            config_file: config_file.map(|config_file| {
+                let config_file = base.join(config_file);
+                absolute(&config_file).unwrap_or(config_file)
+            }),
+            ..self
+        }
+    }
+
+    pub fn chained(self, then: Vec<Filter>) -> Self {
+        Self { then, ..self }
+    }
+
+    /// Whether the filter is restricted to paths matching `relative_entry`
+    fn is_assigned_to(&self, relative_entry: &Path) -> bool {
+        self.paths
+            .iter()
+            .any(|pattern| glob_match(pattern, relative_entry))
+    }
+
+    pub fn sandboxed(self, sandbox: Option<&Sandbox>) -> Self {
+        Self {
+            sandbox: sandbox.cloned(),
+            then: self
+                .then
+                .into_iter()
+                .map(|next| next.sandboxed(sandbox))
+                .collect(),
+            ..self
+        }
+    }
+
+    /// Attaches the native filter standing in for the program, and for each filter chained after
+    /// it, if there are any
+    pub fn natively(self, native_filters: &NativeFilters) -> Self {
+        Self {
+            native: native_filters
+                .get(&self.program)
+                .map(|native| NativeHandle(native.clone())),
+            then: self
+                .then
+                .into_iter()
+                .map(|next| next.natively(native_filters))
+                .collect(),
+            ..self
+        }
+    }
+
+    pub fn command(&self) -> Command {
+        let mut command = match &self.sandbox {
+            Some(sandbox) => sandbox.command(&self.program),
+            None => Command::new(&self.program),
+        };
+        command.env(PROTOCOL_VERSION_VAR, FILTER_PROTOCOL_VERSION.to_string());
+        if let Some(config_file) = &self.config_file {
+            command.env(CONFIG_FILE_VAR, config_file);
+        }
+        if let Some(working_dir) = &self.working_dir {
+            command.current_dir(working_dir);
+        }
+        command
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.program)
+    }
+}
+
+/// Version of the filter protocol described in `filter_spec.md`, bumped whenever a change would
+/// break existing filters
+pub const FILTER_PROTOCOL_VERSION: u32 = 1;
+
+/// Environment variable holding the filter protocol version, passed to every invocation
+pub const PROTOCOL_VERSION_VAR: &str = "MIRRORMAN_FILTER_PROTOCOL";
+
+/// Environment variable holding the path of the filter's config file, if it was given one
+pub const CONFIG_FILE_VAR: &str = "MIRRORMAN_FILTER_CONFIG";
+
+/// Environment variable holding the mirror entry's path relative to the mirror root
+pub const RELATIVE_PATH_VAR: &str = "MIRRORMAN_REL_PATH";
+
+/// Prefix of `run` output lines declaring an extra file the filter produced
+const EXTRA_OUTPUT_PREFIX: &str = "output\t";
+
+/// What a filter reported on stdout after running
+pub struct FilterOutput {
+    pub metadata: Option<Value>,
+    /// Files produced besides the mirror entry, resolved against its directory
+    pub extra_outputs: Vec<PathBuf>,
+}
+
+/// Runs `filter` on the given entry, returning any JSON metadata and extra outputs the filter
+/// printed to stdout
+pub fn run_filter_for_entry(
+    source_entry: &Path,
+    mirror_entry: &Path,
+    relative_mirror_entry: &Path,
+    filter: &Filter,
+    log: Option<&FilterLog>,
+) -> Result<FilterOutput> {
+    let temp_output = temp_output_path(mirror_entry);
+    clear_mirror_entry(&temp_output);
+
+    if let Some(NativeHandle(native)) = &filter.native {
+        let metadata = match native.run(source_entry, &temp_output, relative_mirror_entry) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                discard_temp_output(&temp_output);
+                return Err(e.context(format!(
+                    "Filter `{0}` failed for `{1}`",
+                    filter,
+                    source_entry.display()
+                )));
+            }
+        };
+        commit_temp_output(&temp_output, mirror_entry)?;
+        return Ok(FilterOutput {
+            metadata,
+            extra_outputs: Vec::new(),
+        });
+    }
+
+    // Both pipes are read at once, so a filter can't block writing lots to either
+    let output = match filter
+        .command()
+        .arg("run")
+        .arg(source_entry)
+        .arg(&temp_output)
+        .env(RELATIVE_PATH_VAR, relative_mirror_entry)
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => {
+            discard_temp_output(&temp_output);
+            bail!("Failed to invoke filter `{0}`: {e}", filter)
+        }
+    };
+    if let Some(log) = log {
+        log.record(filter, &format!("`{0}`", source_entry.display()), &output);
+    }
+
+    if !output.status.success() {
+        discard_temp_output(&temp_output);
+        log_failed_output(filter, &format!("`{0}`", source_entry.display()), &output);
+        bail!(
+            "Filter `{0}` failed for `{1}` ({2})",
+            filter,
+            source_entry.display(),
+            output.status
+        );
+    }
+    forward_stderr(&output);
+    commit_temp_output(&temp_output, mirror_entry)?;
+    Ok(parse_filter_output(filter, mirror_entry, &output.stdout))
+}
+
+/// Where a filter writes the output for `mirror_entry`, which is only moved into place once the
+/// filter succeeds, so a failed or interrupted run never leaves a partial file that looks valid.
+/// It's next to the mirror entry so it can be renamed over it, and ends with the same name, as
+/// filters may choose the output format from the extension.
+fn temp_output_path(mirror_entry: &Path) -> PathBuf {
+    let file_name = mirror_entry.file_name().unwrap_or_default();
+    let mut temp_name = OsString::from(format!(".mirrorman-{0}-", process::id()));
+    temp_name.push(file_name);
+    mirror_entry.with_file_name(temp_name)
+}
+
+/// Moves a successful filter's output into place, replacing the previous mirror entry
+fn commit_temp_output(temp_output: &Path, mirror_entry: &Path) -> Result<()> {
+    if !temp_output.exists() {
+        // The filter produced nothing, so nothing should be left behind from before either
+        clear_mirror_entry(mirror_entry);
+        return Ok(());
+    }
+    fs::rename(temp_output, mirror_entry).with_context(|| {
+        format!(
+            "Failed to move filter output `{0}` into place at `{1}`",
+            temp_output.display(),
+            mirror_entry.display()
+        )
+    })
+}
+
+/// Removes what a failed filter wrote, leaving the previous mirror entry as it was
+fn discard_temp_output(temp_output: &Path) {
+    if let Err(e) = fs::remove_file(temp_output) {
+        if e.kind() != ErrorKind::NotFound {
+            log::error!(
+                "Failed to remove partial filter output `{0}`: {e}",
+                temp_output.display()
+            );
+        }
+    }
+}
+
+fn clear_mirror_entry(mirror_entry: &Path) {
     if mirror_entry.exists() {
         log::trace!(
             "`{0}` is in the way, removing before running filter...",
@@ -18,56 +299,252 @@ pub fn run_filter_for_entry(source_entry: &Path, mirror_entry: &Path, filter: &s
             );
         }
     }
+}
 
-    match Command::new(filter)
-        .arg("run")
-        .arg(source_entry)
-        .arg(mirror_entry)
-        .status()
-    {
-        Ok(status) => {
-            if !status.success() {
-                log::error!(
-                    "Filter `{0}` failed for `{1}`, skipping...",
-                    filter,
-                    source_entry.display()
-                );
+/// Splits `output\t{path}` lines out of a filter's stdout, treating the rest as JSON metadata
+fn parse_filter_output(filter: &Filter, mirror_entry: &Path, stdout: &[u8]) -> FilterOutput {
+    let stdout = String::from_utf8_lossy(stdout);
+    let mirror_dir = mirror_entry.parent().unwrap_or(Path::new(""));
+
+    let mut extra_outputs = Vec::new();
+    let mut rest = String::new();
+    for line in stdout.lines() {
+        match line.strip_prefix(EXTRA_OUTPUT_PREFIX) {
+            Some(path) => {
+                let path = Path::new(path.trim());
+                // Outputs have to stay next to (or below) the mirror entry
+                if path
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)))
+                {
+                    extra_outputs.push(mirror_dir.join(path));
+                } else {
+                    log::error!(
+                        "Ignoring extra output `{0}` from filter `{1}`, outputs must be relative paths inside the mirror entry's directory",
+                        path.display(),
+                        filter
+                    );
+                }
+            }
+            None => {
+                rest.push_str(line);
+                rest.push('\n');
             }
         }
+    }
+
+    FilterOutput {
+        metadata: parse_filter_metadata(filter, &rest),
+        extra_outputs,
+    }
+}
+
+/// A filter's stdout as JSON metadata, or `None` if it printed nothing or something else
+fn parse_filter_metadata(filter: &Filter, stdout: &str) -> Option<Value> {
+    let stdout = stdout.trim();
+    if stdout.is_empty() {
+        return None;
+    }
+
+    match serde_json::from_str(stdout) {
+        Ok(metadata) => Some(metadata),
         Err(e) => {
-            log::error!("Failed to invoke filter `{0}`, skipping: {e}", filter);
+            log::debug!("Ignoring non-JSON output from filter `{0}`: {e}", filter);
+            None
         }
     }
 }
 
+/// Finds the first filter which handles `entry`, trying filters assigned to its path before
+/// unrestricted ones, and skipping filters restricted to other paths
 pub fn find_filter_for_entry<'a>(
     entry: &Path,
+    relative_entry: &Path,
     mirror_entry: &mut PathBuf,
-    filters: &'a [String],
-) -> Option<&'a String> {
-    entry.extension().and_then(|ext| {
-        filters.iter().find(
-            |filter| match Command::new(filter).arg("ext").arg(ext).output() {
-                Ok(output) => {
-                    if output.status.success() {
-                        let new_extension = match String::from_utf8(output.stdout) {
-                            Ok(output) => output.trim().to_owned(),
-                            Err(e) => {
-                                log::error!("Failed to parse filter `{0}` output: {e}", filter);
-                                return false;
-                            }
-                        };
-                        mirror_entry.set_extension(&new_extension);
-                        true
-                    } else {
-                        false
-                    }
-                }
-                Err(e) => {
-                    log::error!("Failed to invoke filter `{0}`, skipping: {e}", filter);
-                    false
-                }
-            },
-        )
+    filters: &'a [Filter],
+    sniff: bool,
+    case_sensitive: bool,
+) -> Option<&'a Filter> {
+    let extension = entry_extension(entry, sniff, case_sensitive)?;
+
+    let assigned = filters
+        .iter()
+        .filter(|filter| filter.is_assigned_to(relative_entry));
+    let unrestricted = filters.iter().filter(|filter| filter.paths.is_empty());
+    assigned.chain(unrestricted).find_map(|filter| {
+        let ext_output = chain_extensions(filter, &extension, entry)?.pop()?;
+        // A filter echoing back the extension it was asked about keeps the source's spelling of it
+        if entry.extension().is_none() || !echoes_extension(&ext_output, &extension) {
+            *mirror_entry = mirror_path_for_extension(mirror_entry, &ext_output);
+        }
+        Some(filter)
     })
 }
+
+/// The extension filters are asked about for `entry`, sniffed from its contents if it has none and
+/// `sniff` is set
+pub fn entry_extension(entry: &Path, sniff: bool, case_sensitive: bool) -> Option<OsString> {
+    let extension = entry.extension().map(OsStr::to_os_string).or_else(|| {
+        (sniff && entry.is_file())
+            .then(|| sniff_extension(entry))
+            .flatten()
+            .map(OsString::from)
+    })?;
+    Some(probe_extension(&extension, case_sensitive))
+}
+
+/// The extension filters are asked about, lowercased unless `case_sensitive`, so `.JPG` and `.Jpg`
+/// files match a filter handling `jpg`
+pub fn probe_extension(extension: &OsStr, case_sensitive: bool) -> OsString {
+    match case_sensitive {
+        true => extension.to_os_string(),
+        false => extension.to_ascii_lowercase(),
+    }
+}
+
+/// Whether a filter's `ext` output is just `extension`, i.e. it doesn't change the extension
+fn echoes_extension(ext_output: &str, extension: &OsStr) -> bool {
+    let output = ext_output.trim();
+    output.strip_prefix('.').unwrap_or(output) == extension
+}
+
+/// Asks `filter` whether it handles `extension` for `entry`, returning its raw output if it does and
+/// the extension stays in the mirror entry's directory
+fn resolve_filter_extension(filter: &Filter, extension: &OsStr, entry: &Path) -> Option<String> {
+    let ext_output = query_filter_extension(filter, extension)?;
+    // An extension with a separator would move the file, possibly out of the mirror
+    if ext_output.trim().contains(['/', '\\']) {
+        log::error!(
+            "Ignoring filter `{0}` for `{1}`, its extension `{2}` contains a path separator",
+            filter,
+            entry.display(),
+            ext_output.trim()
+        );
+        return None;
+    }
+    Some(ext_output)
+}
+
+/// Asks `filter` whether it handles `extension`, returning its raw output if it does
+fn query_filter_extension(filter: &Filter, extension: &OsStr) -> Option<String> {
+    if let Some(NativeHandle(native)) = &filter.native {
+        return native.ext(extension);
+    }
+    match filter.command().arg("ext").arg(extension).output() {
+        Ok(output) if output.status.success() => match String::from_utf8(output.stdout) {
+            Ok(output) => Some(output),
+            Err(e) => {
+                log::error!("Failed to parse filter `{0}` output: {e}", filter);
+                None
+            }
+        },
+        Ok(_) => None,
+        Err(e) => {
+            log::error!("Failed to invoke filter `{0}`, skipping: {e}", filter);
+            None
+        }
+    }
+}
+
+/// Invokes `filter` once with an extension no filter should handle, as a harmless check that it can
+/// be found and speaks the filter protocol. Returns what's wrong with it, if anything.
+pub fn probe_filter(filter: &Filter) -> Option<String> {
+    if filter.native.is_some() {
+        return None;
+    }
+    match filter.command().arg("ext").arg("mmdb-probe").output() {
+        // Handling the extension is fine, as is exiting with an error, but succeeding without
+        // printing an extension isn't how filters respond
+        Ok(output) if output.status.success() && output.stdout.is_empty() => Some(format!(
+            "`{filter}` printed nothing for `{filter} ext mmdb-probe`, it doesn't look like a filter"
+        )),
+        Ok(_) => None,
+        Err(e) => Some(format!("`{filter}` could not be invoked: {e}")),
+    }
+}
+
+/// Rewrites the extension of `mirror_entry` to the one a filter printed for `{filter} ext`.
+///
+/// Only the last extension is replaced (`a.tar.gz` becomes `a.tar.{ext}`), a leading `.` in the
+/// output is ignored, and empty output removes the extension entirely.
+pub fn mirror_path_for_extension(mirror_entry: &Path, ext_output: &str) -> PathBuf {
+    let extension = ext_output.trim();
+    let extension = extension.strip_prefix('.').unwrap_or(extension);
+    mirror_entry.with_extension(extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{echoes_extension, mirror_path_for_extension, probe_extension};
+    use std::{ffi::OsStr, path::Path};
+
+    #[test]
+    fn replaces_extension() {
+        assert_eq!(
+            mirror_path_for_extension(Path::new("music/song.flac"), "mp3\n"),
+            Path::new("music/song.mp3")
+        );
+    }
+
+    #[test]
+    fn multi_dot_names_keep_inner_dots() {
+        assert_eq!(
+            mirror_path_for_extension(Path::new("backups/site.tar.gz"), "zst"),
+            Path::new("backups/site.tar.zst")
+        );
+        assert_eq!(
+            mirror_path_for_extension(Path::new("my.song.v2.flac"), "mp3"),
+            Path::new("my.song.v2.mp3")
+        );
+    }
+
+    #[test]
+    fn empty_output_removes_extension() {
+        assert_eq!(
+            mirror_path_for_extension(Path::new("notes/todo.md"), ""),
+            Path::new("notes/todo")
+        );
+        assert_eq!(
+            mirror_path_for_extension(Path::new("notes/todo.md"), "  \n"),
+            Path::new("notes/todo")
+        );
+    }
+
+    #[test]
+    fn uppercase_extensions_are_kept() {
+        assert_eq!(
+            mirror_path_for_extension(Path::new("photos/IMG_0001.HEIC"), "JPG"),
+            Path::new("photos/IMG_0001.JPG")
+        );
+        assert_eq!(
+            mirror_path_for_extension(Path::new("photos/IMG_0001.heic"), "JPG"),
+            Path::new("photos/IMG_0001.JPG")
+        );
+    }
+
+    #[test]
+    fn leading_dot_is_ignored() {
+        assert_eq!(
+            mirror_path_for_extension(Path::new("song.flac"), ".mp3"),
+            Path::new("song.mp3")
+        );
+    }
+
+    #[test]
+    fn extensions_are_probed_in_lowercase() {
+        for extension in ["JPG", "Jpg", "jpg"] {
+            assert_eq!(probe_extension(OsStr::new(extension), false), "jpg");
+        }
+        assert_eq!(probe_extension(OsStr::new("JPG"), true), "JPG");
+    }
+
+    #[test]
+    fn echoed_extensions_keep_their_case() {
+        for extension in ["JPG", "Jpg", "jpg"] {
+            let probe = probe_extension(OsStr::new(extension), false);
+            assert!(echoes_extension("jpg\n", &probe));
+            assert!(echoes_extension(".jpg", &probe));
+            assert!(!echoes_extension("webp", &probe));
+        }
+    }
+}
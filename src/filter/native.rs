@@ -0,0 +1,49 @@
+use anyhow::Result;
+use serde_json::Value;
+use std::{cmp::Ordering, collections::BTreeMap, ffi::OsStr, path::Path, sync::Arc};
+
+/// A filter implemented in Rust, run in-process in place of a filter program rather than as a
+/// subprocess. It follows the same protocol as filter programs (see `filter_spec.md`).
+pub trait NativeFilter: Send + Sync {
+    /// As for `{filter} ext {extension}`, the extension files with `extension` are mirrored with,
+    /// or `None` if they aren't handled
+    fn ext(&self, extension: &OsStr) -> Option<String>;
+
+    /// As for `{filter} run {input} {output}`, writes the mirror entry for `input` to `output`,
+    /// returning any JSON metadata to record for it. Writing nothing leaves no mirror entry.
+    fn run(
+        &self,
+        input: &Path,
+        output: &Path,
+        relative_mirror_entry: &Path,
+    ) -> Result<Option<Value>>;
+}
+
+/// Key = Filter program the native filter stands in for, as it's invoked, e.g. `thumbnail`
+pub type NativeFilters = BTreeMap<String, Arc<dyn NativeFilter>>;
+
+/// A native filter attached to a [`super::Filter`]
+#[derive(Clone)]
+pub struct NativeHandle(pub Arc<dyn NativeFilter>);
+
+// Native filters are attached by program name, which filters are already compared by, so handles
+// never tell two filters apart
+impl PartialEq for NativeHandle {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for NativeHandle {}
+
+impl PartialOrd for NativeHandle {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NativeHandle {
+    fn cmp(&self, _other: &Self) -> Ordering {
+        Ordering::Equal
+    }
+}
@@ -0,0 +1,100 @@
+use clap::ValueEnum;
+use std::{env, ffi::OsString, path::PathBuf, process::Command};
+
+/// Environment variables filters may still see inside a sandbox, on top of `--sandbox-allow-env`
+const DEFAULT_ALLOWED_ENV: &[&str] = &["PATH", "HOME", "LANG", "LC_ALL", "LC_CTYPE"];
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum SandboxMode {
+    /// Only scrub the environment
+    Env,
+    /// Scrub the environment and run under `bwrap` (bubblewrap)
+    Bwrap,
+    /// Scrub the environment and run under `firejail`
+    Firejail,
+    /// Use `bwrap` or `firejail` when installed, only scrubbing the environment otherwise
+    Auto,
+}
+
+/// Restrictions applied to every filter process
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Sandbox {
+    pub mode: SandboxMode,
+    /// Extra environment variables to pass through
+    pub allow_env: Vec<String>,
+    /// Scratch directory filters may write to, exposed as `TMPDIR`
+    pub tmp_dir: Option<PathBuf>,
+    /// Directories filters may write to, besides `tmp_dir` (e.g. the mirror)
+    pub writable: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    /// Picks a concrete mode for `Auto`, based on what's installed
+    pub fn resolve(mut self) -> Self {
+        if self.mode == SandboxMode::Auto {
+            self.mode = if on_path("bwrap") {
+                SandboxMode::Bwrap
+            } else if on_path("firejail") {
+                SandboxMode::Firejail
+            } else {
+                log::warn!("Neither `bwrap` nor `firejail` found, filters will only have their environment scrubbed");
+                SandboxMode::Env
+            };
+        }
+        self
+    }
+
+    /// Builds a command running `program` inside the sandbox
+    pub fn command(&self, program: &str) -> Command {
+        let writable = self.writable.iter().chain(self.tmp_dir.as_ref());
+        let mut command = match self.mode {
+            SandboxMode::Env | SandboxMode::Auto => Command::new(program),
+            SandboxMode::Bwrap => {
+                let mut command = Command::new("bwrap");
+                command.args(["--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc"]);
+                if self.tmp_dir.is_none() {
+                    command.args(["--tmpfs", "/tmp"]);
+                }
+                for dir in writable {
+                    command.arg("--bind").arg(dir).arg(dir);
+                }
+                command.args(["--unshare-all", "--die-with-parent", "--", program]);
+                command
+            }
+            SandboxMode::Firejail => {
+                let mut command = Command::new("firejail");
+                command.args(["--quiet", "--noprofile", "--net=none", "--read-only=/"]);
+                if self.tmp_dir.is_none() {
+                    command.arg("--private-tmp");
+                }
+                for dir in writable {
+                    let mut arg = OsString::from("--read-write=");
+                    arg.push(dir);
+                    command.arg(arg);
+                }
+                command.args(["--", program]);
+                command
+            }
+        };
+
+        command.env_clear();
+        DEFAULT_ALLOWED_ENV
+            .iter()
+            .copied()
+            .chain(self.allow_env.iter().map(String::as_str))
+            .for_each(|var| {
+                if let Some(value) = env::var_os(var) {
+                    command.env(var, value);
+                }
+            });
+        if let Some(tmp_dir) = &self.tmp_dir {
+            command.env("TMPDIR", tmp_dir);
+        }
+        command
+    }
+}
+
+fn on_path(program: &str) -> bool {
+    env::var_os("PATH")
+        .is_some_and(|paths| env::split_paths(&paths).any(|dir| dir.join(program).is_file()))
+}
@@ -0,0 +1,47 @@
+use std::sync::{Condvar, Mutex};
+
+/// A counting semaphore, limiting how many filter processes may run at once
+pub struct Semaphore {
+    permits: Mutex<usize>,
+    available: Condvar,
+}
+
+pub struct SemaphoreGuard<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Self {
+            permits: Mutex::new(permits),
+            available: Condvar::new(),
+        }
+    }
+
+    pub fn acquire(&self) -> SemaphoreGuard<'_> {
+        let mut permits = match self.permits.lock() {
+            Ok(permits) => permits,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        while *permits == 0 {
+            permits = match self.available.wait(permits) {
+                Ok(permits) => permits,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+        }
+        *permits -= 1;
+
+        SemaphoreGuard { semaphore: self }
+    }
+}
+
+impl Drop for SemaphoreGuard<'_> {
+    fn drop(&mut self) {
+        let mut permits = match self.semaphore.permits.lock() {
+            Ok(permits) => permits,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *permits += 1;
+        self.semaphore.available.notify_one();
+    }
+}
@@ -0,0 +1,67 @@
+use std::{fs::File, io::Read, path::Path};
+
+/// Bytes read from the start of a file for sniffing, enough for every signature `infer` checks
+const HEADER_SIZE: u64 = 8192;
+
+/// Guesses an extension for `path` from the magic bytes at the start of the file
+pub fn sniff_extension(path: &Path) -> Option<&'static str> {
+    let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+    File::open(path)
+        .ok()?
+        .take(HEADER_SIZE)
+        .read_to_end(&mut header)
+        .ok()?;
+
+    infer::get(&header).map(|kind| kind.extension())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sniff_extension;
+    use std::{
+        env::temp_dir,
+        fs::{self, create_dir_all, remove_dir_all},
+        process,
+    };
+
+    #[test]
+    fn common_signatures_are_recognised() {
+        let dir = temp_dir().join(format!("mirrorman-test-{0}-sniff", process::id()));
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+
+        let cases: &[(&[u8], Option<&str>)] = &[
+            (b"\x89PNG\r\n\x1a\n", Some("png")),
+            (b"\xff\xd8\xff\xe0", Some("jpg")),
+            (b"GIF87a", Some("gif")),
+            (b"GIF89a", Some("gif")),
+            (b"RIFF\0\0\0\0WEBPVP8 ", Some("webp")),
+            (b"RIFF\0\0\0\0WAVEfmt ", Some("wav")),
+            (b"%PDF-1.7", Some("pdf")),
+            (b"PK\x03\x04", Some("zip")),
+            (b"\x1f\x8b\x08", Some("gz")),
+            (b"fLaC", Some("flac")),
+            (b"OggS", Some("ogg")),
+            (b"ID3\x04", Some("mp3")),
+            (b"\xff\xfb\x90", Some("mp3")),
+            (b"\0\0\0\x20ftypM4A ", Some("m4a")),
+            (b"\0\0\0\x20ftypisom", Some("mp4")),
+            (b"\x1a\x45\xdf\xa3\x93\x42\x82\x88matroska", Some("mkv")),
+            (b"just some text", None),
+            (b"", None),
+        ];
+        for (index, (magic, extension)) in cases.iter().enumerate() {
+            let path = dir.join(index.to_string());
+            // Padded past the lengths signatures check, as a real file would be
+            let mut contents = magic.to_vec();
+            if !contents.is_empty() {
+                contents.resize(64, 0);
+            }
+            fs::write(&path, contents).unwrap();
+            assert_eq!(sniff_extension(&path), *extension, "{magic:?}");
+        }
+
+        assert_eq!(sniff_extension(&dir.join("missing")), None);
+        remove_dir_all(dir).unwrap();
+    }
+}
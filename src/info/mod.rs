@@ -0,0 +1,108 @@
+use crate::database::{Database, HASH_ALGORITHM};
+use anyhow::Result;
+use std::path::Path;
+
+/// Prints a summary of a database, from the database file alone
+pub fn info(database_path: &Path) -> Result<()> {
+    let database = Database::load(database_path)?;
+
+    let filters = match database.filter_programs() {
+        [] => "none".to_owned(),
+        filters => filters
+            .iter()
+            .map(|filter| {
+                let chained = std::iter::once(filter)
+                    .chain(database.filter_chain(filter))
+                    .map(String::as_str)
+                    .collect::<Vec<_>>()
+                    .join(" | ");
+                match database.filter_paths(filter) {
+                    [] => chained,
+                    paths => format!("{chained} ({0})", paths.join(", ")),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", "),
+    };
+    let transforms = match database.transforms() {
+        [] => "none".to_owned(),
+        transforms => transforms
+            .iter()
+            .map(|transform| transform.pattern.as_str())
+            .collect::<Vec<_>>()
+            .join(", "),
+    };
+
+    let rows = [
+        ("Database", database_path.display().to_string()),
+        ("Source", database.source_path().display().to_string()),
+        ("Mirror", database.mirror_path().display().to_string()),
+        ("Filters", filters),
+        (
+            "Filters dir",
+            database
+                .filters_dir()
+                .map_or("database's".to_owned(), |dir| dir.display().to_string()),
+        ),
+        ("Transforms", transforms),
+        (
+            "Layout",
+            match database.flatten() {
+                true => "flat",
+                false => "same as source",
+            }
+            .to_owned(),
+        ),
+        (
+            "Relative to",
+            database
+                .relative_to()
+                .map_or("source".to_owned(), |dir| dir.display().to_string()),
+        ),
+        (
+            "Cleanup",
+            match (database.archive_append(), database.owned_cleanup()) {
+                (true, _) => "never, the mirror is an append-only archive",
+                (false, true) => "only files written by mirrorman",
+                (false, false) => "anything not from the source",
+            }
+            .to_owned(),
+        ),
+        (
+            "Sync defaults",
+            match database.settings().describe() {
+                settings if settings.is_empty() => "none".to_owned(),
+                settings => settings.join(", "),
+            },
+        ),
+        (
+            "Mirror template",
+            database.mirror_template().unwrap_or("none").to_owned(),
+        ),
+        (
+            "Encryption key",
+            database
+                .encryption_key_file()
+                .map_or("none".to_owned(), |key| key.display().to_string()),
+        ),
+        ("Tracked files", database.tracked_files().to_string()),
+        (
+            "Tracked bytes",
+            database
+                .tracked_bytes()
+                .map_or("unknown until the next sync".to_owned(), |bytes| {
+                    bytes.to_string()
+                }),
+        ),
+        ("Collisions", database.collisions().len().to_string()),
+        ("Hash algorithm", HASH_ALGORITHM.to_owned()),
+        ("Format version", database.format_version().to_string()),
+    ];
+
+    let width = rows.iter().map(|(key, _)| key.len()).max().unwrap_or(0);
+    for (key, value) in rows {
+        println!("{key:>width$}: {value}");
+    }
+
+    Ok(())
+}
@@ -1,19 +1,73 @@
+mod archive;
+mod artifacts;
 mod database;
+mod doctor;
+mod encryption;
+mod estimate;
 mod filter;
+mod info;
+mod manifest;
+mod plan;
+mod priority;
+mod remote;
+mod report;
+mod transform;
+mod walk;
 
 use anyhow::{bail, Context, Result};
+use artifacts::OutputDir;
 use clap::{Parser, Subcommand};
-use database::{database_path_from_mirror, Database};
-use std::path::{Path, PathBuf};
+use database::{
+    database_folder, database_path_from_mirror, expand_env, has_db_ext, remote_files, Database,
+    DatabaseConfig, DatabaseLocation, DatabaseLock, EmptyDirs, HashCache, Owner, PreservedTime,
+    ProgressStyle, ReflinkMode, SyncOptions, SyncSettings, SyncStats, TimeBudget, Trace,
+    DB_EXT_VAR, DEFAULT_DB_EXT, TMPDIR_VAR,
+};
+use filter::{is_relative_path, Filter, FilterLog, Sandbox, SandboxMode};
+use manifest::Manifest;
+use plan::Plan;
+use remote::RemoteMirror;
+use report::Report;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::create_dir_all,
+    io::{self, stdin, stdout, Write},
+    num::NonZeroUsize,
+    panic::resume_unwind,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use transform::Transform;
+use walk::WalkdirResultExtension;
 use walkdir::WalkDir;
 
+/// Exit status of a sync which ran out of `--time-budget` before every file was mirrored
+const TIME_BUDGET_EXIT_CODE: i32 = 3;
+
 #[derive(Parser)]
 #[command(version)]
 struct Cli {
     #[command(subcommand)]
     cmd: Commands,
+
+    /// Increase logging verbosity (`-v` info, `-vv` debug, `-vvv` trace), overriding `RUST_LOG`
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Directory to create temporary files in, e.g. fast local scratch space when the mirror is
+    /// on a network mount (only used for files renamed into place if it's on the same file system)
+    #[arg(long, env = TMPDIR_VAR, global = true)]
+    tmpdir: Option<PathBuf>,
 }
 
+// Only ever parsed once, so there's nothing to gain from boxing the larger variants
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 pub enum Commands {
     /// Initialise a new database, taking files from `source_directory`, and copying them to
@@ -21,25 +75,708 @@ pub enum Commands {
     Init {
         /// Directory to take files and directory structure from when mirroring
         source_directory: PathBuf,
-        /// Directory to mirror to, all files will be copied or filtered to here
+        /// Directory to mirror to, all files will be copied or filtered to here, or an
+        /// `ssh://[user@]host[:port]/path` URL to mirror to over SFTP
         mirror_directory: PathBuf,
         /// A set of executable filter programs
         filters: Vec<String>,
+
+        /// Working directory to run a filter in, relative to the database, given as `{filter}={dir}`
+        #[arg(long = "filter-dir", value_name = "FILTER=DIR", value_parser = parse_filter_dir)]
+        filter_dirs: Vec<(String, PathBuf)>,
+
+        /// Restrict a filter to source files whose relative path matches a glob, given as
+        /// `{filter}={glob}`, and try it before any unrestricted filters (e.g. `./minify.sh=assets/**`)
+        #[arg(long = "filter-path", value_name = "FILTER=GLOB", value_parser = parse_filter_path)]
+        filter_paths: Vec<(String, String)>,
+
+        /// Pass a filter's output through further filters in turn, given as
+        /// `{filter},{next}[,{next}...]` (e.g. `./normalize.sh,./transcode.sh`). The first must be
+        /// one of the database's filters, and the mirror entry's extension comes from the last.
+        #[arg(long = "chain", value_name = "FILTER,NEXT", value_parser = parse_filter_chain)]
+        filter_chains: Vec<(String, Vec<String>)>,
+
+        /// Config file to pass to a filter in `MIRRORMAN_FILTER_CONFIG`, relative to the database,
+        /// given as `{filter}={path}`
+        #[arg(
+            long = "filter-args-file",
+            value_name = "FILTER=PATH",
+            value_parser = parse_filter_config
+        )]
+        filter_configs: Vec<(String, PathBuf)>,
+
+        /// Directory to find filters given as relative paths (e.g. `./transcode.sh`) in, stored
+        /// relative to the database. Defaults to the database's own directory
+        #[arg(long, value_name = "DIR")]
+        filters_dir: Option<PathBuf>,
+
+        /// Built-in transforms to apply to unfiltered files matching a glob, given as
+        /// `{glob}={transform}[,{transform}...]` (transforms: `crlf-to-lf`, `strip-bom`, `lowercase-ext`)
+        #[arg(short, long = "transform", value_name = "GLOB=TRANSFORMS")]
+        transforms: Vec<Transform>,
+
+        /// Template for mirrored file paths, relative to the mirror directory, using the tokens
+        /// `{parent}`, `{stem}`, `{ext}` and `{hash}` (e.g. `{parent}/{stem}_mirror.{ext}`)
+        #[arg(long, value_name = "TEMPLATE")]
+        mirror_template: Option<String>,
+
+        /// Mirror every file straight into the mirror directory, ignoring the source's directory
+        /// structure, with a hash suffix added to files which would share a name
+        #[arg(long, conflicts_with = "mirror_template")]
+        flatten: bool,
+
+        /// Lay files out in the mirror by their path relative to this directory, which must
+        /// contain the source, rather than relative to the source itself
+        #[arg(long, value_name = "DIR")]
+        relative_to: Option<PathBuf>,
+
+        /// Only ever clean up files this database wrote to the mirror, rather than anything
+        /// which doesn't come from the source, so the mirror directory can be shared
+        #[arg(long)]
+        owned_cleanup: bool,
+
+        /// Treat the mirror as an append-only archive: copy source files which aren't in it yet,
+        /// but never overwrite or remove anything, even once the source changes. Recorded in the
+        /// database, so every sync works this way
+        #[arg(long)]
+        archive_append: bool,
+
+        /// Encrypt mirrored files with a key derived from this file, relative to the database
+        #[arg(long, value_name = "PATH")]
+        encrypt_key_file: Option<PathBuf>,
+
+        /// Default `--filter-concurrency` for every sync of the database
+        #[arg(long, value_name = "N")]
+        filter_concurrency: Option<NonZeroUsize>,
+
+        /// Default `--max-errors` for every sync of the database
+        #[arg(long, value_name = "N")]
+        max_errors: Option<usize>,
+
+        /// Default `--preserve-times` for every sync of the database
+        #[arg(long, value_enum, value_delimiter = ',', value_name = "TIMES")]
+        preserve_times: Vec<PreservedTime>,
+
+        /// Sniff the type of files without an extension in every sync of the database
+        #[arg(long)]
+        sniff: bool,
+
+        /// Where to create the database, placing it in the mirror or source makes it travel with them
+        #[arg(long, value_enum, default_value_t = DatabaseLocation::Cwd)]
+        db_location: DatabaseLocation,
+
+        /// Adopt a mirror directory which isn't empty, after listing the files in it which don't
+        /// come from the source (and so would be removed) and asking for confirmation
+        #[arg(long)]
+        force_nonempty: bool,
+
+        /// Don't ask for confirmation before adopting a non-empty mirror directory
+        #[arg(short, long, requires = "force_nonempty")]
+        yes: bool,
+
+        /// Mirror a sample of the source to a scratch directory, and estimate the full mirror's
+        /// size and first sync time from it, without creating the database or mirror
+        #[arg(long)]
+        estimate: bool,
+
+        /// Number of source files to sample, used with `--estimate`
+        #[arg(long, value_name = "N", default_value_t = 100, requires = "estimate")]
+        sample_size: usize,
     },
     /// Syncs any databases (`.mmdb` files) in the current directory, or optionally one or many specific databases
     Sync {
-        /// An optional set of databases to explicitly sync
+        /// An optional set of databases to explicitly sync, or directories to sync all databases within
         databases: Vec<PathBuf>,
 
         /// Use recursive directory traversal
         #[arg(short, long)]
         recursive: bool,
+
+        /// Extension of the databases to look for in directories, databases given as files are
+        /// synced whatever their extension
+        #[arg(long, value_name = "EXT", env = DB_EXT_VAR, default_value = DEFAULT_DB_EXT)]
+        db_ext: String,
+
+        /// Buffer per-file log messages and output them sorted by path once syncing finishes
+        #[arg(long)]
+        ordered_log: bool,
+
+        /// Don't cross file system boundaries (e.g. mount points) when walking the source directory
+        #[arg(short = 'x', long)]
+        one_file_system: bool,
+
+        /// Maximum number of filter processes to run at once, independent of copying and hashing,
+        /// overriding the database's default
+        #[arg(long, value_name = "N")]
+        filter_concurrency: Option<NonZeroUsize>,
+
+        /// Check every filter of each database can be invoked and responds to the filter protocol
+        /// before syncing it, failing with a list of those which don't rather than part way through
+        #[arg(long)]
+        require_filters: bool,
+
+        /// Start by mirroring a couple of files at once, then keep doubling it while throughput
+        /// improves, settling on the fastest for the rest of the sync. Helps on storage whose best
+        /// parallelism isn't known
+        #[arg(long)]
+        concurrency_auto: bool,
+
+        /// Memory map files of 64 MiB or more to hash them, rather than reading them. Faster for
+        /// large files, but a source file truncated while it's hashed kills the sync
+        #[arg(long)]
+        mmap: bool,
+
+        /// Only mirror source files which were added or changed since this database was synced,
+        /// writing a changelog of what was added, changed and removed to the mirror root
+        #[arg(long, value_name = "DATABASE", conflicts_with_all = ["file_list", "plan_out", "confirm"])]
+        since_db: Option<PathBuf>,
+
+        /// Mirror to a fresh `{timestamp}` snapshot within this directory, rather than the mirror,
+        /// hard linking files unchanged since the last sync from the latest earlier snapshot
+        #[arg(long, value_name = "DIR", conflicts_with_all = ["file_list", "plan_out", "since_db"])]
+        snapshots: Option<PathBuf>,
+
+        /// How to display progress, defaults to `bar` in a terminal and `plain` otherwise, or
+        /// `none` when syncing databases in parallel
+        #[arg(long, value_enum)]
+        progress: Option<ProgressStyle>,
+
+        /// Maximum number of databases to sync at once, e.g. when they mirror to different drives
+        #[arg(long, value_name = "N", default_value = "1")]
+        parallel_databases: NonZeroUsize,
+
+        /// Print only a closing summary of the sync, with no progress or per-database output
+        #[arg(long, conflicts_with = "progress")]
+        summary_only: bool,
+
+        /// Print each database's statistics as a line of JSON, and nothing else, for scripts
+        #[arg(long, conflicts_with_all = ["progress", "summary_only", "confirm", "plan_out"])]
+        json: bool,
+
+        /// Work out and list what each sync will add, change and remove, and ask before going
+        /// ahead with it
+        #[arg(long, conflicts_with_all = ["summary_only", "file_list", "parallel_databases"])]
+        confirm: bool,
+
+        /// Work out what each sync would add, change and remove, and write it to this path as a
+        /// sorted plan with hashes, without syncing anything
+        #[arg(
+            long,
+            value_name = "PATH",
+            conflicts_with_all = ["confirm", "file_list", "init_if_missing", "manifest", "report", "output_dir"]
+        )]
+        plan_out: Option<PathBuf>,
+
+        /// Record every decision made while syncing to this path, as newline-delimited JSON
+        #[arg(long, value_name = "PATH")]
+        trace_file: Option<PathBuf>,
+
+        /// Reuse and record source file hashes in this file, which can be shared by databases
+        /// mirroring the same files. Entries are trusted while a file's size and modification
+        /// time are unchanged
+        #[arg(long, value_name = "PATH")]
+        hash_cache: Option<PathBuf>,
+
+        /// Record everything filters write to stdout and stderr to this file, tagged with the
+        /// files they were run on
+        #[arg(long, value_name = "PATH")]
+        filter_log: Option<PathBuf>,
+
+        /// Track a rolled-up hash of each directory's metadata, skipping re-hashing of files in
+        /// unchanged directories. The whole source is still walked and every file stat'ed to work
+        /// out the rollups, only reading file contents is saved
+        #[arg(long)]
+        merkle: bool,
+
+        /// Always hash the full contents of every source file, never trusting sizes or
+        /// modification times to skip any (e.g. after suspected corruption, or when restoring from
+        /// a backup has reset timestamps). This is the default unless `--merkle` is given, with
+        /// which directory rollups are still recorded for later syncs, but not used to skip files
+        #[arg(long)]
+        checksum: bool,
+
+        /// Clone unfiltered files with copy-on-write reflinks on supporting file systems
+        #[arg(long, value_enum, default_value_t = ReflinkMode::Never)]
+        reflink: ReflinkMode,
+
+        /// Detect the type of files without an extension from their magic bytes when choosing a
+        /// filter, by default only if the database was initialised with `--sniff`
+        #[arg(long, overrides_with = "no_sniff")]
+        sniff: bool,
+
+        /// Don't sniff files without an extension, even if the database does by default
+        #[arg(long, overrides_with = "sniff")]
+        no_sniff: bool,
+
+        /// Ask filters about extensions as they're spelled, rather than lowercased, so `.JPG`
+        /// files don't match a filter handling `jpg`
+        #[arg(long)]
+        case_sensitive_ext: bool,
+
+        /// Source timestamps to carry over to mirrored files, creation times can only be set on
+        /// Windows and macOS. Overrides the database's default.
+        #[arg(long, value_enum, value_delimiter = ',', value_name = "TIMES")]
+        preserve_times: Vec<PreservedTime>,
+
+        /// Give every mirrored file this owner, as `{user}:{group}`, `{user}` or `:{group}`, names
+        /// or numeric ids. Needs root (or `CAP_CHOWN`), files are left as they are otherwise.
+        #[arg(long, value_name = "USER:GROUP")]
+        chown: Option<Owner>,
+
+        /// Stop starting on new files once the sync has run this long (e.g. `2h` or `1h30m`),
+        /// finishing those in progress and saving the database, so the next sync picks up where
+        /// it left off. Exits with status 3 if any files were left
+        #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+        time_budget: Option<Duration>,
+
+        /// Never let the mirror grow past this size (e.g. `100GB` or `50GiB`), skipping new or
+        /// grown files which would take it over. Files already mirrored count towards it, and
+        /// are never removed to make room
+        #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+        max_total_size: Option<u64>,
+
+        /// Abort the sync once more than this many files have failed, overriding the database's
+        /// default, which is unlimited unless set at `init`
+        #[arg(long, value_name = "N")]
+        max_errors: Option<usize>,
+
+        /// Never overwrite mirror files which are newer than their source, warning instead
+        #[arg(long)]
+        newer_only: bool,
+
+        /// Lower the CPU priority of the sync (and its filters) by adding N to its current nice level,
+        /// Linux only
+        #[arg(long, value_name = "N", allow_hyphen_values = true)]
+        nice: Option<i32>,
+
+        /// Only use the disk when nothing else is (the idle IO scheduling class), Linux only
+        #[arg(long)]
+        idle_io: bool,
+
+        /// Log and skip source directories which can't be read (keeping their mirrors as they
+        /// are), rather than aborting the sync
+        #[arg(long)]
+        keep_going_on_walk_errors: bool,
+
+        /// Mirror temporary artifacts (swap files, partial downloads...) which are skipped by
+        /// default, see the README for the list
+        #[arg(long)]
+        no_default_ignores: bool,
+
+        /// Rename mirror entries which can't be created on Windows (reserved device names like
+        /// `aux`, trailing dots or spaces, characters like `:` or `?`), warning about each
+        #[arg(long)]
+        portable_names: bool,
+
+        /// Follow symlinked directories in the source, and hash each real file only once per sync,
+        /// however many symlinked paths lead to it (each path is still mirrored)
+        #[arg(long)]
+        dereference_db: bool,
+
+        /// If another process is already syncing a database, wait for it to finish rather than
+        /// failing
+        #[arg(long)]
+        wait: bool,
+
+        /// Remove every empty directory from the mirror, including those mirroring empty source
+        /// directories (by default, those are kept, and only directories emptied by cleanup are
+        /// removed)
+        #[arg(long, conflicts_with = "keep_empty_dirs")]
+        prune_empty_dirs: bool,
+
+        /// Never remove directories from the mirror, even once cleanup has emptied them
+        #[arg(long)]
+        keep_empty_dirs: bool,
+
+        /// Rebuild large (16 MiB+), unfiltered mirror files from the blocks of them the source
+        /// still holds, rsync-style, only reading the rest from the source
+        #[arg(long)]
+        delta: bool,
+
+        /// Read new and resized unfiltered files only once, hashing them while they're copied
+        #[arg(long)]
+        single_pass: bool,
+
+        /// Create the mirror's directories and empty placeholder files without copying or
+        /// filtering any contents, e.g. to check the layout before a long sync. The next sync
+        /// without it fills them in
+        #[arg(long)]
+        structure_only: bool,
+
+        /// Allow a sync to empty (or remove 90% or more of) a mirror which had files, which
+        /// otherwise aborts, in case the source isn't mounted
+        #[arg(long)]
+        empty_mirror_ok: bool,
+
+        /// Mirror only the files listed in this file (or stdin, if `-`), one per line and relative
+        /// to the source directory, without walking the source or cleaning up the mirror
+        #[arg(long, value_name = "PATH", conflicts_with = "init_if_missing")]
+        file_list: Option<PathBuf>,
+
+        /// Run filters in a restricted environment, see the README for what each mode restricts
+        #[arg(long, value_enum, value_name = "MODE")]
+        sandbox: Option<SandboxMode>,
+
+        /// Extra environment variables sandboxed filters may see
+        #[arg(long, value_name = "VAR", requires = "sandbox")]
+        sandbox_allow_env: Vec<String>,
+
+        /// Scratch directory sandboxed filters may write to, passed as `TMPDIR`
+        #[arg(long, value_name = "DIR", requires = "sandbox")]
+        sandbox_tmp_dir: Option<PathBuf>,
+
+        /// Write a sorted manifest (hash, size and path) of every mirrored file to this path
+        #[arg(long, value_name = "PATH")]
+        manifest: Option<PathBuf>,
+
+        /// Write a Markdown summary of the files added, changed, removed and failed to this path
+        #[arg(long, value_name = "PATH")]
+        report: Option<PathBuf>,
+
+        /// Write the manifest, report, trace and filter log to this directory, named after the
+        /// database and the time of the sync. Paths given to their own flags take precedence
+        #[arg(long, value_name = "DIR")]
+        output_dir: Option<PathBuf>,
+
+        /// Initialise the database for `--mirror` if it doesn't exist yet, otherwise sync it
+        #[arg(long, requires_all = ["source", "mirror"], conflicts_with = "databases")]
+        init_if_missing: bool,
+
+        /// Source directory to initialise from with `--init-if-missing`, or to look for the
+        /// database of `--mirror` in
+        #[arg(
+            long,
+            value_name = "DIR",
+            requires = "mirror",
+            conflicts_with = "databases"
+        )]
+        source: Option<PathBuf>,
+
+        /// Mirror directory to initialise with `--init-if-missing`, or whose database to sync,
+        /// found where `--db-location` places it, or failing that any of the other locations
+        #[arg(long, value_name = "DIR", conflicts_with = "databases")]
+        mirror: Option<PathBuf>,
+
+        /// Filters to initialise with, used with `--init-if-missing`
+        #[arg(long = "filter", value_name = "FILTER", requires = "init_if_missing")]
+        filters: Vec<String>,
+
+        /// Filter working directories to initialise with, used with `--init-if-missing`
+        #[arg(
+            long = "filter-dir",
+            value_name = "FILTER=DIR",
+            value_parser = parse_filter_dir,
+            requires = "init_if_missing"
+        )]
+        filter_dirs: Vec<(String, PathBuf)>,
+
+        /// Filter path restrictions to initialise with, used with `--init-if-missing`
+        #[arg(
+            long = "filter-path",
+            value_name = "FILTER=GLOB",
+            value_parser = parse_filter_path,
+            requires = "init_if_missing"
+        )]
+        filter_paths: Vec<(String, String)>,
+
+        /// Filter chains to initialise with, used with `--init-if-missing`
+        #[arg(
+            long = "chain",
+            value_name = "FILTER,NEXT",
+            value_parser = parse_filter_chain,
+            requires = "init_if_missing"
+        )]
+        filter_chains: Vec<(String, Vec<String>)>,
+
+        /// Filter config files to initialise with, used with `--init-if-missing`
+        #[arg(
+            long = "filter-args-file",
+            value_name = "FILTER=PATH",
+            value_parser = parse_filter_config,
+            requires = "init_if_missing"
+        )]
+        filter_configs: Vec<(String, PathBuf)>,
+
+        /// Directory relative filters are found in, used with `--init-if-missing`
+        #[arg(long, value_name = "DIR", requires = "init_if_missing")]
+        filters_dir: Option<PathBuf>,
+
+        /// Transforms to initialise with, used with `--init-if-missing`
+        #[arg(
+            long = "transform",
+            value_name = "GLOB=TRANSFORMS",
+            requires = "init_if_missing"
+        )]
+        transforms: Vec<Transform>,
+
+        /// Mirror path template to initialise with, used with `--init-if-missing`
+        #[arg(long, value_name = "TEMPLATE", requires = "init_if_missing")]
+        mirror_template: Option<String>,
+
+        /// Flatten the mirror when initialising, used with `--init-if-missing`
+        #[arg(long, requires = "init_if_missing", conflicts_with = "mirror_template")]
+        flatten: bool,
+
+        /// Directory mirror paths are relative to, used with `--init-if-missing`
+        #[arg(long, value_name = "DIR", requires = "init_if_missing")]
+        relative_to: Option<PathBuf>,
+
+        /// Only clean up files the database wrote, used with `--init-if-missing`
+        #[arg(long, requires = "init_if_missing")]
+        owned_cleanup: bool,
+
+        /// Make the mirror an append-only archive, used with `--init-if-missing`
+        #[arg(long, requires = "init_if_missing")]
+        archive_append: bool,
+
+        /// Encryption key file to initialise with, used with `--init-if-missing`
+        #[arg(long, value_name = "PATH", requires = "init_if_missing")]
+        encrypt_key_file: Option<PathBuf>,
+
+        /// Where to look for the database of `--mirror` first, and where `--init-if-missing`
+        /// creates it
+        #[arg(long, value_enum, default_value_t = DatabaseLocation::Cwd)]
+        db_location: DatabaseLocation,
+    },
+    /// Forgets all stored hashes, treating every file as new, and re-syncs the database
+    Rebuild {
+        /// The database to rebuild
+        database: PathBuf,
+
+        /// Empty the mirror directory before syncing
+        #[arg(long)]
+        clean: bool,
+
+        /// Don't ask for confirmation before emptying the mirror directory
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Re-attempts only the files which failed during the last sync of a database
+    Retry {
+        /// The database to retry failed files for
+        database: PathBuf,
+    },
+    /// Removes files from the mirror which no tracked source file maps to, without walking or
+    /// hashing the source, forgetting tracked files whose source has since been deleted
+    PurgeOrphans {
+        /// The database to purge the mirror of
+        database: PathBuf,
+
+        /// List the files which would be removed, without removing them
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Whether files without an extension were sniffed when syncing, as with `sync --sniff`
+        #[arg(long, overrides_with = "no_sniff")]
+        sniff: bool,
+
+        /// Whether sniffing was turned off when syncing, as with `sync --no-sniff`
+        #[arg(long, overrides_with = "sniff")]
+        no_sniff: bool,
+
+        /// Whether extensions were matched case-sensitively when syncing, as with
+        /// `sync --case-sensitive-ext`
+        #[arg(long)]
+        case_sensitive_ext: bool,
+
+        /// Whether mirror entries were renamed for Windows when syncing, as with
+        /// `sync --portable-names`
+        #[arg(long)]
+        portable_names: bool,
+
+        /// Allow purging to empty (or remove 90% or more of) a mirror which had files, in case
+        /// the source isn't mounted
+        #[arg(long)]
+        empty_mirror_ok: bool,
+    },
+    /// Diagnoses common problems with a database, its source, mirror and filters
+    Doctor {
+        /// The database to check
+        database: PathBuf,
+    },
+    /// Prints a summary of a database, without looking at its source or mirror
+    Info {
+        /// The database to summarise
+        database: PathBuf,
+    },
+    /// Decrypts every file in an encrypted mirror into `output_directory`
+    Decrypt {
+        /// The encrypted database to decrypt the mirror of
+        database: PathBuf,
+        /// Directory to write the decrypted files to
+        output_directory: PathBuf,
+    },
+    /// Re-hashes a mirror and checks it against a manifest written by `sync --manifest`, without
+    /// the source or database, listing missing, extra and mismatched files
+    VerifyManifest {
+        /// The manifest to check against
+        manifest: PathBuf,
+        /// The mirror directory (or copy of it) to check
+        mirror: PathBuf,
     },
     /// Outputs the example filter
     ExampleFilter,
+    /// Outputs the specification of the protocol filters follow
+    FilterSpec,
+}
+
+fn parse_filter_dir(s: &str) -> Result<(String, PathBuf)> {
+    let (filter, dir) = s.rsplit_once('=').with_context(|| {
+        format!("Invalid filter directory `{s}`, expected `{{filter}}={{dir}}`")
+    })?;
+    Ok((filter.to_owned(), PathBuf::from(dir)))
+}
+
+fn parse_filter_config(s: &str) -> Result<(String, PathBuf)> {
+    let (filter, path) = s.rsplit_once('=').with_context(|| {
+        format!("Invalid filter config file `{s}`, expected `{{filter}}={{path}}`")
+    })?;
+    Ok((filter.to_owned(), PathBuf::from(path)))
+}
+
+fn parse_filter_path(s: &str) -> Result<(String, String)> {
+    let (filter, pattern) = s
+        .rsplit_once('=')
+        .with_context(|| format!("Invalid filter path `{s}`, expected `{{filter}}={{glob}}`"))?;
+    Ok((filter.to_owned(), pattern.to_owned()))
+}
+
+fn parse_filter_chain(s: &str) -> Result<(String, Vec<String>)> {
+    let mut filters = s.split(',').map(str::to_owned);
+    match (filters.next(), filters.collect::<Vec<_>>()) {
+        (Some(filter), then) if !then.is_empty() && !s.split(',').any(str::is_empty) => {
+            Ok((filter, then))
+        }
+        _ => bail!("Invalid filter chain `{s}`, expected `{{filter}},{{next}}[,{{next}}...]`"),
+    }
+}
+
+/// Parses a duration made of `{n}h`, `{n}m` and `{n}s` parts, e.g. `2h` or `1h30m`, a bare number
+/// being seconds
+fn parse_duration(s: &str) -> Result<Duration> {
+    let invalid = || format!("Invalid duration `{s}`, expected e.g. `2h`, `90m` or `1h30m`");
+    let mut seconds = 0_u64;
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        bail!(invalid());
+    }
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let number = rest[..digits].parse::<u64>().with_context(invalid)?;
+        let (unit, remainder) = match rest[digits..].chars().next() {
+            Some('h') => (3600, &rest[digits + 1..]),
+            Some('m') => (60, &rest[digits + 1..]),
+            Some('s') => (1, &rest[digits + 1..]),
+            None => (1, ""),
+            Some(_) => bail!(invalid()),
+        };
+        seconds = number
+            .checked_mul(unit)
+            .and_then(|part| seconds.checked_add(part))
+            .with_context(invalid)?;
+        rest = remainder;
+    }
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Parses a size in bytes, optionally with a decimal (`KB`, `MB`, `GB`, `TB`) or binary (`KiB`,
+/// `MiB`, `GiB`, `TiB`) unit, e.g. `100GB`
+fn parse_size(s: &str) -> Result<u64> {
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(digits);
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1_000,
+        "m" | "mb" => 1_000_000,
+        "g" | "gb" => 1_000_000_000,
+        "t" | "tb" => 1_000_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => bail!(
+            "Invalid size `{s}`, expected a number of bytes with an optional unit, e.g. `100GB`"
+        ),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .with_context(|| format!("Invalid size `{s}`, expected a number of bytes with an optional unit, e.g. `100GB`"))
+}
+
+/// The choice made by a flag and its `--no-` form, `None` if neither was passed
+fn flag_choice(flag: bool, no_flag: bool) -> Option<bool> {
+    match (flag, no_flag) {
+        (false, false) => None,
+        (flag, _) => Some(flag),
+    }
 }
 
-fn init(source: &Path, mirror: &Path, filters: &[String]) -> Result<()> {
+/// Groups `{filter}={glob}` pairs by filter
+fn group_filter_paths(filter_paths: Vec<(String, String)>) -> BTreeMap<String, Vec<String>> {
+    let mut grouped = BTreeMap::<String, Vec<String>>::new();
+    for (filter, pattern) in filter_paths {
+        grouped.entry(filter).or_default().push(pattern);
+    }
+    grouped
+}
+
+/// What `--output-dir` names a sync's artifacts after: the database, when only one is synced, or
+/// `mirrorman` otherwise
+fn artifact_name(
+    databases: &[PathBuf],
+    db_ext: &str,
+    init_if_missing: bool,
+    mirror: Option<&Path>,
+) -> Result<String> {
+    let database = match (init_if_missing, mirror, databases) {
+        (true, Some(mirror), _) => database_path_from_mirror(mirror)?,
+        (false, _, [database]) if database.is_file() => database.clone(),
+        _ => return Ok("mirrorman".to_string()),
+    };
+    let name = database
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let stem = name
+        .strip_suffix(db_ext.trim_start_matches('.'))
+        .and_then(|stem| stem.strip_suffix('.'))
+        .unwrap_or(&name);
+    Ok(stem.to_string())
+}
+
+/// Reads a newline-separated `--file-list`, from stdin if `path` is `-`
+fn read_file_list(path: &Path) -> Result<Vec<PathBuf>> {
+    let contents = if path == Path::new("-") {
+        io::read_to_string(stdin()).with_context(|| "Failed to read file list from stdin")?
+    } else {
+        std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read file list `{0}`", path.display()))?
+    };
+    Ok(contents
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+fn init(
+    source: &Path,
+    mirror: &Path,
+    mut config: DatabaseConfig,
+    location: DatabaseLocation,
+    options: &SyncOptions,
+    force_nonempty: bool,
+    yes: bool,
+) -> Result<Option<(Database, SyncStats)>> {
+    let remote = RemoteMirror::parse(mirror)?;
+    if remote.is_some() && location == DatabaseLocation::Mirror {
+        bail!("Mirror `{0}` is on another host, so the database can't be kept inside it, choose another `--db-location`", mirror.display())
+    }
     if !source.exists() {
         bail!(
             "Invalid source directory, `{0}` does not exist.",
@@ -47,7 +784,32 @@ fn init(source: &Path, mirror: &Path, filters: &[String]) -> Result<()> {
         )
     }
 
-    let database_path = database_path_from_mirror(mirror)?;
+    if let Some(filter) = config
+        .filter_paths
+        .keys()
+        .find(|filter| !config.filters.contains(filter))
+    {
+        bail!("Filter `{filter}` is given a path with `--filter-path`, but isn't one of the database's filters");
+    }
+    if let Some(filter) = config
+        .filter_chains
+        .keys()
+        .find(|filter| !config.filters.contains(filter))
+    {
+        bail!("Filter `{filter}` starts a `--chain`, but isn't one of the database's filters");
+    }
+    let chained = config.filter_chains.values().flatten();
+    if let Some(filter) = config.filter_configs.keys().find(|filter| {
+        !config
+            .filters
+            .iter()
+            .chain(chained.clone())
+            .any(|f| f == *filter)
+    }) {
+        bail!("Filter `{filter}` is given a config file with `--filter-args-file`, but isn't one of the database's filters");
+    }
+
+    let database_path = location.database_path(source, mirror)?;
     if database_path.exists() {
         bail!(
             "Database file `{0}` already exists. Run `sync` instead.",
@@ -55,90 +817,650 @@ fn init(source: &Path, mirror: &Path, filters: &[String]) -> Result<()> {
         )
     }
 
-    if mirror.exists()
-        && mirror
-            .read_dir()
-            .with_context(|| "Failed to inspect mirror directory")?
-            .next()
-            .is_some()
+    let mirror_is_empty = match &remote {
+        Some(remote) => remote_files(remote, options)?
+            .list()
+            .with_context(|| format!("Failed to list remote mirror `{remote}`"))?
+            .is_empty(),
+        None => {
+            !mirror.exists()
+                || mirror
+                    .read_dir()
+                    .with_context(|| "Failed to inspect mirror directory")?
+                    .next()
+                    .is_none()
+        }
+    };
+    // Only cleaning up its own files (or none at all), nothing already in the mirror is at risk
+    let adopting_files = !mirror_is_empty && !config.owned_cleanup && !config.archive_append;
+    if adopting_files && !force_nonempty {
+        bail!("Mirror directory `{0}` is not empty, mirroring would erase all existing files. Mirrorman will now abort, if you really wish to proceed (are you sure?) please clear the directory and try again, or pass `--force-nonempty` to see which files would be removed first.", mirror.display())
+    }
+
+    // Paths are stored relative to a database kept in the mirror or source, so it travels with it
+    let database_folder = database_folder(&database_path)?;
+    create_dir_all(&database_folder).with_context(|| {
+        format!(
+            "Failed to create directory for database `{0}`",
+            database_path.display()
+        )
+    })?;
+    config.relative_to = config
+        .relative_to
+        .map(|relative_to| location.stored_path(&database_folder, &relative_to))
+        .transpose()?;
+    config.filters_dir = config
+        .filters_dir
+        .map(|filters_dir| location.stored_path(&database_folder, &filters_dir))
+        .transpose()?;
+    // Relative filters are found from the database rather than the current directory, so one
+    // given relative to the latter would only fail once the first file is filtered
+    let filters_root = match &config.filters_dir {
+        Some(filters_dir) => database_folder.join(filters_dir),
+        None => database_folder.clone(),
+    };
+    let chained = config.filter_chains.values().flatten();
+    for filter in config.filters.iter().chain(chained) {
+        let Ok(program) = expand_env(filter) else {
+            continue;
+        };
+        let resolved = Filter::resolve(&program, &filters_root, None, &database_folder).program;
+        if is_relative_path(&program) && !Path::new(&resolved).is_file() {
+            bail!("Filter `{filter}` wasn't found at `{resolved}`. Filters given as relative paths are found in the database's directory (`{0}`), or the directory given with `--filters-dir`, rather than the current directory.", database_folder.display());
+        }
+    }
+    // A remote mirror is the same wherever the database is kept
+    let mirror_path = match remote {
+        Some(_) => mirror.to_path_buf(),
+        None => location.stored_path(&database_folder, mirror)?,
+    };
+    let mut database = Database::new(
+        location.stored_path(&database_folder, source)?,
+        mirror_path,
+        config,
+    );
+    // Fails if the source isn't inside the directory mirror paths are relative to
+    database.mirror_prefix(&database_folder)?;
+    if adopting_files && !confirm_unmanaged_files(&database, &database_path, mirror, options, yes)?
     {
-        bail!("Mirror directory `{0}` is not empty, mirroring would erase all existing files. Mirrorman will now abort, if you really wish to proceed (are you sure?) please clear the directory and try again.", mirror.display())
+        println!("Aborting init.");
+        return Ok(None);
+    }
+
+    if !options.quiet() {
+        println!(
+            "Beginning first sync of database `{0}`...",
+            database_path.display()
+        );
+    }
+    let stats = database.sync(&database_path, options)?;
+    if options.json {
+        println!("{0}", stats.to_json()?);
+    }
+    if !options.quiet() {
+        println!("{stats:#}");
+        println!(
+            "`{1}` mirrored at `{2}` successfully! (Database created at `{0}`)",
+            database_path.display(),
+            source.display(),
+            mirror.display()
+        );
+    }
+
+    Ok(Some((database, stats)))
+}
+
+/// Syncs a database, returning `None` if it was skipped when asked to confirm the sync
+fn sync_database(
+    database_path: &Path,
+    options: &SyncOptions,
+) -> Result<Option<(Database, SyncStats)>> {
+    let _lock = DatabaseLock::acquire(database_path, options.wait)?;
+    let mut database = Database::load(database_path)?;
+    if options.confirm && !confirm_plan(&database, database_path, options)? {
+        println!("Skipping database `{0}`.", database_path.display());
+        return Ok(None);
+    }
+    if !options.quiet() {
+        println!("Syncing database `{0}`...", database_path.display());
     }
+    let stats = database.sync(database_path, options)?;
+    Ok(Some((database, stats)))
+}
 
-    let mut database = Database::new(source.to_path_buf(), mirror.to_path_buf(), filters.to_vec());
+/// Lists everything a sync of `database` would change, asking whether to go ahead
+fn confirm_plan(database: &Database, database_path: &Path, options: &SyncOptions) -> Result<bool> {
     println!(
-        "Beginning first sync of database `{0}`...",
+        "Planning sync of database `{0}`...",
         database_path.display()
     );
-    database.sync(&database_path)?;
+    let plan = database.plan(database_path, options)?;
+    if plan.added.is_empty() && plan.changed.is_empty() && plan.removed.is_empty() {
+        println!("Nothing to add, change or remove.");
+        return Ok(true);
+    }
 
+    let mirror_root = database.mirror_root(&database_folder(database_path)?);
+    for path in &plan.added {
+        println!("  + {0}", path.display());
+    }
+    for path in &plan.changed {
+        println!("  ~ {0}", path.display());
+    }
+    for path in &plan.removed {
+        println!("  - {0}", mirror_root.join(path).display());
+    }
     println!(
-        "`{1}` mirrored at `{2}` successfully! (Database created at `{0}`)",
-        database_path.display(),
-        source.display(),
-        mirror.display()
+        "{0} files to add, {1} to change, and {2} to remove from the mirror.",
+        plan.added.len(),
+        plan.changed.len(),
+        plan.removed.len()
     );
 
-    Ok(())
+    confirm("Go ahead with the sync?")
 }
 
-fn sync_database(database_path: &Path) -> Result<()> {
-    let mut database = Database::load(database_path)?;
-    println!("Syncing database `{0}`...", database_path.display());
-    database.sync(database_path)?;
+/// Adds a synced database to the manifest and report, if they're being written
+fn record_database(
+    database_path: &Path,
+    database: &Database,
+    stats: &SyncStats,
+    manifest: Option<&mut Manifest>,
+    report: Option<&mut Report>,
+) -> Result<()> {
+    if let Some(manifest) = manifest {
+        match database.remote_mirror()? {
+            Some(remote) => {
+                manifest.add_remote_mirror(&remote, &remote.connect()?, database.mirror_hashes())?
+            }
+            None => manifest.add_mirror(
+                &database.mirror_root(&database_folder(database_path)?),
+                database.mirror_hashes(),
+            )?,
+        }
+    }
+    if let Some(report) = report {
+        report.add_database(database_path, database, stats);
+    }
+
     Ok(())
 }
 
-fn sync(databases: Vec<PathBuf>, recursive: bool) -> Result<()> {
-    pretty_env_logger::init();
+/// A database for `sync` to sync
+struct SyncTarget {
+    path: PathBuf,
+    /// Named on the command line, so failing to sync it aborts rather than being logged and skipped
+    explicit: bool,
+}
 
-    if databases.is_empty() {
-        let mut any_db = false;
+/// Finds every database in `directory`
+fn find_databases(directory: &Path, recursive: bool, db_ext: &str) -> Result<Vec<SyncTarget>> {
+    let mut walkdir = WalkDir::new(directory);
+    if !recursive {
+        walkdir = walkdir.max_depth(1);
+    }
 
-        let mut walkdir = WalkDir::new(Path::new("."));
-        if !recursive {
-            walkdir = walkdir.max_depth(1);
+    let mut targets = Vec::new();
+    walkdir.into_iter().try_for_each(|entry| -> Result<()> {
+        let entry_path = entry.handle_to_string()?.into_path();
+        if entry_path.is_file() && has_db_ext(&entry_path, db_ext) {
+            targets.push(SyncTarget {
+                path: entry_path,
+                explicit: false,
+            });
         }
+        Ok(())
+    })?;
 
-        walkdir.into_iter().try_for_each(|entry| -> Result<()> {
-            let entry_path = entry?.into_path();
-            if entry_path.is_file() && entry_path.extension().unwrap_or_default() == "mmdb" {
-                if let Err(e) = sync_database(&entry_path) {
-                    log::error!(
-                        "Failed to syncronise database `{0}`: {e}",
-                        entry_path.display()
-                    );
-                }
+    Ok(targets)
+}
 
-                any_db = true;
+/// Syncs every target, up to `parallel` at once. Databases don't share any state other than
+/// these outputs while syncing, so ones on independent drives can overlap.
+fn sync_targets(
+    targets: &[SyncTarget],
+    parallel: usize,
+    options: &SyncOptions,
+    manifest: Option<&mut Manifest>,
+    report: Option<&mut Report>,
+) -> Result<()> {
+    let next = AtomicUsize::new(0);
+    let manifest = Mutex::new(manifest);
+    let report = Mutex::new(report);
+    let work = || -> Result<()> {
+        while let Some(target) = targets.get(next.fetch_add(1, Ordering::Relaxed)) {
+            let result = sync_database(&target.path, options).and_then(|synced| {
+                let Some((database, stats)) = synced else {
+                    return Ok(());
+                };
+                if options.json {
+                    println!("{0}", stats.to_json()?);
+                }
+                if !options.quiet() {
+                    match parallel {
+                        1 => println!("{stats:#}"),
+                        _ => println!("`{0}`: {stats:#}", target.path.display()),
+                    }
+                }
+                let mut manifest = match manifest.lock() {
+                    Ok(manifest) => manifest,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                let mut report = match report.lock() {
+                    Ok(report) => report,
+                    Err(poisoned) => poisoned.into_inner(),
+                };
+                record_database(
+                    &target.path,
+                    &database,
+                    &stats,
+                    manifest.as_deref_mut(),
+                    report.as_deref_mut(),
+                )
+            });
+            match result {
+                Ok(()) => {}
+                Err(e) if target.explicit => {
+                    // Don't start any more, the others finish what they're syncing
+                    next.store(targets.len(), Ordering::Relaxed);
+                    return Err(e);
+                }
+                Err(e) => log::error!(
+                    "Failed to syncronise database `{0}`: {e}",
+                    target.path.display()
+                ),
             }
-            Ok(())
-        })?;
+        }
+        Ok(())
+    };
 
-        if !any_db {
+    if parallel <= 1 {
+        return work();
+    }
+    thread::scope(|scope| {
+        let workers = (0..parallel.min(targets.len()))
+            .map(|_| scope.spawn(work))
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().unwrap_or_else(|panic| resume_unwind(panic)))
+    })
+}
+
+fn sync(
+    databases: Vec<PathBuf>,
+    recursive: bool,
+    db_ext: &str,
+    options: &SyncOptions,
+    parallel: usize,
+    manifest_path: Option<PathBuf>,
+    report_path: Option<PathBuf>,
+) -> Result<()> {
+    let mut manifest = manifest_path.as_ref().map(|_| Manifest::new());
+    // The summary is totalled from the report, even when it isn't written out
+    let mut report = (report_path.is_some() || options.summary_only).then(Report::new);
+
+    let targets = collect_targets(databases, recursive, db_ext)?;
+    if options.snapshots.is_some() && targets.len() > 1 {
+        bail!("`--snapshots` can only be used when syncing a single database");
+    }
+    sync_targets(
+        &targets,
+        parallel,
+        options,
+        manifest.as_mut(),
+        report.as_mut(),
+    )?;
+
+    if let (Some(manifest), Some(manifest_path)) = (manifest.as_mut(), manifest_path) {
+        manifest.write(&manifest_path)?;
+        if !options.quiet() {
+            println!("Manifest written to `{0}`", manifest_path.display());
+        }
+    }
+    if let (Some(report), Some(report_path)) = (&report, report_path) {
+        report.write(&report_path)?;
+        if !options.quiet() {
+            println!("Report written to `{0}`", report_path.display());
+        }
+    }
+
+    if let Some(trace) = &options.trace {
+        trace.flush()?;
+    }
+    if let Some(filter_log) = &options.filter_log {
+        filter_log.flush()?;
+    }
+    if let Some(hash_cache) = &options.hash_cache {
+        hash_cache.save(options.tmpdir.as_deref())?;
+    }
+
+    match report.filter(|_| options.summary_only) {
+        Some(report) => println!("{0}", report.summary()),
+        None if options.json => {}
+        None => println!("Sync complete!"),
+    }
+
+    Ok(())
+}
+
+/// The databases to sync, found in `databases` (or the current directory if there are none)
+fn collect_targets(
+    databases: Vec<PathBuf>,
+    recursive: bool,
+    db_ext: &str,
+) -> Result<Vec<SyncTarget>> {
+    let mut targets = Vec::new();
+    if databases.is_empty() {
+        targets = find_databases(Path::new("."), recursive, db_ext)?;
+        if targets.is_empty() {
             println!("No databases were found in the current directory to sync, are you in the right place?");
-            println!("[hint] I'm looking for `.mmdb` files...");
+            println!("[hint] I'm looking for `.{db_ext}` files...");
         }
     } else {
-        databases
-            .iter()
-            .try_for_each(|database_path| -> Result<()> {
-                if database_path.is_file()
-                    && database_path.extension().unwrap_or_default() == "mmdb"
-                {
-                    sync_database(database_path)?
-                } else {
-                    log::error!(
-                        "Invalid database file `{0}`, skipping...",
+        for database_path in databases {
+            if database_path.is_dir() {
+                let found = find_databases(&database_path, recursive, db_ext)?;
+                if found.is_empty() {
+                    log::warn!(
+                        "No databases were found in `{0}`, skipping...",
                         database_path.display()
-                    )
+                    );
                 }
-                Ok(())
-            })?;
+                targets.extend(found);
+            } else if database_path.is_file() {
+                targets.push(SyncTarget {
+                    path: database_path,
+                    explicit: true,
+                });
+            } else {
+                log::error!(
+                    "Invalid database file `{0}`, skipping...",
+                    database_path.display()
+                )
+            }
+        }
     }
+    // A database named twice would otherwise be locked against itself when syncing in parallel
+    let mut seen = BTreeSet::new();
+    targets.retain(|target| {
+        seen.insert(
+            target
+                .path
+                .canonicalize()
+                .unwrap_or_else(|_| target.path.clone()),
+        )
+    });
+    Ok(targets)
+}
 
-    println!("Sync complete!");
+/// Writes what syncing each target would change to `plan_path`, without syncing any of them
+fn write_plan(targets: &[SyncTarget], options: &SyncOptions, plan_path: &Path) -> Result<()> {
+    let mut plan = Plan::new();
+    for target in targets {
+        match plan_database(&mut plan, &target.path, options) {
+            Ok(()) => {}
+            Err(e) if target.explicit => return Err(e),
+            Err(e) => log::error!(
+                "Failed to plan sync of database `{0}`: {e}",
+                target.path.display()
+            ),
+        }
+    }
+    plan.write(plan_path)?;
+    println!("Plan written to `{0}`", plan_path.display());
+    if let Some(hash_cache) = &options.hash_cache {
+        hash_cache.save(options.tmpdir.as_deref())?;
+    }
+    Ok(())
+}
+
+fn plan_database(plan: &mut Plan, database_path: &Path, options: &SyncOptions) -> Result<()> {
+    let _lock = DatabaseLock::acquire(database_path, options.wait)?;
+    let database = Database::load(database_path)?;
+    println!(
+        "Planning sync of database `{0}`...",
+        database_path.display()
+    );
+    let changes = database.plan(database_path, options)?;
+    println!(
+        "{0} files to add, {1} to change, and {2} to remove from the mirror.",
+        changes.added.len(),
+        changes.changed.len(),
+        changes.removed.len()
+    );
+    let mirror_root = database.mirror_root(&database_folder(database_path)?);
+    plan.add_database(database_path, &database, &changes, &mirror_root)
+}
 
+fn init_or_sync(
+    source: &Path,
+    mirror: &Path,
+    config: DatabaseConfig,
+    location: DatabaseLocation,
+    options: &SyncOptions,
+    manifest_path: Option<PathBuf>,
+    report_path: Option<PathBuf>,
+) -> Result<()> {
+    // A database kept elsewhere than `location` is still synced, rather than duplicated
+    if let Some(database_path) = location.find_database(Some(source), mirror)? {
+        return sync(
+            vec![database_path],
+            false,
+            DEFAULT_DB_EXT,
+            options,
+            1,
+            manifest_path,
+            report_path,
+        );
+    }
+
+    let database_path = location.database_path(source, mirror)?;
+    let mut manifest = manifest_path.as_ref().map(|_| Manifest::new());
+    let mut report = (report_path.is_some() || options.summary_only).then(Report::new);
+    let initialised = init(source, mirror, config, location, options, false, false)?;
+    if let Some(hash_cache) = &options.hash_cache {
+        hash_cache.save(options.tmpdir.as_deref())?;
+    }
+    let Some((database, stats)) = initialised else {
+        return Ok(());
+    };
+    record_database(
+        &database_path,
+        &database,
+        &stats,
+        manifest.as_mut(),
+        report.as_mut(),
+    )?;
+
+    if let (Some(manifest), Some(manifest_path)) = (manifest.as_mut(), manifest_path) {
+        manifest.write(&manifest_path)?;
+        if !options.quiet() {
+            println!("Manifest written to `{0}`", manifest_path.display());
+        }
+    }
+    if let Some(report) = report {
+        if let Some(report_path) = report_path {
+            report.write(&report_path)?;
+            if !options.quiet() {
+                println!("Report written to `{0}`", report_path.display());
+            }
+        }
+        if options.summary_only {
+            println!("{0}", report.summary());
+        }
+    }
+
+    Ok(())
+}
+
+/// Lists files in a non-empty mirror which the first sync would remove, asking whether to go ahead
+fn confirm_unmanaged_files(
+    database: &Database,
+    database_path: &Path,
+    mirror: &Path,
+    options: &SyncOptions,
+    yes: bool,
+) -> Result<bool> {
+    const SAMPLE_SIZE: usize = 10;
+
+    let unmanaged = database.unmanaged_mirror_files(database_path, options)?;
+    if unmanaged.is_empty() {
+        println!(
+            "Every file already in `{0}` comes from the source, nothing will be removed.",
+            mirror.display()
+        );
+        return Ok(true);
+    }
+
+    println!(
+        "{0} files already in `{1}` don't come from the source, and will be removed by the first sync:",
+        unmanaged.len(),
+        mirror.display()
+    );
+    for path in unmanaged.iter().take(SAMPLE_SIZE) {
+        println!("  {0}", path.display());
+    }
+    if unmanaged.len() > SAMPLE_SIZE {
+        println!("  ...and {0} more", unmanaged.len() - SAMPLE_SIZE);
+    }
+    println!(
+        "[hint] To keep any of them, list them in a `.mmdbignore` file at the root of the mirror"
+    );
+
+    Ok(yes || confirm("Continue?")?)
+}
+
+fn confirm(prompt: &str) -> Result<bool> {
+    print!("{prompt} [y/N] ");
+    stdout()
+        .flush()
+        .with_context(|| "Failed to flush output buffer")?;
+
+    let mut answer = String::new();
+    stdin()
+        .read_line(&mut answer)
+        .with_context(|| "Failed to read confirmation")?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+fn rebuild(database_path: &Path, clean: bool, yes: bool, tmpdir: Option<PathBuf>) -> Result<()> {
+    let _lock = DatabaseLock::acquire(database_path, false)?;
+    let mut database = Database::load(database_path)?;
+
+    if clean {
+        database.require_local_mirror("rebuild --clean")?;
+        let mirror_root = database.mirror_root(&database_folder(database_path)?);
+        if !yes
+            && !confirm(&format!(
+            "This will delete everything in `{0}` not protected by its `.mmdbignore`, continue?",
+            mirror_root.display()
+        ))? {
+            println!("Aborting rebuild.");
+            return Ok(());
+        }
+
+        database.clear_mirror(database_path)?;
+    }
+
+    database.clear_hashes();
+    println!("Rebuilding database `{0}`...", database_path.display());
+    let options = SyncOptions {
+        tmpdir,
+        ..Default::default()
+    };
+    let stats = database.sync(database_path, &options)?;
+    println!("{stats:#}");
+    println!("Rebuild complete!");
+
+    Ok(())
+}
+
+fn retry(database_path: &Path, tmpdir: Option<PathBuf>) -> Result<()> {
+    let _lock = DatabaseLock::acquire(database_path, false)?;
+    let mut database = Database::load(database_path)?;
+    if database.failed().is_empty() {
+        println!("No failed files to retry.");
+        return Ok(());
+    }
+
+    println!(
+        "Retrying {0} failed files from database `{1}`...",
+        database.failed().len(),
+        database_path.display()
+    );
+    let options = SyncOptions {
+        tmpdir,
+        ..Default::default()
+    };
+    let stats = database.retry(database_path, &options)?;
+    println!("{stats:#}");
+    match database.failed().len() {
+        0 => println!("All failed files have been mirrored!"),
+        remaining => println!("{remaining} files are still failing."),
+    }
+
+    Ok(())
+}
+
+fn purge_orphans(database_path: &Path, options: &SyncOptions, dry_run: bool) -> Result<()> {
+    let _lock = DatabaseLock::acquire(database_path, false)?;
+    let mut database = Database::load(database_path)?;
+    let mirror_root = database.mirror_root(&database_folder(database_path)?);
+
+    let orphans = database.purge_orphans(database_path, options, dry_run)?;
+    if dry_run {
+        for orphan in &orphans {
+            println!("Would remove `{0}`", mirror_root.join(orphan).display());
+        }
+        println!("{0} orphaned files would be removed.", orphans.len());
+    } else {
+        println!(
+            "Removed {0} orphaned files from `{1}`.",
+            orphans.len(),
+            mirror_root.display()
+        );
+    }
+    Ok(())
+}
+
+fn decrypt(database_path: &Path, output_directory: &Path) -> Result<()> {
+    let database = Database::load(database_path)?;
+    println!(
+        "Decrypting database `{0}` to `{1}`...",
+        database_path.display(),
+        output_directory.display()
+    );
+    let count = database.decrypt(database_path, output_directory)?;
+    println!("Decrypted {count} files.");
+    Ok(())
+}
+
+fn verify_manifest(manifest_path: &Path, mirror: &Path) -> Result<()> {
+    println!(
+        "Verifying `{0}` against manifest `{1}`...",
+        mirror.display(),
+        manifest_path.display()
+    );
+    let diff = Manifest::read(manifest_path)?.verify(mirror)?;
+    for path in &diff.missing {
+        println!("Missing: `{0}`", path.display());
+    }
+    for path in &diff.extra {
+        println!("Extra: `{0}`", path.display());
+    }
+    for path in &diff.mismatched {
+        println!("Mismatched: `{0}`", path.display());
+    }
+    if !diff.is_empty() {
+        bail!(
+            "Mirror doesn't match the manifest: {0} missing, {1} extra and {2} mismatched files",
+            diff.missing.len(),
+            diff.extra.len(),
+            diff.mismatched.len()
+        );
+    }
+    println!("All {0} files match the manifest.", diff.matched);
     Ok(())
 }
 
@@ -147,19 +1469,350 @@ fn example_filter() -> Result<()> {
     Ok(())
 }
 
+fn filter_spec() -> Result<()> {
+    println!("{}", include_str!("../filter_spec.md"));
+    Ok(())
+}
+
+fn init_logger(verbose: u8) {
+    let level = match verbose {
+        0 => {
+            pretty_env_logger::init();
+            return;
+        }
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    pretty_env_logger::formatted_builder()
+        .filter_level(level)
+        .init();
+}
+
 fn main() -> Result<()> {
     let args = Cli::parse();
+    init_logger(args.verbose);
 
     match args.cmd {
         Commands::Init {
             source_directory,
             mirror_directory,
             filters,
-        } => init(&source_directory, &mirror_directory, &filters),
+            filter_dirs,
+            filter_paths,
+            filter_chains,
+            filter_configs,
+            filters_dir,
+            transforms,
+            mirror_template,
+            flatten,
+            relative_to,
+            owned_cleanup,
+            archive_append,
+            encrypt_key_file,
+            filter_concurrency,
+            max_errors,
+            preserve_times,
+            sniff,
+            db_location,
+            force_nonempty,
+            yes,
+            estimate,
+            sample_size,
+        } => {
+            let config = DatabaseConfig {
+                filters,
+                filter_dirs: filter_dirs.into_iter().collect(),
+                filter_paths: group_filter_paths(filter_paths),
+                filter_chains: filter_chains.into_iter().collect(),
+                filter_configs: filter_configs.into_iter().collect(),
+                filters_dir,
+                transforms,
+                mirror_template,
+                flatten,
+                relative_to,
+                owned_cleanup,
+                archive_append,
+                encryption_key_file: encrypt_key_file,
+                settings: SyncSettings {
+                    filter_concurrency: filter_concurrency.map(NonZeroUsize::get),
+                    max_errors,
+                    preserve_times,
+                    sniff,
+                },
+            };
+            if estimate {
+                let database_path =
+                    db_location.database_path(&source_directory, &mirror_directory)?;
+                return estimate::estimate(&source_directory, &database_path, config, sample_size);
+            }
+            init(
+                &source_directory,
+                &mirror_directory,
+                config,
+                db_location,
+                &SyncOptions {
+                    tmpdir: args.tmpdir,
+                    ..Default::default()
+                },
+                force_nonempty,
+                yes,
+            )
+            .map(|_| ())
+        }
         Commands::Sync {
             databases,
             recursive,
-        } => sync(databases, recursive),
+            db_ext,
+            ordered_log,
+            one_file_system,
+            filter_concurrency,
+            require_filters,
+            concurrency_auto,
+            mmap,
+            since_db,
+            snapshots,
+            progress,
+            parallel_databases,
+            summary_only,
+            json,
+            confirm,
+            plan_out,
+            trace_file,
+            hash_cache,
+            filter_log,
+            merkle,
+            checksum,
+            reflink,
+            sniff,
+            no_sniff,
+            case_sensitive_ext,
+            preserve_times,
+            chown,
+            time_budget,
+            max_total_size,
+            max_errors,
+            newer_only,
+            nice,
+            idle_io,
+            keep_going_on_walk_errors,
+            no_default_ignores,
+            portable_names,
+            dereference_db,
+            wait,
+            prune_empty_dirs,
+            keep_empty_dirs,
+            delta,
+            single_pass,
+            structure_only,
+            empty_mirror_ok,
+            file_list,
+            sandbox,
+            sandbox_allow_env,
+            sandbox_tmp_dir,
+            manifest,
+            report,
+            output_dir,
+            init_if_missing,
+            source,
+            mirror,
+            filters,
+            filter_dirs,
+            filter_paths,
+            filter_chains,
+            filter_configs,
+            filters_dir,
+            transforms,
+            mirror_template,
+            flatten,
+            relative_to,
+            owned_cleanup,
+            archive_append,
+            encrypt_key_file,
+            db_location,
+        } => {
+            priority::lower_priority(nice, idle_io)?;
+            let output_dir = output_dir
+                .map(|directory| {
+                    let name =
+                        artifact_name(&databases, &db_ext, init_if_missing, mirror.as_deref())?;
+                    OutputDir::create(&directory, &name)
+                })
+                .transpose()?;
+            let artifact = |path: Option<PathBuf>, artifact: &str| {
+                path.or_else(|| {
+                    output_dir
+                        .as_ref()
+                        .map(|output_dir| output_dir.path(artifact))
+                })
+            };
+            let trace_file = artifact(trace_file, "trace.jsonl");
+            let filter_log = artifact(filter_log, "filters.log");
+            let manifest = artifact(manifest, "manifest.txt");
+            let report = artifact(report, "report.md");
+            let options = SyncOptions {
+                ordered_log,
+                one_file_system,
+                filter_concurrency: filter_concurrency.map(NonZeroUsize::get),
+                require_filters,
+                concurrency_auto,
+                mmap,
+                // Several live progress displays would garble each other
+                progress: match (summary_only || json, parallel_databases.get()) {
+                    (true, _) => ProgressStyle::None,
+                    (false, 1) => progress.unwrap_or_default(),
+                    (false, _) => progress.unwrap_or(ProgressStyle::None),
+                },
+                summary_only,
+                json,
+                confirm,
+                trace: trace_file.as_deref().map(Trace::create).transpose()?,
+                filter_log: filter_log.as_deref().map(FilterLog::create).transpose()?,
+                merkle,
+                checksum,
+                hash_cache: hash_cache.as_deref().map(HashCache::load).transpose()?,
+                reflink,
+                sniff: flag_choice(sniff, no_sniff),
+                case_sensitive_ext,
+                preserve_times,
+                chown,
+                max_total_size,
+                time_budget: time_budget.map(TimeBudget::new),
+                max_errors,
+                newer_only,
+                keep_going_on_walk_errors,
+                no_default_ignores,
+                portable_names,
+                dereference_db,
+                wait,
+                empty_dirs: match (prune_empty_dirs, keep_empty_dirs) {
+                    (true, _) => EmptyDirs::Prune,
+                    (_, true) => EmptyDirs::Keep,
+                    _ => EmptyDirs::Mirror,
+                },
+                delta,
+                single_pass,
+                structure_only,
+                empty_mirror_ok,
+                sandbox: sandbox.map(|mode| Sandbox {
+                    mode,
+                    allow_env: sandbox_allow_env,
+                    tmp_dir: sandbox_tmp_dir,
+                    writable: Vec::new(),
+                }),
+                tmpdir: args.tmpdir,
+                since_db,
+                snapshots,
+                file_list: file_list.as_deref().map(read_file_list).transpose()?,
+                // The command line always hashes with SHA-256 and runs filters as programs
+                ..Default::default()
+            };
+            // Without `--init-if-missing`, `--mirror` names the one database to sync
+            let databases = match (init_if_missing, &mirror) {
+                (false, Some(mirror)) => {
+                    let database_path = db_location
+                        .find_database(source.as_deref(), mirror)?
+                        .with_context(|| {
+                            format!(
+                                "No database mirroring to `{0}` was found in the current directory, the mirror or the source (if given with `--source`). Pass `--init-if-missing` to create one.",
+                                mirror.display()
+                            )
+                        })?;
+                    vec![database_path]
+                }
+                _ => databases,
+            };
+            match (init_if_missing, source, mirror) {
+                (true, Some(source), Some(mirror)) => {
+                    let config = DatabaseConfig {
+                        filters,
+                        filter_dirs: filter_dirs.into_iter().collect(),
+                        filter_paths: group_filter_paths(filter_paths),
+                        filter_chains: filter_chains.into_iter().collect(),
+                        filter_configs: filter_configs.into_iter().collect(),
+                        filters_dir,
+                        transforms,
+                        mirror_template,
+                        flatten,
+                        relative_to,
+                        owned_cleanup,
+                        archive_append,
+                        encryption_key_file: encrypt_key_file,
+                        // Kept for later syncs, as `init` keeps them
+                        settings: SyncSettings {
+                            filter_concurrency: options.filter_concurrency,
+                            max_errors: options.max_errors,
+                            preserve_times: options.preserve_times.clone(),
+                            sniff: options.sniff.unwrap_or_default(),
+                        },
+                    };
+                    init_or_sync(
+                        &source,
+                        &mirror,
+                        config,
+                        db_location,
+                        &options,
+                        manifest,
+                        report,
+                    )?
+                }
+                _ => match plan_out {
+                    Some(plan_out) => write_plan(
+                        &collect_targets(databases, recursive, &db_ext)?,
+                        &options,
+                        &plan_out,
+                    )?,
+                    None => sync(
+                        databases,
+                        recursive,
+                        &db_ext,
+                        &options,
+                        parallel_databases.get(),
+                        manifest,
+                        report,
+                    )?,
+                },
+            }
+            let deferred = options.time_budget.as_ref().map_or(0, TimeBudget::deferred);
+            if deferred > 0 {
+                println!("Time budget ran out, {deferred} entries were left for the next sync.");
+                process::exit(TIME_BUDGET_EXIT_CODE);
+            }
+            Ok(())
+        }
+        Commands::Rebuild {
+            database,
+            clean,
+            yes,
+        } => rebuild(&database, clean, yes, args.tmpdir),
+        Commands::Retry { database } => retry(&database, args.tmpdir),
+        Commands::PurgeOrphans {
+            database,
+            dry_run,
+            sniff,
+            no_sniff,
+            case_sensitive_ext,
+            portable_names,
+            empty_mirror_ok,
+        } => {
+            let options = SyncOptions {
+                sniff: flag_choice(sniff, no_sniff),
+                case_sensitive_ext,
+                portable_names,
+                empty_mirror_ok,
+                tmpdir: args.tmpdir,
+                ..Default::default()
+            };
+            purge_orphans(&database, &options, dry_run)
+        }
+        Commands::Doctor { database } => doctor::doctor(&database),
+        Commands::Info { database } => info::info(&database),
+        Commands::Decrypt {
+            database,
+            output_directory,
+        } => decrypt(&database, &output_directory),
+        Commands::VerifyManifest { manifest, mirror } => verify_manifest(&manifest, &mirror),
         Commands::ExampleFilter => example_filter(),
+        Commands::FilterSpec => filter_spec(),
     }
 }
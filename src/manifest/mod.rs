@@ -0,0 +1,178 @@
+mod verify;
+
+use crate::{
+    database::hash_file,
+    remote::{RemoteFiles, RemoteMirror},
+};
+use anyhow::{Context, Result};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+pub struct ManifestEntry {
+    pub path: PathBuf,
+    pub hash: String,
+    pub size: u64,
+}
+
+/// A listing of every file in one or more mirrors, written out as a stable, sorted text file
+/// (`{hash}  {size}  {path}` per line, paths relative to their mirror's root) suitable for
+/// auditing or signing.
+#[derive(Default)]
+pub struct Manifest {
+    entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds every file in the mirror at `mirror_root`, keyed relative to it so the manifest is the
+    /// same wherever the mirror lives. Files listed in `known_hashes` (e.g. by the sync that just
+    /// wrote them) with the size they still have aren't read again.
+    pub fn add_mirror(
+        &mut self,
+        mirror_root: &Path,
+        known_hashes: &BTreeMap<PathBuf, (String, u64)>,
+    ) -> Result<()> {
+        let files = WalkDir::new(mirror_root)
+            .into_iter()
+            .filter_map(|entry| match entry {
+                Ok(entry) if !entry.file_type().is_file() => None,
+                Ok(entry) => Some(Ok(entry)),
+                Err(e) => Some(Err(e).with_context(|| {
+                    format!("Failed to read mirror `{0}`", mirror_root.display())
+                })),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let entries = files
+            .into_par_iter()
+            .map(|entry| -> Result<ManifestEntry> {
+                let size = entry
+                    .metadata()
+                    .with_context(|| {
+                        format!("Failed to read metadata of `{0}`", entry.path().display())
+                    })?
+                    .len();
+                let path = entry.path().strip_prefix(mirror_root)?.to_path_buf();
+                let hash = match known_hashes.get(&path) {
+                    Some((hash, known_size)) if *known_size == size => hash.clone(),
+                    _ => hash_file(entry.path())?,
+                };
+                Ok(ManifestEntry { path, hash, size })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    /// Adds every file in a remote mirror as [`Self::add_mirror`] does, hashing those which aren't
+    /// known on the host
+    pub fn add_remote_mirror(
+        &mut self,
+        remote: &RemoteMirror,
+        files: &dyn RemoteFiles,
+        known_hashes: &BTreeMap<PathBuf, (String, u64)>,
+    ) -> Result<()> {
+        let listing = files
+            .list()
+            .with_context(|| format!("Failed to read mirror `{remote}`"))?;
+        for entry in listing.into_iter().filter(|entry| !entry.is_dir) {
+            let hash = match known_hashes.get(&entry.path) {
+                Some((hash, known_size)) if *known_size == entry.size => hash.clone(),
+                _ => files
+                    .hash_file(&entry.path)
+                    .with_context(|| format!("Failed to hash `{0}`", remote.url_of(&entry.path)))?,
+            };
+            self.entries.push(ManifestEntry {
+                path: entry.path,
+                hash,
+                size: entry.size,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn write(&mut self, file_path: &Path) -> Result<()> {
+        self.entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let file = File::create(file_path)
+            .with_context(|| format!("Failed to open {0} for writing", file_path.display()))?;
+        let mut writer = BufWriter::new(file);
+        for entry in &self.entries {
+            writeln!(
+                writer,
+                "{0}  {1}  {2}",
+                entry.hash,
+                entry.size,
+                entry.path.display()
+            )
+            .with_context(|| format!("Failed to write manifest `{0}`", file_path.display()))?;
+        }
+        writer
+            .flush()
+            .with_context(|| format!("Failed to write manifest `{0}`", file_path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Manifest;
+    use crate::database::{hash_file, Database, DatabaseConfig, ProgressStyle, SyncOptions};
+    use std::{
+        env::temp_dir,
+        fs::{self, create_dir_all, remove_dir_all},
+        path::Path,
+        process,
+    };
+
+    #[test]
+    fn manifests_are_relative_to_the_mirror_and_reuse_sync_hashes() {
+        let root = temp_dir().join(format!("mirrorman-test-{0}-manifest", process::id()));
+        let _ = remove_dir_all(&root);
+        create_dir_all(root.join("source/nested")).unwrap();
+        fs::write(root.join("source/a.txt"), "a").unwrap();
+        fs::write(root.join("source/nested/b.txt"), "bb").unwrap();
+        let mut database =
+            Database::new("source".into(), "mirror".into(), DatabaseConfig::default());
+        let options = SyncOptions {
+            progress: ProgressStyle::None,
+            ..Default::default()
+        };
+        database.sync(&root.join("test.mmdb"), &options).unwrap();
+        assert_eq!(
+            database.mirror_hashes()[Path::new("nested/b.txt")],
+            (hash_file(&root.join("source/nested/b.txt")).unwrap(), 2)
+        );
+
+        let mut manifest = Manifest::new();
+        manifest
+            .add_mirror(&root.join("mirror"), database.mirror_hashes())
+            .unwrap();
+        manifest.write(&root.join("manifest.txt")).unwrap();
+        let written = fs::read_to_string(root.join("manifest.txt")).unwrap();
+        let paths = written
+            .lines()
+            .map(|line| line.splitn(3, "  ").last().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["a.txt", "nested/b.txt"]);
+
+        // A restored copy elsewhere matches, until a file in it changes
+        fs::rename(root.join("mirror"), root.join("restored")).unwrap();
+        let manifest = Manifest::read(&root.join("manifest.txt")).unwrap();
+        let diff = manifest.verify(&root.join("restored")).unwrap();
+        assert!(diff.is_empty());
+        assert_eq!(diff.matched, 2);
+        fs::write(root.join("restored/a.txt"), "b").unwrap();
+        let diff = manifest.verify(&root.join("restored")).unwrap();
+        assert_eq!(diff.mismatched, [Path::new("a.txt")]);
+        remove_dir_all(root).unwrap();
+    }
+}
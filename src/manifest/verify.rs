@@ -0,0 +1,161 @@
+use super::{Manifest, ManifestEntry};
+use crate::database::hash_file;
+use anyhow::{Context, Result};
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+/// How a mirror differs from a manifest, with paths relative to the mirror
+#[derive(Default)]
+pub struct ManifestDiff {
+    pub missing: Vec<PathBuf>,
+    pub extra: Vec<PathBuf>,
+    pub mismatched: Vec<PathBuf>,
+    /// Files whose size and hash match the manifest
+    pub matched: usize,
+}
+
+impl ManifestDiff {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+impl Manifest {
+    /// Reads a manifest written by [`Manifest::write`]
+    pub fn read(file_path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(file_path)
+            .with_context(|| format!("Failed to read manifest `{0}`", file_path.display()))?;
+        let entries = contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(number, line)| {
+                let mut fields = line.splitn(3, "  ");
+                let entry = match (fields.next(), fields.next(), fields.next()) {
+                    (Some(hash), Some(size), Some(path)) => size.parse().ok().map(|size| ManifestEntry {
+                        path: PathBuf::from(path),
+                        hash: hash.to_string(),
+                        size,
+                    }),
+                    _ => None,
+                };
+                entry.with_context(|| {
+                    format!(
+                        "Invalid line {0} in manifest `{1}`, expected `{{hash}}  {{size}}  {{path}}`",
+                        number + 1,
+                        file_path.display()
+                    )
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { entries })
+    }
+
+    /// Re-hashes every file in `mirror_path` and compares it with the manifest, which may have
+    /// been written for the mirror at another path, e.g. on another machine
+    pub fn verify(&self, mirror_path: &Path) -> Result<ManifestDiff> {
+        // Manifests used to record absolute paths, which have to be mapped back onto the mirror
+        let recorded_root = match self.entries.iter().any(|entry| entry.path.is_absolute()) {
+            true => self.recorded_root(mirror_path),
+            false => PathBuf::new(),
+        };
+        let expected = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let relative = entry
+                    .path
+                    .strip_prefix(&recorded_root)
+                    .unwrap_or(&entry.path);
+                (relative.to_path_buf(), entry)
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let mut actual = BTreeSet::new();
+        for entry in WalkDir::new(mirror_path) {
+            let entry = entry
+                .with_context(|| format!("Failed to read mirror `{0}`", mirror_path.display()))?;
+            if entry.file_type().is_file() {
+                let relative = entry.path().strip_prefix(mirror_path)?;
+                actual.insert(relative.to_path_buf());
+            }
+        }
+
+        let mut diff = ManifestDiff {
+            missing: expected
+                .keys()
+                .filter(|path| !actual.contains(*path))
+                .cloned()
+                .collect(),
+            extra: actual
+                .iter()
+                .filter(|path| !expected.contains_key(*path))
+                .cloned()
+                .collect(),
+            ..Default::default()
+        };
+        let compared = expected
+            .into_iter()
+            .filter(|(path, _)| actual.contains(path))
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|(path, entry)| {
+                let file = mirror_path.join(&path);
+                let size = fs::metadata(&file)
+                    .with_context(|| format!("Failed to read metadata of `{0}`", file.display()))?
+                    .len();
+                // Only files of the right size are worth hashing
+                let matches = size == entry.size && hash_file(&file)? == entry.hash;
+                Ok((path, matches))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for (path, matches) in compared {
+            match matches {
+                true => diff.matched += 1,
+                false => diff.mismatched.push(path),
+            }
+        }
+        Ok(diff)
+    }
+
+    /// The directory an older manifest's absolute paths were recorded under. Paths were recorded
+    /// as the mirror was walked, so this is the deepest directory all of them share, or whichever of its
+    /// ancestors leaves the most of them pointing at files in `mirror_path` (in case every file was
+    /// in one subdirectory)
+    fn recorded_root(&self, mirror_path: &Path) -> PathBuf {
+        let Some(common) = self
+            .entries
+            .iter()
+            .filter_map(|entry| entry.path.parent())
+            .map(Path::to_path_buf)
+            .reduce(|common, parent| {
+                common
+                    .ancestors()
+                    .find(|ancestor| parent.starts_with(ancestor))
+                    .map(Path::to_path_buf)
+                    .unwrap_or_default()
+            })
+        else {
+            return PathBuf::new();
+        };
+
+        let candidates = common.ancestors().collect::<Vec<_>>();
+        candidates
+            .into_iter()
+            .rev()
+            .max_by_key(|candidate| {
+                self.entries
+                    .iter()
+                    .filter_map(|entry| entry.path.strip_prefix(candidate).ok())
+                    .filter(|relative| mirror_path.join(relative).is_file())
+                    .count()
+            })
+            .map(Path::to_path_buf)
+            .unwrap_or_default()
+    }
+}
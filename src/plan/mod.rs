@@ -0,0 +1,97 @@
+use crate::database::{hash_file, Database, SyncChanges};
+use anyhow::{Context, Result};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+struct PlanEntry {
+    path: PathBuf,
+    /// `+` to add, `~` to change or `-` to remove
+    change: char,
+    hash: String,
+}
+
+struct DatabasePlan {
+    database_path: PathBuf,
+    entries: Vec<PlanEntry>,
+}
+
+/// What syncing one or more databases would change, written out as a stable, sorted text file for
+/// reviewing (or diffing) before syncing. Each database's section starts with `# {database}`,
+/// followed by a `{change} {hash}  {path}` line per file, where paths are relative to the
+/// database and hashes are of the new source file, or of the mirror file to be removed.
+#[derive(Default)]
+pub struct Plan {
+    databases: Vec<DatabasePlan>,
+}
+
+impl Plan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_database(
+        &mut self,
+        database_path: &Path,
+        database: &Database,
+        changes: &SyncChanges,
+        mirror_root: &Path,
+    ) -> Result<()> {
+        let planned = |change, paths: &[PathBuf]| {
+            paths
+                .iter()
+                .map(|path| PlanEntry {
+                    path: path.clone(),
+                    change,
+                    hash: changes.hashes.get(path).cloned().unwrap_or_default(),
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut entries = planned('+', &changes.added);
+        entries.extend(planned('~', &changes.changed));
+        for removed in &changes.removed {
+            entries.push(PlanEntry {
+                path: database.mirror_path().join(removed),
+                change: '-',
+                hash: hash_file(&mirror_root.join(removed))?,
+            });
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path).then(a.change.cmp(&b.change)));
+
+        self.databases.push(DatabasePlan {
+            database_path: database_path.to_path_buf(),
+            entries,
+        });
+        Ok(())
+    }
+
+    pub fn write(&mut self, file_path: &Path) -> Result<()> {
+        self.databases
+            .sort_by(|a, b| a.database_path.cmp(&b.database_path));
+
+        let file = File::create(file_path)
+            .with_context(|| format!("Failed to open {0} for writing", file_path.display()))?;
+        let mut writer = BufWriter::new(file);
+        self.write_entries(&mut writer)
+            .and_then(|_| writer.flush())
+            .with_context(|| format!("Failed to write plan `{0}`", file_path.display()))
+    }
+
+    fn write_entries(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        for database in &self.databases {
+            writeln!(writer, "# {0}", database.database_path.display())?;
+            for entry in &database.entries {
+                writeln!(
+                    writer,
+                    "{0} {1}  {2}",
+                    entry.change,
+                    entry.hash,
+                    entry.path.display()
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+use anyhow::Result;
+
+/// Lowers the CPU priority (`nice`) and, optionally, the IO priority of the whole process, so a
+/// sync yields to interactive work. Threads and filters started afterwards inherit both.
+pub fn lower_priority(nice: Option<i32>, idle_io: bool) -> Result<()> {
+    if let Some(nice) = nice {
+        set_nice(nice)?;
+    }
+    if idle_io {
+        set_idle_io()?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_nice(nice: i32) -> Result<()> {
+    use anyhow::Context;
+    use std::io;
+
+    // SAFETY: Only reads the calling process's own priority. It can legitimately be -1, so
+    // `errno` is cleared first to tell that apart from an error.
+    let current = unsafe {
+        *libc::__errno_location() = 0;
+        libc::getpriority(libc::PRIO_PROCESS, 0)
+    };
+    let error = io::Error::last_os_error();
+    if current == -1 && error.raw_os_error() != Some(0) {
+        return Err(error).with_context(|| "Failed to read the current nice level");
+    }
+
+    // Out of range levels are clamped by the kernel
+    let lowered = current.saturating_add(nice);
+    // SAFETY: Only affects the calling process, which is still single threaded at startup
+    let result = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, lowered) };
+    if result != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("Failed to lower nice level from {current} to {lowered}"));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_idle_io() -> Result<()> {
+    use anyhow::Context;
+    use std::io;
+
+    // From `linux/ioprio.h`
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    // SAFETY: `ioprio_set` takes plain integers, and only affects the calling process
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_ioprio_set,
+            IOPRIO_WHO_PROCESS,
+            0,
+            IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error()).with_context(|| "Failed to set idle IO priority");
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_nice(_nice: i32) -> Result<()> {
+    log::warn!("`--nice` is only supported on Linux, ignoring...");
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_idle_io() -> Result<()> {
+    log::warn!("`--idle-io` is only supported on Linux, ignoring...");
+    Ok(())
+}
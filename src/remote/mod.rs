@@ -0,0 +1,425 @@
+use crate::database::{hash_reader, home_dir, temp_file_name};
+use anyhow::{bail, Context, Result};
+use ssh2::{CheckResult, FileStat, KnownHostFileKind, RenameFlags, Session, Sftp};
+use std::{
+    env, fmt,
+    fs::File,
+    io::{self, ErrorKind, Read},
+    net::TcpStream,
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// URL schemes of mirrors on another host, reached over SFTP
+const REMOTE_SCHEMES: &[&str] = &["ssh", "sftp"];
+
+const DEFAULT_PORT: u16 = 22;
+
+/// Keys tried in turn when the SSH agent can't authenticate, as `ssh` does
+const IDENTITY_FILES: &[&str] = &["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// A mirror on another host, given as `ssh://[user@]host[:port]/path`. A path starting `/~/` is
+/// relative to the user's home directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RemoteMirror {
+    user: Option<String>,
+    host: String,
+    port: u16,
+    // Without trailing slashes, relative to the home directory unless it starts with `/`
+    root: String,
+    url: String,
+}
+
+impl RemoteMirror {
+    /// The remote mirror `mirror_path` names, `None` if it's a local path
+    pub fn parse(mirror_path: &Path) -> Result<Option<Self>> {
+        let Some(url) = mirror_path.to_str() else {
+            return Ok(None);
+        };
+        let Some((scheme, rest)) = url.split_once("://") else {
+            return Ok(None);
+        };
+        if !REMOTE_SCHEMES.contains(&scheme) {
+            bail!("Mirror `{url}` is a `{scheme}://` URL, but only local directories and `ssh://` URLs can be mirrored to");
+        }
+
+        let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let (user, host_port) = match authority.rsplit_once('@') {
+            Some((user, host_port)) => (Some(user.to_string()), host_port),
+            None => (None, authority),
+        };
+        // IPv6 addresses are bracketed, so their colons aren't taken for a port
+        let port_start = match host_port.strip_prefix('[') {
+            Some(bracketed) => bracketed.find(']').map(|end| end + 2),
+            None => host_port.find(':'),
+        };
+        let (host, port) = match port_start.filter(|start| *start < host_port.len()) {
+            Some(start) => {
+                let port = host_port[start..].trim_start_matches(':');
+                let port = port
+                    .parse()
+                    .with_context(|| format!("Invalid port `{port}` in mirror `{url}`"))?;
+                (&host_port[..start], port)
+            }
+            None => (host_port, DEFAULT_PORT),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || user.as_deref() == Some("") {
+            bail!("Mirror `{url}` should be given as `{scheme}://[user@]host[:port]/path`");
+        }
+
+        let path = path.trim_end_matches('/');
+        let root = match path.strip_prefix("/~") {
+            Some(home_relative) => home_relative.trim_start_matches('/'),
+            None => path,
+        };
+        if root.is_empty() {
+            bail!("Mirror `{url}` doesn't name a directory on `{host}` to mirror to");
+        }
+
+        Ok(Some(Self {
+            user,
+            host: host.to_string(),
+            port,
+            root: root.to_string(),
+            url: format!("{scheme}://{authority}{path}"),
+        }))
+    }
+
+    /// The URL of an entry of the mirror, for messages
+    pub fn url_of(&self, relative_entry: &Path) -> String {
+        let mut url = self.url.clone();
+        for name in normal_names(relative_entry) {
+            url.push('/');
+            url.push_str(&name);
+        }
+        url
+    }
+
+    /// Connects to the host, which must already be in `~/.ssh/known_hosts`, authenticating with
+    /// the SSH agent or a key in `~/.ssh`
+    pub fn connect(&self) -> Result<SftpFiles> {
+        let user = match &self.user {
+            Some(user) => user.clone(),
+            None => env::var("USER")
+                .or_else(|_| env::var("USERNAME"))
+                .with_context(|| format!("No user given in `{self}`, and `$USER` isn't set"))?,
+        };
+        let stream = TcpStream::connect((self.host.as_str(), self.port))
+            .with_context(|| format!("Failed to connect to `{0}:{1}`", self.host, self.port))?;
+        let mut session = Session::new().with_context(|| "Failed to start an SSH session")?;
+        session.set_tcp_stream(stream);
+        session
+            .handshake()
+            .with_context(|| format!("SSH handshake with `{0}` failed", self.host))?;
+        self.check_host_key(&session)?;
+        self.authenticate(&session, &user)?;
+
+        let sftp = session
+            .sftp()
+            .with_context(|| format!("Failed to start SFTP on `{0}`", self.host))?;
+        Ok(SftpFiles {
+            sftp,
+            root: self.root.clone(),
+        })
+    }
+
+    /// Refuses hosts whose key isn't already known, rather than trusting whoever answers
+    fn check_host_key(&self, session: &Session) -> Result<()> {
+        let mut known_hosts = session
+            .known_hosts()
+            .with_context(|| "Failed to read known hosts")?;
+        let known_hosts_path = home_dir()
+            .map(|home| home.join(".ssh").join("known_hosts"))
+            .filter(|path| path.is_file());
+        if let Some(path) = &known_hosts_path {
+            known_hosts
+                .read_file(path, KnownHostFileKind::OpenSSH)
+                .with_context(|| format!("Failed to read `{0}`", path.display()))?;
+        }
+        let (key, _) = session
+            .host_key()
+            .with_context(|| format!("`{0}` sent no host key", self.host))?;
+
+        match known_hosts.check_port(&self.host, self.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::NotFound => bail!(
+                "`{0}` isn't in `~/.ssh/known_hosts`, connect to it with `ssh` once to check and accept its key",
+                self.host
+            ),
+            CheckResult::Mismatch => bail!(
+                "The host key of `{0}` doesn't match the one in `~/.ssh/known_hosts`, refusing to connect",
+                self.host
+            ),
+            CheckResult::Failure => bail!("Failed to check the host key of `{0}`", self.host),
+        }
+    }
+
+    fn authenticate(&self, session: &Session, user: &str) -> Result<()> {
+        if session.userauth_agent(user).is_ok() && session.authenticated() {
+            return Ok(());
+        }
+        let ssh_dir = home_dir().map(|home| home.join(".ssh"));
+        let identities = ssh_dir
+            .iter()
+            .flat_map(|ssh_dir| IDENTITY_FILES.iter().map(|name| ssh_dir.join(name)))
+            .filter(|identity| identity.is_file());
+        for identity in identities {
+            if session
+                .userauth_pubkey_file(user, None, &identity, None)
+                .is_ok()
+                && session.authenticated()
+            {
+                return Ok(());
+            }
+        }
+        bail!(
+            "Failed to authenticate to `{0}` as `{user}`, add a key to `ssh-agent` or an unencrypted one to `~/.ssh`",
+            self.host
+        )
+    }
+}
+
+impl fmt::Display for RemoteMirror {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{0}", self.url)
+    }
+}
+
+/// A file or directory in a remote mirror
+pub struct RemoteEntry {
+    /// Relative to the mirror root
+    pub path: PathBuf,
+    pub is_dir: bool,
+    pub size: u64,
+}
+
+/// Files of a remote mirror, with every path relative to its root
+pub trait RemoteFiles: Send + Sync {
+    /// Every file and directory in the mirror, nothing if the mirror root doesn't exist yet
+    fn list(&self) -> io::Result<Vec<RemoteEntry>>;
+
+    fn open(&self, relative_entry: &Path) -> io::Result<Box<dyn Read + '_>>;
+
+    /// Creates a directory and any of its parents (including the mirror root) which are missing
+    fn create_dir_all(&self, relative_entry: &Path) -> io::Result<()>;
+
+    /// Writes the local file `local` to `relative_entry` through a temporary file renamed into
+    /// place, with the given access and modification times
+    fn upload(
+        &self,
+        local: &Path,
+        relative_entry: &Path,
+        times: (SystemTime, SystemTime),
+    ) -> io::Result<()>;
+
+    fn remove_file(&self, relative_entry: &Path) -> io::Result<()>;
+
+    fn remove_dir(&self, relative_entry: &Path) -> io::Result<()>;
+
+    /// The contents of a file, `None` if there isn't one
+    fn read(&self, relative_entry: &Path) -> io::Result<Option<Vec<u8>>> {
+        let mut file = match self.open(relative_entry) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        Ok(Some(contents))
+    }
+
+    /// Hashes a file as [`crate::database::hash_file`] does a local one, reading it on the host
+    fn hash_file(&self, relative_entry: &Path) -> io::Result<String> {
+        hash_reader(&mut self.open(relative_entry)?)
+    }
+}
+
+/// A remote mirror's files, over SFTP
+pub struct SftpFiles {
+    sftp: Sftp,
+    root: String,
+}
+
+impl SftpFiles {
+    /// The path on the host of an entry of the mirror, always separated by `/`
+    fn path(&self, relative_entry: &Path) -> PathBuf {
+        let mut path = self.root.clone();
+        for name in normal_names(relative_entry) {
+            path.push('/');
+            path.push_str(&name);
+        }
+        PathBuf::from(path)
+    }
+
+    /// Moves an uploaded temporary file over `path`. Servers speaking SFTP version 3 (OpenSSH's)
+    /// won't rename over an existing file, so it's removed first for them.
+    fn replace(&self, temp_path: &Path, path: &Path) -> io::Result<()> {
+        let flags = RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE;
+        if self.sftp.rename(temp_path, path, Some(flags)).is_ok() {
+            return Ok(());
+        }
+        if self.sftp.lstat(path).is_ok() {
+            self.sftp.unlink(path)?;
+        }
+        Ok(self.sftp.rename(temp_path, path, Some(flags))?)
+    }
+}
+
+impl RemoteFiles for SftpFiles {
+    fn list(&self) -> io::Result<Vec<RemoteEntry>> {
+        let mut entries = Vec::new();
+        let mut dirs = vec![PathBuf::new()];
+        while let Some(dir) = dirs.pop() {
+            let listed = match self.sftp.readdir(self.path(&dir)) {
+                Ok(listed) => listed,
+                Err(e) => match io::Error::from(e) {
+                    e if e.kind() == ErrorKind::NotFound && dir.as_os_str().is_empty() => {
+                        return Ok(Vec::new())
+                    }
+                    e => return Err(e),
+                },
+            };
+            for (path, stat) in listed {
+                let Some(name) = path.file_name() else {
+                    continue;
+                };
+                let relative_entry = dir.join(name);
+                // Symlinks are listed as files, so they're removed rather than followed
+                let is_dir = stat.is_dir();
+                if is_dir {
+                    dirs.push(relative_entry.clone());
+                }
+                entries.push(RemoteEntry {
+                    path: relative_entry,
+                    is_dir,
+                    size: stat.size.unwrap_or_default(),
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn open(&self, relative_entry: &Path) -> io::Result<Box<dyn Read + '_>> {
+        Ok(Box::new(self.sftp.open(self.path(relative_entry))?))
+    }
+
+    fn create_dir_all(&self, relative_entry: &Path) -> io::Result<()> {
+        let path = self.path(relative_entry).to_string_lossy().into_owned();
+        if self.sftp.stat(Path::new(&path)).is_ok() {
+            return Ok(());
+        }
+        let mut partial = String::new();
+        for (index, name) in path.split('/').enumerate() {
+            if index > 0 {
+                partial.push('/');
+            }
+            partial.push_str(name);
+            if name.is_empty() || self.sftp.stat(Path::new(&partial)).is_ok() {
+                continue;
+            }
+            self.sftp.mkdir(Path::new(&partial), 0o755)?;
+        }
+        Ok(())
+    }
+
+    fn upload(
+        &self,
+        local: &Path,
+        relative_entry: &Path,
+        (accessed, modified): (SystemTime, SystemTime),
+    ) -> io::Result<()> {
+        let path = self.path(relative_entry);
+        let file_name = relative_entry.file_name().unwrap_or_default();
+        let temp_path = self.path(&relative_entry.with_file_name(temp_file_name(file_name)));
+        let seconds = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs())
+        };
+
+        let result = (|| -> io::Result<()> {
+            let mut remote_file = self.sftp.create(&temp_path)?;
+            io::copy(&mut File::open(local)?, &mut remote_file)?;
+            // Only servers with OpenSSH's extension can flush to disk, others are left to it
+            let _ = remote_file.fsync();
+            remote_file.setstat(FileStat {
+                size: None,
+                uid: None,
+                gid: None,
+                perm: None,
+                atime: Some(seconds(accessed)),
+                mtime: Some(seconds(modified)),
+            })?;
+            drop(remote_file);
+            self.replace(&temp_path, &path)
+        })();
+        if result.is_err() {
+            let _ = self.sftp.unlink(&temp_path);
+        }
+        result
+    }
+
+    fn remove_file(&self, relative_entry: &Path) -> io::Result<()> {
+        Ok(self.sftp.unlink(&self.path(relative_entry))?)
+    }
+
+    fn remove_dir(&self, relative_entry: &Path) -> io::Result<()> {
+        Ok(self.sftp.rmdir(&self.path(relative_entry))?)
+    }
+}
+
+/// The names making up a relative path, skipping anything but plain names
+fn normal_names(relative_entry: &Path) -> impl Iterator<Item = String> + '_ {
+    relative_entry
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RemoteMirror;
+    use std::path::Path;
+
+    fn parse(url: &str) -> Option<RemoteMirror> {
+        RemoteMirror::parse(Path::new(url)).unwrap()
+    }
+
+    #[test]
+    fn ssh_urls_are_parsed() {
+        let mirror = parse("ssh://backup@nas.local:2222/srv/mirror/").unwrap();
+        assert_eq!(mirror.user.as_deref(), Some("backup"));
+        assert_eq!(mirror.host, "nas.local");
+        assert_eq!(mirror.port, 2222);
+        assert_eq!(mirror.root, "/srv/mirror");
+        assert_eq!(
+            mirror.url_of(Path::new("music/a.mp3")),
+            "ssh://backup@nas.local:2222/srv/mirror/music/a.mp3"
+        );
+
+        let mirror = parse("sftp://[::1]/~/mirror").unwrap();
+        assert_eq!(mirror.user, None);
+        assert_eq!(mirror.host, "::1");
+        assert_eq!(mirror.port, 22);
+        assert_eq!(mirror.root, "mirror");
+        assert_eq!(mirror.to_string(), "sftp://[::1]/~/mirror");
+    }
+
+    #[test]
+    fn local_paths_and_bad_urls_are_told_apart() {
+        assert_eq!(parse("mirror"), None);
+        assert_eq!(parse("/mnt/ssh:/mirror"), None);
+        for url in [
+            "ftp://host/mirror",
+            "ssh://host",
+            "ssh://host/",
+            "ssh://host/~",
+            "ssh://@host/mirror",
+            "ssh://host:port/mirror",
+        ] {
+            assert!(RemoteMirror::parse(Path::new(url)).is_err(), "{url}");
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use crate::database::{Database, SyncChanges, SyncStats};
+use anyhow::{Context, Result};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+struct DatabaseReport {
+    database_path: PathBuf,
+    stats: SyncStats,
+    changes: SyncChanges,
+    failed: Vec<PathBuf>,
+}
+
+/// A human-readable Markdown summary of a sync, listing what changed in each database's mirror
+pub struct Report {
+    started: Instant,
+    databases: Vec<DatabaseReport>,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            databases: Vec::new(),
+        }
+    }
+
+    pub fn add_database(&mut self, database_path: &Path, database: &Database, stats: &SyncStats) {
+        self.databases.push(DatabaseReport {
+            database_path: database_path.to_path_buf(),
+            stats: stats.clone(),
+            changes: database.changes().clone(),
+            failed: database.failed().iter().cloned().collect(),
+        });
+    }
+
+    pub fn write(&self, file_path: &Path) -> Result<()> {
+        let file = File::create(file_path)
+            .with_context(|| format!("Failed to open {0} for writing", file_path.display()))?;
+        let mut writer = BufWriter::new(file);
+        self.write_markdown(&mut writer)
+            .and_then(|_| writer.flush())
+            .with_context(|| format!("Failed to write report `{0}`", file_path.display()))
+    }
+
+    /// A one line summary of the totals across every database, for printing once a sync ends
+    pub fn summary(&self) -> String {
+        let total = |field| self.total(field);
+        format!(
+            "Synced {0} databases in {1:.2}s: {2} files, {3} added, {4} changed, {5} removed, {6} failed, {7} bytes copied, {8} bytes skipped",
+            self.databases.len(),
+            self.started.elapsed().as_secs_f64(),
+            total(|database| database.stats.files as u64),
+            total(|database| database.changes.added.len() as u64),
+            total(|database| database.changes.changed.len() as u64),
+            total(|database| database.changes.removed.len() as u64),
+            total(|database| database.failed.len() as u64),
+            total(|database| database.stats.bytes_copied),
+            total(|database| database.stats.bytes_skipped)
+        )
+    }
+
+    fn total(&self, field: fn(&DatabaseReport) -> u64) -> u64 {
+        self.databases.iter().map(field).sum()
+    }
+
+    fn write_markdown(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let total = |field| self.total(field);
+
+        writeln!(writer, "# MirrorMan Sync Report")?;
+        writeln!(writer)?;
+        writeln!(writer, "- Databases: {0}", self.databases.len())?;
+        writeln!(
+            writer,
+            "- Files: {0}",
+            total(|database| database.stats.files as u64)
+        )?;
+        writeln!(
+            writer,
+            "- Added: {0}, changed: {1}, removed: {2}, failed: {3}",
+            total(|database| database.changes.added.len() as u64),
+            total(|database| database.changes.changed.len() as u64),
+            total(|database| database.changes.removed.len() as u64),
+            total(|database| database.failed.len() as u64)
+        )?;
+        writeln!(
+            writer,
+            "- Bytes copied: {0}",
+            total(|database| database.stats.bytes_copied)
+        )?;
+        writeln!(
+            writer,
+            "- Bytes skipped: {0}",
+            total(|database| database.stats.bytes_skipped)
+        )?;
+        writeln!(
+            writer,
+            "- Duration: {0:.2}s",
+            self.started.elapsed().as_secs_f64()
+        )?;
+
+        for database in &self.databases {
+            writeln!(writer)?;
+            writeln!(writer, "## `{0}`", database.database_path.display())?;
+            writeln!(writer)?;
+            writeln!(writer, "{0}", database.stats)?;
+            Self::write_section(writer, "Added", &database.changes.added)?;
+            Self::write_section(writer, "Changed", &database.changes.changed)?;
+            Self::write_section(writer, "Removed", &database.changes.removed)?;
+            Self::write_section(writer, "Failed", &database.failed)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_section(
+        writer: &mut impl Write,
+        title: &str,
+        paths: &[PathBuf],
+    ) -> std::io::Result<()> {
+        writeln!(writer)?;
+        writeln!(writer, "### {title} ({0})", paths.len())?;
+        writeln!(writer)?;
+        if paths.is_empty() {
+            writeln!(writer, "None.")?;
+        }
+        for path in paths {
+            writeln!(writer, "- `{0}`", path.display())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use std::path::Path;
+
+/// Matches `path` against a glob `pattern`.
+///
+/// Supports `*` (any run of characters within a path segment), `?` (a single character within a
+/// segment) and `**` (any number of whole segments). Patterns without a `/` are matched against
+/// the file name only, so `*.txt` matches text files at any depth.
+pub fn glob_match(pattern: &str, path: &Path) -> bool {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let subject = if pattern.contains('/') {
+        path.trim_start_matches("./")
+    } else {
+        path.rsplit('/').next().unwrap_or_default()
+    };
+
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let subject = subject.chars().collect::<Vec<_>>();
+    Matcher {
+        failed: vec![false; (pattern.len() + 1) * (subject.len() + 1)],
+        pattern: &pattern,
+        subject: &subject,
+    }
+    .match_from(0, 0)
+}
+
+/// Remembers which positions in the pattern and subject are known not to match from, so patterns
+/// with many wildcards take at most `pattern × subject` steps rather than backtracking
+/// exponentially
+struct Matcher<'a> {
+    pattern: &'a [char],
+    subject: &'a [char],
+    // Index = Pattern position × (subject length + 1) + subject position
+    failed: Vec<bool>,
+}
+
+impl Matcher<'_> {
+    fn match_from(&mut self, p: usize, s: usize) -> bool {
+        let state = p * (self.subject.len() + 1) + s;
+        if self.failed[state] {
+            return false;
+        }
+
+        let subject = &self.subject[s..];
+        let matched = match self.pattern[p..] {
+            [] => subject.is_empty(),
+            ['*', '*', '/', ..] => {
+                self.match_from(p + 3, s)
+                    || (s..self.subject.len())
+                        .filter(|i| self.subject[*i] == '/')
+                        .any(|i| self.match_from(p + 3, i + 1))
+            }
+            ['*', '*', ..] => (s..=self.subject.len()).any(|i| self.match_from(p + 2, i)),
+            ['*', ..] => (s..=self.subject.len())
+                .take_while(|i| *i == s || self.subject[i - 1] != '/')
+                .any(|i| self.match_from(p + 1, i)),
+            ['?', ..] => match subject {
+                [c, ..] if *c != '/' => self.match_from(p + 1, s + 1),
+                _ => false,
+            },
+            [expected, ..] => match subject {
+                [c, ..] if *c == expected => self.match_from(p + 1, s + 1),
+                _ => false,
+            },
+        };
+        if !matched {
+            self.failed[state] = true;
+        }
+        matched
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+    use std::path::Path;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        glob_match(pattern, Path::new(path))
+    }
+
+    #[test]
+    fn stars_stay_within_a_segment() {
+        assert!(matches("*.txt", "notes.txt"));
+        assert!(matches("*", "anything"));
+        assert!(matches("a*b*c", "aXbYc"));
+        assert!(!matches("*.txt", "notes.md"));
+        assert!(matches("docs/*.txt", "docs/notes.txt"));
+        assert!(!matches("docs/*.txt", "docs/nested/notes.txt"));
+        assert!(!matches("docs*", "docs/notes.txt"));
+    }
+
+    #[test]
+    fn double_stars_span_segments() {
+        assert!(matches("docs/**/*.txt", "docs/notes.txt"));
+        assert!(matches("docs/**/*.txt", "docs/a/b/notes.txt"));
+        assert!(matches("**/build/**", "src/build/out.o"));
+        assert!(matches("docs/**", "docs/a/b"));
+        assert!(!matches("docs/**/*.txt", "other/docs/notes.txt"));
+    }
+
+    #[test]
+    fn question_marks_match_one_character() {
+        assert!(matches("file?.txt", "file1.txt"));
+        assert!(!matches("file?.txt", "file.txt"));
+        assert!(!matches("file?.txt", "file12.txt"));
+        assert!(!matches("a?b", "a/b"));
+    }
+
+    #[test]
+    fn patterns_with_slashes_are_anchored() {
+        // Without a slash, only the file name is matched, at any depth
+        assert!(matches("*.txt", "a/b/notes.txt"));
+        assert!(matches("notes.txt", "a/notes.txt"));
+        // With one, the whole path is matched from the root
+        assert!(matches("a/notes.txt", "a/notes.txt"));
+        assert!(matches("a/notes.txt", "./a/notes.txt"));
+        assert!(!matches("a/notes.txt", "b/a/notes.txt"));
+        assert!(!matches("a/notes", "a/notes.txt"));
+    }
+
+    #[test]
+    fn backslashes_are_separators() {
+        assert!(matches("a/*.txt", "a\\notes.txt"));
+        assert!(matches("*.txt", "a\\b\\notes.txt"));
+        assert!(!matches("a/*", "a\\b\\notes.txt"));
+    }
+
+    #[test]
+    fn many_wildcards_dont_backtrack_exponentially() {
+        let pattern = "*a".repeat(30) + "b";
+        let subject = "a".repeat(200);
+        assert!(!matches(&pattern, &subject));
+        let pattern = "**/".repeat(20) + "x";
+        let subject = "a/".repeat(100) + "y";
+        assert!(!matches(&pattern, &subject));
+    }
+}
@@ -0,0 +1,134 @@
+mod glob;
+
+pub use glob::*;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// A built-in, in-process transform, cheap enough to apply without spawning a filter.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransformKind {
+    /// Converts `\r\n` line endings to `\n`
+    CrlfToLf,
+    /// Removes a leading UTF-8 byte order mark
+    StripBom,
+    /// Lowercases the extension of the mirrored file
+    LowercaseExt,
+}
+
+impl TransformKind {
+    fn is_content_transform(self) -> bool {
+        !matches!(self, Self::LowercaseExt)
+    }
+}
+
+impl FromStr for TransformKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "crlf-to-lf" => Ok(Self::CrlfToLf),
+            "strip-bom" => Ok(Self::StripBom),
+            "lowercase-ext" => Ok(Self::LowercaseExt),
+            _ => bail!(
+                "Unknown transform `{s}`, expected one of `crlf-to-lf`, `strip-bom` or `lowercase-ext`"
+            ),
+        }
+    }
+}
+
+/// A set of transforms applied to every source file matching `pattern`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Transform {
+    pub pattern: String,
+    pub kinds: Vec<TransformKind>,
+}
+
+impl FromStr for Transform {
+    type Err = anyhow::Error;
+
+    /// Parses `{glob}={transform}[,{transform}...]`, e.g. `*.txt=crlf-to-lf,strip-bom`
+    fn from_str(s: &str) -> Result<Self> {
+        let (pattern, kinds) = s.rsplit_once('=').with_context(|| {
+            format!("Invalid transform `{s}`, expected `{{glob}}={{transform}}`")
+        })?;
+        let kinds = kinds
+            .split(',')
+            .map(TransformKind::from_str)
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            pattern: pattern.to_owned(),
+            kinds,
+        })
+    }
+}
+
+pub fn find_transforms_for_entry(
+    relative_entry: &Path,
+    transforms: &[Transform],
+) -> Vec<TransformKind> {
+    let mut kinds = Vec::new();
+    transforms
+        .iter()
+        .filter(|transform| glob_match(&transform.pattern, relative_entry))
+        .flat_map(|transform| transform.kinds.iter())
+        .for_each(|kind| {
+            if !kinds.contains(kind) {
+                kinds.push(*kind);
+            }
+        });
+    kinds
+}
+
+pub fn apply_path_transforms(kinds: &[TransformKind], mirror_entry: &mut PathBuf) {
+    if kinds.contains(&TransformKind::LowercaseExt) {
+        if let Some(ext) = mirror_entry.extension() {
+            let ext = ext.to_string_lossy().to_lowercase();
+            mirror_entry.set_extension(ext);
+        }
+    }
+}
+
+pub fn has_content_transforms(kinds: &[TransformKind]) -> bool {
+    kinds.iter().any(|kind| kind.is_content_transform())
+}
+
+pub fn run_transforms_for_entry(
+    source_entry: &Path,
+    mirror_entry: &Path,
+    kinds: &[TransformKind],
+) -> Result<()> {
+    let mut contents = fs::read(source_entry)
+        .with_context(|| format!("Failed to read `{0}`", source_entry.display()))?;
+
+    for kind in kinds {
+        match kind {
+            TransformKind::CrlfToLf => {
+                let mut converted = Vec::with_capacity(contents.len());
+                for (i, b) in contents.iter().enumerate() {
+                    if *b == b'\r' && contents.get(i + 1) == Some(&b'\n') {
+                        continue;
+                    }
+                    converted.push(*b);
+                }
+                contents = converted;
+            }
+            TransformKind::StripBom => {
+                if contents.starts_with(&[0xEF, 0xBB, 0xBF]) {
+                    contents.drain(..3);
+                }
+            }
+            TransformKind::LowercaseExt => {}
+        }
+    }
+
+    fs::write(mirror_entry, contents)
+        .with_context(|| format!("Failed to write `{0}`", mirror_entry.display()))
+}
@@ -0,0 +1,29 @@
+use anyhow::{anyhow, Result};
+use walkdir::{DirEntry, Error};
+
+/// Friendlier errors for directory walks, naming the offending path and how deep it was
+pub trait WalkdirResultExtension {
+    fn handle_to_string(self) -> Result<DirEntry>;
+}
+
+impl WalkdirResultExtension for walkdir::Result<DirEntry> {
+    fn handle_to_string(self) -> Result<DirEntry> {
+        self.map_err(|e| anyhow!(describe_walk_error(&e)))
+    }
+}
+
+pub fn describe_walk_error(e: &Error) -> String {
+    let reason = match (e.io_error(), e.loop_ancestor()) {
+        (Some(io_error), _) => io_error.to_string(),
+        (None, Some(ancestor)) => format!("symlink loop back to `{0}`", ancestor.display()),
+        (None, None) => e.to_string(),
+    };
+    match e.path() {
+        Some(path) => format!(
+            "Failed to walk `{0}` (depth {1}): {reason}",
+            path.display(),
+            e.depth()
+        ),
+        None => format!("Failed to walk directory (depth {0}): {reason}", e.depth()),
+    }
+}